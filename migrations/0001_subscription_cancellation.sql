@@ -0,0 +1,18 @@
+-- Отмена подписки: немедленная или в конце оплаченного периода
+ALTER TABLE subscriptions
+    ADD COLUMN IF NOT EXISTS cancel_at_period_end BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN IF NOT EXISTS canceled_at TIMESTAMPTZ;
+
+-- Опрос о причинах отмены (для аналитики оттока)
+CREATE TABLE IF NOT EXISTS subscription_cancellations (
+    id UUID PRIMARY KEY,
+    subscription_id UUID NOT NULL REFERENCES subscriptions(id),
+    user_id UUID NOT NULL REFERENCES users(id),
+    reason TEXT NOT NULL,
+    comment TEXT,
+    immediate BOOLEAN NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_subscription_cancellations_reason
+    ON subscription_cancellations (reason, created_at);
@@ -0,0 +1,17 @@
+-- Автопродление подписок
+ALTER TABLE subscriptions
+    ADD COLUMN IF NOT EXISTS auto_renew BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN IF NOT EXISTS last_renewal_attempt_at TIMESTAMPTZ;
+
+-- Сохранённые платёжные методы (токены платёжного провайдера)
+CREATE TABLE IF NOT EXISTS payment_methods (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id),
+    token TEXT NOT NULL,
+    is_default BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_payment_methods_user ON payment_methods (user_id, is_default);
+CREATE INDEX IF NOT EXISTS idx_subscriptions_renewal
+    ON subscriptions (expires_at) WHERE is_active = true AND auto_renew = true;
@@ -0,0 +1,23 @@
+-- Повторные списания и льготный период после неудачного продления
+ALTER TABLE subscriptions
+    ADD COLUMN IF NOT EXISTS billing_status TEXT NOT NULL DEFAULT 'ok', -- ok | past_due | expired
+    ADD COLUMN IF NOT EXISTS dunning_started_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS dunning_attempts INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS next_retry_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS grace_until TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS expiration_reason TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_subscriptions_dunning
+    ON subscriptions (next_retry_at) WHERE billing_status = 'past_due';
+
+-- Исходящие уведомления пользователям (outbox, доставляется отдельным отправителем)
+CREATE TABLE IF NOT EXISTS notifications (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id),
+    kind TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    sent_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_notifications_unsent ON notifications (created_at) WHERE sent_at IS NULL;
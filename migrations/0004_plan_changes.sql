@@ -0,0 +1,19 @@
+-- Смена тарифа: понижение применяется в конце периода
+ALTER TABLE subscriptions
+    ADD COLUMN IF NOT EXISTS scheduled_plan_id TEXT;
+
+-- Закрываем истёкшие и дублирующиеся активные подписки, оставляя самую позднюю
+UPDATE subscriptions SET is_active = false, billing_status = 'expired', expiration_reason = 'lapsed'
+WHERE is_active = true AND expires_at <= NOW()
+  AND NOT (billing_status = 'past_due' AND grace_until > NOW());
+
+UPDATE subscriptions s SET is_active = false, expiration_reason = 'duplicate'
+WHERE s.is_active = true AND EXISTS (
+    SELECT 1 FROM subscriptions o
+    WHERE o.user_id = s.user_id AND o.is_active = true
+      AND (o.expires_at, o.id) > (s.expires_at, s.id)
+);
+
+-- Не более одной активной подписки на пользователя
+CREATE UNIQUE INDEX IF NOT EXISTS uniq_subscriptions_active_user
+    ON subscriptions (user_id) WHERE is_active = true;
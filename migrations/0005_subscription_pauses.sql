@@ -0,0 +1,17 @@
+-- Приостановка подписки: доступ и списания прекращаются до возобновления
+ALTER TABLE subscriptions
+    ADD COLUMN IF NOT EXISTS paused_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS pause_resumes_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS subscription_pauses (
+    id UUID PRIMARY KEY,
+    subscription_id UUID NOT NULL REFERENCES subscriptions(id),
+    user_id UUID NOT NULL REFERENCES users(id),
+    paused_at TIMESTAMPTZ NOT NULL,
+    resume_at TIMESTAMPTZ NOT NULL,
+    resumed_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_subscription_pauses_user ON subscription_pauses (user_id, paused_at);
+CREATE INDEX IF NOT EXISTS idx_subscriptions_pause_resume
+    ON subscriptions (pause_resumes_at) WHERE paused_at IS NOT NULL;
@@ -0,0 +1,3 @@
+-- Клиент у платёжного провайдера; payment_methods.token теперь хранит идентификатор
+-- привязанного платёжного метода, а не одноразовый токен
+ALTER TABLE users ADD COLUMN IF NOT EXISTS payment_customer_id TEXT;
@@ -0,0 +1,13 @@
+-- Входящие события платёжного провайдера (webhooks); id события — ключ дедупликации
+CREATE TABLE IF NOT EXISTS payment_events (
+    id TEXT PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    processed_at TIMESTAMPTZ,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_payment_events_unprocessed
+    ON payment_events (received_at) WHERE processed_at IS NULL;
+CREATE INDEX IF NOT EXISTS idx_users_payment_customer ON users (payment_customer_id);
@@ -0,0 +1,12 @@
+-- Идемпотентные запросы (заголовок Idempotency-Key); ключи действуют 24 часа
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    user_id UUID NOT NULL REFERENCES users(id),
+    idempotency_key TEXT NOT NULL,
+    request_hash TEXT NOT NULL,
+    status TEXT NOT NULL, -- in_progress | completed
+    response_status INTEGER,
+    response_body JSONB,
+    locked_until TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, idempotency_key)
+);
@@ -0,0 +1,76 @@
+-- Платежи: суммы в минимальных единицах валюты (центы, копейки)
+CREATE TABLE IF NOT EXISTS payments (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id),
+    subscription_id UUID REFERENCES subscriptions(id),
+    kind TEXT NOT NULL, -- purchase | renewal | upgrade
+    amount_minor BIGINT NOT NULL CHECK (amount_minor >= 0),
+    refunded_minor BIGINT NOT NULL DEFAULT 0 CHECK (refunded_minor >= 0),
+    currency TEXT NOT NULL,
+    status TEXT NOT NULL, -- pending | succeeded | failed | partially_refunded | refunded
+    provider_reference TEXT,
+    failure_reason TEXT,
+    description TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_payments_user ON payments (user_id, created_at DESC);
+CREATE UNIQUE INDEX IF NOT EXISTS uniq_payments_provider_reference
+    ON payments (provider_reference) WHERE provider_reference IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS payment_status_history (
+    id BIGSERIAL PRIMARY KEY,
+    payment_id UUID NOT NULL REFERENCES payments(id),
+    from_status TEXT,
+    to_status TEXT NOT NULL,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Журнал движения денег. Только добавление: charge (+), refund (-), credit (-, неденежная скидка)
+CREATE TABLE IF NOT EXISTS ledger_entries (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id),
+    payment_id UUID REFERENCES payments(id),
+    entry_type TEXT NOT NULL,
+    amount_minor BIGINT NOT NULL,
+    currency TEXT NOT NULL,
+    description TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ledger_entries_payment ON ledger_entries (payment_id);
+CREATE INDEX IF NOT EXISTS idx_ledger_entries_created ON ledger_entries (created_at);
+
+CREATE OR REPLACE FUNCTION forbid_ledger_mutation() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'ledger_entries is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS ledger_entries_append_only ON ledger_entries;
+CREATE TRIGGER ledger_entries_append_only
+    BEFORE UPDATE OR DELETE ON ledger_entries
+    FOR EACH ROW EXECUTE FUNCTION forbid_ledger_mutation();
+
+-- Сверка для финансов: денежные обороты по дням и валютам
+CREATE OR REPLACE VIEW finance_daily_ledger AS
+SELECT date_trunc('day', created_at) AS day, currency, entry_type,
+       COUNT(*) AS entries, SUM(amount_minor) AS amount_minor
+FROM ledger_entries
+GROUP BY 1, 2, 3;
+
+-- Сверка для финансов: платежи, чьи проводки не сходятся со статусом и суммами
+CREATE OR REPLACE VIEW finance_payment_discrepancies AS
+SELECT p.id AS payment_id, p.status, p.amount_minor, p.refunded_minor, p.provider_reference,
+       COALESCE(SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'charge'), 0) AS ledger_charged_minor,
+       COALESCE(-SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'refund'), 0) AS ledger_refunded_minor
+FROM payments p
+LEFT JOIN ledger_entries l ON l.payment_id = p.id
+GROUP BY p.id
+HAVING (p.status IN ('succeeded', 'partially_refunded', 'refunded')
+        AND COALESCE(SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'charge'), 0) <> p.amount_minor)
+    OR (p.status IN ('pending', 'failed')
+        AND COALESCE(SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'charge'), 0) <> 0)
+    OR COALESCE(-SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'refund'), 0) <> p.refunded_minor
+    OR (p.status = 'pending' AND p.created_at < NOW() - INTERVAL '1 day');
@@ -0,0 +1,5 @@
+-- Страна аккаунта (ISO 3166-1 alpha-2) для выбора валюты
+ALTER TABLE users ADD COLUMN IF NOT EXISTS country TEXT;
+
+-- Валюта фиксируется при покупке и не меняется до конца жизни подписки
+ALTER TABLE subscriptions ADD COLUMN IF NOT EXISTS currency TEXT NOT NULL DEFAULT 'USD';
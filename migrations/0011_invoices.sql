@@ -0,0 +1,48 @@
+-- Реквизиты покупателя для счетов
+ALTER TABLE users ADD COLUMN IF NOT EXISTS billing_name TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS billing_address TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS vat_id TEXT;
+
+-- Сквозная нумерация счетов по годам без пропусков: номер берётся в той же
+-- транзакции, что и вставка счёта
+CREATE TABLE IF NOT EXISTS invoice_sequences (
+    year INT PRIMARY KEY,
+    last_number BIGINT NOT NULL
+);
+
+-- Счёт — неизменяемый снимок реквизитов и сумм на момент оплаты
+CREATE TABLE IF NOT EXISTS invoices (
+    id UUID PRIMARY KEY,
+    number TEXT NOT NULL UNIQUE,
+    payment_id UUID NOT NULL UNIQUE REFERENCES payments(id),
+    user_id UUID NOT NULL REFERENCES users(id),
+    issued_at TIMESTAMPTZ NOT NULL,
+    currency TEXT NOT NULL,
+    seller JSONB NOT NULL,
+    buyer JSONB NOT NULL,
+    line_items JSONB NOT NULL,
+    subtotal_minor BIGINT NOT NULL,
+    discount_minor BIGINT NOT NULL DEFAULT 0,
+    tax_minor BIGINT NOT NULL DEFAULT 0,
+    total_minor BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_invoices_user ON invoices (user_id, issued_at DESC);
+
+CREATE OR REPLACE FUNCTION forbid_invoice_mutation() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'invoices are immutable';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS invoices_immutable ON invoices;
+CREATE TRIGGER invoices_immutable
+    BEFORE UPDATE OR DELETE ON invoices
+    FOR EACH ROW EXECUTE FUNCTION forbid_invoice_mutation();
+
+-- Сверка для финансов: оплаченные платежи без счёта
+CREATE OR REPLACE VIEW finance_payments_without_invoice AS
+SELECT p.id AS payment_id, p.user_id, p.amount_minor, p.currency, p.created_at
+FROM payments p
+LEFT JOIN invoices i ON i.payment_id = p.id
+WHERE p.status IN ('succeeded', 'partially_refunded', 'refunded') AND i.id IS NULL;
@@ -0,0 +1,40 @@
+-- Роли: user | support | admin. Сотрудники поддержки делают возвраты
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user';
+
+-- Пометка аккаунта (например, после chargeback): покупки блокируются до снятия
+ALTER TABLE users ADD COLUMN IF NOT EXISTS flagged_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS flag_reason TEXT;
+
+-- Журнал действий сотрудников
+CREATE TABLE IF NOT EXISTS admin_actions (
+    id BIGSERIAL PRIMARY KEY,
+    actor_id UUID NOT NULL REFERENCES users(id),
+    action TEXT NOT NULL,
+    target_user_id UUID REFERENCES users(id),
+    payment_id UUID REFERENCES payments(id),
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_admin_actions_target ON admin_actions (target_user_id, created_at DESC);
+
+-- Статус disputed и проводки chargeback / chargeback_reversal учитываются при сверке
+CREATE OR REPLACE VIEW finance_payment_discrepancies AS
+SELECT p.id AS payment_id, p.status, p.amount_minor, p.refunded_minor, p.provider_reference,
+       COALESCE(SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'charge'), 0) AS ledger_charged_minor,
+       COALESCE(-SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'refund'), 0) AS ledger_refunded_minor
+FROM payments p
+LEFT JOIN ledger_entries l ON l.payment_id = p.id
+GROUP BY p.id
+HAVING (p.status IN ('succeeded', 'partially_refunded', 'refunded', 'disputed')
+        AND COALESCE(SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'charge'), 0) <> p.amount_minor)
+    OR (p.status IN ('pending', 'failed')
+        AND COALESCE(SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'charge'), 0) <> 0)
+    OR COALESCE(-SUM(l.amount_minor) FILTER (WHERE l.entry_type = 'refund'), 0) <> p.refunded_minor
+    OR (p.status = 'pending' AND p.created_at < NOW() - INTERVAL '1 day');
+
+CREATE OR REPLACE VIEW finance_payments_without_invoice AS
+SELECT p.id AS payment_id, p.user_id, p.amount_minor, p.currency, p.created_at
+FROM payments p
+LEFT JOIN invoices i ON i.payment_id = p.id
+WHERE p.status IN ('succeeded', 'partially_refunded', 'refunded', 'disputed') AND i.id IS NULL;
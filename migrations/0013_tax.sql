@@ -0,0 +1,50 @@
+-- Ставки НДС/налога с продаж по юрисдикциям. Версия таблицы ставок — метка
+-- выпуска (например, 2025-07); действует ставка с последним valid_from <= даты платежа
+CREATE TABLE IF NOT EXISTS tax_rates (
+    country TEXT NOT NULL,
+    valid_from DATE NOT NULL,
+    rate_bps INTEGER NOT NULL CHECK (rate_bps >= 0 AND rate_bps <= 10000), -- 1900 = 19%
+    version TEXT NOT NULL,
+    PRIMARY KEY (country, valid_from)
+);
+
+-- Стандартные ставки ЕС и Великобритании
+INSERT INTO tax_rates (country, valid_from, rate_bps, version) VALUES
+    ('AT', '2025-01-01', 2000, '2025-01'), ('BE', '2025-01-01', 2100, '2025-01'),
+    ('BG', '2025-01-01', 2000, '2025-01'), ('CY', '2025-01-01', 1900, '2025-01'),
+    ('CZ', '2025-01-01', 2100, '2025-01'), ('DE', '2025-01-01', 1900, '2025-01'),
+    ('DK', '2025-01-01', 2500, '2025-01'), ('EE', '2025-01-01', 2200, '2025-01'),
+    ('ES', '2025-01-01', 2100, '2025-01'), ('FI', '2025-01-01', 2550, '2025-01'),
+    ('FR', '2025-01-01', 2000, '2025-01'), ('GR', '2025-01-01', 2400, '2025-01'),
+    ('HR', '2025-01-01', 2500, '2025-01'), ('HU', '2025-01-01', 2700, '2025-01'),
+    ('IE', '2025-01-01', 2300, '2025-01'), ('IT', '2025-01-01', 2200, '2025-01'),
+    ('LT', '2025-01-01', 2100, '2025-01'), ('LU', '2025-01-01', 1700, '2025-01'),
+    ('LV', '2025-01-01', 2100, '2025-01'), ('MT', '2025-01-01', 1800, '2025-01'),
+    ('NL', '2025-01-01', 2100, '2025-01'), ('PL', '2025-01-01', 2300, '2025-01'),
+    ('PT', '2025-01-01', 2300, '2025-01'), ('RO', '2025-01-01', 1900, '2025-01'),
+    ('SE', '2025-01-01', 2500, '2025-01'), ('SI', '2025-01-01', 2200, '2025-01'),
+    ('SK', '2025-01-01', 2300, '2025-01'), ('GB', '2025-01-01', 2000, '2025-01'),
+    ('EE', '2025-07-01', 2400, '2025-07'), ('RO', '2025-08-01', 2100, '2025-07')
+ON CONFLICT (country, valid_from) DO NOTHING;
+
+-- Налоговая разбивка платежа. amount_minor — итог к оплате (net + tax)
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS net_minor BIGINT;
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS tax_minor BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS tax_rate_bps INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS tax_country TEXT;
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS tax_treatment TEXT NOT NULL DEFAULT 'not_taxed'; -- not_taxed | standard | reverse_charge
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS tax_rate_version TEXT;
+UPDATE payments SET net_minor = amount_minor WHERE net_minor IS NULL;
+ALTER TABLE payments ALTER COLUMN net_minor SET NOT NULL;
+
+-- Ставка и режим налога на счёте (для пометки reverse charge)
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS tax_rate_bps INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS tax_treatment TEXT NOT NULL DEFAULT 'not_taxed';
+
+-- Налог к уплате по странам и месяцам (для деклараций OSS)
+CREATE OR REPLACE VIEW finance_tax_by_country AS
+SELECT date_trunc('month', created_at)::date AS month, tax_country, currency, tax_treatment, tax_rate_bps,
+       COUNT(*) AS payments, SUM(net_minor) AS net_minor, SUM(tax_minor) AS tax_minor
+FROM payments
+WHERE status IN ('succeeded', 'partially_refunded', 'refunded', 'disputed')
+GROUP BY 1, 2, 3, 4, 5;
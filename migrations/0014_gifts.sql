@@ -0,0 +1,25 @@
+-- Подарочные подписки: покупатель оплачивает тариф на несколько периодов,
+-- код доставляется получателю письмом в назначенное время
+CREATE TABLE IF NOT EXISTS gifts (
+    id UUID PRIMARY KEY,
+    code TEXT NOT NULL UNIQUE,
+    purchaser_id UUID NOT NULL REFERENCES users(id),
+    payment_id UUID NOT NULL REFERENCES payments(id),
+    plan_id TEXT NOT NULL,
+    periods INTEGER NOT NULL CHECK (periods > 0),
+    duration_days INTEGER NOT NULL CHECK (duration_days > 0),
+    currency TEXT NOT NULL,
+    recipient_email TEXT NOT NULL,
+    recipient_name TEXT,
+    message TEXT,
+    deliver_at TIMESTAMPTZ NOT NULL,
+    delivered_at TIMESTAMPTZ,
+    status TEXT NOT NULL DEFAULT 'scheduled', -- scheduled | delivered | redeemed | voided
+    redeemed_by UUID REFERENCES users(id),
+    redeemed_at TIMESTAMPTZ,
+    subscription_id UUID REFERENCES subscriptions(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_gifts_due ON gifts (deliver_at) WHERE status = 'scheduled';
+CREATE INDEX IF NOT EXISTS idx_gifts_purchaser ON gifts (purchaser_id, created_at DESC);
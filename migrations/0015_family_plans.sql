@@ -0,0 +1,21 @@
+-- Семейный тариф: владелец подписки family приглашает участников по email,
+-- участники получают доступ владельца, пока его подписка действует
+CREATE TABLE IF NOT EXISTS family_members (
+    id UUID PRIMARY KEY,
+    owner_id UUID NOT NULL REFERENCES users(id),
+    subscription_id UUID NOT NULL REFERENCES subscriptions(id),
+    email TEXT NOT NULL,
+    member_id UUID REFERENCES users(id),
+    invite_token TEXT NOT NULL UNIQUE,
+    status TEXT NOT NULL DEFAULT 'invited', -- invited | active | removed | left | ended
+    invited_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    joined_at TIMESTAMPTZ,
+    ended_at TIMESTAMPTZ
+);
+
+-- Одно открытое приглашение на email в рамках подписки и одна активная семья на пользователя
+CREATE UNIQUE INDEX IF NOT EXISTS uniq_family_members_open_email
+    ON family_members (subscription_id, lower(email)) WHERE status IN ('invited', 'active');
+CREATE UNIQUE INDEX IF NOT EXISTS uniq_family_members_active_member
+    ON family_members (member_id) WHERE status = 'active';
+CREATE INDEX IF NOT EXISTS idx_family_members_subscription ON family_members (subscription_id);
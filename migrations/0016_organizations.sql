@@ -0,0 +1,55 @@
+-- Лицензии организаций (университеты, компании): сотрудники поддержки заводят
+-- организацию с числом мест и сроком лицензии, администраторы организации
+-- приглашают участников, а пользователи с подтверждённым email из
+-- подтверждённого домена присоединяются сами
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verification_token TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verification_sent_at TIMESTAMPTZ;
+CREATE UNIQUE INDEX IF NOT EXISTS uniq_users_email_verification_token
+    ON users (email_verification_token) WHERE email_verification_token IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS organizations (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    plan_id TEXT NOT NULL,              -- Уровень доступа участников: basic | premium
+    seats INTEGER NOT NULL CHECK (seats >= 0),
+    license_expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Каждый участник (включая администраторов) занимает место
+CREATE TABLE IF NOT EXISTS organization_members (
+    organization_id UUID NOT NULL REFERENCES organizations(id),
+    user_id UUID NOT NULL REFERENCES users(id),
+    role TEXT NOT NULL DEFAULT 'member', -- member | admin
+    joined_via TEXT NOT NULL,            -- staff | invite | domain
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (organization_id, user_id)
+);
+CREATE INDEX IF NOT EXISTS idx_organization_members_user ON organization_members (user_id);
+
+-- Неотвеченное приглашение резервирует место
+CREATE TABLE IF NOT EXISTS organization_invites (
+    id UUID PRIMARY KEY,
+    organization_id UUID NOT NULL REFERENCES organizations(id),
+    email TEXT NOT NULL,
+    role TEXT NOT NULL DEFAULT 'member',
+    token TEXT NOT NULL UNIQUE,
+    status TEXT NOT NULL DEFAULT 'pending', -- pending | accepted | revoked
+    invited_by UUID NOT NULL REFERENCES users(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    accepted_at TIMESTAMPTZ
+);
+CREATE UNIQUE INDEX IF NOT EXISTS uniq_organization_invites_pending_email
+    ON organization_invites (organization_id, lower(email)) WHERE status = 'pending';
+
+-- Домен принадлежит одной организации; автоприсоединение только после проверки
+-- TXT-записи сотрудником поддержки
+CREATE TABLE IF NOT EXISTS organization_domains (
+    domain TEXT PRIMARY KEY,
+    organization_id UUID NOT NULL REFERENCES organizations(id),
+    verification_token TEXT NOT NULL,
+    verified_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS idx_organization_domains_organization ON organization_domains (organization_id);
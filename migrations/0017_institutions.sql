@@ -0,0 +1,33 @@
+-- Доступ учреждений (библиотеки, кампусы) без входа в аккаунт: по диапазонам IP
+-- (IPv4 и IPv6 в нотации CIDR) и по доверенным referrer'ам
+CREATE TABLE IF NOT EXISTS institutions (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    plan_id TEXT NOT NULL, -- Уровень доступа: basic | premium
+    is_active BOOLEAN NOT NULL DEFAULT true,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS institution_ip_ranges (
+    institution_id UUID NOT NULL REFERENCES institutions(id),
+    cidr TEXT NOT NULL, -- Канонический вид: "10.0.0.0/8", "2001:db8::/32"
+    PRIMARY KEY (institution_id, cidr)
+);
+
+CREATE TABLE IF NOT EXISTS institution_referrers (
+    institution_id UUID NOT NULL REFERENCES institutions(id),
+    host TEXT NOT NULL, -- "library.example.edu" или "*.example.edu" для поддоменов
+    PRIMARY KEY (institution_id, host)
+);
+
+-- Счётчики в духе COUNTER R5 по дням: total_item_requests — выдачи контента
+-- через учреждение, no_license — отказы, когда лицензии учреждения не хватило
+CREATE TABLE IF NOT EXISTS institution_usage (
+    institution_id UUID NOT NULL REFERENCES institutions(id),
+    content_id UUID NOT NULL,
+    day DATE NOT NULL,
+    metric TEXT NOT NULL,        -- total_item_requests | no_license
+    access_method TEXT NOT NULL, -- ip | referrer
+    count BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (institution_id, day, content_id, metric, access_method)
+);
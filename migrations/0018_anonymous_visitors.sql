@@ -0,0 +1,19 @@
+-- Анонимные посетители, опознаваемые по подписанной cookie, и счётчик
+-- бесплатных просмотров платного контента (metered paywall)
+CREATE TABLE IF NOT EXISTS anonymous_visitors (
+    id UUID PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    converted_user_id UUID REFERENCES users(id), -- Аккаунт, зарегистрированный с этой cookie
+    converted_at TIMESTAMPTZ
+);
+
+-- Один учитываемый просмотр на материал; повторное чтение в окне счётчика бесплатно
+CREATE TABLE IF NOT EXISTS anonymous_content_views (
+    visitor_id UUID NOT NULL REFERENCES anonymous_visitors(id),
+    content_id UUID NOT NULL,
+    viewed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (visitor_id, content_id)
+);
+CREATE INDEX IF NOT EXISTS idx_anonymous_content_views_visitor_time
+    ON anonymous_content_views (visitor_id, viewed_at);
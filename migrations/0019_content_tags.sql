@@ -0,0 +1,3 @@
+-- Теги материалов для условий правил доступа (content_tags_any / content_tags_all)
+ALTER TABLE content ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+CREATE INDEX IF NOT EXISTS idx_content_tags ON content USING GIN (tags);
@@ -0,0 +1,19 @@
+-- Выборочно сохранённые решения get_content с полной записью о причинах
+-- (подписка, источники доступа, счётчик, правило, прогноз модели)
+CREATE TABLE IF NOT EXISTS paywall_decisions (
+    id UUID PRIMARY KEY,
+    content_id UUID NOT NULL,
+    user_id UUID,
+    visitor_id UUID,
+    institution_id UUID,
+    rule TEXT, -- NULL — ни одно правило не подошло
+    outcome TEXT NOT NULL,
+    access_granted BOOLEAN NOT NULL,
+    sample_reason TEXT NOT NULL CHECK (sample_reason IN ('sampled', 'debug')),
+    record JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS idx_paywall_decisions_user ON paywall_decisions (user_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_paywall_decisions_visitor ON paywall_decisions (visitor_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_paywall_decisions_content ON paywall_decisions (content_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_paywall_decisions_created ON paywall_decisions (created_at);
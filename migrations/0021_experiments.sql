@@ -0,0 +1,40 @@
+-- A/B-эксперименты с вариантами пейвола. Вариант может заменять действующие
+-- правила доступа (rules — набор правил в формате PAYWALL_RULES_PATH)
+CREATE TABLE IF NOT EXISTS experiments (
+    id UUID PRIMARY KEY,
+    key TEXT NOT NULL UNIQUE, -- Участвует в хеше распределения пользователей
+    name TEXT NOT NULL,
+    description TEXT,
+    status TEXT NOT NULL DEFAULT 'draft' CHECK (status IN ('draft', 'running', 'stopped')),
+    traffic_percent INTEGER NOT NULL CHECK (traffic_percent BETWEEN 1 AND 100),
+    created_by UUID REFERENCES users(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    started_at TIMESTAMPTZ,
+    stopped_at TIMESTAMPTZ
+);
+-- Одновременно идёт не больше одного эксперимента: варианты меняют правила целиком
+CREATE UNIQUE INDEX IF NOT EXISTS idx_experiments_single_running
+    ON experiments (status) WHERE status = 'running';
+
+CREATE TABLE IF NOT EXISTS experiment_variants (
+    experiment_id UUID NOT NULL REFERENCES experiments(id),
+    key TEXT NOT NULL,
+    name TEXT NOT NULL,
+    position INTEGER NOT NULL, -- Первый вариант — контрольный
+    weight INTEGER NOT NULL CHECK (weight > 0),
+    rules JSONB, -- NULL — действующие правила
+    PRIMARY KEY (experiment_id, key)
+);
+
+-- Пользователь, увидевший вариант на платном материале без права доступа
+CREATE TABLE IF NOT EXISTS experiment_exposures (
+    experiment_id UUID NOT NULL REFERENCES experiments(id),
+    user_id UUID NOT NULL REFERENCES users(id),
+    variant_key TEXT NOT NULL,
+    first_exposed_at TIMESTAMPTZ NOT NULL,
+    last_exposed_at TIMESTAMPTZ NOT NULL,
+    exposures INTEGER NOT NULL DEFAULT 1,
+    PRIMARY KEY (experiment_id, user_id)
+);
+CREATE INDEX IF NOT EXISTS idx_experiment_exposures_variant
+    ON experiment_exposures (experiment_id, variant_key);
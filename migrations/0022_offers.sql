@@ -0,0 +1,27 @@
+-- Предложения после положительного прогноза модели: вариант (arm) выбирает бандит
+-- по накопленной статистике в контексте показа (устройство и уровень материала)
+CREATE TABLE IF NOT EXISTS offer_arm_stats (
+    context TEXT NOT NULL, -- "<device>:<content_plan>", например "mobile:premium"
+    arm TEXT NOT NULL, -- trial_7d | discount_30 | first_month_1
+    impressions BIGINT NOT NULL DEFAULT 0,
+    conversions BIGINT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (context, arm)
+);
+
+-- Показанное пользователю предложение; действует до expires_at или до покупки
+CREATE TABLE IF NOT EXISTS offer_impressions (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id),
+    content_id UUID NOT NULL,
+    context TEXT NOT NULL,
+    arm TEXT NOT NULL,
+    policy TEXT NOT NULL, -- thompson | epsilon_greedy
+    shown_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    converted_at TIMESTAMPTZ, -- Покупка подписки, пока предложение действовало
+    redeemed BOOLEAN NOT NULL DEFAULT FALSE, -- Покупка на условиях предложения
+    subscription_id UUID REFERENCES subscriptions(id)
+);
+CREATE INDEX IF NOT EXISTS idx_offer_impressions_user
+    ON offer_impressions (user_id, shown_at DESC);
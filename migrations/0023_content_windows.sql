@@ -0,0 +1,8 @@
+-- Окна доступа к материалу: скрыт до publish_at (NULL — черновик), бесплатен до
+-- premium_from (срочные новости) и начиная с free_from (архив); в остальное время
+-- действует required_plan
+ALTER TABLE content ADD COLUMN IF NOT EXISTS publish_at TIMESTAMPTZ;
+UPDATE content SET publish_at = created_at WHERE publish_at IS NULL;
+ALTER TABLE content ALTER COLUMN publish_at SET DEFAULT NOW();
+ALTER TABLE content ADD COLUMN IF NOT EXISTS premium_from TIMESTAMPTZ;
+ALTER TABLE content ADD COLUMN IF NOT EXISTS free_from TIMESTAMPTZ;
@@ -0,0 +1,6 @@
+-- Редактирование материалов через API (editor.rs). Удалённые материалы остаются
+-- в таблице для отчётов и истории просмотров, но не выдаются.
+-- Доступ к API — у пользователей с users.role = 'editor' или 'admin'
+ALTER TABLE content ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+ALTER TABLE content ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS idx_content_publish_at ON content (publish_at) WHERE deleted_at IS NULL;
@@ -0,0 +1,7 @@
+-- Ключ идемпотентности, с которым платёж отправлен провайдеру. Повтор с тем же
+-- ключом (повтор запроса клиента, повторная попытка продления) продолжает тот же
+-- платёж, а провайдер возвращает уже созданное списание
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS idempotency_key TEXT;
+UPDATE payments SET idempotency_key = id::text WHERE idempotency_key IS NULL;
+ALTER TABLE payments ALTER COLUMN idempotency_key SET NOT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS uniq_payments_idempotency_key ON payments (idempotency_key);
@@ -0,0 +1,4 @@
+-- Доставка подарков: подарок забирается к отправке (status = 'sending', deliver_at —
+-- срок, после которого его заберут снова), письмо отправляется вне транзакции.
+DROP INDEX IF EXISTS idx_gifts_due;
+CREATE INDEX IF NOT EXISTS idx_gifts_due ON gifts (deliver_at) WHERE status IN ('scheduled', 'sending');
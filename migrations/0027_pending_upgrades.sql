@@ -0,0 +1,3 @@
+-- Повышение тарифа, оплата которого ещё не подтверждена (списание в статусе pending).
+-- Тариф меняется, когда вебхук подтвердит платёж; при отказе поле очищается
+ALTER TABLE subscriptions ADD COLUMN IF NOT EXISTS pending_plan_id TEXT;
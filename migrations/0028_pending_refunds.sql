@@ -0,0 +1,14 @@
+-- Возвраты подтверждённых платежей, которые не к чему применить. Запись делается в
+-- транзакции обработки вебхука, а сам возврат у провайдера — после её фиксации, с
+-- ключом идемпотентности от списания. Незавершённые повторяет фоновый воркер
+CREATE TABLE IF NOT EXISTS pending_refunds (
+    payment_id UUID PRIMARY KEY REFERENCES payments(id),
+    charge_id TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    completed_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_pending_refunds_open ON pending_refunds (created_at) WHERE completed_at IS NULL;
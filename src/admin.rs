@@ -0,0 +1,353 @@
+// src/admin.rs
+// Действия поддержки: возвраты платежей (полные и частичные) с отзывом или
+// сокращением подписки и снятие пометки с аккаунта. Все действия пишутся в admin_actions.
+use crate::auth;
+use crate::db;
+use crate::idempotency;
+use crate::models::{AdminRefundRequest, Payment};
+use crate::money::Money;
+use crate::payment::{self, PaymentError, PaymentGateway};
+use crate::paywall;
+use actix_web::{HttpRequest, HttpResponse, post, web};
+use moka::future::Cache;
+use serde_json::json;
+use uuid::Uuid;
+
+const MAX_REASON_LENGTH: usize = 500;
+const MAX_SHORTEN_DAYS: i64 = 3650;
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(refund_payment);
+    cfg.service(unflag_user);
+}
+
+#[post("/admin/payments/{payment_id}/refund")]
+pub async fn refund_payment(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    gateway: web::Data<dyn PaymentGateway>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    refund_req: web::Json<AdminRefundRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let admin_id = match auth::require_staff(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+
+    let reason = refund_req.reason.trim();
+    if reason.is_empty() || reason.len() > MAX_REASON_LENGTH {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Refund reason is required"})));
+    }
+    match (
+        refund_req.subscription_action.as_str(),
+        refund_req.shorten_days,
+    ) {
+        ("keep" | "revoke", None) => {}
+        ("shorten", Some(days)) if days > 0 && days <= MAX_SHORTEN_DAYS => {}
+        ("shorten", _) => {
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid shorten_days"})));
+        }
+        _ => {
+            return Ok(
+                HttpResponse::BadRequest().json(json!({"error": "Invalid subscription_action"}))
+            );
+        }
+    }
+
+    // Ключ принадлежит сотруднику: повтор с того же аккаунта не сделает второй возврат
+    let idempotency_key = match idempotency::key_from_request(&req) {
+        Ok(key) => key,
+        Err(response) => return Ok(response),
+    };
+    if let Some(key) = &idempotency_key {
+        let hash = idempotency::request_hash(req.path(), &*refund_req);
+        match idempotency::begin(&pool, admin_id, key, &hash).await {
+            Ok(idempotency::Begin::Proceed(_)) => {}
+            Ok(idempotency::Begin::Respond(response)) => return Ok(response),
+            Err(e) => {
+                tracing::error!("Database error acquiring idempotency key: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        }
+    }
+
+    let response = process_refund(
+        &pool,
+        &cache,
+        gateway.get_ref(),
+        admin_id,
+        path.into_inner(),
+        &refund_req,
+    )
+    .await?;
+
+    match idempotency_key {
+        Some(key) => Ok(idempotency::finish(&pool, admin_id, &key, response).await),
+        None => Ok(response),
+    }
+}
+
+async fn process_refund(
+    pool: &sqlx::PgPool,
+    cache: &Cache<String, serde_json::Value>,
+    gateway: &dyn PaymentGateway,
+    admin_id: Uuid,
+    payment_id: Uuid,
+    refund_req: &AdminRefundRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let payment = match db::get_payment(pool, payment_id).await {
+        Ok(Some(payment)) => payment,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "Payment not found"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error loading payment {}: {}", payment_id, e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    let charge_id = match (&payment.provider_reference, payment.status.as_str()) {
+        (Some(reference), "succeeded" | "partially_refunded") => reference.clone(),
+        _ => {
+            return Ok(HttpResponse::Conflict().json(json!({"error": "Payment is not refundable"})));
+        }
+    };
+
+    let amount = match refund_amount(&payment, refund_req.amount_minor) {
+        Some(amount) => amount,
+        None => {
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid refund amount"})));
+        }
+    };
+
+    let refund =
+        match payment::refund_payment(pool, gateway, payment.id, &charge_id, Some(amount)).await {
+            Ok(refund) => refund,
+            Err(PaymentError::Database(e)) => {
+                // Возврат у провайдера прошёл; журнал догонит вебхук charge.refunded
+                tracing::error!(
+                    "Refund for payment {} succeeded but was not recorded: {}",
+                    payment.id,
+                    e
+                );
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+            Err(e) => {
+                tracing::warn!("Refund for payment {} failed: {}", payment.id, e);
+                return Ok(HttpResponse::BadGateway().json(json!({"error": "Refund failed"})));
+            }
+        };
+    tracing::info!(
+        "Payment {} refunded {} by {} ({})",
+        payment.id,
+        refund.amount,
+        admin_id,
+        refund_req.reason.trim()
+    );
+
+    let subscription = match apply_subscription_action(pool, &payment, refund_req).await {
+        Ok(result) => result,
+        Err(e) => {
+            tracing::error!(
+                "Failed to apply subscription action after refund of {}: {}",
+                payment.id,
+                e
+            );
+            json!({"error": "Subscription was not updated"})
+        }
+    };
+    paywall::invalidate_user_cache(cache, payment.user_id);
+
+    let details = json!({
+        "refund_id": refund.id,
+        "amount_minor": refund.amount.amount_minor(),
+        "currency": refund.amount.currency().code(),
+        "reason": refund_req.reason.trim(),
+        "subscription_action": refund_req.subscription_action,
+        "subscription": subscription,
+    });
+    if let Err(e) = db::record_admin_action(
+        pool,
+        admin_id,
+        "refund",
+        Some(payment.user_id),
+        Some(payment.id),
+        &details,
+    )
+    .await
+    {
+        tracing::error!("Failed to record admin action: {}", e);
+    }
+
+    let payment = match db::get_payment(pool, payment.id).await {
+        Ok(Some(updated)) => updated,
+        _ => payment,
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "refund": refund,
+        "payment": payment,
+        "subscription": subscription,
+    })))
+}
+
+// Сумма возврата в валюте платежа: по умолчанию весь невозвращённый остаток
+fn refund_amount(payment: &Payment, amount_minor: Option<i64>) -> Option<Money> {
+    let refundable = payment
+        .amount()
+        .ok()?
+        .checked_sub(payment.refunded().ok()?)
+        .ok()?;
+    let amount = match amount_minor {
+        Some(minor) => Money::new(minor, refundable.currency()),
+        None => refundable,
+    };
+    (amount.is_positive() && amount.amount_minor() <= refundable.amount_minor()).then_some(amount)
+}
+
+// Отзыв или сокращение подписки, оплаченной возвращённым платежом
+async fn apply_subscription_action(
+    pool: &sqlx::PgPool,
+    payment: &Payment,
+    refund_req: &AdminRefundRequest,
+) -> Result<serde_json::Value, sqlx::Error> {
+    // Неиспользованный подарок аннулируется вместе с отзывом
+    if payment.kind == "gift"
+        && refund_req.subscription_action == "revoke"
+        && db::void_unredeemed_gift(&mut *pool.acquire().await?, payment.id).await?
+    {
+        return Ok(json!({"action": "gift_voided"}));
+    }
+    let Some(subscription_id) = payment.subscription_id else {
+        return Ok(json!({"action": "none"}));
+    };
+    if refund_req.subscription_action == "keep" {
+        return Ok(json!({"action": "keep", "subscription_id": subscription_id}));
+    }
+
+    let mut tx = pool.begin().await?;
+    let subscription = db::lock_subscription(&mut tx, subscription_id)
+        .await?
+        .filter(|s| s.is_active);
+    let Some(subscription) = subscription else {
+        return Ok(json!({"action": "none", "subscription_id": subscription_id}));
+    };
+
+    let result = match (
+        refund_req.subscription_action.as_str(),
+        refund_req.shorten_days,
+    ) {
+        ("shorten", Some(days)) => {
+            let expires_at = db::shorten_subscription(&mut tx, subscription.id, days).await?;
+            db::create_notification(
+                &mut tx,
+                subscription.user_id,
+                "subscription_shortened",
+                &json!({"subscription_id": subscription.id, "expires_at": expires_at}),
+            )
+            .await?;
+            json!({"action": "shorten", "subscription_id": subscription.id, "expires_at": expires_at})
+        }
+        _ => {
+            db::expire_subscription(&mut tx, subscription.id, "refunded").await?;
+            db::create_notification(
+                &mut tx,
+                subscription.user_id,
+                "subscription_expired",
+                &json!({"subscription_id": subscription.id, "reason": "refunded"}),
+            )
+            .await?;
+            json!({"action": "revoke", "subscription_id": subscription.id})
+        }
+    };
+    tx.commit().await?;
+    Ok(result)
+}
+
+// Снятие пометки после разбора спора: покупки снова доступны
+#[post("/admin/users/{user_id}/unflag")]
+pub async fn unflag_user(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let admin_id = match auth::require_staff(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let user_id = path.into_inner();
+
+    match db::unflag_user(&pool, user_id).await {
+        Ok(true) => {
+            if let Err(e) =
+                db::record_admin_action(&pool, admin_id, "unflag", Some(user_id), None, &json!({}))
+                    .await
+            {
+                tracing::error!("Failed to record admin action: {}", e);
+            }
+            Ok(HttpResponse::Ok().json(json!({"user_id": user_id, "flagged": false})))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({"error": "Flagged user not found"}))),
+        Err(e) => {
+            tracing::error!("Database error unflagging user {}: {}", user_id, e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Currency;
+    use chrono::Utc;
+
+    fn payment(amount_minor: i64, refunded_minor: i64) -> Payment {
+        Payment {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            subscription_id: None,
+            kind: "purchase".to_string(),
+            amount_minor,
+            refunded_minor,
+            currency: "EUR".to_string(),
+            status: "succeeded".to_string(),
+            provider_reference: Some("pi_1".to_string()),
+            failure_reason: None,
+            description: "Premium".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            net_minor: amount_minor,
+            tax_minor: 0,
+            tax_rate_bps: 0,
+            tax_country: None,
+            tax_treatment: "not_taxed".to_string(),
+            tax_rate_version: None,
+            idempotency_key: "key".to_string(),
+        }
+    }
+
+    #[test]
+    fn full_refund_by_default_is_the_remaining_amount() {
+        assert_eq!(
+            refund_amount(&payment(1000, 300), None),
+            Some(Money::new(700, Currency::Eur))
+        );
+        // Полностью возвращённый платёж
+        assert_eq!(refund_amount(&payment(1000, 1000), None), None);
+    }
+
+    #[test]
+    fn partial_refund_is_limited_to_the_remaining_amount() {
+        let payment = payment(1000, 300);
+        assert_eq!(
+            refund_amount(&payment, Some(700)),
+            Some(Money::new(700, Currency::Eur))
+        );
+        assert_eq!(refund_amount(&payment, Some(701)), None);
+        assert_eq!(refund_amount(&payment, Some(0)), None);
+        assert_eq!(refund_amount(&payment, Some(-5)), None);
+    }
+}
@@ -1,7 +1,12 @@
 // src/auth.rs
 use crate::config::Config;
 use crate::db;
-use crate::models::{Claims, LoginRequest, RegisterRequest, User};
+use crate::mailer::{EmailMessage, Mailer};
+use crate::models::{Claims, LoginRequest, RegisterRequest, User, VerifyEmailRequest};
+use crate::organizations;
+use crate::paywall;
+use crate::pricing;
+use crate::visitors;
 use actix_web::{
     HttpMessage, // Для extensions() и extensions_mut()
     HttpRequest, // Убран Scope
@@ -12,12 +17,15 @@ use actix_web::{
 use bcrypt::{DEFAULT_COST, hash, verify};
 use chrono::{Duration, Utc};
 use jsonwebtoken::encode;
+use moka::future::Cache;
 use serde_json::json;
 use uuid::Uuid;
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(login);
     cfg.service(register);
+    cfg.service(request_email_verification);
+    cfg.service(verify_email);
 }
 
 // Получение user_id из расширений запроса
@@ -27,6 +35,36 @@ pub fn get_user_id_from_request(req: &HttpRequest) -> Option<Uuid> {
         .and_then(|id_str| Uuid::parse_str(id_str).ok())
 }
 
+// Сотрудник (support или admin), выполняющий запрос; Err — готовый ответ 401/403
+pub async fn require_staff(pool: &sqlx::PgPool, req: &HttpRequest) -> Result<Uuid, HttpResponse> {
+    let Some(user_id) = get_user_id_from_request(req) else {
+        return Err(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"})));
+    };
+    match db::get_user_by_id(pool, user_id).await {
+        Ok(Some(user)) if user.role == "admin" || user.role == "support" => Ok(user_id),
+        Ok(_) => Err(HttpResponse::Forbidden().json(json!({"error": "Forbidden"}))),
+        Err(e) => {
+            tracing::error!("Database error checking staff role: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Редактор материалов (editor или admin)
+pub async fn require_editor(pool: &sqlx::PgPool, req: &HttpRequest) -> Result<Uuid, HttpResponse> {
+    let Some(user_id) = get_user_id_from_request(req) else {
+        return Err(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"})));
+    };
+    match db::get_user_by_id(pool, user_id).await {
+        Ok(Some(user)) if user.role == "editor" || user.role == "admin" => Ok(user_id),
+        Ok(_) => Err(HttpResponse::Forbidden().json(json!({"error": "Forbidden"}))),
+        Err(e) => {
+            tracing::error!("Database error checking editor role: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
 #[post("/auth/login")]
 pub async fn login(
     pool: web::Data<sqlx::PgPool>,
@@ -74,6 +112,8 @@ pub async fn login(
 #[post("/auth/register")]
 pub async fn register(
     pool: web::Data<sqlx::PgPool>,
+    config: web::Data<Config>,
+    http_req: HttpRequest,
     req: web::Json<RegisterRequest>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let check_result = db::get_user_by_username(&pool, &req.username).await;
@@ -90,6 +130,14 @@ pub async fn register(
         _ => {} // User not found, proceed
     }
 
+    let country = match req.country.as_deref().map(pricing::normalize_country) {
+        Some(None) => {
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid country code"})));
+        }
+        Some(country) => country,
+        None => None,
+    };
+
     let hashed_password_result = hash(&req.password, DEFAULT_COST);
     let hashed_password = match hashed_password_result {
         Ok(hash) => hash,
@@ -107,17 +155,124 @@ pub async fn register(
         email: req.email.clone(),
         password_hash: hashed_password,
         created_at: Utc::now(),
+        country,
+        role: "user".to_string(),
+        flagged_at: None,
+        email_verified_at: None,
     };
 
     let create_result = db::create_user(&pool, &new_user).await;
     match create_result {
-        Ok(()) => Ok(HttpResponse::Created().json(json!({
-            "message": "User created successfully",
-            "user_id": new_user.id,
-        }))),
+        Ok(()) => {
+            // Регистрация после стены: связываем анонимного посетителя с аккаунтом
+            if let Some(visitor_id) = visitors::visitor_from_request(&http_req, &config.jwt_secret)
+                && let Err(e) = db::mark_visitor_converted(&pool, visitor_id, new_user.id).await
+            {
+                tracing::warn!("Failed to record visitor {} conversion: {}", visitor_id, e);
+            }
+            Ok(HttpResponse::Created().json(json!({
+                "message": "User created successfully",
+                "user_id": new_user.id,
+            })))
+        }
         Err(e) => {
             tracing::error!("User creation error: {}", e);
             Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
         }
     }
 }
+
+// Письмо с кодом подтверждения email; новый запрос заменяет прежний код
+#[post("/auth/email/verification")]
+pub async fn request_email_verification(
+    pool: web::Data<sqlx::PgPool>,
+    mailer: web::Data<dyn Mailer>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    let user = match db::get_user_by_id(&pool, user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(HttpResponse::NotFound().json(json!({"error": "User not found"}))),
+        Err(e) => {
+            tracing::error!("Database error fetching user: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    if user.email_verified_at.is_some() {
+        return Ok(HttpResponse::Conflict().json(json!({"error": "Email already verified"})));
+    }
+
+    let token = Uuid::new_v4().simple().to_string();
+    if let Err(e) = db::set_email_verification_token(&pool, user_id, &token).await {
+        tracing::error!("Database error storing email verification token: {}", e);
+        return Ok(
+            HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+        );
+    }
+    let message = EmailMessage {
+        to: user.email.clone(),
+        subject: "Confirm your email address".to_string(),
+        text: format!(
+            "Hi {},\n\nUse this code to confirm your email address (valid for 24 hours):\n{}\n",
+            user.username, token
+        ),
+    };
+    match mailer.send(&message).await {
+        Ok(()) => Ok(HttpResponse::Accepted().json(json!({"email_sent": true}))),
+        Err(e) => {
+            tracing::error!(
+                "Failed to send verification email to user {}: {}",
+                user_id,
+                e
+            );
+            Ok(HttpResponse::BadGateway()
+                .json(json!({"error": "Failed to send verification email"})))
+        }
+    }
+}
+
+// Подтверждение email; если домен адреса принадлежит организации, пользователь
+// сразу присоединяется к её лицензии
+#[post("/auth/email/verify")]
+pub async fn verify_email(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    verify_req: web::Json<VerifyEmailRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user = match db::verify_user_email(&pool, verify_req.token.trim()).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return Ok(
+                HttpResponse::NotFound().json(json!({"error": "Verification code not found"}))
+            );
+        }
+        Err(e) => {
+            tracing::error!("Database error verifying email: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    // Отказ в автоприсоединении (нет домена, мест и т.п.) не мешает подтверждению
+    let organization = match organizations::join_by_domain(&pool, &user).await {
+        Ok(Ok(organization)) => {
+            paywall::invalidate_user_cache(&cache, user.id);
+            Some(organization)
+        }
+        Ok(Err(_)) => None,
+        Err(e) => {
+            tracing::error!("Organization auto-join failed for user {}: {}", user.id, e);
+            None
+        }
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "verified": true,
+        "organization_joined": organization,
+    })))
+}
@@ -0,0 +1,250 @@
+// src/billing.rs
+// История платежей пользователя, счета и платёжные реквизиты
+use crate::auth;
+use crate::db;
+use crate::invoice;
+use crate::models::BillingDetailsRequest;
+use crate::pricing;
+use crate::tax;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse, get, put, web};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+const DEFAULT_HISTORY_LIMIT: i64 = 50;
+const MAX_HISTORY_LIMIT: i64 = 200;
+const MAX_BILLING_FIELD_LENGTH: usize = 500;
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct InvoiceQuery {
+    format: Option<String>, // json (по умолчанию) | html
+    #[serde(default)]
+    download: bool,
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_payments);
+    cfg.service(list_invoices);
+    cfg.service(get_invoice);
+    cfg.service(update_billing_details);
+}
+
+#[get("/user/payments")]
+pub async fn get_payments(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    let payments = match db::list_user_payments(&pool, user_id, limit).await {
+        Ok(payments) => payments,
+        Err(e) => {
+            tracing::error!("Database error fetching payments: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    let ledger = match db::list_user_ledger_entries(&pool, user_id, limit).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!("Database error fetching ledger entries: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "payments": payments,
+        "ledger": ledger,
+    })))
+}
+
+#[get("/user/invoices")]
+pub async fn list_invoices(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    match db::list_user_invoices(&pool, user_id, limit).await {
+        Ok(invoices) => Ok(HttpResponse::Ok().json(json!({"invoices": invoices}))),
+        Err(e) => {
+            tracing::error!("Database error fetching invoices: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// JSON по умолчанию; ?format=html (или Accept: text/html) — печатный документ,
+// &download=true — вложением для сохранения
+#[get("/user/invoices/{invoice_id}")]
+pub async fn get_invoice(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<InvoiceQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    let invoice = match db::get_user_invoice(&pool, user_id, path.into_inner()).await {
+        Ok(Some(invoice)) => invoice,
+        Ok(None) => return Ok(HttpResponse::NotFound().json(json!({"error": "Invoice not found"}))),
+        Err(e) => {
+            tracing::error!("Database error fetching invoice: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    let wants_html = match query.format.as_deref() {
+        Some("html") => true,
+        Some("json") => false,
+        Some(_) => {
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Unsupported format"})));
+        }
+        None => req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html")),
+    };
+    if !wants_html {
+        return Ok(HttpResponse::Ok().json(invoice));
+    }
+
+    match invoice::render_html(&invoice) {
+        Ok(html) => {
+            let disposition = if query.download {
+                "attachment"
+            } else {
+                "inline"
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .insert_header((
+                    header::CONTENT_DISPOSITION,
+                    format!("{}; filename=\"{}.html\"", disposition, invoice.number),
+                ))
+                .body(html))
+        }
+        Err(e) => {
+            tracing::error!("Failed to render invoice {}: {}", invoice.number, e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Пустые строки сбрасывают поле
+fn billing_field(value: Option<&str>) -> Result<Option<String>, &'static str> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) if v.len() > MAX_BILLING_FIELD_LENGTH => Err("Billing field is too long"),
+        Some(v) => Ok(Some(v.to_string())),
+        None => Ok(None),
+    }
+}
+
+// Реквизиты попадают только в счета, выставленные после изменения
+#[put("/user/billing-details")]
+pub async fn update_billing_details(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    details: web::Json<BillingDetailsRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    let fields = (
+        billing_field(details.billing_name.as_deref()),
+        billing_field(details.billing_address.as_deref()),
+        billing_field(details.vat_id.as_deref()),
+    );
+    let (billing_name, billing_address, vat_id) = match fields {
+        (Ok(name), Ok(address), Ok(vat_id)) => (name, address, vat_id),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            return Ok(HttpResponse::BadRequest().json(json!({"error": e})));
+        }
+    };
+    let country = match details.country.as_deref().map(pricing::normalize_country) {
+        Some(None) => {
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid country code"})));
+        }
+        Some(country) => country,
+        None => None,
+    };
+
+    // Формат VAT ID проверяется по стране реквизитов (новой или уже сохранённой)
+    let vat_id = match vat_id {
+        Some(vat_id) => {
+            let vat_country = match &country {
+                Some(country) => Some(country.clone()),
+                None => match db::get_user_by_id(&pool, user_id).await {
+                    Ok(user) => user.and_then(|u| u.country),
+                    Err(e) => {
+                        tracing::error!("Database error fetching user: {}", e);
+                        return Ok(HttpResponse::InternalServerError()
+                            .json(json!({"error": "Internal server error"})));
+                    }
+                },
+            };
+            let Some(vat_country) = vat_country else {
+                return Ok(HttpResponse::BadRequest()
+                    .json(json!({"error": "Country is required for VAT ID"})));
+            };
+            match tax::normalize_vat_id(&vat_id, &vat_country) {
+                Some(normalized) => Some(normalized),
+                None => {
+                    return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid VAT ID"})));
+                }
+            }
+        }
+        None => None,
+    };
+
+    match db::update_billing_details(
+        &pool,
+        user_id,
+        billing_name.as_deref(),
+        billing_address.as_deref(),
+        vat_id.as_deref(),
+        country.as_deref(),
+    )
+    .await
+    {
+        Ok(Some(updated)) => Ok(HttpResponse::Ok().json(updated)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "User not found"}))),
+        Err(e) => {
+            tracing::error!("Database error updating billing details: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
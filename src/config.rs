@@ -6,11 +6,204 @@ pub struct Config {
     pub database_url: String,
     pub jwt_secret: String,
     pub payment_api_key: String,
-    pub payment_api_url: String,
+    pub payment_api_url: String, // Базовый URL Stripe-совместимого API
+    // stripe | fake (фейковый шлюз для локальной разработки)
+    #[serde(default = "default_payment_gateway")]
+    pub payment_gateway: String,
+    // Секрет подписи webhooks провайдера; пустой — webhooks отклоняются
+    #[serde(default)]
+    pub payment_webhook_secret: String,
+    #[serde(default = "default_webhook_tolerance_secs")]
+    pub webhook_tolerance_secs: i64,
+    // Автопродление: период опроса и за сколько часов до expires_at списывать оплату
+    #[serde(default = "default_renewal_interval_secs")]
+    pub renewal_interval_secs: u64,
+    #[serde(default = "default_renewal_lead_hours")]
+    pub renewal_lead_hours: i64,
+    // Дни повторных списаний от первой неудачи, например DUNNING_RETRY_DAYS=1,3,5,7
+    #[serde(default = "default_dunning_retry_days")]
+    pub dunning_retry_days: Vec<i64>,
+    #[serde(default = "default_max_pauses_per_year")]
+    pub max_pauses_per_year: i64,
+    // CSV с диапазонами IP: start_ip,end_ip,country (формат DB-IP / IP2Location Lite)
+    #[serde(default)]
+    pub geoip_db_path: Option<String>,
+    // Адреса/сети балансировщиков, которым доверяется X-Forwarded-For,
+    // например TRUSTED_PROXIES=10.0.0.0/8,fd00::/8; пусто — заголовок игнорируется
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    // Как часто перечитывать диапазоны и referrer'ы учреждений из базы
+    #[serde(default = "default_institution_refresh_secs")]
+    pub institution_refresh_secs: u64,
+    // Анонимные посетители: бесплатные просмотры платных материалов за окно,
+    // после которых показывается предложение зарегистрироваться
+    #[serde(default = "default_anonymous_free_views")]
+    pub anonymous_free_views: i64,
+    #[serde(default = "default_anonymous_meter_window_days")]
+    pub anonymous_meter_window_days: i64,
+    // Cookie посетителя только по HTTPS; false — для локальной разработки по HTTP
+    #[serde(default = "default_visitor_cookie_secure")]
+    pub visitor_cookie_secure: bool,
+    // JSON с правилами доступа (rules.rs); не задан — встроенные правила по умолчанию.
+    // Файл проверяется на изменения каждые RULES_RELOAD_SECS секунд
+    #[serde(default)]
+    pub paywall_rules_path: Option<String>,
+    #[serde(default = "default_rules_reload_secs")]
+    pub rules_reload_secs: u64,
+    // Доля сохраняемых решений get_content (0..1) для выдач и отказов отдельно
+    // и срок хранения записей в днях
+    #[serde(default = "default_decision_sample_rate")]
+    pub decision_sample_rate: f64,
+    #[serde(default = "default_decision_sample_rate_denied")]
+    pub decision_sample_rate_denied: f64,
+    #[serde(default = "default_decision_retention_days")]
+    pub decision_retention_days: i64,
+    // Как часто подхватывать запуск и остановку экспериментов с других экземпляров
+    #[serde(default = "default_experiment_refresh_secs")]
+    pub experiment_refresh_secs: u64,
+    // Политика выбора предложения (offers.rs): thompson | epsilon_greedy,
+    // доля случайных выборов для epsilon_greedy и срок действия предложения в часах
+    #[serde(default = "default_offer_policy")]
+    pub offer_policy: String,
+    #[serde(default = "default_offer_epsilon")]
+    pub offer_epsilon: f64,
+    #[serde(default = "default_offer_valid_hours")]
+    pub offer_valid_hours: i64,
+    // Реквизиты продавца в счетах
+    #[serde(default)]
+    pub seller_name: String,
+    #[serde(default)]
+    pub seller_address: String,
+    #[serde(default)]
+    pub seller_vat_id: String,
+    #[serde(default)]
+    pub seller_email: String,
+    #[serde(default = "default_invoice_prefix")]
+    pub invoice_prefix: String,
+    // Страна регистрации продавца: внутри страны reverse charge не применяется
+    #[serde(default)]
+    pub seller_country: String,
+    // inclusive — цены тарифов включают налог, exclusive — налог добавляется сверху
+    #[serde(default = "default_tax_pricing_mode")]
+    pub tax_pricing_mode: String,
+    // Почта: "log" (только в лог) или "http" (JSON API почтового сервиса)
+    #[serde(default = "default_mail_provider")]
+    pub mail_provider: String,
+    #[serde(default)]
+    pub mail_api_url: String,
+    #[serde(default)]
+    pub mail_api_key: String,
+    #[serde(default)]
+    pub mail_from: String,
+    #[serde(default = "default_gift_delivery_interval_secs")]
+    pub gift_delivery_interval_secs: u64,
+    // Сколько участников (кроме владельца) можно пригласить в семейный тариф
+    #[serde(default = "default_family_max_members")]
+    pub family_max_members: i64,
+}
+
+fn default_payment_gateway() -> String {
+    "fake".to_string()
+}
+
+fn default_webhook_tolerance_secs() -> i64 {
+    300
+}
+
+fn default_renewal_interval_secs() -> u64 {
+    300
+}
+
+fn default_renewal_lead_hours() -> i64 {
+    24
+}
+
+fn default_dunning_retry_days() -> Vec<i64> {
+    vec![1, 3, 5, 7]
+}
+
+fn default_institution_refresh_secs() -> u64 {
+    60
+}
+
+fn default_anonymous_free_views() -> i64 {
+    3
+}
+
+fn default_anonymous_meter_window_days() -> i64 {
+    30
+}
+
+fn default_visitor_cookie_secure() -> bool {
+    true
+}
+
+fn default_rules_reload_secs() -> u64 {
+    10
+}
+
+fn default_decision_sample_rate() -> f64 {
+    0.01
+}
+
+fn default_decision_sample_rate_denied() -> f64 {
+    0.1
+}
+
+fn default_decision_retention_days() -> i64 {
+    90
+}
+
+fn default_experiment_refresh_secs() -> u64 {
+    30
+}
+
+fn default_offer_policy() -> String {
+    "thompson".to_string()
+}
+
+fn default_offer_epsilon() -> f64 {
+    0.1
+}
+
+fn default_offer_valid_hours() -> i64 {
+    72
+}
+
+fn default_max_pauses_per_year() -> i64 {
+    2
+}
+
+fn default_invoice_prefix() -> String {
+    "INV".to_string()
+}
+
+fn default_tax_pricing_mode() -> String {
+    "inclusive".to_string()
+}
+
+fn default_mail_provider() -> String {
+    "log".to_string()
+}
+
+fn default_gift_delivery_interval_secs() -> u64 {
+    60
+}
+
+fn default_family_max_members() -> i64 {
+    5
 }
 
 impl Config {
     pub fn from_env() -> Result<Self, envy::Error> {
-        envy::from_env() // Используем envy напрямую
+        let config: Config = envy::from_env()?; // Используем envy напрямую
+        // Без попыток не было бы и льготного периода: подписка заканчивалась бы при первой неудаче
+        if config.dunning_retry_days.is_empty() || config.dunning_retry_days.iter().any(|d| *d <= 0)
+        {
+            return Err(envy::Error::Custom(
+                "DUNNING_RETRY_DAYS must list at least one positive number of days".to_string(),
+            ));
+        }
+        Ok(config)
     }
 }
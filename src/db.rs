@@ -1,28 +1,101 @@
 // src/db.rs
-use crate::models::{Content, Subscription, User, UserBehavior};
-use chrono::{DateTime, Utc};
-use sqlx::{PgPool, Row}; // Row для доступа к полям
+use crate::models::{
+    BillingDetails, Content, ContentChanges, Experiment, ExperimentRevenueRow, ExperimentVariant,
+    ExperimentVariantStats, FamilyMember, Gift, IdempotencyRecord, Institution, InstitutionIpRange,
+    InstitutionReferrer, InstitutionUsageRow, Invoice, LedgerEntry, OfferArmStats, OfferImpression,
+    Organization, OrganizationDomain, OrganizationInvite, OrganizationMember,
+    OrganizationMembership, Payment, PaywallDecision, PendingRefund, Subscription, User,
+    UserBehavior,
+};
+use crate::money::Money;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{PgConnection, PgPool, Row}; // Row для доступа к полям
 use uuid::Uuid;
 
+// Полный список колонок для query_as::<_, Subscription>
+const SUBSCRIPTION_COLUMNS: &str = "id, user_id, plan_id, started_at, expires_at, is_active, cancel_at_period_end, canceled_at, auto_renew, last_renewal_attempt_at, billing_status, dunning_started_at, dunning_attempts, next_retry_at, grace_until, expiration_reason, scheduled_plan_id, paused_at, pause_resumes_at, currency, pending_plan_id";
+
 pub async fn get_user_by_username(
     pool: &PgPool,
     username: &str,
 ) -> Result<Option<User>, sqlx::Error> {
     sqlx::query_as::<_, User>(
-        "SELECT id, username, email, password_hash, created_at FROM users WHERE username = $1",
+        "SELECT id, username, email, password_hash, created_at, country, role, flagged_at, email_verified_at FROM users WHERE username = $1",
     )
     .bind(username)
     .fetch_optional(pool)
     .await
 }
 
+pub async fn get_user_by_id(pool: &PgPool, user_id: Uuid) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as::<_, User>(
+        "SELECT id, username, email, password_hash, created_at, country, role, flagged_at, email_verified_at FROM users WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+}
+
+// Идентификатор клиента у платёжного провайдера
+pub async fn get_payment_customer_id(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT payment_customer_id FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map(Option::flatten)
+}
+
+pub async fn set_payment_customer_id(
+    pool: &PgPool,
+    user_id: Uuid,
+    customer_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET payment_customer_id = $2 WHERE id = $1")
+        .bind(user_id)
+        .bind(customer_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Новый токен подтверждения email заменяет предыдущий
+pub async fn set_email_verification_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET email_verification_token = $2, email_verification_sent_at = NOW() WHERE id = $1")
+        .bind(user_id)
+        .bind(token)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Подтверждение по токену, выданному не раньше чем сутки назад; None — токен неизвестен или устарел
+pub async fn verify_user_email(pool: &PgPool, token: &str) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as::<_, User>(
+        "UPDATE users SET email_verified_at = NOW(), email_verification_token = NULL \
+         WHERE email_verification_token = $1 AND email_verification_sent_at > NOW() - INTERVAL '24 hours' \
+         RETURNING id, username, email, password_hash, created_at, country, role, flagged_at, email_verified_at",
+    )
+    .bind(token)
+    .fetch_optional(pool)
+    .await
+}
+
 pub async fn create_user(pool: &PgPool, user: &User) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO users (id, username, email, password_hash, created_at) VALUES ($1, $2, $3, $4, $5)")
+    sqlx::query("INSERT INTO users (id, username, email, password_hash, created_at, country, role) VALUES ($1, $2, $3, $4, $5, $6, $7)")
         .bind(user.id)
         .bind(&user.username)
         .bind(&user.email)
         .bind(&user.password_hash)
         .bind(user.created_at)
+        .bind(&user.country)
+        .bind(&user.role)
         .execute(pool)
         .await?;
     Ok(())
@@ -32,9 +105,10 @@ pub async fn get_active_subscription(
     pool: &PgPool,
     user_id: Uuid,
 ) -> Result<Option<Subscription>, sqlx::Error> {
-    sqlx::query_as::<_, Subscription>(
-        "SELECT id, user_id, plan_id, started_at, expires_at, is_active FROM subscriptions WHERE user_id = $1 AND is_active = true AND expires_at > NOW()"
-    )
+    sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM subscriptions WHERE user_id = $1 AND is_active = true AND paused_at IS NULL AND (expires_at > NOW() OR (billing_status = 'past_due' AND grace_until > NOW())) ORDER BY expires_at DESC LIMIT 1",
+        SUBSCRIPTION_COLUMNS
+    ))
     .bind(user_id)
     .fetch_optional(pool)
     .await
@@ -44,169 +118,2738 @@ pub async fn create_subscription(
     pool: &PgPool,
     subscription: &Subscription,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO subscriptions (id, user_id, plan_id, started_at, expires_at, is_active) VALUES ($1, $2, $3, $4, $5, $6)")
-        .bind(subscription.id)
-        .bind(subscription.user_id)
-        .bind(&subscription.plan_id)
-        .bind(subscription.started_at)
-        .bind(subscription.expires_at)
-        .bind(subscription.is_active)
-        .execute(pool)
-        .await?;
+    insert_subscription(&mut *pool.acquire().await?, subscription).await
+}
+
+pub async fn insert_subscription(
+    conn: &mut PgConnection,
+    subscription: &Subscription,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT INTO subscriptions ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21)",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(subscription.id)
+    .bind(subscription.user_id)
+    .bind(&subscription.plan_id)
+    .bind(subscription.started_at)
+    .bind(subscription.expires_at)
+    .bind(subscription.is_active)
+    .bind(subscription.cancel_at_period_end)
+    .bind(subscription.canceled_at)
+    .bind(subscription.auto_renew)
+    .bind(subscription.last_renewal_attempt_at)
+    .bind(&subscription.billing_status)
+    .bind(subscription.dunning_started_at)
+    .bind(subscription.dunning_attempts)
+    .bind(subscription.next_retry_at)
+    .bind(subscription.grace_until)
+    .bind(&subscription.expiration_reason)
+    .bind(&subscription.scheduled_plan_id)
+    .bind(subscription.paused_at)
+    .bind(subscription.pause_resumes_at)
+    .bind(&subscription.currency)
+    .bind(&subscription.pending_plan_id)
+    .execute(conn)
+    .await?;
     Ok(())
 }
 
-pub async fn get_content_by_id(
+// Закрывает истёкшие подписки пользователя, чтобы не мешать уникальному индексу
+// uniq_subscriptions_active_user при покупке новой
+pub async fn deactivate_lapsed_subscriptions(
     pool: &PgPool,
-    content_id: Uuid,
-) -> Result<Option<Content>, sqlx::Error> {
-    sqlx::query_as::<_, Content>(
-        "SELECT id, title, body, required_plan, created_at FROM content WHERE id = $1",
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE subscriptions SET is_active = false, billing_status = 'expired', expiration_reason = COALESCE(expiration_reason, 'lapsed') WHERE user_id = $1 AND is_active = true AND paused_at IS NULL AND expires_at <= NOW() AND NOT (billing_status = 'past_due' AND grace_until > NOW())",
     )
-    .bind(content_id)
-    .fetch_optional(pool)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Повышение тарифа вступает в силу сразу, с сохранением даты окончания периода
+pub async fn upgrade_subscription_plan(
+    pool: &PgPool,
+    subscription_id: Uuid,
+    plan_id: &str,
+) -> Result<Subscription, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "UPDATE subscriptions SET plan_id = $2, scheduled_plan_id = NULL WHERE id = $1 RETURNING {}",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(subscription_id)
+    .bind(plan_id)
+    .fetch_one(pool)
     .await
 }
 
-pub async fn log_user_behavior(pool: &PgPool, behavior: &UserBehavior) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO user_behaviors (user_id, content_id, view_time_seconds, scroll_depth_percent, interaction_score, timestamp) VALUES ($1, $2, $3, $4, $5, $6)")
-        .bind(behavior.user_id)
-        .bind(behavior.content_id)
-        .bind(behavior.view_time_seconds)
-        .bind(behavior.scroll_depth_percent)
-        .bind(behavior.interaction_score)
-        .bind(behavior.timestamp)
+// Повышение, которое применится после подтверждения оплаты
+pub async fn set_pending_upgrade(
+    pool: &PgPool,
+    subscription_id: Uuid,
+    plan_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE subscriptions SET pending_plan_id = $2 WHERE id = $1")
+        .bind(subscription_id)
+        .bind(plan_id)
         .execute(pool)
         .await?;
     Ok(())
 }
 
-pub async fn get_user_profile(
-    pool: &PgPool,
-    user_id: Uuid,
-) -> Result<serde_json::Value, sqlx::Error> {
-    // Получаем информацию о подписке
-    let subscription_row = sqlx::query(
-        "SELECT plan_id, started_at, expires_at FROM subscriptions WHERE user_id = $1 AND is_active = true ORDER BY started_at DESC LIMIT 1"
+pub async fn apply_pending_upgrade(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "UPDATE subscriptions SET plan_id = pending_plan_id, scheduled_plan_id = NULL, pending_plan_id = NULL \
+         WHERE id = $1 AND is_active = true AND pending_plan_id IS NOT NULL RETURNING {}",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(subscription_id)
+    .fetch_optional(conn)
+    .await
+}
+
+// Оплата повышения не состоялась: тариф остаётся прежним, зачёт остатка отменяется
+pub async fn void_pending_upgrade(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+    payment_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE subscriptions SET pending_plan_id = NULL WHERE id = $1")
+        .bind(subscription_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(
+        "INSERT INTO ledger_entries (user_id, payment_id, entry_type, amount_minor, currency, description, created_at) \
+         SELECT user_id, payment_id, 'credit', -SUM(amount_minor), currency, 'Upgrade credit reversed', NOW() \
+         FROM ledger_entries WHERE payment_id = $1 AND entry_type = 'credit' \
+         GROUP BY user_id, payment_id, currency HAVING SUM(amount_minor) <> 0",
     )
-    .bind(user_id)
-    .fetch_optional(pool)
+    .bind(payment_id)
+    .execute(conn)
     .await?;
+    Ok(())
+}
 
-    let subscription_info = subscription_row.map(|row| {
-        serde_json::json!({
-            "plan_id": row.get::<String, _>("plan_id"),
-            "started_at": row.get::<DateTime<Utc>, _>("started_at"),
-            "expires_at": row.get::<DateTime<Utc>, _>("expires_at"),
-        })
-    });
-
-    // Получаем статистику взаимодействий
-    let behavior_stats_row: (i64, Option<f64>) = sqlx::query_as(
-        "SELECT COUNT(*), AVG(interaction_score) FROM user_behaviors WHERE user_id = $1",
+// Неудачные списания по подписке: номер попытки в ключе идемпотентности
+pub async fn count_failed_payments(
+    pool: &PgPool,
+    subscription_id: Uuid,
+    kind: &str,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT COUNT(*) FROM payments WHERE subscription_id = $1 AND kind = $2 AND status = 'failed'",
     )
-    .bind(user_id)
+    .bind(subscription_id)
+    .bind(kind)
     .fetch_one(pool)
-    .await?;
+    .await
+}
 
-    let avg_score = behavior_stats_row.1.unwrap_or(0.0);
+// None снимает запланированную смену тарифа
+pub async fn schedule_plan_change(
+    pool: &PgPool,
+    subscription_id: Uuid,
+    plan_id: Option<&str>,
+) -> Result<Subscription, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "UPDATE subscriptions SET scheduled_plan_id = $2 WHERE id = $1 RETURNING {}",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(subscription_id)
+    .bind(plan_id)
+    .fetch_one(pool)
+    .await
+}
 
-    Ok(serde_json::json!({
-        "user_id": user_id,
-        "subscription": subscription_info,
-        "total_interactions": behavior_stats_row.0,
-        "avg_interaction_score": avg_score,
-    }))
+// Отмена подписки вместе с ответом на опрос о причине (в одной транзакции).
+// Немедленная отмена закрывает доступ сразу, иначе подписка остаётся активной
+// до expires_at и get_active_subscription продолжает её возвращать.
+pub async fn cancel_subscription(
+    pool: &PgPool,
+    subscription: &Subscription,
+    immediate: bool,
+    reason: &str,
+    comment: Option<&str>,
+) -> Result<Subscription, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let updated = if immediate {
+        sqlx::query_as::<_, Subscription>(&format!(
+            "UPDATE subscriptions SET is_active = false, cancel_at_period_end = false, canceled_at = NOW(), expires_at = LEAST(expires_at, NOW()), next_retry_at = NULL, paused_at = NULL, pause_resumes_at = NULL, expiration_reason = 'canceled' WHERE id = $1 RETURNING {}",
+            SUBSCRIPTION_COLUMNS
+        ))
+        .bind(subscription.id)
+        .fetch_one(&mut *tx)
+        .await?
+    } else {
+        sqlx::query_as::<_, Subscription>(&format!(
+            "UPDATE subscriptions SET cancel_at_period_end = true, canceled_at = NOW() WHERE id = $1 RETURNING {}",
+            SUBSCRIPTION_COLUMNS
+        ))
+        .bind(subscription.id)
+        .fetch_one(&mut *tx)
+        .await?
+    };
+
+    // Пауза отменённой подписки закрывается без возобновления
+    if subscription.paused_at.is_some() {
+        sqlx::query("UPDATE subscription_pauses SET resumed_at = NOW() WHERE subscription_id = $1 AND resumed_at IS NULL")
+            .bind(subscription.id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    sqlx::query("INSERT INTO subscription_cancellations (id, subscription_id, user_id, reason, comment, immediate, created_at) VALUES ($1, $2, $3, $4, $5, $6, NOW())")
+        .bind(Uuid::new_v4())
+        .bind(subscription.id)
+        .bind(subscription.user_id)
+        .bind(reason)
+        .bind(comment)
+        .bind(immediate)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(updated)
 }
 
-// Функции для обучения ML модели (остаются как заглушки или для будущего использования)
-pub async fn get_ml_training_data(
-    _pool: &PgPool,
-) -> Result<Vec<crate::models::MLFeatures>, sqlx::Error> {
-    // Заглушка или реализация для получения реальных данных
-    Ok(Vec::new())
+// Отмена запланированной отмены; возможна только до окончания периода
+pub async fn undo_cancellation(
+    pool: &PgPool,
+    subscription_id: Uuid,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "UPDATE subscriptions SET cancel_at_period_end = false, canceled_at = NULL WHERE id = $1 AND is_active = true AND cancel_at_period_end = true AND expires_at > NOW() RETURNING {}",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(subscription_id)
+    .fetch_optional(pool)
+    .await
 }
 
-pub async fn get_ml_targets(_pool: &PgPool) -> Result<Vec<f32>, sqlx::Error> {
-    // Заглушка
-    Ok(Vec::new())
+// Последняя подписка в любом состоянии (для объяснения отказа в доступе)
+pub async fn get_latest_subscription(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM subscriptions WHERE user_id = $1 ORDER BY started_at DESC LIMIT 1",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
 }
 
-// Исправленные функции для извлечения признаков ML
-pub async fn get_user_subscription_days(pool: &PgPool, user_id: Uuid) -> Result<f64, sqlx::Error> {
-    let result: Option<f64> = sqlx::query_scalar(
-        "SELECT EXTRACT(EPOCH FROM (MAX(expires_at) - MIN(started_at))) / 86400.0 FROM subscriptions WHERE user_id = $1 AND is_active = true"
+// Была ли у пользователя подписка или покупка по предложению (для вводных предложений)
+pub async fn has_subscription_history(pool: &PgPool, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM subscriptions WHERE user_id = $1) OR EXISTS (SELECT 1 FROM offer_impressions WHERE user_id = $1 AND redeemed)",
     )
     .bind(user_id)
+    .fetch_one(pool)
+    .await
+}
+
+// Покупка, ожидающая подтверждения оплаты (3-D Secure и т.п.) не дольше суток
+pub async fn get_awaiting_payment_subscription(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM subscriptions WHERE user_id = $1 AND billing_status = 'awaiting_payment' AND started_at > NOW() - INTERVAL '1 day' ORDER BY started_at DESC LIMIT 1",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(user_id)
     .fetch_optional(pool)
-    .await?
-    .flatten(); // Обрабатываем Option<Option<f64>>
-    Ok(result.unwrap_or(0.0))
+    .await
 }
 
-pub async fn get_user_avg_view_time(pool: &PgPool, user_id: Uuid) -> Result<f64, sqlx::Error> {
-    let result: Option<f64> = sqlx::query_scalar(
-        "SELECT AVG(view_time_seconds)::double precision FROM user_behaviors WHERE user_id = $1",
-    )
+pub async fn get_paused_subscription(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM subscriptions WHERE user_id = $1 AND is_active = true AND paused_at IS NOT NULL",
+        SUBSCRIPTION_COLUMNS
+    ))
     .bind(user_id)
     .fetch_optional(pool)
-    .await?
-    .flatten();
-    Ok(result.unwrap_or(0.0))
+    .await
 }
 
-pub async fn get_content_popularity_score(
+pub async fn count_pauses_last_year(pool: &PgPool, user_id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT COUNT(*) FROM subscription_pauses WHERE user_id = $1 AND paused_at > NOW() - INTERVAL '1 year'",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn pause_subscription(
     pool: &PgPool,
-    content_id: Uuid,
-) -> Result<f64, sqlx::Error> {
-    // Простая метрика популярности: доля просмотров этого контента от общего числа просмотров
-    let total_views: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_behaviors")
-        .fetch_one(pool)
-        .await
-        .unwrap_or(1); // Избегаем деления на 0
+    subscription: &Subscription,
+    resume_at: DateTime<Utc>,
+) -> Result<Subscription, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query_as::<_, Subscription>(&format!(
+        "UPDATE subscriptions SET paused_at = NOW(), pause_resumes_at = $2 WHERE id = $1 AND paused_at IS NULL RETURNING {}",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(subscription.id)
+    .bind(resume_at)
+    .fetch_one(&mut *tx)
+    .await?;
 
-    let content_views: i64 =
-        sqlx::query_scalar("SELECT COUNT(*) FROM user_behaviors WHERE content_id = $1")
-            .bind(content_id)
-            .fetch_one(pool)
-            .await
-            .unwrap_or(0);
+    sqlx::query("INSERT INTO subscription_pauses (id, subscription_id, user_id, paused_at, resume_at) VALUES ($1, $2, $3, NOW(), $4)")
+        .bind(Uuid::new_v4())
+        .bind(subscription.id)
+        .bind(subscription.user_id)
+        .bind(resume_at)
+        .execute(&mut *tx)
+        .await?;
 
-    Ok((content_views as f64) / (total_views as f64).max(1.0)) // max(1.0) для избежания 0
+    tx.commit().await?;
+    Ok(updated)
 }
 
-pub async fn get_time_since_last_interaction(
+// Возобновление сдвигает expires_at на длительность паузы. Без subscription_id
+// возобновляет все паузы, срок которых наступил (фоновая задача).
+pub async fn resume_subscriptions(
+    conn: &mut PgConnection,
+    subscription_id: Option<Uuid>,
+) -> Result<Vec<Subscription>, sqlx::Error> {
+    let resumed = sqlx::query_as::<_, Subscription>(&format!(
+        "UPDATE subscriptions SET expires_at = expires_at + (NOW() - paused_at), paused_at = NULL, pause_resumes_at = NULL WHERE paused_at IS NOT NULL AND CASE WHEN $1::uuid IS NULL THEN pause_resumes_at <= NOW() ELSE id = $1 END RETURNING {}",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(subscription_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let ids: Vec<Uuid> = resumed.iter().map(|s| s.id).collect();
+    sqlx::query("UPDATE subscription_pauses SET resumed_at = NOW() WHERE subscription_id = ANY($1) AND resumed_at IS NULL")
+        .bind(&ids)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(resumed)
+}
+
+pub async fn set_auto_renew(
+    pool: &PgPool,
+    subscription_id: Uuid,
+    enabled: bool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE subscriptions SET auto_renew = $2 WHERE id = $1")
+        .bind(subscription_id)
+        .bind(enabled)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Платёжный метод по умолчанию заменяет предыдущий
+pub async fn save_payment_method(
     pool: &PgPool,
     user_id: Uuid,
-) -> Result<f64, sqlx::Error> {
-    let result: Option<f64> = sqlx::query_scalar(
-        "SELECT EXTRACT(EPOCH FROM (NOW() - MAX(timestamp))) FROM user_behaviors WHERE user_id = $1"
+    token: &str,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE payment_methods SET is_default = false WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO payment_methods (id, user_id, token, is_default, created_at) VALUES ($1, $2, $3, true, NOW())")
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(token)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+pub async fn get_default_payment_method(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT token FROM payment_methods WHERE user_id = $1 AND is_default = true ORDER BY created_at DESC LIMIT 1",
     )
     .bind(user_id)
-    .fetch_optional(pool)
-    .await?
-    .flatten();
-    Ok(result.unwrap_or(0.0)) // 0 если нет взаимодействий
+    .fetch_optional(conn)
+    .await
 }
 
-pub async fn get_user_total_interactions(pool: &PgPool, user_id: Uuid) -> Result<i64, sqlx::Error> {
-    let result: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_behaviors WHERE user_id = $1")
-        .bind(user_id)
-        .fetch_one(pool)
-        .await?;
-    Ok(result)
+// Следующая подписка для продления: либо подходит (или недавно прошёл, пока воркер
+// стоял) срок окончания, либо наступила очередная попытка списания в льготном периоде.
+// Пропущенные дольше max_overdue_days не продлеваются (см. expire_stale_renewals).
+// FOR UPDATE SKIP LOCKED позволяет нескольким воркерам работать параллельно,
+// не продлевая одну подписку дважды.
+pub async fn lock_next_renewal_candidate(
+    conn: &mut PgConnection,
+    lead_hours: i64,
+    max_overdue_days: i64,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM subscriptions WHERE is_active = true AND auto_renew = true AND cancel_at_period_end = false AND paused_at IS NULL AND ((billing_status = 'ok' AND expires_at <= NOW() + make_interval(hours => $1::int) AND expires_at > NOW() - make_interval(days => $2::int) AND (next_retry_at IS NULL OR next_retry_at <= NOW())) OR (billing_status = 'past_due' AND next_retry_at <= NOW())) ORDER BY expires_at LIMIT 1 FOR UPDATE SKIP LOCKED",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(lead_hours)
+    .bind(max_overdue_days)
+    .fetch_optional(conn)
+    .await
 }
 
-pub async fn get_content_avg_interaction_score(
-    pool: &PgPool,
-    content_id: Uuid,
-) -> Result<f64, sqlx::Error> {
-    let result: Option<f64> = sqlx::query_scalar(
-        "SELECT AVG(interaction_score)::double precision FROM user_behaviors WHERE content_id = $1",
+// Продление, пропущенное дольше льготного периода (воркер долго стоял): подписка
+// истекает без попытки списания и без нового льготного периода
+pub async fn expire_stale_renewals(
+    conn: &mut PgConnection,
+    max_overdue_days: i64,
+) -> Result<Vec<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "UPDATE subscriptions SET is_active = false, billing_status = 'expired', next_retry_at = NULL, grace_until = NULL, expiration_reason = 'lapsed', last_renewal_attempt_at = NOW() \
+         WHERE is_active = true AND auto_renew = true AND cancel_at_period_end = false AND paused_at IS NULL AND billing_status = 'ok' \
+         AND expires_at <= NOW() - make_interval(days => $1::int) RETURNING {}",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(max_overdue_days)
+    .fetch_all(conn)
+    .await
+}
+
+// Успешное списание: новый период (с запланированным тарифом) и выход из льготного периода.
+// Просроченная без dunning подписка продлевается от текущего момента, а не от прошедшего срока
+pub async fn extend_subscription(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+    plan_id: &str,
+    days: i64,
+) -> Result<DateTime<Utc>, sqlx::Error> {
+    sqlx::query_scalar(
+        "UPDATE subscriptions SET plan_id = $3, scheduled_plan_id = NULL, expires_at = CASE WHEN billing_status = 'ok' THEN GREATEST(expires_at, NOW()) ELSE expires_at END + make_interval(days => $2::int), last_renewal_attempt_at = NOW(), billing_status = 'ok', dunning_started_at = NULL, dunning_attempts = 0, next_retry_at = NULL, grace_until = NULL WHERE id = $1 RETURNING expires_at",
     )
-    .bind(content_id)
-    .fetch_optional(pool)
-    .await?
-    .flatten();
-    Ok(result.unwrap_or(0.0))
+    .bind(subscription_id)
+    .bind(days)
+    .bind(plan_id)
+    .fetch_one(conn)
+    .await
+}
+
+// Новый срок после подарка (см. gifts::gift_expires_at) и выход из льготного периода
+pub async fn extend_subscription_with_gift(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+    expires_at: DateTime<Utc>,
+) -> Result<DateTime<Utc>, sqlx::Error> {
+    sqlx::query_scalar(
+        "UPDATE subscriptions SET scheduled_plan_id = NULL, expires_at = $2, billing_status = 'ok', dunning_started_at = NULL, dunning_attempts = 0, next_retry_at = NULL, grace_until = NULL WHERE id = $1 RETURNING expires_at",
+    )
+    .bind(subscription_id)
+    .bind(expires_at)
+    .fetch_one(conn)
+    .await
+}
+
+// Оплата покупки подтверждена: период отсчитывается с момента подтверждения
+pub async fn activate_awaiting_subscription(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+) -> Result<DateTime<Utc>, sqlx::Error> {
+    sqlx::query_scalar(
+        "UPDATE subscriptions SET is_active = true, billing_status = 'ok', expires_at = NOW() + (expires_at - started_at), started_at = NOW() WHERE id = $1 RETURNING expires_at",
+    )
+    .bind(subscription_id)
+    .fetch_one(conn)
+    .await
+}
+
+// Подписка, истёкшая из-за неудачных продлений, восстанавливается поздно пришедшей оплатой
+pub async fn restore_subscription(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+    plan_id: &str,
+    days: i64,
+) -> Result<DateTime<Utc>, sqlx::Error> {
+    sqlx::query_scalar(
+        "UPDATE subscriptions SET is_active = true, auto_renew = true, plan_id = $3, scheduled_plan_id = NULL, expires_at = GREATEST(expires_at, NOW()) + make_interval(days => $2::int), expiration_reason = NULL, last_renewal_attempt_at = NOW(), billing_status = 'ok', dunning_started_at = NULL, dunning_attempts = 0, next_retry_at = NULL, grace_until = NULL WHERE id = $1 RETURNING expires_at",
+    )
+    .bind(subscription_id)
+    .bind(days)
+    .bind(plan_id)
+    .fetch_one(conn)
+    .await
+}
+
+// Первая неудачная попытка продления переводит подписку в статус past_due
+pub async fn start_dunning(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+    next_retry_at: DateTime<Utc>,
+    grace_until: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE subscriptions SET billing_status = 'past_due', dunning_started_at = NOW(), dunning_attempts = 1, next_retry_at = $2, grace_until = $3, last_renewal_attempt_at = NOW() WHERE id = $1",
+    )
+    .bind(subscription_id)
+    .bind(next_retry_at)
+    .bind(grace_until)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn record_dunning_failure(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+    next_retry_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE subscriptions SET dunning_attempts = dunning_attempts + 1, next_retry_at = $2, last_renewal_attempt_at = NOW() WHERE id = $1",
+    )
+    .bind(subscription_id)
+    .bind(next_retry_at)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Исход списания неизвестен: повторная проверка без учёта попытки как неудачной
+pub async fn defer_renewal(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+    recheck_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE subscriptions SET next_retry_at = $2, last_renewal_attempt_at = NOW() WHERE id = $1",
+    )
+    .bind(subscription_id)
+    .bind(recheck_at)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Попытки продления исчерпаны: автопродление выключается, доступ остаётся до конца
+// оплаченного или льготного периода (что позже)
+pub async fn end_subscription_after_failed_renewal(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+) -> Result<DateTime<Utc>, sqlx::Error> {
+    sqlx::query_scalar(
+        "UPDATE subscriptions SET auto_renew = false, billing_status = 'expired', expires_at = GREATEST(expires_at, COALESCE(grace_until, expires_at)), next_retry_at = NULL, grace_until = NULL, expiration_reason = 'payment_failed', last_renewal_attempt_at = NOW() WHERE id = $1 RETURNING expires_at",
+    )
+    .bind(subscription_id)
+    .fetch_one(conn)
+    .await
+}
+
+pub async fn expire_subscription(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+    reason: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE subscriptions SET is_active = false, billing_status = 'expired', expires_at = LEAST(expires_at, NOW()), next_retry_at = NULL, grace_until = NULL, expiration_reason = $2, last_renewal_attempt_at = NOW() WHERE id = $1",
+    )
+    .bind(subscription_id)
+    .bind(reason)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn create_notification(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    kind: &str,
+    payload: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO notifications (id, user_id, kind, payload, created_at) VALUES ($1, $2, $3, $4, NOW())")
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(kind)
+        .bind(payload)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// Сохраняет сырое событие провайдера; повторная доставка не создаёт дубликат
+pub async fn store_payment_event(
+    pool: &PgPool,
+    event_id: &str,
+    event_type: &str,
+    payload: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO payment_events (id, event_type, payload, received_at) VALUES ($1, $2, $3, NOW()) ON CONFLICT (id) DO NOTHING")
+        .bind(event_id)
+        .bind(event_type)
+        .bind(payload)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Блокирует событие на время обработки; возвращает время обработки, если событие уже обработано
+pub async fn lock_payment_event(
+    conn: &mut PgConnection,
+    event_id: &str,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar("SELECT processed_at FROM payment_events WHERE id = $1 FOR UPDATE")
+        .bind(event_id)
+        .fetch_one(conn)
+        .await
+}
+
+pub async fn mark_payment_event_processed(
+    conn: &mut PgConnection,
+    event_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE payment_events SET processed_at = NOW(), error = NULL WHERE id = $1")
+        .bind(event_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn record_payment_event_error(
+    pool: &PgPool,
+    event_id: &str,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE payment_events SET error = $2 WHERE id = $1")
+        .bind(event_id)
+        .bind(error)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_user_id_by_payment_customer(
+    conn: &mut PgConnection,
+    customer_id: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar("SELECT id FROM users WHERE payment_customer_id = $1")
+        .bind(customer_id)
+        .fetch_optional(conn)
+        .await
+}
+
+// Активная (в том числе просроченная или приостановленная) подписка под блокировкой строки
+pub async fn lock_active_subscription_for_user(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM subscriptions WHERE user_id = $1 AND is_active = true ORDER BY expires_at DESC LIMIT 1 FOR UPDATE",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(user_id)
+    .fetch_optional(conn)
+    .await
+}
+
+const PAYMENT_COLUMNS: &str = "id, user_id, subscription_id, kind, amount_minor, refunded_minor, currency, status, provider_reference, failure_reason, description, created_at, updated_at, \
+     net_minor, tax_minor, tax_rate_bps, tax_country, tax_treatment, tax_rate_version, idempotency_key";
+
+// Новый платёж вместе с первой записью в истории статусов
+pub async fn create_payment(conn: &mut PgConnection, payment: &Payment) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT INTO payments ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20)",
+        PAYMENT_COLUMNS
+    ))
+    .bind(payment.id)
+    .bind(payment.user_id)
+    .bind(payment.subscription_id)
+    .bind(&payment.kind)
+    .bind(payment.amount_minor)
+    .bind(payment.refunded_minor)
+    .bind(&payment.currency)
+    .bind(&payment.status)
+    .bind(&payment.provider_reference)
+    .bind(&payment.failure_reason)
+    .bind(&payment.description)
+    .bind(payment.created_at)
+    .bind(payment.updated_at)
+    .bind(payment.net_minor)
+    .bind(payment.tax_minor)
+    .bind(payment.tax_rate_bps)
+    .bind(&payment.tax_country)
+    .bind(&payment.tax_treatment)
+    .bind(&payment.tax_rate_version)
+    .bind(&payment.idempotency_key)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("INSERT INTO payment_status_history (payment_id, from_status, to_status, changed_at) VALUES ($1, NULL, $2, NOW())")
+        .bind(payment.id)
+        .bind(&payment.status)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// Сначала блокируется подписка платежа, затем платёж — в том же порядке, что у
+// воркера продления (подписка в его транзакции, платёж в record_charge_result)
+pub async fn lock_payment_by_reference(
+    conn: &mut PgConnection,
+    provider_reference: &str,
+) -> Result<Option<Payment>, sqlx::Error> {
+    let payment = sqlx::query_as::<_, Payment>(&format!(
+        "SELECT {} FROM payments WHERE provider_reference = $1",
+        PAYMENT_COLUMNS
+    ))
+    .bind(provider_reference)
+    .fetch_optional(&mut *conn)
+    .await?;
+    lock_found_payment(conn, payment).await
+}
+
+// Платёж, по которому не дошёл ответ провайдера (ищется по metadata события)
+pub async fn lock_unreferenced_payment(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+) -> Result<Option<Payment>, sqlx::Error> {
+    let payment = sqlx::query_as::<_, Payment>(&format!(
+        "SELECT {} FROM payments WHERE id = $1 AND provider_reference IS NULL",
+        PAYMENT_COLUMNS
+    ))
+    .bind(payment_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+    lock_found_payment(conn, payment).await
+}
+
+async fn lock_found_payment(
+    conn: &mut PgConnection,
+    payment: Option<Payment>,
+) -> Result<Option<Payment>, sqlx::Error> {
+    let Some(payment) = payment else {
+        return Ok(None);
+    };
+    if let Some(subscription_id) = payment.subscription_id {
+        lock_subscription(conn, subscription_id).await?;
+    }
+    lock_payment(conn, payment.id).await
+}
+
+pub async fn get_payment(pool: &PgPool, payment_id: Uuid) -> Result<Option<Payment>, sqlx::Error> {
+    sqlx::query_as::<_, Payment>(&format!(
+        "SELECT {} FROM payments WHERE id = $1",
+        PAYMENT_COLUMNS
+    ))
+    .bind(payment_id)
+    .fetch_optional(pool)
+    .await
+}
+
+// Платёж, ранее отправленный провайдеру с этим ключом
+pub async fn get_payment_by_idempotency_key(
+    pool: &PgPool,
+    idempotency_key: &str,
+) -> Result<Option<Payment>, sqlx::Error> {
+    sqlx::query_as::<_, Payment>(&format!(
+        "SELECT {} FROM payments WHERE idempotency_key = $1",
+        PAYMENT_COLUMNS
+    ))
+    .bind(idempotency_key)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn lock_payment(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+) -> Result<Option<Payment>, sqlx::Error> {
+    sqlx::query_as::<_, Payment>(&format!(
+        "SELECT {} FROM payments WHERE id = $1 FOR UPDATE",
+        PAYMENT_COLUMNS
+    ))
+    .bind(payment_id)
+    .fetch_optional(conn)
+    .await
+}
+
+// Смена статуса платежа с записью перехода; повторная установка того же статуса не пишется
+pub async fn transition_payment(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+    status: &str,
+    provider_reference: Option<&str>,
+    failure_reason: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let previous: Option<String> =
+        sqlx::query_scalar("SELECT status FROM payments WHERE id = $1 FOR UPDATE")
+            .bind(payment_id)
+            .fetch_optional(&mut *conn)
+            .await?;
+    let Some(previous) = previous else {
+        return Err(sqlx::Error::RowNotFound);
+    };
+    sqlx::query("UPDATE payments SET status = $2, provider_reference = COALESCE($3, provider_reference), failure_reason = COALESCE($4, failure_reason), updated_at = NOW() WHERE id = $1")
+        .bind(payment_id)
+        .bind(status)
+        .bind(provider_reference)
+        .bind(failure_reason)
+        .execute(&mut *conn)
+        .await?;
+    if previous != status {
+        sqlx::query("INSERT INTO payment_status_history (payment_id, from_status, to_status, changed_at) VALUES ($1, $2, $3, NOW())")
+            .bind(payment_id)
+            .bind(previous)
+            .bind(status)
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn add_payment_refund(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+    amount_minor: i64,
+) -> Result<Payment, sqlx::Error> {
+    sqlx::query_as::<_, Payment>(&format!(
+        "UPDATE payments SET refunded_minor = refunded_minor + $2, updated_at = NOW() WHERE id = $1 RETURNING {}",
+        PAYMENT_COLUMNS
+    ))
+    .bind(payment_id)
+    .bind(amount_minor)
+    .fetch_one(conn)
+    .await
+}
+
+pub async fn link_payment_subscription(
+    pool: &PgPool,
+    payment_id: Uuid,
+    subscription_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    set_payment_subscription(&mut *pool.acquire().await?, payment_id, subscription_id).await
+}
+
+pub async fn set_payment_subscription(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+    subscription_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE payments SET subscription_id = $2, updated_at = NOW() WHERE id = $1")
+        .bind(payment_id)
+        .bind(subscription_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn create_pending_refund(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+    charge_id: &str,
+    reason: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO pending_refunds (payment_id, charge_id, reason) VALUES ($1, $2, $3) ON CONFLICT (payment_id) DO NOTHING",
+    )
+    .bind(payment_id)
+    .bind(charge_id)
+    .bind(reason)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_pending_refund_by_charge(
+    pool: &PgPool,
+    charge_id: &str,
+) -> Result<Option<PendingRefund>, sqlx::Error> {
+    sqlx::query_as::<_, PendingRefund>(
+        "SELECT payment_id, charge_id, reason FROM pending_refunds WHERE charge_id = $1 AND completed_at IS NULL",
+    )
+    .bind(charge_id)
+    .fetch_optional(pool)
+    .await
+}
+
+// Незавершённые возвраты для повтора; свежие ещё выполняет обработчик вебхука
+pub async fn list_stale_pending_refunds(
+    pool: &PgPool,
+    older_than_mins: i64,
+    max_attempts: i32,
+    limit: i64,
+) -> Result<Vec<PendingRefund>, sqlx::Error> {
+    sqlx::query_as::<_, PendingRefund>(
+        "SELECT payment_id, charge_id, reason FROM pending_refunds WHERE completed_at IS NULL AND attempts < $2 \
+         AND created_at < NOW() - make_interval(mins => $1::int) ORDER BY created_at LIMIT $3",
+    )
+    .bind(older_than_mins)
+    .bind(max_attempts)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+// Блокировка незавершённого возврата; false — его уже завершили
+pub async fn lock_open_pending_refund(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let locked: Option<Uuid> = sqlx::query_scalar(
+        "SELECT payment_id FROM pending_refunds WHERE payment_id = $1 AND completed_at IS NULL FOR UPDATE",
+    )
+    .bind(payment_id)
+    .fetch_optional(conn)
+    .await?;
+    Ok(locked.is_some())
+}
+
+pub async fn complete_pending_refund(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE pending_refunds SET completed_at = NOW(), attempts = attempts + 1, last_error = NULL WHERE payment_id = $1",
+    )
+    .bind(payment_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn record_pending_refund_error(
+    pool: &PgPool,
+    payment_id: Uuid,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE pending_refunds SET attempts = attempts + 1, last_error = $2 WHERE payment_id = $1",
+    )
+    .bind(payment_id)
+    .bind(error)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Запись в журнал; таблица только для добавления (изменение запрещено триггером)
+pub async fn append_ledger_entry(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    payment_id: Option<Uuid>,
+    entry_type: &str,
+    amount: Money,
+    description: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO ledger_entries (user_id, payment_id, entry_type, amount_minor, currency, description, created_at) VALUES ($1, $2, $3, $4, $5, $6, NOW())")
+        .bind(user_id)
+        .bind(payment_id)
+        .bind(entry_type)
+        .bind(amount.amount_minor())
+        .bind(amount.currency().code())
+        .bind(description)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn list_user_payments(
+    pool: &PgPool,
+    user_id: Uuid,
+    limit: i64,
+) -> Result<Vec<Payment>, sqlx::Error> {
+    sqlx::query_as::<_, Payment>(&format!(
+        "SELECT {} FROM payments WHERE user_id = $1 ORDER BY created_at DESC LIMIT $2",
+        PAYMENT_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn list_user_ledger_entries(
+    pool: &PgPool,
+    user_id: Uuid,
+    limit: i64,
+) -> Result<Vec<LedgerEntry>, sqlx::Error> {
+    sqlx::query_as::<_, LedgerEntry>(
+        "SELECT id, user_id, payment_id, entry_type, amount_minor, currency, description, created_at FROM ledger_entries WHERE user_id = $1 ORDER BY created_at DESC, id DESC LIMIT $2",
+    )
+    .bind(user_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_billing_details(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Option<BillingDetails>, sqlx::Error> {
+    sqlx::query_as::<_, BillingDetails>(
+        "SELECT username, email, country, billing_name, billing_address, vat_id FROM users WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn update_billing_details(
+    pool: &PgPool,
+    user_id: Uuid,
+    billing_name: Option<&str>,
+    billing_address: Option<&str>,
+    vat_id: Option<&str>,
+    country: Option<&str>,
+) -> Result<Option<BillingDetails>, sqlx::Error> {
+    sqlx::query_as::<_, BillingDetails>(
+        "UPDATE users SET billing_name = $2, billing_address = $3, vat_id = $4, country = COALESCE($5, country) WHERE id = $1 \
+         RETURNING username, email, country, billing_name, billing_address, vat_id",
+    )
+    .bind(user_id)
+    .bind(billing_name)
+    .bind(billing_address)
+    .bind(vat_id)
+    .bind(country)
+    .fetch_optional(pool)
+    .await
+}
+
+// Ставка, действующая в стране на дату, и версия таблицы ставок
+pub async fn get_tax_rate(
+    conn: &mut PgConnection,
+    country: &str,
+    on: NaiveDate,
+) -> Result<Option<(i32, String)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT rate_bps, version FROM tax_rates WHERE country = $1 AND valid_from <= $2 \
+         ORDER BY valid_from DESC LIMIT 1",
+    )
+    .bind(country)
+    .bind(on)
+    .fetch_optional(conn)
+    .await
+}
+
+const INVOICE_COLUMNS: &str = "id, number, payment_id, user_id, issued_at, currency, seller, buyer, line_items, subtotal_minor, discount_minor, tax_minor, total_minor, \
+     tax_rate_bps, tax_treatment";
+
+// Следующий номер счёта за год; строка счётчика блокируется до конца транзакции,
+// поэтому номера идут без пропусков
+pub async fn next_invoice_number(conn: &mut PgConnection, year: i32) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "INSERT INTO invoice_sequences (year, last_number) VALUES ($1, 1) \
+         ON CONFLICT (year) DO UPDATE SET last_number = invoice_sequences.last_number + 1 \
+         RETURNING last_number",
+    )
+    .bind(year)
+    .fetch_one(conn)
+    .await
+}
+
+pub async fn create_invoice(conn: &mut PgConnection, invoice: &Invoice) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT INTO invoices ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)",
+        INVOICE_COLUMNS
+    ))
+    .bind(invoice.id)
+    .bind(&invoice.number)
+    .bind(invoice.payment_id)
+    .bind(invoice.user_id)
+    .bind(invoice.issued_at)
+    .bind(&invoice.currency)
+    .bind(&invoice.seller)
+    .bind(&invoice.buyer)
+    .bind(&invoice.line_items)
+    .bind(invoice.subtotal_minor)
+    .bind(invoice.discount_minor)
+    .bind(invoice.tax_minor)
+    .bind(invoice.total_minor)
+    .bind(invoice.tax_rate_bps)
+    .bind(&invoice.tax_treatment)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_invoice_by_payment(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+) -> Result<Option<Invoice>, sqlx::Error> {
+    sqlx::query_as::<_, Invoice>(&format!(
+        "SELECT {} FROM invoices WHERE payment_id = $1",
+        INVOICE_COLUMNS
+    ))
+    .bind(payment_id)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn get_user_invoice(
+    pool: &PgPool,
+    user_id: Uuid,
+    invoice_id: Uuid,
+) -> Result<Option<Invoice>, sqlx::Error> {
+    sqlx::query_as::<_, Invoice>(&format!(
+        "SELECT {} FROM invoices WHERE id = $1 AND user_id = $2",
+        INVOICE_COLUMNS
+    ))
+    .bind(invoice_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn list_user_invoices(
+    pool: &PgPool,
+    user_id: Uuid,
+    limit: i64,
+) -> Result<Vec<Invoice>, sqlx::Error> {
+    sqlx::query_as::<_, Invoice>(&format!(
+        "SELECT {} FROM invoices WHERE user_id = $1 ORDER BY issued_at DESC LIMIT $2",
+        INVOICE_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+// Пометка сохраняет время и причину первой пометки
+pub async fn flag_user(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    reason: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET flagged_at = COALESCE(flagged_at, NOW()), flag_reason = COALESCE(flag_reason, $2) WHERE id = $1")
+        .bind(user_id)
+        .bind(reason)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn unflag_user(pool: &PgPool, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE users SET flagged_at = NULL, flag_reason = NULL WHERE id = $1 AND flagged_at IS NOT NULL",
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn record_admin_action(
+    pool: &PgPool,
+    actor_id: Uuid,
+    action: &str,
+    target_user_id: Option<Uuid>,
+    payment_id: Option<Uuid>,
+    details: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO admin_actions (actor_id, action, target_user_id, payment_id, details, created_at) VALUES ($1, $2, $3, $4, $5, NOW())")
+        .bind(actor_id)
+        .bind(action)
+        .bind(target_user_id)
+        .bind(payment_id)
+        .bind(details)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn lock_subscription(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM subscriptions WHERE id = $1 FOR UPDATE",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(subscription_id)
+    .fetch_optional(conn)
+    .await
+}
+
+// Сокращение оплаченного периода (например, после частичного возврата); не раньше текущего момента
+pub async fn shorten_subscription(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+    days: i64,
+) -> Result<DateTime<Utc>, sqlx::Error> {
+    sqlx::query_scalar(
+        "UPDATE subscriptions SET expires_at = GREATEST(NOW(), expires_at - make_interval(days => $2::int)) WHERE id = $1 RETURNING expires_at",
+    )
+    .bind(subscription_id)
+    .bind(days)
+    .fetch_one(conn)
+    .await
+}
+
+// Захват ключа идемпотентности. Ключ можно занять заново, если он старше суток
+// или если предыдущий запрос с тем же телом не завершился до locked_until.
+// Возвращает None, если ключ уже занят или завершён, иначе время занятия ключа
+// (повтор после истёкшей блокировки сохраняет прежнее время).
+pub async fn begin_idempotent_request(
+    pool: &PgPool,
+    user_id: Uuid,
+    key: &str,
+    request_hash: &str,
+    lock_secs: i64,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar(
+        "INSERT INTO idempotency_keys (user_id, idempotency_key, request_hash, status, locked_until, created_at) VALUES ($1, $2, $3, 'in_progress', NOW() + make_interval(secs => $4::double precision), NOW()) \
+         ON CONFLICT (user_id, idempotency_key) DO UPDATE SET request_hash = EXCLUDED.request_hash, status = 'in_progress', response_status = NULL, response_body = NULL, locked_until = EXCLUDED.locked_until, \
+         created_at = CASE WHEN idempotency_keys.created_at < NOW() - INTERVAL '24 hours' THEN NOW() ELSE idempotency_keys.created_at END \
+         WHERE idempotency_keys.created_at < NOW() - INTERVAL '24 hours' OR (idempotency_keys.status = 'in_progress' AND idempotency_keys.locked_until < NOW() AND idempotency_keys.request_hash = EXCLUDED.request_hash) \
+         RETURNING created_at",
+    )
+    .bind(user_id)
+    .bind(key)
+    .bind(request_hash)
+    .bind(lock_secs as f64)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn get_idempotency_record(
+    pool: &PgPool,
+    user_id: Uuid,
+    key: &str,
+) -> Result<Option<IdempotencyRecord>, sqlx::Error> {
+    sqlx::query_as::<_, IdempotencyRecord>(
+        "SELECT request_hash, status, response_status, response_body FROM idempotency_keys WHERE user_id = $1 AND idempotency_key = $2",
+    )
+    .bind(user_id)
+    .bind(key)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn complete_idempotent_request(
+    pool: &PgPool,
+    user_id: Uuid,
+    key: &str,
+    response_status: i32,
+    response_body: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE idempotency_keys SET status = 'completed', response_status = $3, response_body = $4 WHERE user_id = $1 AND idempotency_key = $2")
+        .bind(user_id)
+        .bind(key)
+        .bind(response_status)
+        .bind(response_body)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+const CONTENT_COLUMNS: &str = "id, title, body, required_plan, tags, created_at, publish_at, premium_from, free_from, updated_at";
+
+// Удалённые материалы не выдаются
+pub async fn get_content_by_id(
+    pool: &PgPool,
+    content_id: Uuid,
+) -> Result<Option<Content>, sqlx::Error> {
+    sqlx::query_as::<_, Content>(&format!(
+        "SELECT {} FROM content WHERE id = $1 AND deleted_at IS NULL",
+        CONTENT_COLUMNS
+    ))
+    .bind(content_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn create_content(pool: &PgPool, content: &Content) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO content (id, title, body, required_plan, tags, created_at, publish_at, premium_from, free_from, updated_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+    )
+    .bind(content.id)
+    .bind(&content.title)
+    .bind(&content.body)
+    .bind(&content.required_plan)
+    .bind(&content.tags)
+    .bind(content.created_at)
+    .bind(content.publish_at)
+    .bind(content.premium_from)
+    .bind(content.free_from)
+    .bind(content.updated_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Редактируемые поля; публикация меняется отдельно (set_content_publish_at)
+pub async fn update_content(
+    pool: &PgPool,
+    content_id: Uuid,
+    changes: &ContentChanges,
+) -> Result<Option<Content>, sqlx::Error> {
+    sqlx::query_as::<_, Content>(&format!(
+        "UPDATE content SET title = $2, body = $3, required_plan = $4, tags = $5, \
+         premium_from = $6, free_from = $7, updated_at = NOW() \
+         WHERE id = $1 AND deleted_at IS NULL RETURNING {}",
+        CONTENT_COLUMNS
+    ))
+    .bind(content_id)
+    .bind(&changes.title)
+    .bind(&changes.body)
+    .bind(&changes.required_plan)
+    .bind(&changes.tags)
+    .bind(changes.premium_from)
+    .bind(changes.free_from)
+    .fetch_optional(pool)
+    .await
+}
+
+// None в publish_at снимает материал с публикации (черновик)
+pub async fn set_content_publish_at(
+    pool: &PgPool,
+    content_id: Uuid,
+    publish_at: Option<DateTime<Utc>>,
+) -> Result<Option<Content>, sqlx::Error> {
+    sqlx::query_as::<_, Content>(&format!(
+        "UPDATE content SET publish_at = $2, updated_at = NOW() \
+         WHERE id = $1 AND deleted_at IS NULL RETURNING {}",
+        CONTENT_COLUMNS
+    ))
+    .bind(content_id)
+    .bind(publish_at)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete_content(pool: &PgPool, content_id: Uuid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE content SET deleted_at = NOW(), updated_at = NOW() WHERE id = $1 AND deleted_at IS NULL",
+    )
+    .bind(content_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// status: draft (без publish_at) | scheduled (publish_at в будущем) | published; None — все
+pub async fn list_content(
+    pool: &PgPool,
+    status: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<Content>, sqlx::Error> {
+    sqlx::query_as::<_, Content>(&format!(
+        "SELECT {} FROM content WHERE deleted_at IS NULL AND ($1::TEXT IS NULL \
+         OR ($1 = 'draft' AND publish_at IS NULL) \
+         OR ($1 = 'scheduled' AND publish_at > NOW()) \
+         OR ($1 = 'published' AND publish_at <= NOW())) \
+         ORDER BY updated_at DESC LIMIT $2 OFFSET $3",
+        CONTENT_COLUMNS
+    ))
+    .bind(status)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+}
+
+// Платные на момент просмотра материалы, открытые пользователем с начала окна счётчика,
+// и был ли среди них этот
+pub async fn get_user_meter_views(
+    pool: &PgPool,
+    user_id: Uuid,
+    content_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<(i64, bool), sqlx::Error> {
+    sqlx::query_as(
+        "SELECT COUNT(DISTINCT b.content_id), COALESCE(BOOL_OR(b.content_id = $2), FALSE) \
+         FROM user_behaviors b JOIN content c ON c.id = b.content_id \
+         WHERE b.user_id = $1 AND b.timestamp >= $3 AND c.required_plan <> 'free' \
+         AND (c.premium_from IS NULL OR c.premium_from <= b.timestamp) \
+         AND (c.free_from IS NULL OR c.free_from > b.timestamp)",
+    )
+    .bind(user_id)
+    .bind(content_id)
+    .bind(since)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn log_user_behavior(pool: &PgPool, behavior: &UserBehavior) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO user_behaviors (user_id, content_id, view_time_seconds, scroll_depth_percent, interaction_score, timestamp) VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(behavior.user_id)
+        .bind(behavior.content_id)
+        .bind(behavior.view_time_seconds)
+        .bind(behavior.scroll_depth_percent)
+        .bind(behavior.interaction_score)
+        .bind(behavior.timestamp)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_user_profile(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<serde_json::Value, sqlx::Error> {
+    // Получаем информацию о подписке
+    let subscription_row = sqlx::query(
+        "SELECT plan_id, started_at, expires_at FROM subscriptions WHERE user_id = $1 AND is_active = true ORDER BY started_at DESC LIMIT 1"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    let subscription_info = subscription_row.map(|row| {
+        serde_json::json!({
+            "plan_id": row.get::<String, _>("plan_id"),
+            "started_at": row.get::<DateTime<Utc>, _>("started_at"),
+            "expires_at": row.get::<DateTime<Utc>, _>("expires_at"),
+        })
+    });
+
+    // Получаем статистику взаимодействий
+    let behavior_stats_row: (i64, Option<f64>) = sqlx::query_as(
+        "SELECT COUNT(*), AVG(interaction_score) FROM user_behaviors WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    let avg_score = behavior_stats_row.1.unwrap_or(0.0);
+
+    Ok(serde_json::json!({
+        "user_id": user_id,
+        "subscription": subscription_info,
+        "total_interactions": behavior_stats_row.0,
+        "avg_interaction_score": avg_score,
+    }))
+}
+
+// Функции для обучения ML модели (остаются как заглушки или для будущего использования)
+#[allow(dead_code)]
+pub async fn get_ml_training_data(
+    _pool: &PgPool,
+) -> Result<Vec<crate::models::MLFeatures>, sqlx::Error> {
+    // Заглушка или реализация для получения реальных данных
+    Ok(Vec::new())
+}
+
+#[allow(dead_code)]
+pub async fn get_ml_targets(_pool: &PgPool) -> Result<Vec<f32>, sqlx::Error> {
+    // Заглушка
+    Ok(Vec::new())
+}
+
+// Исправленные функции для извлечения признаков ML
+pub async fn get_user_subscription_days(pool: &PgPool, user_id: Uuid) -> Result<f64, sqlx::Error> {
+    let result: Option<f64> = sqlx::query_scalar(
+        "SELECT EXTRACT(EPOCH FROM (MAX(expires_at) - MIN(started_at))) / 86400.0 FROM subscriptions WHERE user_id = $1 AND is_active = true"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .flatten(); // Обрабатываем Option<Option<f64>>
+    Ok(result.unwrap_or(0.0))
+}
+
+pub async fn get_user_avg_view_time(pool: &PgPool, user_id: Uuid) -> Result<f64, sqlx::Error> {
+    let result: Option<f64> = sqlx::query_scalar(
+        "SELECT AVG(view_time_seconds)::double precision FROM user_behaviors WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .flatten();
+    Ok(result.unwrap_or(0.0))
+}
+
+pub async fn get_content_popularity_score(
+    pool: &PgPool,
+    content_id: Uuid,
+) -> Result<f64, sqlx::Error> {
+    // Простая метрика популярности: доля просмотров этого контента от общего числа просмотров
+    let total_views: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_behaviors")
+        .fetch_one(pool)
+        .await
+        .unwrap_or(1); // Избегаем деления на 0
+
+    let content_views: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM user_behaviors WHERE content_id = $1")
+            .bind(content_id)
+            .fetch_one(pool)
+            .await
+            .unwrap_or(0);
+
+    Ok((content_views as f64) / (total_views as f64).max(1.0)) // max(1.0) для избежания 0
+}
+
+pub async fn get_time_since_last_interaction(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<f64, sqlx::Error> {
+    let result: Option<f64> = sqlx::query_scalar(
+        "SELECT EXTRACT(EPOCH FROM (NOW() - MAX(timestamp))) FROM user_behaviors WHERE user_id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .flatten();
+    Ok(result.unwrap_or(0.0)) // 0 если нет взаимодействий
+}
+
+pub async fn get_user_total_interactions(pool: &PgPool, user_id: Uuid) -> Result<i64, sqlx::Error> {
+    let result: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_behaviors WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(result)
+}
+
+pub async fn get_content_avg_interaction_score(
+    pool: &PgPool,
+    content_id: Uuid,
+) -> Result<f64, sqlx::Error> {
+    let result: Option<f64> = sqlx::query_scalar(
+        "SELECT AVG(interaction_score)::double precision FROM user_behaviors WHERE content_id = $1",
+    )
+    .bind(content_id)
+    .fetch_optional(pool)
+    .await?
+    .flatten();
+    Ok(result.unwrap_or(0.0))
+}
+
+const GIFT_COLUMNS: &str = "id, code, purchaser_id, payment_id, plan_id, periods, duration_days, currency, recipient_email, recipient_name, message, \
+     deliver_at, delivered_at, status, redeemed_by, redeemed_at, subscription_id, created_at";
+
+pub async fn create_gift(pool: &PgPool, gift: &Gift) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT INTO gifts ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)",
+        GIFT_COLUMNS
+    ))
+    .bind(gift.id)
+    .bind(&gift.code)
+    .bind(gift.purchaser_id)
+    .bind(gift.payment_id)
+    .bind(&gift.plan_id)
+    .bind(gift.periods)
+    .bind(gift.duration_days)
+    .bind(&gift.currency)
+    .bind(&gift.recipient_email)
+    .bind(&gift.recipient_name)
+    .bind(&gift.message)
+    .bind(gift.deliver_at)
+    .bind(gift.delivered_at)
+    .bind(&gift.status)
+    .bind(gift.redeemed_by)
+    .bind(gift.redeemed_at)
+    .bind(gift.subscription_id)
+    .bind(gift.created_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn list_purchaser_gifts(
+    pool: &PgPool,
+    user_id: Uuid,
+    limit: i64,
+) -> Result<Vec<Gift>, sqlx::Error> {
+    sqlx::query_as::<_, Gift>(&format!(
+        "SELECT {} FROM gifts WHERE purchaser_id = $1 ORDER BY created_at DESC LIMIT $2",
+        GIFT_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+// Подарок к отправке забирается на lease_minutes; после сбоя процесса его заберут снова
+pub async fn claim_due_gift(
+    pool: &PgPool,
+    lease_minutes: i64,
+) -> Result<Option<Gift>, sqlx::Error> {
+    sqlx::query_as::<_, Gift>(&format!(
+        "UPDATE gifts SET status = 'sending', deliver_at = NOW() + make_interval(mins => $1::int) \
+         WHERE id = (SELECT id FROM gifts WHERE status IN ('scheduled', 'sending') AND deliver_at <= NOW() \
+         ORDER BY deliver_at LIMIT 1 FOR UPDATE SKIP LOCKED) RETURNING {}",
+        GIFT_COLUMNS
+    ))
+    .bind(lease_minutes)
+    .fetch_optional(pool)
+    .await
+}
+
+// Статус меняется, только если подарок не погасили, пока шла отправка
+pub async fn mark_gift_delivered(
+    conn: &mut PgConnection,
+    gift_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE gifts SET status = 'delivered', delivered_at = NOW() WHERE id = $1 AND status = 'sending'")
+        .bind(gift_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn postpone_gift_delivery(
+    pool: &PgPool,
+    gift_id: Uuid,
+    minutes: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE gifts SET status = 'scheduled', deliver_at = NOW() + make_interval(mins => $2::int) WHERE id = $1 AND status = 'sending'",
+    )
+    .bind(gift_id)
+    .bind(minutes)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn lock_gift_by_code(
+    conn: &mut PgConnection,
+    code: &str,
+) -> Result<Option<Gift>, sqlx::Error> {
+    sqlx::query_as::<_, Gift>(&format!(
+        "SELECT {} FROM gifts WHERE code = $1 FOR UPDATE",
+        GIFT_COLUMNS
+    ))
+    .bind(code)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn lock_gift_by_payment(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+) -> Result<Option<Gift>, sqlx::Error> {
+    sqlx::query_as::<_, Gift>(&format!(
+        "SELECT {} FROM gifts WHERE payment_id = $1 FOR UPDATE",
+        GIFT_COLUMNS
+    ))
+    .bind(payment_id)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn redeem_gift(
+    conn: &mut PgConnection,
+    gift_id: Uuid,
+    user_id: Uuid,
+    subscription_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE gifts SET status = 'redeemed', redeemed_by = $2, redeemed_at = NOW(), subscription_id = $3 WHERE id = $1")
+        .bind(gift_id)
+        .bind(user_id)
+        .bind(subscription_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// Оплата подарка подтверждена: он встаёт в очередь доставки
+pub async fn activate_awaiting_gift(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+) -> Result<Option<Gift>, sqlx::Error> {
+    sqlx::query_as::<_, Gift>(&format!(
+        "UPDATE gifts SET status = 'scheduled', deliver_at = GREATEST(deliver_at, NOW()) WHERE payment_id = $1 AND status = 'awaiting_payment' RETURNING {}",
+        GIFT_COLUMNS
+    ))
+    .bind(payment_id)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn void_awaiting_gift(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE gifts SET status = 'voided' WHERE payment_id = $1 AND status = 'awaiting_payment'",
+    )
+    .bind(payment_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Аннулирование неиспользованного подарка (после возврата оплаты)
+pub async fn void_unredeemed_gift(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE gifts SET status = 'voided' WHERE payment_id = $1 AND status IN ('scheduled', 'sending', 'delivered')",
+    )
+    .bind(payment_id)
+    .execute(conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+const FAMILY_MEMBER_COLUMNS: &str = "id, owner_id, subscription_id, email, member_id, invite_token, status, invited_at, joined_at, ended_at";
+
+// Подписка family владельца, в которой пользователь — активный участник
+pub async fn get_family_subscription(
+    pool: &PgPool,
+    member_id: Uuid,
+) -> Result<Option<Subscription>, sqlx::Error> {
+    sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM subscriptions WHERE id = (SELECT subscription_id FROM family_members WHERE member_id = $1 AND status = 'active') \
+         AND plan_id = 'family' AND is_active = true AND paused_at IS NULL \
+         AND (expires_at > NOW() OR (billing_status = 'past_due' AND grace_until > NOW()))",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(member_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn count_open_family_members(
+    conn: &mut PgConnection,
+    subscription_id: Uuid,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT COUNT(*) FROM family_members WHERE subscription_id = $1 AND status IN ('invited', 'active')",
+    )
+    .bind(subscription_id)
+    .fetch_one(conn)
+    .await
+}
+
+pub async fn create_family_invite(
+    conn: &mut PgConnection,
+    member: &FamilyMember,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT INTO family_members ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        FAMILY_MEMBER_COLUMNS
+    ))
+    .bind(member.id)
+    .bind(member.owner_id)
+    .bind(member.subscription_id)
+    .bind(&member.email)
+    .bind(member.member_id)
+    .bind(&member.invite_token)
+    .bind(&member.status)
+    .bind(member.invited_at)
+    .bind(member.joined_at)
+    .bind(member.ended_at)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn list_family_members(
+    pool: &PgPool,
+    subscription_id: Uuid,
+) -> Result<Vec<FamilyMember>, sqlx::Error> {
+    sqlx::query_as::<_, FamilyMember>(&format!(
+        "SELECT {} FROM family_members WHERE subscription_id = $1 AND status IN ('invited', 'active') ORDER BY invited_at",
+        FAMILY_MEMBER_COLUMNS
+    ))
+    .bind(subscription_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_active_family_membership(
+    pool: &PgPool,
+    member_id: Uuid,
+) -> Result<Option<FamilyMember>, sqlx::Error> {
+    sqlx::query_as::<_, FamilyMember>(&format!(
+        "SELECT {} FROM family_members WHERE member_id = $1 AND status = 'active'",
+        FAMILY_MEMBER_COLUMNS
+    ))
+    .bind(member_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn lock_family_invite_by_token(
+    conn: &mut PgConnection,
+    token: &str,
+) -> Result<Option<FamilyMember>, sqlx::Error> {
+    sqlx::query_as::<_, FamilyMember>(&format!(
+        "SELECT {} FROM family_members WHERE invite_token = $1 FOR UPDATE",
+        FAMILY_MEMBER_COLUMNS
+    ))
+    .bind(token)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn activate_family_member(
+    conn: &mut PgConnection,
+    membership_id: Uuid,
+    member_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE family_members SET status = 'active', member_id = $2, joined_at = NOW() WHERE id = $1")
+        .bind(membership_id)
+        .bind(member_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// Завершение приглашения или участия (removed | left); None — уже завершено
+pub async fn end_family_membership(
+    pool: &PgPool,
+    membership_id: Uuid,
+    status: &str,
+) -> Result<Option<FamilyMember>, sqlx::Error> {
+    sqlx::query_as::<_, FamilyMember>(&format!(
+        "UPDATE family_members SET status = $2, ended_at = NOW() WHERE id = $1 AND status IN ('invited', 'active') RETURNING {}",
+        FAMILY_MEMBER_COLUMNS
+    ))
+    .bind(membership_id)
+    .bind(status)
+    .fetch_optional(pool)
+    .await
+}
+
+// Участие заканчивается, когда подписка владельца истекла, отменена или сменила тариф
+pub async fn end_lapsed_family_memberships(
+    conn: &mut PgConnection,
+) -> Result<Vec<FamilyMember>, sqlx::Error> {
+    sqlx::query_as::<_, FamilyMember>(&format!(
+        "UPDATE family_members f SET status = 'ended', ended_at = NOW() \
+         FROM subscriptions s WHERE s.id = f.subscription_id AND f.status IN ('invited', 'active') \
+         AND (s.is_active = false OR s.plan_id <> 'family' \
+              OR (s.paused_at IS NULL AND s.expires_at <= NOW() AND NOT (s.billing_status = 'past_due' AND s.grace_until > NOW()))) \
+         RETURNING {}",
+        FAMILY_MEMBER_COLUMNS
+            .split(", ")
+            .map(|c| format!("f.{}", c))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+    .fetch_all(conn)
+    .await
+}
+
+const ORGANIZATION_COLUMNS: &str = "id, name, plan_id, seats, license_expires_at, created_at";
+
+const ORGANIZATION_INVITE_COLUMNS: &str =
+    "id, organization_id, email, role, token, status, invited_by, created_at, accepted_at";
+
+pub async fn create_organization(
+    conn: &mut PgConnection,
+    organization: &Organization,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO organizations (id, name, plan_id, seats, license_expires_at, created_at) VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(organization.id)
+        .bind(&organization.name)
+        .bind(&organization.plan_id)
+        .bind(organization.seats)
+        .bind(organization.license_expires_at)
+        .bind(organization.created_at)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_organization(
+    pool: &PgPool,
+    organization_id: Uuid,
+) -> Result<Option<Organization>, sqlx::Error> {
+    sqlx::query_as::<_, Organization>(&format!(
+        "SELECT {} FROM organizations WHERE id = $1",
+        ORGANIZATION_COLUMNS
+    ))
+    .bind(organization_id)
+    .fetch_optional(pool)
+    .await
+}
+
+// Блокировка организации сериализует всё, что занимает или освобождает места
+pub async fn lock_organization(
+    conn: &mut PgConnection,
+    organization_id: Uuid,
+) -> Result<Option<Organization>, sqlx::Error> {
+    sqlx::query_as::<_, Organization>(&format!(
+        "SELECT {} FROM organizations WHERE id = $1 FOR UPDATE",
+        ORGANIZATION_COLUMNS
+    ))
+    .bind(organization_id)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn update_organization_license(
+    pool: &PgPool,
+    organization_id: Uuid,
+    plan_id: Option<&str>,
+    seats: Option<i32>,
+    license_expires_at: Option<DateTime<Utc>>,
+) -> Result<Option<Organization>, sqlx::Error> {
+    sqlx::query_as::<_, Organization>(&format!(
+        "UPDATE organizations SET plan_id = COALESCE($2, plan_id), seats = COALESCE($3, seats), \
+         license_expires_at = COALESCE($4, license_expires_at) WHERE id = $1 RETURNING {}",
+        ORGANIZATION_COLUMNS
+    ))
+    .bind(organization_id)
+    .bind(plan_id)
+    .bind(seats)
+    .bind(license_expires_at)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn add_organization_member(
+    conn: &mut PgConnection,
+    organization_id: Uuid,
+    user_id: Uuid,
+    role: &str,
+    joined_via: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO organization_members (organization_id, user_id, role, joined_via, joined_at) VALUES ($1, $2, $3, $4, NOW())")
+        .bind(organization_id)
+        .bind(user_id)
+        .bind(role)
+        .bind(joined_via)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn remove_organization_member(
+    conn: &mut PgConnection,
+    organization_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result =
+        sqlx::query("DELETE FROM organization_members WHERE organization_id = $1 AND user_id = $2")
+            .bind(organization_id)
+            .bind(user_id)
+            .execute(conn)
+            .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_organization_role(
+    conn: &mut PgConnection,
+    organization_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT role FROM organization_members WHERE organization_id = $1 AND user_id = $2",
+    )
+    .bind(organization_id)
+    .bind(user_id)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn count_organization_admins(
+    conn: &mut PgConnection,
+    organization_id: Uuid,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT COUNT(*) FROM organization_members WHERE organization_id = $1 AND role = 'admin'",
+    )
+    .bind(organization_id)
+    .fetch_one(conn)
+    .await
+}
+
+// Занятые места: участники и неотвеченные приглашения
+pub async fn count_organization_seats_used(
+    conn: &mut PgConnection,
+    organization_id: Uuid,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT (SELECT COUNT(*) FROM organization_members WHERE organization_id = $1) \
+              + (SELECT COUNT(*) FROM organization_invites WHERE organization_id = $1 AND status = 'pending')",
+    )
+    .bind(organization_id)
+    .fetch_one(conn)
+    .await
+}
+
+pub async fn is_organization_member_email(
+    conn: &mut PgConnection,
+    organization_id: Uuid,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM organization_members m JOIN users u ON u.id = m.user_id \
+         WHERE m.organization_id = $1 AND lower(u.email) = lower($2))",
+    )
+    .bind(organization_id)
+    .bind(email)
+    .fetch_one(conn)
+    .await
+}
+
+pub async fn list_user_organizations(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Vec<OrganizationMembership>, sqlx::Error> {
+    sqlx::query_as::<_, OrganizationMembership>(
+        "SELECT o.id AS organization_id, o.name, o.plan_id, o.license_expires_at, m.role, m.joined_via, m.joined_at \
+         FROM organization_members m JOIN organizations o ON o.id = m.organization_id \
+         WHERE m.user_id = $1 ORDER BY m.joined_at",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+// Участники с датой последнего просмотра контента (для отчёта об использовании мест)
+pub async fn list_organization_members(
+    pool: &PgPool,
+    organization_id: Uuid,
+) -> Result<Vec<OrganizationMember>, sqlx::Error> {
+    sqlx::query_as::<_, OrganizationMember>(
+        "SELECT m.user_id, u.username, u.email, m.role, m.joined_via, m.joined_at, \
+         (SELECT MAX(b.timestamp) FROM user_behaviors b WHERE b.user_id = m.user_id) AS last_active_at \
+         FROM organization_members m JOIN users u ON u.id = m.user_id \
+         WHERE m.organization_id = $1 ORDER BY m.joined_at",
+    )
+    .bind(organization_id)
+    .fetch_all(pool)
+    .await
+}
+
+// Тарифы действующих лицензий организаций, в которых состоит пользователь
+// Действующие лицензии организаций пользователя: (организация, тариф)
+pub async fn get_organization_licenses(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Vec<(Uuid, String)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT o.id, o.plan_id FROM organization_members m JOIN organizations o ON o.id = m.organization_id \
+         WHERE m.user_id = $1 AND o.license_expires_at > NOW()",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn create_organization_invite(
+    conn: &mut PgConnection,
+    invite: &OrganizationInvite,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO organization_invites (id, organization_id, email, role, token, status, invited_by, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+        .bind(invite.id)
+        .bind(invite.organization_id)
+        .bind(&invite.email)
+        .bind(&invite.role)
+        .bind(&invite.token)
+        .bind(&invite.status)
+        .bind(invite.invited_by)
+        .bind(invite.created_at)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn list_pending_organization_invites(
+    pool: &PgPool,
+    organization_id: Uuid,
+) -> Result<Vec<OrganizationInvite>, sqlx::Error> {
+    sqlx::query_as::<_, OrganizationInvite>(&format!(
+        "SELECT {} FROM organization_invites WHERE organization_id = $1 AND status = 'pending' ORDER BY created_at",
+        ORGANIZATION_INVITE_COLUMNS
+    ))
+    .bind(organization_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn lock_organization_invite_by_token(
+    conn: &mut PgConnection,
+    token: &str,
+) -> Result<Option<OrganizationInvite>, sqlx::Error> {
+    sqlx::query_as::<_, OrganizationInvite>(&format!(
+        "SELECT {} FROM organization_invites WHERE token = $1 FOR UPDATE",
+        ORGANIZATION_INVITE_COLUMNS
+    ))
+    .bind(token)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn accept_organization_invite(
+    conn: &mut PgConnection,
+    invite_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE organization_invites SET status = 'accepted', accepted_at = NOW() WHERE id = $1",
+    )
+    .bind(invite_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Приглашения на адрес, владелец которого присоединился по домену, больше не держат места;
+// возвращаются роли из принятых приглашений
+pub async fn accept_pending_organization_invites_for_email(
+    conn: &mut PgConnection,
+    organization_id: Uuid,
+    email: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "UPDATE organization_invites SET status = 'accepted', accepted_at = NOW() \
+         WHERE organization_id = $1 AND lower(email) = lower($2) AND status = 'pending' RETURNING role",
+    )
+    .bind(organization_id)
+    .bind(email)
+    .fetch_all(conn)
+    .await
+}
+
+pub async fn revoke_organization_invite(
+    pool: &PgPool,
+    organization_id: Uuid,
+    invite_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE organization_invites SET status = 'revoked' WHERE id = $1 AND organization_id = $2 AND status = 'pending'",
+    )
+    .bind(invite_id)
+    .bind(organization_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn create_organization_domain(
+    pool: &PgPool,
+    domain: &OrganizationDomain,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO organization_domains (domain, organization_id, verification_token, verified_at, created_at) VALUES ($1, $2, $3, $4, $5)")
+        .bind(&domain.domain)
+        .bind(domain.organization_id)
+        .bind(&domain.verification_token)
+        .bind(domain.verified_at)
+        .bind(domain.created_at)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn list_organization_domains(
+    pool: &PgPool,
+    organization_id: Uuid,
+) -> Result<Vec<OrganizationDomain>, sqlx::Error> {
+    sqlx::query_as::<_, OrganizationDomain>(
+        "SELECT domain, organization_id, verification_token, verified_at, created_at \
+         FROM organization_domains WHERE organization_id = $1 ORDER BY domain",
+    )
+    .bind(organization_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn verify_organization_domain(
+    pool: &PgPool,
+    organization_id: Uuid,
+    domain: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE organization_domains SET verified_at = COALESCE(verified_at, NOW()) \
+         WHERE organization_id = $1 AND domain = $2",
+    )
+    .bind(organization_id)
+    .bind(domain)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Организация, владеющая подтверждённым доменом
+pub async fn get_verified_domain_organization(
+    pool: &PgPool,
+    domain: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT organization_id FROM organization_domains WHERE domain = $1 AND verified_at IS NOT NULL",
+    )
+    .bind(domain)
+    .fetch_optional(pool)
+    .await
+}
+
+const INSTITUTION_COLUMNS: &str = "id, name, plan_id, is_active, created_at";
+
+pub async fn create_institution(
+    conn: &mut PgConnection,
+    institution: &Institution,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO institutions (id, name, plan_id, is_active, created_at) VALUES ($1, $2, $3, $4, $5)")
+        .bind(institution.id)
+        .bind(&institution.name)
+        .bind(&institution.plan_id)
+        .bind(institution.is_active)
+        .bind(institution.created_at)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn update_institution(
+    conn: &mut PgConnection,
+    institution_id: Uuid,
+    name: Option<&str>,
+    plan_id: Option<&str>,
+    is_active: Option<bool>,
+) -> Result<Option<Institution>, sqlx::Error> {
+    sqlx::query_as::<_, Institution>(&format!(
+        "UPDATE institutions SET name = COALESCE($2, name), plan_id = COALESCE($3, plan_id), \
+         is_active = COALESCE($4, is_active) WHERE id = $1 RETURNING {}",
+        INSTITUTION_COLUMNS
+    ))
+    .bind(institution_id)
+    .bind(name)
+    .bind(plan_id)
+    .bind(is_active)
+    .fetch_optional(conn)
+    .await
+}
+
+pub async fn replace_institution_ip_ranges(
+    conn: &mut PgConnection,
+    institution_id: Uuid,
+    cidrs: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM institution_ip_ranges WHERE institution_id = $1")
+        .bind(institution_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("INSERT INTO institution_ip_ranges (institution_id, cidr) SELECT $1, UNNEST($2::TEXT[]) ON CONFLICT DO NOTHING")
+        .bind(institution_id)
+        .bind(cidrs)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn replace_institution_referrers(
+    conn: &mut PgConnection,
+    institution_id: Uuid,
+    hosts: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM institution_referrers WHERE institution_id = $1")
+        .bind(institution_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("INSERT INTO institution_referrers (institution_id, host) SELECT $1, UNNEST($2::TEXT[]) ON CONFLICT DO NOTHING")
+        .bind(institution_id)
+        .bind(hosts)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn list_institutions(pool: &PgPool) -> Result<Vec<Institution>, sqlx::Error> {
+    sqlx::query_as::<_, Institution>(&format!(
+        "SELECT {} FROM institutions ORDER BY name",
+        INSTITUTION_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_institution(
+    pool: &PgPool,
+    institution_id: Uuid,
+) -> Result<Option<Institution>, sqlx::Error> {
+    sqlx::query_as::<_, Institution>(&format!(
+        "SELECT {} FROM institutions WHERE id = $1",
+        INSTITUTION_COLUMNS
+    ))
+    .bind(institution_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn list_institution_ip_ranges(
+    pool: &PgPool,
+) -> Result<Vec<InstitutionIpRange>, sqlx::Error> {
+    sqlx::query_as::<_, InstitutionIpRange>(
+        "SELECT institution_id, cidr FROM institution_ip_ranges ORDER BY institution_id, cidr",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn list_institution_referrers(
+    pool: &PgPool,
+) -> Result<Vec<InstitutionReferrer>, sqlx::Error> {
+    sqlx::query_as::<_, InstitutionReferrer>(
+        "SELECT institution_id, host FROM institution_referrers ORDER BY institution_id, host",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn record_institution_usage(
+    pool: &PgPool,
+    institution_id: Uuid,
+    content_id: Uuid,
+    metric: &str,
+    access_method: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO institution_usage (institution_id, content_id, day, metric, access_method, count) \
+         VALUES ($1, $2, CURRENT_DATE, $3, $4, 1) \
+         ON CONFLICT (institution_id, day, content_id, metric, access_method) \
+         DO UPDATE SET count = institution_usage.count + 1",
+    )
+    .bind(institution_id)
+    .bind(content_id)
+    .bind(metric)
+    .bind(access_method)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Помесячные суммы за период [begin, end] включительно
+pub async fn get_institution_usage(
+    pool: &PgPool,
+    institution_id: Uuid,
+    begin: NaiveDate,
+    end: NaiveDate,
+) -> Result<Vec<InstitutionUsageRow>, sqlx::Error> {
+    sqlx::query_as::<_, InstitutionUsageRow>(
+        "SELECT u.content_id, c.title, date_trunc('month', u.day)::DATE AS month, u.metric, u.access_method, \
+         SUM(u.count)::BIGINT AS count \
+         FROM institution_usage u LEFT JOIN content c ON c.id = u.content_id \
+         WHERE u.institution_id = $1 AND u.day BETWEEN $2 AND $3 \
+         GROUP BY u.content_id, c.title, month, u.metric, u.access_method \
+         ORDER BY c.title, u.content_id, month",
+    )
+    .bind(institution_id)
+    .bind(begin)
+    .bind(end)
+    .fetch_all(pool)
+    .await
+}
+
+// Регистрирует посетителя при первом появлении или обновляет время последнего визита
+pub async fn touch_anonymous_visitor(
+    conn: &mut PgConnection,
+    visitor_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO anonymous_visitors (id, created_at, last_seen_at) VALUES ($1, NOW(), NOW()) \
+         ON CONFLICT (id) DO UPDATE SET last_seen_at = NOW()",
+    )
+    .bind(visitor_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Материалы, открытые посетителем с начала окна счётчика
+pub async fn list_anonymous_views_since(
+    pool: &PgPool,
+    visitor_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT content_id FROM anonymous_content_views WHERE visitor_id = $1 AND viewed_at >= $2",
+    )
+    .bind(visitor_id)
+    .bind(since)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn record_anonymous_view(
+    conn: &mut PgConnection,
+    visitor_id: Uuid,
+    content_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO anonymous_content_views (visitor_id, content_id, viewed_at) VALUES ($1, $2, NOW()) \
+         ON CONFLICT (visitor_id, content_id) DO UPDATE SET viewed_at = NOW()",
+    )
+    .bind(visitor_id)
+    .bind(content_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn mark_visitor_converted(
+    pool: &PgPool,
+    visitor_id: Uuid,
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE anonymous_visitors SET converted_user_id = $2, converted_at = NOW() \
+         WHERE id = $1 AND converted_user_id IS NULL",
+    )
+    .bind(visitor_id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn insert_paywall_decision(
+    pool: &PgPool,
+    decision: &PaywallDecision,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO paywall_decisions (id, content_id, user_id, visitor_id, institution_id, rule, outcome, \
+         access_granted, sample_reason, record, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+    )
+    .bind(decision.id)
+    .bind(decision.content_id)
+    .bind(decision.user_id)
+    .bind(decision.visitor_id)
+    .bind(decision.institution_id)
+    .bind(&decision.rule)
+    .bind(&decision.outcome)
+    .bind(decision.access_granted)
+    .bind(&decision.sample_reason)
+    .bind(&decision.record)
+    .bind(decision.created_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+const PAYWALL_DECISION_COLUMNS: &str = "id, content_id, user_id, visitor_id, institution_id, rule, outcome, access_granted, sample_reason, record, created_at";
+
+pub async fn get_paywall_decision(
+    pool: &PgPool,
+    decision_id: Uuid,
+) -> Result<Option<PaywallDecision>, sqlx::Error> {
+    sqlx::query_as::<_, PaywallDecision>(&format!(
+        "SELECT {} FROM paywall_decisions WHERE id = $1",
+        PAYWALL_DECISION_COLUMNS
+    ))
+    .bind(decision_id)
+    .fetch_optional(pool)
+    .await
+}
+
+// Последние решения; незаданные фильтры не применяются
+pub async fn list_paywall_decisions(
+    pool: &PgPool,
+    user_id: Option<Uuid>,
+    visitor_id: Option<Uuid>,
+    content_id: Option<Uuid>,
+    outcome: Option<&str>,
+    limit: i64,
+) -> Result<Vec<PaywallDecision>, sqlx::Error> {
+    sqlx::query_as::<_, PaywallDecision>(&format!(
+        "SELECT {} FROM paywall_decisions \
+         WHERE ($1::uuid IS NULL OR user_id = $1) AND ($2::uuid IS NULL OR visitor_id = $2) \
+         AND ($3::uuid IS NULL OR content_id = $3) AND ($4::text IS NULL OR outcome = $4) \
+         ORDER BY created_at DESC LIMIT $5",
+        PAYWALL_DECISION_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(visitor_id)
+    .bind(content_id)
+    .bind(outcome)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn delete_paywall_decisions_before(
+    pool: &PgPool,
+    before: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM paywall_decisions WHERE created_at < $1")
+        .bind(before)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+const EXPERIMENT_COLUMNS: &str = "id, key, name, description, status, traffic_percent, created_by, created_at, started_at, stopped_at";
+
+pub async fn create_experiment(
+    conn: &mut PgConnection,
+    experiment: &Experiment,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO experiments (id, key, name, description, status, traffic_percent, created_by, created_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+    )
+    .bind(experiment.id)
+    .bind(&experiment.key)
+    .bind(&experiment.name)
+    .bind(&experiment.description)
+    .bind(&experiment.status)
+    .bind(experiment.traffic_percent)
+    .bind(experiment.created_by)
+    .bind(experiment.created_at)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn create_experiment_variant(
+    conn: &mut PgConnection,
+    variant: &ExperimentVariant,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO experiment_variants (experiment_id, key, name, position, weight, rules) \
+         VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(variant.experiment_id)
+    .bind(&variant.key)
+    .bind(&variant.name)
+    .bind(variant.position)
+    .bind(variant.weight)
+    .bind(&variant.rules)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_experiment(
+    pool: &PgPool,
+    experiment_id: Uuid,
+) -> Result<Option<Experiment>, sqlx::Error> {
+    sqlx::query_as::<_, Experiment>(&format!(
+        "SELECT {} FROM experiments WHERE id = $1",
+        EXPERIMENT_COLUMNS
+    ))
+    .bind(experiment_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn get_running_experiment(pool: &PgPool) -> Result<Option<Experiment>, sqlx::Error> {
+    sqlx::query_as::<_, Experiment>(&format!(
+        "SELECT {} FROM experiments WHERE status = 'running'",
+        EXPERIMENT_COLUMNS
+    ))
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn list_experiments(pool: &PgPool) -> Result<Vec<Experiment>, sqlx::Error> {
+    sqlx::query_as::<_, Experiment>(&format!(
+        "SELECT {} FROM experiments ORDER BY created_at DESC",
+        EXPERIMENT_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn list_experiment_variants(
+    pool: &PgPool,
+    experiment_id: Uuid,
+) -> Result<Vec<ExperimentVariant>, sqlx::Error> {
+    sqlx::query_as::<_, ExperimentVariant>(
+        "SELECT experiment_id, key, name, position, weight, rules FROM experiment_variants \
+         WHERE experiment_id = $1 ORDER BY position",
+    )
+    .bind(experiment_id)
+    .fetch_all(pool)
+    .await
+}
+
+// Переход draft -> running; None — эксперимент не в черновике.
+// Второй идущий эксперимент отсекает уникальный индекс
+pub async fn start_experiment(
+    pool: &PgPool,
+    experiment_id: Uuid,
+) -> Result<Option<Experiment>, sqlx::Error> {
+    sqlx::query_as::<_, Experiment>(&format!(
+        "UPDATE experiments SET status = 'running', started_at = NOW() \
+         WHERE id = $1 AND status = 'draft' RETURNING {}",
+        EXPERIMENT_COLUMNS
+    ))
+    .bind(experiment_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn stop_experiment(
+    pool: &PgPool,
+    experiment_id: Uuid,
+) -> Result<Option<Experiment>, sqlx::Error> {
+    sqlx::query_as::<_, Experiment>(&format!(
+        "UPDATE experiments SET status = 'stopped', stopped_at = NOW() \
+         WHERE id = $1 AND status = 'running' RETURNING {}",
+        EXPERIMENT_COLUMNS
+    ))
+    .bind(experiment_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn record_experiment_exposure(
+    pool: &PgPool,
+    experiment_id: Uuid,
+    user_id: Uuid,
+    variant_key: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO experiment_exposures (experiment_id, user_id, variant_key, first_exposed_at, last_exposed_at, exposures) \
+         VALUES ($1, $2, $3, NOW(), NOW(), 1) \
+         ON CONFLICT (experiment_id, user_id) DO UPDATE SET last_exposed_at = NOW(), \
+         exposures = experiment_exposures.exposures + 1",
+    )
+    .bind(experiment_id)
+    .bind(user_id)
+    .bind(variant_key)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Конверсия — подписка, начатая после первого показа и до конца эксперимента ($2);
+// оплата — успешная покупка или повышение тарифа в том же окне
+pub async fn get_experiment_variant_stats(
+    pool: &PgPool,
+    experiment_id: Uuid,
+    until: DateTime<Utc>,
+) -> Result<Vec<ExperimentVariantStats>, sqlx::Error> {
+    sqlx::query_as::<_, ExperimentVariantStats>(
+        "SELECT e.variant_key, COUNT(*) AS exposed_users, \
+         COUNT(*) FILTER (WHERE EXISTS (SELECT 1 FROM subscriptions s WHERE s.user_id = e.user_id \
+             AND s.started_at >= e.first_exposed_at AND s.started_at < $2)) AS converted_users, \
+         COUNT(*) FILTER (WHERE EXISTS (SELECT 1 FROM payments p WHERE p.user_id = e.user_id \
+             AND p.kind IN ('purchase', 'upgrade') AND p.status IN ('succeeded', 'partially_refunded') \
+             AND p.created_at >= e.first_exposed_at AND p.created_at < $2)) AS paying_users \
+         FROM experiment_exposures e WHERE e.experiment_id = $1 GROUP BY e.variant_key",
+    )
+    .bind(experiment_id)
+    .bind(until)
+    .fetch_all(pool)
+    .await
+}
+
+// Чистая выручка (без налога и возвратов) по вариантам и валютам
+pub async fn get_experiment_revenue(
+    pool: &PgPool,
+    experiment_id: Uuid,
+    until: DateTime<Utc>,
+) -> Result<Vec<ExperimentRevenueRow>, sqlx::Error> {
+    sqlx::query_as::<_, ExperimentRevenueRow>(
+        "SELECT e.variant_key, p.currency, \
+         SUM(p.net_minor - LEAST(p.refunded_minor, p.net_minor))::BIGINT AS net_minor \
+         FROM experiment_exposures e JOIN payments p ON p.user_id = e.user_id \
+         WHERE e.experiment_id = $1 AND p.kind IN ('purchase', 'upgrade') \
+         AND p.status IN ('succeeded', 'partially_refunded') \
+         AND p.created_at >= e.first_exposed_at AND p.created_at < $2 \
+         GROUP BY e.variant_key, p.currency",
+    )
+    .bind(experiment_id)
+    .bind(until)
+    .fetch_all(pool)
+    .await
+}
+
+const OFFER_IMPRESSION_COLUMNS: &str = "id, user_id, content_id, context, arm, policy, shown_at, expires_at, converted_at, redeemed, subscription_id";
+
+pub async fn list_offer_arm_stats(pool: &PgPool) -> Result<Vec<OfferArmStats>, sqlx::Error> {
+    sqlx::query_as::<_, OfferArmStats>(
+        "SELECT context, arm, impressions, conversions, updated_at FROM offer_arm_stats \
+         ORDER BY context, arm",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn increment_offer_arm_stats(
+    conn: &mut PgConnection,
+    context: &str,
+    arm: &str,
+    impressions: i64,
+    conversions: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO offer_arm_stats (context, arm, impressions, conversions, updated_at) \
+         VALUES ($1, $2, $3, $4, NOW()) \
+         ON CONFLICT (context, arm) DO UPDATE SET \
+         impressions = offer_arm_stats.impressions + EXCLUDED.impressions, \
+         conversions = offer_arm_stats.conversions + EXCLUDED.conversions, updated_at = NOW()",
+    )
+    .bind(context)
+    .bind(arm)
+    .bind(impressions)
+    .bind(conversions)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn create_offer_impression(
+    conn: &mut PgConnection,
+    impression: &OfferImpression,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO offer_impressions (id, user_id, content_id, context, arm, policy, shown_at, expires_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+    )
+    .bind(impression.id)
+    .bind(impression.user_id)
+    .bind(impression.content_id)
+    .bind(&impression.context)
+    .bind(&impression.arm)
+    .bind(&impression.policy)
+    .bind(impression.shown_at)
+    .bind(impression.expires_at)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_offer_impression(
+    pool: &PgPool,
+    impression_id: Uuid,
+) -> Result<Option<OfferImpression>, sqlx::Error> {
+    sqlx::query_as::<_, OfferImpression>(&format!(
+        "SELECT {} FROM offer_impressions WHERE id = $1",
+        OFFER_IMPRESSION_COLUMNS
+    ))
+    .bind(impression_id)
+    .fetch_optional(pool)
+    .await
+}
+
+// Последнее действующее предложение пользователя (не истекло и не привело к покупке)
+pub async fn get_active_offer(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Option<OfferImpression>, sqlx::Error> {
+    sqlx::query_as::<_, OfferImpression>(&format!(
+        "SELECT {} FROM offer_impressions WHERE user_id = $1 AND converted_at IS NULL \
+         AND expires_at > NOW() ORDER BY shown_at DESC LIMIT 1",
+        OFFER_IMPRESSION_COLUMNS
+    ))
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+}
+
+// None — предложение уже засчитано (повторная покупка не даёт второй награды)
+pub async fn convert_offer_impression(
+    conn: &mut PgConnection,
+    impression_id: Uuid,
+    subscription_id: Uuid,
+    redeemed: bool,
+) -> Result<Option<OfferImpression>, sqlx::Error> {
+    sqlx::query_as::<_, OfferImpression>(&format!(
+        "UPDATE offer_impressions SET converted_at = NOW(), redeemed = $3, subscription_id = $2 \
+         WHERE id = $1 AND converted_at IS NULL RETURNING {}",
+        OFFER_IMPRESSION_COLUMNS
+    ))
+    .bind(impression_id)
+    .bind(subscription_id)
+    .bind(redeemed)
+    .fetch_optional(conn)
+    .await
 }
@@ -0,0 +1,361 @@
+// src/decisions.rs
+// Объяснимые решения пейвола: запись о том, какие подписка, источники доступа,
+// счётчик, правило и прогноз модели привели к ответу get_content. Сотрудник
+// получает запись прямо в ответе по заголовку X-Paywall-Debug: 1; кроме того,
+// доля решений (DECISION_SAMPLE_RATE для выдач, DECISION_SAMPLE_RATE_DENIED для
+// отказов) сохраняется для разбора обращений и аналитики.
+use crate::auth;
+use crate::config::Config;
+use crate::db;
+use crate::experiments::Assignment;
+use crate::institutions::InstitutionMatch;
+use crate::models::{MLFeatures, OfferImpression, PaywallDecision, Subscription};
+use crate::paywall::{AccessSource, Decision};
+use crate::rules::{Facts, LoadedRules, Outcome, RuleTrace};
+use actix_web::{HttpRequest, HttpResponse, get, web};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const SAMPLE_DEBUG: &str = "debug";
+const SAMPLE_RANDOM: &str = "sampled";
+const DEFAULT_LIST_LIMIT: i64 = 50;
+const MAX_LIST_LIMIT: i64 = 500;
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_decisions);
+    cfg.service(get_decision);
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DecisionRecord {
+    pub id: Uuid,
+    pub decided_at: DateTime<Utc>,
+    pub content_id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub visitor_id: Option<Uuid>,
+    // Действующая подписка, а без неё — последняя (истёкшая, приостановленная, отменённая)
+    pub subscription: Option<serde_json::Value>,
+    pub access_sources: Vec<AccessSource>,
+    pub institution: Option<InstitutionMatch>,
+    pub facts: Facts,
+    pub ml_features: Option<MLFeatures>,
+    pub rules_source: &'static str,
+    pub rules_version: Option<String>,
+    pub experiment: Option<Assignment>,
+    pub rule: Option<String>,
+    pub outcome: Outcome,
+    pub offer: Option<OfferImpression>,
+    pub access_granted: bool,
+    pub cacheable: bool,
+    pub trace: Vec<RuleTrace>,
+}
+
+impl DecisionRecord {
+    pub async fn build(
+        pool: &PgPool,
+        content_id: Uuid,
+        user_id: Option<Uuid>,
+        visitor_id: Option<Uuid>,
+        institution: Option<&InstitutionMatch>,
+        decision: &Decision,
+        rules: &LoadedRules,
+    ) -> Result<DecisionRecord, sqlx::Error> {
+        let subscription = match (&decision.access.subscription, user_id) {
+            (Some(sub), _) => Some(subscription_summary(sub, true)),
+            (None, Some(user_id)) => db::get_latest_subscription(pool, user_id)
+                .await?
+                .map(|sub| subscription_summary(&sub, false)),
+            (None, None) => None,
+        };
+        let evaluation = &decision.evaluation;
+        Ok(DecisionRecord {
+            id: Uuid::new_v4(),
+            decided_at: decision.facts.at,
+            content_id,
+            user_id,
+            visitor_id,
+            subscription,
+            access_sources: decision.access.sources.clone(),
+            institution: institution.cloned(),
+            facts: decision.facts.clone(),
+            ml_features: decision.ml_features.clone(),
+            rules_source: rules.source,
+            rules_version: rules.rules.version.clone(),
+            experiment: decision.experiment.clone(),
+            rule: evaluation.rule.clone(),
+            outcome: evaluation.outcome.clone(),
+            offer: decision.offer.clone(),
+            access_granted: evaluation.outcome.grants_access(),
+            cacheable: !evaluation.volatile,
+            trace: evaluation.trace.clone(),
+        })
+    }
+
+    // Краткая форма для заголовка X-Paywall-Decision; значение заголовка — только ASCII
+    pub fn summary(&self) -> String {
+        let sources = if self.facts.entitlements.is_empty() {
+            "none".to_string()
+        } else {
+            self.facts.entitlements.join(",")
+        };
+        let ml = match self.facts.ml_positive {
+            Some(true) => "positive",
+            Some(false) => "negative",
+            None => "not_evaluated",
+        };
+        let mut summary = format!(
+            "rule={}; outcome={}; entitlements={}; meter={}; ml={}",
+            self.rule.as_deref().unwrap_or("(none)"),
+            self.outcome.name(),
+            sources,
+            self.facts.meter_views,
+            ml
+        );
+        if let Some(experiment) = &self.experiment {
+            summary.push_str(&format!(
+                "; experiment={}/{}",
+                experiment.experiment, experiment.variant
+            ));
+        }
+        summary
+            .chars()
+            .map(|c| {
+                if c == ' ' || c.is_ascii_graphic() {
+                    c
+                } else {
+                    '?'
+                }
+            })
+            .collect()
+    }
+}
+
+fn subscription_summary(sub: &Subscription, active: bool) -> serde_json::Value {
+    json!({
+        "id": sub.id,
+        "plan_id": sub.plan_id,
+        "active": active,
+        "billing_status": sub.billing_status,
+        "started_at": sub.started_at,
+        "expires_at": sub.expires_at,
+        "grace_until": sub.grace_until,
+        "paused_at": sub.paused_at,
+        "canceled_at": sub.canceled_at,
+        "expiration_reason": sub.expiration_reason,
+    })
+}
+
+// Заголовок учитывается только для сотрудников: правила и прогноз модели пользователю не показываются
+pub async fn debug_requested(pool: &PgPool, req: &HttpRequest) -> bool {
+    let requested = req
+        .headers()
+        .get("x-paywall-debug")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+    requested && auth::require_staff(pool, req).await.is_ok()
+}
+
+pub fn sample_reason(config: &Config, granted: bool) -> Option<&'static str> {
+    let rate = if granted {
+        config.decision_sample_rate
+    } else {
+        config.decision_sample_rate_denied
+    };
+    (rate > 0.0 && rand::thread_rng().gen_bool(rate.min(1.0))).then_some(SAMPLE_RANDOM)
+}
+
+// Сохранение не должно ломать выдачу контента
+pub async fn persist(pool: &PgPool, record: &DecisionRecord, reason: &str) {
+    let decision = PaywallDecision {
+        id: record.id,
+        content_id: record.content_id,
+        user_id: record.user_id,
+        visitor_id: record.visitor_id,
+        institution_id: record.institution.as_ref().map(|i| i.id),
+        rule: record.rule.clone(),
+        outcome: record.outcome.name().to_string(),
+        access_granted: record.access_granted,
+        sample_reason: reason.to_string(),
+        record: json!(record),
+        created_at: record.decided_at,
+    };
+    if let Err(e) = db::insert_paywall_decision(pool, &decision).await {
+        tracing::warn!("Failed to persist decision record {}: {}", record.id, e);
+    }
+}
+
+pub fn spawn_decision_purge_worker(pool: PgPool, retention_days: i64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            let cutoff = Utc::now() - chrono::Duration::days(retention_days.max(1));
+            match db::delete_paywall_decisions_before(&pool, cutoff).await {
+                Ok(0) => {}
+                Ok(deleted) => tracing::info!("Purged {} old decision records", deleted),
+                Err(e) => tracing::error!("Failed to purge decision records: {}", e),
+            }
+        }
+    });
+}
+
+#[derive(Deserialize)]
+pub struct DecisionsQuery {
+    user_id: Option<Uuid>,
+    visitor_id: Option<Uuid>,
+    content_id: Option<Uuid>,
+    outcome: Option<String>,
+    limit: Option<i64>,
+}
+
+#[get("/admin/decisions")]
+pub async fn list_decisions(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    query: web::Query<DecisionsQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_staff(&pool, &req).await {
+        return Ok(response);
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
+    match db::list_paywall_decisions(
+        &pool,
+        query.user_id,
+        query.visitor_id,
+        query.content_id,
+        query.outcome.as_deref(),
+        limit,
+    )
+    .await
+    {
+        Ok(decisions) => Ok(HttpResponse::Ok().json(json!({"decisions": decisions}))),
+        Err(e) => {
+            tracing::error!("Database error listing decision records: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[get("/admin/decisions/{decision_id}")]
+pub async fn get_decision(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_staff(&pool, &req).await {
+        return Ok(response);
+    }
+    match db::get_paywall_decision(&pool, path.into_inner()).await {
+        Ok(Some(decision)) => Ok(HttpResponse::Ok().json(decision)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "Decision not found"}))),
+        Err(e) => {
+            tracing::error!("Database error fetching decision record: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(rate: &str, rate_denied: &str) -> Config {
+        envy::from_iter(
+            [
+                ("DATABASE_URL", "postgres://localhost/test"),
+                ("JWT_SECRET", "secret"),
+                ("PAYMENT_API_KEY", "key"),
+                ("PAYMENT_API_URL", "http://localhost"),
+                ("DECISION_SAMPLE_RATE", rate),
+                ("DECISION_SAMPLE_RATE_DENIED", rate_denied),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string())),
+        )
+        .unwrap()
+    }
+
+    fn record(rule: Option<&str>, outcome: Outcome) -> DecisionRecord {
+        let now = Utc::now();
+        DecisionRecord {
+            id: Uuid::new_v4(),
+            decided_at: now,
+            content_id: Uuid::new_v4(),
+            user_id: None,
+            visitor_id: None,
+            subscription: None,
+            access_sources: Vec::new(),
+            institution: None,
+            facts: Facts {
+                logged_in: true,
+                plan: Some("basic".to_string()),
+                entitlements: vec!["family", "institution"],
+                institution: true,
+                content_plan: "premium".to_string(),
+                content_tags: Vec::new(),
+                content_age_hours: 1,
+                referrer: None,
+                device: "desktop",
+                meter_views: 2,
+                already_viewed: false,
+                ml_positive: None,
+                at: now,
+            },
+            ml_features: None,
+            rules_source: "builtin",
+            rules_version: None,
+            experiment: None,
+            rule: rule.map(str::to_string),
+            outcome,
+            offer: None,
+            access_granted: false,
+            cacheable: true,
+            trace: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn summary_lists_rule_sources_meter_and_model() {
+        let record = record(Some("entitled"), Outcome::Grant);
+        assert_eq!(
+            record.summary(),
+            "rule=entitled; outcome=grant; entitlements=family,institution; meter=2; ml=not_evaluated"
+        );
+    }
+
+    #[test]
+    fn summary_includes_experiment_and_stays_ascii() {
+        let mut record = record(None, Outcome::HardWall { message: None });
+        record.facts.entitlements.clear();
+        record.facts.ml_positive = Some(false);
+        record.experiment = Some(Assignment {
+            experiment_id: Uuid::new_v4(),
+            experiment: "стена".to_string(),
+            variant: "b".to_string(),
+            rules: None,
+        });
+        assert_eq!(
+            record.summary(),
+            "rule=(none); outcome=hard_wall; entitlements=none; meter=2; ml=negative; experiment=?????/b"
+        );
+    }
+
+    #[test]
+    fn sampling_uses_rate_for_outcome() {
+        let denied_only = config("0", "1");
+        assert_eq!(sample_reason(&denied_only, true), None);
+        assert_eq!(sample_reason(&denied_only, false), Some(SAMPLE_RANDOM));
+        // Доля больше единицы означает «сохранять всё»
+        let granted_only = config("5", "0");
+        assert_eq!(sample_reason(&granted_only, true), Some(SAMPLE_RANDOM));
+        assert_eq!(sample_reason(&granted_only, false), None);
+    }
+}
@@ -0,0 +1,425 @@
+// src/editor.rs
+// API редакторов материалов (роль editor или admin): создание, правка, публикация
+// по расписанию, снятие с публикации и удаление. Новый материал — черновик;
+// изменения сразу сбрасывают закешированные решения пейвола по материалу.
+use crate::auth;
+use crate::db;
+use crate::models::{Content, ContentChanges, ContentRequest, PublishRequest};
+use crate::paywall;
+use actix_web::{HttpRequest, HttpResponse, delete, get, post, put, web};
+use chrono::Utc;
+use moka::future::Cache;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+const CONTENT_PLANS: &[&str] = &["free", "basic", "premium"];
+const MAX_TITLE_CHARS: usize = 300;
+const MAX_BODY_BYTES: usize = 500_000;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_CHARS: usize = 50;
+const DEFAULT_LIST_LIMIT: i64 = 50;
+const MAX_LIST_LIMIT: i64 = 200;
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_content);
+    cfg.service(get_content);
+    cfg.service(create_content);
+    cfg.service(update_content);
+    cfg.service(publish_content);
+    cfg.service(unpublish_content);
+    cfg.service(delete_content);
+}
+
+fn status(content: &Content) -> &'static str {
+    match content.publish_at {
+        None => "draft",
+        Some(publish_at) if publish_at > Utc::now() => "scheduled",
+        Some(_) => "published",
+    }
+}
+
+fn content_json(content: &Content) -> serde_json::Value {
+    json!({"content": content, "status": status(content)})
+}
+
+// Проверенные поля материала: заголовок и текст без краевых пробелов,
+// теги в нижнем регистре без повторов
+fn validate(content_req: &ContentRequest) -> Result<ContentChanges, HttpResponse> {
+    let bad_request = |error: &str| HttpResponse::BadRequest().json(json!({"error": error}));
+    let title = content_req.title.trim();
+    if title.is_empty() || title.chars().count() > MAX_TITLE_CHARS {
+        return Err(bad_request("Title must be 1-300 characters"));
+    }
+    let body = content_req.body.trim();
+    if body.is_empty() || body.len() > MAX_BODY_BYTES {
+        return Err(bad_request(
+            "Body must be non-empty and at most 500000 bytes",
+        ));
+    }
+    if !CONTENT_PLANS.contains(&content_req.required_plan.as_str()) {
+        return Err(bad_request("Invalid required_plan"));
+    }
+
+    let mut tags: Vec<String> = Vec::new();
+    for tag in &content_req.tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_CHARS {
+            return Err(bad_request("Tags must be 1-50 characters"));
+        }
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(bad_request("Too many tags"));
+    }
+
+    // Окна меняют только платный материал (см. Content::plan_at)
+    let has_windows = content_req.premium_from.is_some() || content_req.free_from.is_some();
+    if has_windows && content_req.required_plan == "free" {
+        return Err(bad_request(
+            "premium_from and free_from require a paid required_plan",
+        ));
+    }
+    if let (Some(premium_from), Some(free_from)) = (content_req.premium_from, content_req.free_from)
+        && premium_from >= free_from
+    {
+        return Err(bad_request("premium_from must be earlier than free_from"));
+    }
+
+    Ok(ContentChanges {
+        title: title.to_string(),
+        body: body.to_string(),
+        required_plan: content_req.required_plan.clone(),
+        tags,
+        premium_from: content_req.premium_from,
+        free_from: content_req.free_from,
+    })
+}
+
+async fn log_action(pool: &sqlx::PgPool, editor_id: Uuid, action: &str, content: &Content) {
+    let details = json!({"content_id": content.id, "title": content.title});
+    if let Err(e) = db::record_admin_action(pool, editor_id, action, None, None, &details).await {
+        tracing::error!("Failed to record admin action: {}", e);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ContentListQuery {
+    status: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[get("/editor/content")]
+pub async fn list_content(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    query: web::Query<ContentListQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_editor(&pool, &req).await {
+        return Ok(response);
+    }
+    let status_filter = query.status.as_deref();
+    if status_filter.is_some_and(|s| !["draft", "scheduled", "published"].contains(&s)) {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid status"})));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    match db::list_content(&pool, status_filter, limit, offset).await {
+        Ok(content) => {
+            let content: Vec<_> = content.iter().map(content_json).collect();
+            Ok(HttpResponse::Ok().json(json!({"content": content})))
+        }
+        Err(e) => {
+            tracing::error!("Database error listing content: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Черновики и запланированные материалы видны редактору
+#[get("/editor/content/{content_id}")]
+pub async fn get_content(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_editor(&pool, &req).await {
+        return Ok(response);
+    }
+    match db::get_content_by_id(&pool, path.into_inner()).await {
+        Ok(Some(content)) => Ok(HttpResponse::Ok().json(content_json(&content))),
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "Content not found"}))),
+        Err(e) => {
+            tracing::error!("Database error fetching content: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/editor/content")]
+pub async fn create_content(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    content_req: web::Json<ContentRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let editor_id = match auth::require_editor(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let valid = match validate(&content_req) {
+        Ok(valid) => valid,
+        Err(response) => return Ok(response),
+    };
+
+    let now = Utc::now();
+    let content = Content {
+        id: Uuid::new_v4(),
+        title: valid.title,
+        body: valid.body,
+        required_plan: valid.required_plan,
+        tags: valid.tags,
+        created_at: now,
+        publish_at: None,
+        premium_from: valid.premium_from,
+        free_from: valid.free_from,
+        updated_at: now,
+    };
+    if let Err(e) = db::create_content(&pool, &content).await {
+        tracing::error!("Database error creating content: {}", e);
+        return Ok(
+            HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+        );
+    }
+    log_action(&pool, editor_id, "content_created", &content).await;
+    Ok(HttpResponse::Created().json(content_json(&content)))
+}
+
+#[put("/editor/content/{content_id}")]
+pub async fn update_content(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    content_req: web::Json<ContentRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let editor_id = match auth::require_editor(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let changes = match validate(&content_req) {
+        Ok(changes) => changes,
+        Err(response) => return Ok(response),
+    };
+
+    match db::update_content(&pool, path.into_inner(), &changes).await {
+        Ok(Some(content)) => {
+            paywall::invalidate_content_cache(&cache, content.id);
+            log_action(&pool, editor_id, "content_updated", &content).await;
+            Ok(HttpResponse::Ok().json(content_json(&content)))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "Content not found"}))),
+        Err(e) => {
+            tracing::error!("Database error updating content: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Публикация сейчас или по расписанию (publish_at в будущем)
+#[post("/editor/content/{content_id}/publish")]
+pub async fn publish_content(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    publish_req: web::Json<PublishRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let editor_id = match auth::require_editor(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let publish_at = publish_req.publish_at.unwrap_or_else(Utc::now);
+
+    match db::set_content_publish_at(&pool, path.into_inner(), Some(publish_at)).await {
+        Ok(Some(content)) => {
+            paywall::invalidate_content_cache(&cache, content.id);
+            log_action(&pool, editor_id, "content_published", &content).await;
+            Ok(HttpResponse::Ok().json(content_json(&content)))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "Content not found"}))),
+        Err(e) => {
+            tracing::error!("Database error publishing content: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/editor/content/{content_id}/unpublish")]
+pub async fn unpublish_content(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let editor_id = match auth::require_editor(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+
+    match db::set_content_publish_at(&pool, path.into_inner(), None).await {
+        Ok(Some(content)) => {
+            paywall::invalidate_content_cache(&cache, content.id);
+            log_action(&pool, editor_id, "content_unpublished", &content).await;
+            Ok(HttpResponse::Ok().json(content_json(&content)))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "Content not found"}))),
+        Err(e) => {
+            tracing::error!("Database error unpublishing content: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Материал скрывается, но остаётся в базе для отчётов и истории просмотров
+#[delete("/editor/content/{content_id}")]
+pub async fn delete_content(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let editor_id = match auth::require_editor(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let content_id = path.into_inner();
+
+    match db::delete_content(&pool, content_id).await {
+        Ok(true) => {
+            paywall::invalidate_content_cache(&cache, content_id);
+            let details = json!({"content_id": content_id});
+            if let Err(e) =
+                db::record_admin_action(&pool, editor_id, "content_deleted", None, None, &details)
+                    .await
+            {
+                tracing::error!("Failed to record admin action: {}", e);
+            }
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({"error": "Content not found"}))),
+        Err(e) => {
+            tracing::error!("Database error deleting content: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn request() -> ContentRequest {
+        ContentRequest {
+            title: "  Title  ".to_string(),
+            body: " Body ".to_string(),
+            required_plan: "premium".to_string(),
+            tags: vec![
+                "Politics".to_string(),
+                "politics ".to_string(),
+                "EU".to_string(),
+            ],
+            premium_from: None,
+            free_from: None,
+        }
+    }
+
+    async fn error_of(content_req: &ContentRequest) -> String {
+        let response = validate(content_req).unwrap_err();
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        value["error"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn valid_request_is_normalized() {
+        let changes = validate(&request()).unwrap();
+        assert_eq!(changes.title, "Title");
+        assert_eq!(changes.body, "Body");
+        // Теги без повторов, в нижнем регистре, в исходном порядке
+        assert_eq!(changes.tags, vec!["politics", "eu"]);
+    }
+
+    #[tokio::test]
+    async fn title_and_body_limits() {
+        let mut content_req = request();
+        content_req.title = "   ".to_string();
+        assert_eq!(
+            error_of(&content_req).await,
+            "Title must be 1-300 characters"
+        );
+        content_req.title = "я".repeat(MAX_TITLE_CHARS);
+        assert!(validate(&content_req).is_ok());
+        content_req.title = "я".repeat(MAX_TITLE_CHARS + 1);
+        assert_eq!(
+            error_of(&content_req).await,
+            "Title must be 1-300 characters"
+        );
+
+        let mut content_req = request();
+        content_req.body = "x".repeat(MAX_BODY_BYTES + 1);
+        assert_eq!(
+            error_of(&content_req).await,
+            "Body must be non-empty and at most 500000 bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn plan_and_tags_are_checked() {
+        let mut content_req = request();
+        content_req.required_plan = "gold".to_string();
+        assert_eq!(error_of(&content_req).await, "Invalid required_plan");
+
+        let mut content_req = request();
+        content_req.tags = vec![" ".to_string()];
+        assert_eq!(error_of(&content_req).await, "Tags must be 1-50 characters");
+
+        // Лимит считается после удаления повторов
+        let mut content_req = request();
+        content_req.tags = (0..MAX_TAGS).map(|i| format!("tag{}", i)).collect();
+        content_req.tags.push("TAG0".to_string());
+        assert!(validate(&content_req).is_ok());
+        content_req.tags.push("extra".to_string());
+        assert_eq!(error_of(&content_req).await, "Too many tags");
+    }
+
+    #[tokio::test]
+    async fn access_windows_are_checked() {
+        let now = Utc::now();
+        let mut content_req = request();
+        content_req.premium_from = Some(now);
+        content_req.free_from = Some(now);
+        assert_eq!(
+            error_of(&content_req).await,
+            "premium_from must be earlier than free_from"
+        );
+        content_req.free_from = Some(now + Duration::days(30));
+        assert!(validate(&content_req).is_ok());
+
+        content_req.required_plan = "free".to_string();
+        assert_eq!(
+            error_of(&content_req).await,
+            "premium_from and free_from require a paid required_plan"
+        );
+    }
+}
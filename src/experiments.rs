@@ -0,0 +1,729 @@
+// src/experiments.rs
+// A/B-эксперименты над пейволом. Вариант эксперимента — альтернативный набор
+// правил доступа (или действующие правила для контроля). Пользователь попадает
+// в эксперимент и вариант детерминированно по хешу ключа эксперимента и user_id,
+// поэтому назначение не хранится и одинаково на всех экземплярах сервиса.
+// Показ фиксируется, когда пользователь без права доступа открывает платный
+// материал; итоги сравнивают конверсию в подписку и оплату после первого показа.
+use crate::auth;
+use crate::db;
+use crate::models::{CreateExperimentRequest, Experiment, ExperimentVariant};
+use crate::money::{Currency, Money};
+use crate::rules::{LoadedRules, RuleSet};
+use actix_web::{HttpRequest, HttpResponse, get, post, web};
+use chrono::Utc;
+use moka::future::Cache;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use uuid::Uuid;
+
+const MAX_VARIANTS: usize = 10;
+const MAX_KEY_LENGTH: usize = 64;
+const MAX_NAME_LENGTH: usize = 200;
+const BUCKETS: u64 = 10_000;
+const Z_95: f64 = 1.959_963_984_540_054;
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_experiments);
+    cfg.service(create_experiment);
+    cfg.service(start_experiment);
+    cfg.service(stop_experiment);
+    cfg.service(experiment_results);
+}
+
+struct RunningVariant {
+    key: String,
+    weight: u64,
+    rules: Option<Arc<LoadedRules>>,
+}
+
+struct RunningExperiment {
+    id: Uuid,
+    key: String,
+    traffic_percent: u64,
+    variants: Vec<RunningVariant>,
+}
+
+// Вариант, назначенный пользователю; rules = None — действующие правила
+#[derive(Serialize, Clone, Debug)]
+pub struct Assignment {
+    pub experiment_id: Uuid,
+    pub experiment: String,
+    pub variant: String,
+    #[serde(skip)]
+    pub rules: Option<Arc<LoadedRules>>,
+}
+
+#[derive(Default)]
+pub struct ExperimentRegistry {
+    running: RwLock<Option<Arc<RunningExperiment>>>,
+}
+
+impl ExperimentRegistry {
+    // Ok(true) — идущий эксперимент сменился (решения в кеше устарели)
+    pub async fn reload(&self, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let running = match db::get_running_experiment(pool).await? {
+            Some(experiment) => {
+                let variants = db::list_experiment_variants(pool, experiment.id).await?;
+                Some(Arc::new(running_experiment(experiment, variants)))
+            }
+            None => None,
+        };
+        let new_id = running.as_ref().map(|e| e.id);
+        let old_id = match self.running.write() {
+            Ok(mut current) => std::mem::replace(&mut *current, running),
+            Err(poisoned) => std::mem::replace(&mut *poisoned.into_inner(), running),
+        }
+        .map(|e| e.id);
+        Ok(old_id != new_id)
+    }
+
+    fn current(&self) -> Option<Arc<RunningExperiment>> {
+        match self.running.read() {
+            Ok(running) => running.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    // Первые 8 байт SHA-256("<ключ>:<user_id>"): остаток от деления на 10000 решает
+    // участие (traffic_percent), частное — вариант пропорционально весам
+    pub fn assign(&self, user_id: Uuid) -> Option<Assignment> {
+        let experiment = self.current()?;
+        let hash = bucket_hash(&experiment.key, user_id);
+        if hash % BUCKETS >= experiment.traffic_percent * (BUCKETS / 100) {
+            return None;
+        }
+        let total: u64 = experiment.variants.iter().map(|v| v.weight).sum();
+        let mut point = (hash / BUCKETS) % total.max(1);
+        let variant = experiment.variants.iter().find(|variant| {
+            if point < variant.weight {
+                true
+            } else {
+                point -= variant.weight;
+                false
+            }
+        })?;
+        Some(Assignment {
+            experiment_id: experiment.id,
+            experiment: experiment.key.clone(),
+            variant: variant.key.clone(),
+            rules: variant.rules.clone(),
+        })
+    }
+}
+
+fn bucket_hash(experiment_key: &str, user_id: Uuid) -> u64 {
+    let digest = Sha256::digest(format!("{}:{}", experiment_key, user_id).as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn running_experiment(
+    experiment: Experiment,
+    variants: Vec<ExperimentVariant>,
+) -> RunningExperiment {
+    let variants = variants
+        .into_iter()
+        .map(|variant| {
+            // Правила проверены при создании; испорченная запись означает действующие правила
+            let rules = variant.rules.and_then(|value| {
+                match serde_json::from_value::<RuleSet>(value)
+                    .map_err(|e| e.to_string())
+                    .and_then(|rules| rules.validate().map(|_| rules))
+                {
+                    Ok(rules) => Some(Arc::new(LoadedRules::new(rules, "experiment"))),
+                    Err(e) => {
+                        tracing::error!(
+                            "Invalid rules in experiment {} variant {}: {}",
+                            experiment.key,
+                            variant.key,
+                            e
+                        );
+                        None
+                    }
+                }
+            });
+            RunningVariant {
+                key: variant.key,
+                weight: variant.weight.max(1) as u64,
+                rules,
+            }
+        })
+        .collect();
+    RunningExperiment {
+        id: experiment.id,
+        key: experiment.key,
+        traffic_percent: experiment.traffic_percent.clamp(0, 100) as u64,
+        variants,
+    }
+}
+
+// Эксперимент, запущенный или остановленный на другом экземпляре, подхватывается периодически
+pub fn spawn_experiment_refresh_worker(
+    pool: PgPool,
+    registry: web::Data<ExperimentRegistry>,
+    cache: Cache<String, serde_json::Value>,
+    interval_secs: u64,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            match registry.reload(&pool).await {
+                Ok(true) => cache.invalidate_all(),
+                Ok(false) => {}
+                Err(e) => tracing::error!("Failed to reload experiments: {}", e),
+            }
+        }
+    });
+}
+
+// Учёт не должен ломать выдачу контента
+pub async fn record_exposure(pool: &PgPool, assignment: &Assignment, user_id: Uuid) {
+    if let Err(e) =
+        db::record_experiment_exposure(pool, assignment.experiment_id, user_id, &assignment.variant)
+            .await
+    {
+        tracing::warn!(
+            "Failed to record exposure for experiment {}: {}",
+            assignment.experiment,
+            e
+        );
+    }
+}
+
+// Смена идущего эксперимента меняет правила для части пользователей
+async fn apply_changes(
+    pool: &PgPool,
+    registry: &ExperimentRegistry,
+    cache: &Cache<String, serde_json::Value>,
+) {
+    match registry.reload(pool).await {
+        Ok(_) => cache.invalidate_all(),
+        Err(e) => tracing::error!("Failed to reload experiments: {}", e),
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LENGTH
+        && key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+fn validate_experiment(
+    create_req: &CreateExperimentRequest,
+) -> Result<Vec<ExperimentVariant>, HttpResponse> {
+    let bad_request = |error: String| HttpResponse::BadRequest().json(json!({"error": error}));
+    if !valid_key(&create_req.key) {
+        return Err(bad_request(
+            "Experiment key must be 1-64 characters of a-z, 0-9, '-' or '_'".to_string(),
+        ));
+    }
+    let name = create_req.name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(bad_request("Invalid experiment name".to_string()));
+    }
+    if !(1..=100).contains(&create_req.traffic_percent) {
+        return Err(bad_request(
+            "traffic_percent must be between 1 and 100".to_string(),
+        ));
+    }
+    if create_req.variants.len() < 2 || create_req.variants.len() > MAX_VARIANTS {
+        return Err(bad_request(format!(
+            "An experiment needs 2 to {} variants",
+            MAX_VARIANTS
+        )));
+    }
+
+    let mut keys = HashSet::new();
+    let mut variants = Vec::new();
+    for (position, variant) in create_req.variants.iter().enumerate() {
+        if !valid_key(&variant.key) || !keys.insert(variant.key.as_str()) {
+            return Err(bad_request(format!(
+                "Invalid or duplicate variant key '{}'",
+                variant.key
+            )));
+        }
+        if variant.weight <= 0 || variant.weight > 1000 {
+            return Err(bad_request(format!(
+                "Variant '{}' weight must be between 1 and 1000",
+                variant.key
+            )));
+        }
+        if let Some(rules) = &variant.rules {
+            let parsed = serde_json::from_value::<RuleSet>(rules.clone())
+                .map_err(|e| e.to_string())
+                .and_then(|rules| rules.validate());
+            if let Err(e) = parsed {
+                return Err(HttpResponse::UnprocessableEntity().json(json!({
+                    "error": format!("Invalid rules in variant '{}'", variant.key),
+                    "details": e,
+                })));
+            }
+        }
+        variants.push(ExperimentVariant {
+            experiment_id: Uuid::nil(),
+            key: variant.key.clone(),
+            name: variant
+                .name
+                .as_deref()
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .unwrap_or(&variant.key)
+                .to_string(),
+            position: position as i32,
+            weight: variant.weight,
+            rules: variant.rules.clone(),
+        });
+    }
+    Ok(variants)
+}
+
+#[get("/admin/experiments")]
+pub async fn list_experiments(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_staff(&pool, &req).await {
+        return Ok(response);
+    }
+
+    let result = async {
+        let mut listed = Vec::new();
+        for experiment in db::list_experiments(&pool).await? {
+            let variants = db::list_experiment_variants(&pool, experiment.id).await?;
+            listed.push(json!({"experiment": experiment, "variants": variants}));
+        }
+        Ok::<_, sqlx::Error>(listed)
+    }
+    .await;
+    match result {
+        Ok(experiments) => Ok(HttpResponse::Ok().json(json!({"experiments": experiments}))),
+        Err(e) => {
+            tracing::error!("Database error listing experiments: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/admin/experiments")]
+pub async fn create_experiment(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    create_req: web::Json<CreateExperimentRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let staff_id = match auth::require_staff(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let mut variants = match validate_experiment(&create_req) {
+        Ok(variants) => variants,
+        Err(response) => return Ok(response),
+    };
+
+    let experiment = Experiment {
+        id: Uuid::new_v4(),
+        key: create_req.key.clone(),
+        name: create_req.name.trim().to_string(),
+        description: create_req
+            .description
+            .as_deref()
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .map(str::to_string),
+        status: "draft".to_string(),
+        traffic_percent: create_req.traffic_percent,
+        created_by: Some(staff_id),
+        created_at: Utc::now(),
+        started_at: None,
+        stopped_at: None,
+    };
+    for variant in &mut variants {
+        variant.experiment_id = experiment.id;
+    }
+    let result = async {
+        let mut tx = pool.begin().await?;
+        db::create_experiment(&mut tx, &experiment).await?;
+        for variant in &variants {
+            db::create_experiment_variant(&mut tx, variant).await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    match result {
+        Ok(()) => {}
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Ok(HttpResponse::Conflict()
+                .json(json!({"error": "An experiment with this key already exists"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error creating experiment: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    }
+
+    let details = json!({"experiment_id": experiment.id, "key": experiment.key});
+    if let Err(e) =
+        db::record_admin_action(&pool, staff_id, "experiment_created", None, None, &details).await
+    {
+        tracing::error!("Failed to record admin action: {}", e);
+    }
+    Ok(HttpResponse::Created().json(json!({"experiment": experiment, "variants": variants})))
+}
+
+#[post("/admin/experiments/{experiment_id}/start")]
+pub async fn start_experiment(
+    pool: web::Data<sqlx::PgPool>,
+    registry: web::Data<ExperimentRegistry>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let staff_id = match auth::require_staff(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let experiment_id = path.into_inner();
+
+    let experiment = match db::start_experiment(&pool, experiment_id).await {
+        Ok(Some(experiment)) => experiment,
+        Ok(None) => {
+            return match db::get_experiment(&pool, experiment_id).await {
+                Ok(Some(_)) => Ok(HttpResponse::Conflict()
+                    .json(json!({"error": "Only draft experiments can be started"}))),
+                Ok(None) => {
+                    Ok(HttpResponse::NotFound().json(json!({"error": "Experiment not found"})))
+                }
+                Err(e) => {
+                    tracing::error!("Database error fetching experiment: {}", e);
+                    Ok(HttpResponse::InternalServerError()
+                        .json(json!({"error": "Internal server error"})))
+                }
+            };
+        }
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Ok(HttpResponse::Conflict()
+                .json(json!({"error": "Another experiment is already running"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error starting experiment: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    apply_changes(&pool, &registry, &cache).await;
+
+    let details = json!({"experiment_id": experiment.id, "key": experiment.key});
+    if let Err(e) =
+        db::record_admin_action(&pool, staff_id, "experiment_started", None, None, &details).await
+    {
+        tracing::error!("Failed to record admin action: {}", e);
+    }
+    Ok(HttpResponse::Ok().json(json!({"experiment": experiment})))
+}
+
+#[post("/admin/experiments/{experiment_id}/stop")]
+pub async fn stop_experiment(
+    pool: web::Data<sqlx::PgPool>,
+    registry: web::Data<ExperimentRegistry>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let staff_id = match auth::require_staff(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let experiment_id = path.into_inner();
+
+    let experiment = match db::stop_experiment(&pool, experiment_id).await {
+        Ok(Some(experiment)) => experiment,
+        Ok(None) => {
+            return Ok(HttpResponse::Conflict()
+                .json(json!({"error": "Experiment not found or not running"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error stopping experiment: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    apply_changes(&pool, &registry, &cache).await;
+
+    let details = json!({"experiment_id": experiment.id, "key": experiment.key});
+    if let Err(e) =
+        db::record_admin_action(&pool, staff_id, "experiment_stopped", None, None, &details).await
+    {
+        tracing::error!("Failed to record admin action: {}", e);
+    }
+    Ok(HttpResponse::Ok().json(json!({"experiment": experiment})))
+}
+
+// Доверительный интервал Уилсона для доли successes/n
+fn wilson_interval(successes: i64, n: i64) -> Option<(f64, f64)> {
+    if n <= 0 {
+        return None;
+    }
+    let n = n as f64;
+    let p = successes as f64 / n;
+    let z2 = Z_95 * Z_95;
+    let denominator = 1.0 + z2 / n;
+    let center = (p + z2 / (2.0 * n)) / denominator;
+    let margin = Z_95 * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / denominator;
+    Some(((center - margin).max(0.0), (center + margin).min(1.0)))
+}
+
+// Аппроксимация Абрамовица — Стиган 7.1.26 (погрешность < 1.5e-7)
+fn erf(x: f64) -> f64 {
+    let sign = if x < 0.0 { -1.0 } else { 1.0 };
+    let x = x.abs();
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let poly = t
+        * (0.254_829_592
+            + t * (-0.284_496_736
+                + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    sign * (1.0 - poly * (-x * x).exp())
+}
+
+// Разница долей с контролем: интервал по раздельным дисперсиям,
+// p-value двустороннего z-теста по объединённой доле
+fn compare_to_control(
+    successes: i64,
+    n: i64,
+    control_successes: i64,
+    control_n: i64,
+) -> Option<serde_json::Value> {
+    if n <= 0 || control_n <= 0 {
+        return None;
+    }
+    let (n, control_n) = (n as f64, control_n as f64);
+    let p = successes as f64 / n;
+    let control_p = control_successes as f64 / control_n;
+    let difference = p - control_p;
+    let se = (p * (1.0 - p) / n + control_p * (1.0 - control_p) / control_n).sqrt();
+    let pooled = (successes + control_successes) as f64 / (n + control_n);
+    let pooled_se = (pooled * (1.0 - pooled) * (1.0 / n + 1.0 / control_n)).sqrt();
+    let p_value = if pooled_se > 0.0 {
+        let z = difference / pooled_se;
+        1.0 - erf(z.abs() / std::f64::consts::SQRT_2)
+    } else {
+        1.0
+    };
+    Some(json!({
+        "difference": difference,
+        "difference_ci95": [difference - Z_95 * se, difference + Z_95 * se],
+        "relative_lift": (control_p > 0.0).then(|| difference / control_p),
+        "p_value": p_value,
+        "significant": p_value < 0.05,
+    }))
+}
+
+fn rate_json(successes: i64, n: i64) -> serde_json::Value {
+    json!({
+        "count": successes,
+        "rate": (n > 0).then(|| successes as f64 / n as f64),
+        "ci95": wilson_interval(successes, n).map(|(low, high)| [low, high]),
+    })
+}
+
+#[get("/admin/experiments/{experiment_id}/results")]
+pub async fn experiment_results(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_staff(&pool, &req).await {
+        return Ok(response);
+    }
+    let experiment_id = path.into_inner();
+
+    let result = async {
+        let Some(experiment) = db::get_experiment(&pool, experiment_id).await? else {
+            return Ok(None);
+        };
+        let until = experiment.stopped_at.unwrap_or_else(Utc::now);
+        let variants = db::list_experiment_variants(&pool, experiment_id).await?;
+        let stats = db::get_experiment_variant_stats(&pool, experiment_id, until).await?;
+        let revenue = db::get_experiment_revenue(&pool, experiment_id, until).await?;
+        Ok::<_, sqlx::Error>(Some((experiment, variants, stats, revenue)))
+    }
+    .await;
+    let (experiment, variants, stats, revenue) = match result {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "Experiment not found"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error computing experiment results: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    let stats: HashMap<&str, _> = stats.iter().map(|s| (s.variant_key.as_str(), s)).collect();
+    let counts = |key: &str| {
+        stats.get(key).map_or((0, 0, 0), |s| {
+            (s.exposed_users, s.converted_users, s.paying_users)
+        })
+    };
+    // Первый вариант — контрольный
+    let (control_exposed, control_converted, control_paying) =
+        variants.first().map_or((0, 0, 0), |v| counts(&v.key));
+
+    let results: Vec<serde_json::Value> = variants
+        .iter()
+        .enumerate()
+        .map(|(i, variant)| {
+            let (exposed, converted, paying) = counts(&variant.key);
+            let variant_revenue: Vec<Money> = revenue
+                .iter()
+                .filter(|r| r.variant_key == variant.key)
+                .filter_map(|r| {
+                    Currency::from_code(&r.currency)
+                        .map(|currency| Money::new(r.net_minor, currency))
+                })
+                .collect();
+            let mut result = json!({
+                "variant": variant.key,
+                "name": variant.name,
+                "control": i == 0,
+                "weight": variant.weight,
+                "exposed_users": exposed,
+                "conversion": rate_json(converted, exposed),
+                "paid_conversion": rate_json(paying, exposed),
+                "net_revenue": variant_revenue,
+            });
+            if i > 0 {
+                result["conversion"]["vs_control"] = json!(compare_to_control(
+                    converted,
+                    exposed,
+                    control_converted,
+                    control_exposed
+                ));
+                result["paid_conversion"]["vs_control"] = json!(compare_to_control(
+                    paying,
+                    exposed,
+                    control_paying,
+                    control_exposed
+                ));
+            }
+            result
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "experiment": experiment,
+        "window_end": experiment.stopped_at.unwrap_or_else(Utc::now),
+        "variants": results,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(traffic_percent: u64, weights: &[u64]) -> ExperimentRegistry {
+        let variants = weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| RunningVariant {
+                key: format!("v{}", i),
+                weight: *weight,
+                rules: None,
+            })
+            .collect();
+        ExperimentRegistry {
+            running: RwLock::new(Some(Arc::new(RunningExperiment {
+                id: Uuid::new_v4(),
+                key: "paywall-copy".to_string(),
+                traffic_percent,
+                variants,
+            }))),
+        }
+    }
+
+    #[test]
+    fn assignment_is_deterministic() {
+        let registry = registry(100, &[1, 1]);
+        let user_id = Uuid::new_v4();
+        let first = registry.assign(user_id).unwrap();
+        for _ in 0..10 {
+            assert_eq!(registry.assign(user_id).unwrap().variant, first.variant);
+        }
+    }
+
+    #[test]
+    fn no_running_experiment_assigns_nothing() {
+        assert!(
+            ExperimentRegistry::default()
+                .assign(Uuid::new_v4())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn traffic_share_and_weights_are_respected() {
+        let registry = registry(50, &[3, 1]);
+        let mut counts = HashMap::new();
+        let users = 20_000;
+        for _ in 0..users {
+            let variant = registry.assign(Uuid::new_v4()).map(|a| a.variant);
+            *counts.entry(variant).or_insert(0) += 1;
+        }
+        let share = |variant: Option<&str>| {
+            counts
+                .get(&variant.map(str::to_string))
+                .copied()
+                .unwrap_or(0) as f64
+                / users as f64
+        };
+        // Половина вне эксперимента, остальные 3:1
+        assert!((share(None) - 0.5).abs() < 0.03);
+        assert!((share(Some("v0")) - 0.375).abs() < 0.03);
+        assert!((share(Some("v1")) - 0.125).abs() < 0.03);
+    }
+
+    #[test]
+    fn experiment_keys() {
+        assert!(valid_key("paywall-copy_2"));
+        assert!(!valid_key(""));
+        assert!(!valid_key("Paywall"));
+        assert!(!valid_key(&"a".repeat(MAX_KEY_LENGTH + 1)));
+    }
+
+    #[test]
+    fn wilson_interval_bounds() {
+        assert_eq!(wilson_interval(0, 0), None);
+        let (low, high) = wilson_interval(0, 100).unwrap();
+        assert!(low.abs() < 1e-9);
+        assert!(high > 0.0 && high < 0.05);
+        let (low, high) = wilson_interval(50, 100).unwrap();
+        assert!((low - 0.404).abs() < 0.001 && (high - 0.596).abs() < 0.001);
+    }
+
+    #[test]
+    fn comparison_with_control() {
+        assert!(compare_to_control(10, 0, 10, 100).is_none());
+        let same = compare_to_control(10, 100, 10, 100).unwrap();
+        assert_eq!(same["difference"], 0.0);
+        assert_eq!(same["significant"], false);
+        let better = compare_to_control(300, 1000, 200, 1000).unwrap();
+        assert!((better["relative_lift"].as_f64().unwrap() - 0.5).abs() < 1e-9);
+        assert_eq!(better["significant"], true);
+        // Без конверсий в контроле относительный прирост не определён
+        assert!(compare_to_control(5, 100, 0, 100).unwrap()["relative_lift"].is_null());
+    }
+}
@@ -0,0 +1,460 @@
+// src/family.rs
+// Семейный тариф: владелец подписки family приглашает до FAMILY_MAX_MEMBERS
+// участников по email. Участник получает доступ владельца в get_content, пока
+// подписка владельца действует; истечение или смена тарифа завершает участие
+// (end_lapsed_memberships в цикле продления).
+use crate::auth;
+use crate::config::Config;
+use crate::db;
+use crate::mailer::{EmailMessage, Mailer};
+use crate::models::{FamilyInviteRequest, FamilyJoinRequest, FamilyMember};
+use crate::paywall;
+use actix_web::{HttpRequest, HttpResponse, delete, get, post, web};
+use chrono::Utc;
+use moka::future::Cache;
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const FAMILY_PLAN: &str = "family";
+const MAX_EMAIL_LENGTH: usize = 254;
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_family);
+    cfg.service(invite_member);
+    cfg.service(remove_member);
+    cfg.service(join_family);
+    cfg.service(leave_family);
+}
+
+// Для владельца — участники и места, для участника — его членство
+#[get("/family")]
+pub async fn get_family(
+    pool: web::Data<sqlx::PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    let subscription = match db::get_active_subscription(&pool, user_id).await {
+        Ok(sub) => sub.filter(|s| s.plan_id == FAMILY_PLAN),
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    if let Some(subscription) = subscription {
+        return match db::list_family_members(&pool, subscription.id).await {
+            Ok(members) => Ok(HttpResponse::Ok().json(json!({
+                "role": "owner",
+                "subscription_id": subscription.id,
+                "seats_total": config.family_max_members,
+                "seats_used": members.len(),
+                "members": members,
+            }))),
+            Err(e) => {
+                tracing::error!("Database error fetching family members: {}", e);
+                Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})))
+            }
+        };
+    }
+
+    match db::get_active_family_membership(&pool, user_id).await {
+        Ok(Some(membership)) => Ok(HttpResponse::Ok().json(json!({
+            "role": "member",
+            "membership": membership,
+        }))),
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "No family plan"}))),
+        Err(e) => {
+            tracing::error!("Database error fetching family membership: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/family/members")]
+pub async fn invite_member(
+    pool: web::Data<sqlx::PgPool>,
+    config: web::Data<Config>,
+    mailer: web::Data<dyn Mailer>,
+    req: HttpRequest,
+    invite_req: web::Json<FamilyInviteRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    let email = invite_req.email.trim().to_string();
+    if email.len() > MAX_EMAIL_LENGTH || !email.contains('@') {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid email"})));
+    }
+
+    let owner = match db::get_user_by_id(&pool, user_id).await {
+        Ok(Some(owner)) => owner,
+        Ok(None) => return Ok(HttpResponse::NotFound().json(json!({"error": "User not found"}))),
+        Err(e) => {
+            tracing::error!("Database error fetching user: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    if owner.email.eq_ignore_ascii_case(&email) {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Cannot invite yourself"})));
+    }
+
+    match create_invite(&pool, &config, user_id, &email).await {
+        Ok(Ok(invite)) => {
+            // Приглашение действует и без письма: владелец может переслать его повторно
+            let email_sent = match mailer.send(&invite_email(&invite, &owner.username)).await {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!("Failed to send family invite {}: {}", invite.id, e);
+                    false
+                }
+            };
+            Ok(HttpResponse::Created().json(json!({
+                "member": invite,
+                "email_sent": email_sent,
+            })))
+        }
+        Ok(Err(response)) => Ok(response),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            Ok(HttpResponse::Conflict().json(json!({"error": "Member already invited"})))
+        }
+        Err(e) => {
+            tracing::error!("Family invite error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Подписка владельца блокируется: параллельные приглашения не превысят число мест
+async fn create_invite(
+    pool: &PgPool,
+    config: &Config,
+    owner_id: Uuid,
+    email: &str,
+) -> Result<Result<FamilyMember, HttpResponse>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let subscription = db::lock_active_subscription_for_user(&mut tx, owner_id)
+        .await?
+        .filter(|s| s.plan_id == FAMILY_PLAN && s.expires_at > Utc::now());
+    let Some(subscription) = subscription else {
+        return Ok(Err(
+            HttpResponse::Forbidden().json(json!({"error": "Family plan required"}))
+        ));
+    };
+    if db::count_open_family_members(&mut tx, subscription.id).await? >= config.family_max_members {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "No seats available"}))
+        ));
+    }
+
+    let invite = FamilyMember {
+        id: Uuid::new_v4(),
+        owner_id,
+        subscription_id: subscription.id,
+        email: email.to_string(),
+        member_id: None,
+        invite_token: Uuid::new_v4().simple().to_string(),
+        status: "invited".to_string(),
+        invited_at: Utc::now(),
+        joined_at: None,
+        ended_at: None,
+    };
+    db::create_family_invite(&mut tx, &invite).await?;
+    tx.commit().await?;
+    tracing::info!(
+        "User {} invited {} to family plan {}",
+        owner_id,
+        invite.email,
+        subscription.id
+    );
+    Ok(Ok(invite))
+}
+
+fn invite_email(invite: &FamilyMember, owner_name: &str) -> EmailMessage {
+    EmailMessage {
+        to: invite.email.clone(),
+        subject: "You are invited to a family subscription".to_string(),
+        text: format!(
+            "Hi,\n\n{} invited you to share their family subscription.\n\n\
+             Sign in (or register with this email address) and use this invitation code to join:\n{}\n",
+            owner_name, invite.invite_token
+        ),
+    }
+}
+
+// Владелец удаляет участника или отзывает приглашение
+#[delete("/family/members/{membership_id}")]
+pub async fn remove_member(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    let membership_id = path.into_inner();
+
+    let subscription = match db::get_active_subscription(&pool, user_id).await {
+        Ok(sub) => sub.filter(|s| s.plan_id == FAMILY_PLAN),
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    let owned = match &subscription {
+        Some(sub) => match db::list_family_members(&pool, sub.id).await {
+            Ok(members) => members.iter().any(|m| m.id == membership_id),
+            Err(e) => {
+                tracing::error!("Database error fetching family members: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        },
+        None => false,
+    };
+    if !owned {
+        return Ok(HttpResponse::NotFound().json(json!({"error": "Member not found"})));
+    }
+
+    match db::end_family_membership(&pool, membership_id, "removed").await {
+        Ok(Some(membership)) => {
+            if let Some(member_id) = membership.member_id {
+                notify_membership_ended(&pool, &cache, &membership, member_id, "removed").await;
+            }
+            Ok(HttpResponse::Ok().json(json!({"removed": true, "member": membership})))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "Member not found"}))),
+        Err(e) => {
+            tracing::error!("Database error removing family member: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/family/join")]
+pub async fn join_family(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    join_req: web::Json<FamilyJoinRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    match join(&pool, user_id, join_req.token.trim()).await {
+        Ok(Ok(membership)) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            tracing::info!(
+                "User {} joined family plan {}",
+                user_id,
+                membership.subscription_id
+            );
+            Ok(HttpResponse::Ok().json(json!({"joined": true, "membership": membership})))
+        }
+        Ok(Err(response)) => Ok(response),
+        // Пользователь уже участник другой семьи (uniq_family_members_active_member)
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(
+            HttpResponse::Conflict().json(json!({"error": "Already a member of a family plan"}))
+        ),
+        Err(e) => {
+            tracing::error!("Family join error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Приглашение принимает только владелец адреса, на который оно отправлено
+async fn join(
+    pool: &PgPool,
+    user_id: Uuid,
+    token: &str,
+) -> Result<Result<FamilyMember, HttpResponse>, sqlx::Error> {
+    let Some(user) = db::get_user_by_id(pool, user_id).await? else {
+        return Ok(Err(
+            HttpResponse::NotFound().json(json!({"error": "User not found"}))
+        ));
+    };
+
+    let mut tx = pool.begin().await?;
+    let invite = db::lock_family_invite_by_token(&mut tx, token)
+        .await?
+        .filter(|i| i.status == "invited");
+    let Some(mut invite) = invite else {
+        return Ok(Err(
+            HttpResponse::NotFound().json(json!({"error": "Invitation not found"}))
+        ));
+    };
+    if !invite.email.eq_ignore_ascii_case(&user.email) {
+        return Ok(Err(HttpResponse::Forbidden().json(
+            json!({"error": "Invitation was sent to a different email"}),
+        )));
+    }
+    if invite.owner_id == user_id {
+        return Ok(Err(
+            HttpResponse::BadRequest().json(json!({"error": "Cannot join your own family plan"}))
+        ));
+    }
+    let owner_subscription = db::lock_active_subscription_for_user(&mut tx, invite.owner_id)
+        .await?
+        .filter(|s| s.id == invite.subscription_id && s.plan_id == FAMILY_PLAN);
+    if owner_subscription.is_none() {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "Family plan is no longer active"}))
+        ));
+    }
+
+    db::activate_family_member(&mut tx, invite.id, user_id).await?;
+    db::create_notification(
+        &mut tx,
+        invite.owner_id,
+        "family_member_joined",
+        &json!({"membership_id": invite.id, "email": invite.email}),
+    )
+    .await?;
+    tx.commit().await?;
+
+    invite.status = "active".to_string();
+    invite.member_id = Some(user_id);
+    invite.joined_at = Some(Utc::now());
+    Ok(Ok(invite))
+}
+
+#[post("/family/leave")]
+pub async fn leave_family(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    let membership = match db::get_active_family_membership(&pool, user_id).await {
+        Ok(Some(membership)) => membership,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "No family plan"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error fetching family membership: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    match db::end_family_membership(&pool, membership.id, "left").await {
+        Ok(_) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            Ok(HttpResponse::Ok().json(json!({"left": true})))
+        }
+        Err(e) => {
+            tracing::error!("Database error leaving family plan: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+async fn notify_membership_ended(
+    pool: &PgPool,
+    cache: &Cache<String, serde_json::Value>,
+    membership: &FamilyMember,
+    member_id: Uuid,
+    reason: &str,
+) {
+    paywall::invalidate_user_cache(cache, member_id);
+    let result = async {
+        let mut conn = pool.acquire().await?;
+        db::create_notification(
+            &mut conn,
+            member_id,
+            "family_membership_ended",
+            &json!({"membership_id": membership.id, "reason": reason}),
+        )
+        .await
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::error!("Failed to notify family member {}: {}", member_id, e);
+    }
+}
+
+// Завершение участия в семьях, чья подписка истекла или сменила тариф (из цикла продления)
+pub async fn end_lapsed_memberships(
+    pool: &PgPool,
+    cache: &Cache<String, serde_json::Value>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let ended = db::end_lapsed_family_memberships(&mut tx).await?;
+    for membership in &ended {
+        if let Some(member_id) = membership.member_id {
+            db::create_notification(
+                &mut tx,
+                member_id,
+                "family_membership_ended",
+                &json!({"membership_id": membership.id, "reason": "owner_subscription_ended"}),
+            )
+            .await?;
+        }
+    }
+    tx.commit().await?;
+
+    for member_id in ended.iter().filter_map(|m| m.member_id) {
+        paywall::invalidate_user_cache(cache, member_id);
+    }
+    if !ended.is_empty() {
+        tracing::info!("Ended {} family memberships", ended.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite() -> FamilyMember {
+        FamilyMember {
+            id: Uuid::new_v4(),
+            owner_id: Uuid::new_v4(),
+            subscription_id: Uuid::new_v4(),
+            email: "kid@example.com".to_string(),
+            member_id: None,
+            invite_token: "abc123".to_string(),
+            status: "invited".to_string(),
+            invited_at: Utc::now(),
+            joined_at: None,
+            ended_at: None,
+        }
+    }
+
+    #[test]
+    fn invite_email_carries_token_to_invitee() {
+        let message = invite_email(&invite(), "parent");
+        assert_eq!(message.to, "kid@example.com");
+        assert!(message.text.contains("parent invited you"));
+        assert!(message.text.contains("abc123"));
+    }
+
+    #[test]
+    fn invite_token_is_not_serialized() {
+        // Токен уходит только в письме, не в ответах API
+        let value = serde_json::to_value(invite()).unwrap();
+        assert!(value.get("invite_token").is_none());
+    }
+}
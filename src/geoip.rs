@@ -0,0 +1,78 @@
+// src/geoip.rs
+// Определение страны по IP из локального CSV-файла диапазонов
+// (строки "start_ip,end_ip,country", как в DB-IP / IP2Location Lite).
+// Файл загружается в память при старте; поиск — бинарный по началу диапазона.
+use crate::network::{TrustedProxies, ip_key};
+use actix_web::HttpRequest;
+use std::fs;
+use std::net::IpAddr;
+
+#[derive(Default)]
+pub struct GeoIp {
+    ranges: Vec<(u128, u128, String)>, // Отсортированы по началу диапазона
+    proxies: TrustedProxies,
+}
+
+impl GeoIp {
+    pub fn load(path: &str) -> std::io::Result<GeoIp> {
+        let data = fs::read_to_string(path)?;
+        let mut ranges = Vec::new();
+        let mut skipped = 0;
+        for line in data.lines() {
+            let mut fields = line.split(',').map(|f| f.trim().trim_matches('"'));
+            let parsed = match (fields.next(), fields.next(), fields.next()) {
+                (Some(start), Some(end), Some(country)) => start
+                    .parse::<IpAddr>()
+                    .ok()
+                    .zip(end.parse::<IpAddr>().ok())
+                    .filter(|_| country.len() == 2)
+                    .map(|(start, end)| (ip_key(start), ip_key(end), country.to_ascii_uppercase())),
+                _ => None,
+            };
+            match parsed {
+                Some(range) => ranges.push(range),
+                None => skipped += 1, // Заголовок, пустые и некорректные строки
+            }
+        }
+        ranges.sort_by_key(|(start, _, _)| *start);
+        tracing::info!(
+            "Loaded {} GeoIP ranges from {} ({} lines skipped)",
+            ranges.len(),
+            path,
+            skipped
+        );
+        Ok(GeoIp {
+            ranges,
+            proxies: TrustedProxies::default(),
+        })
+    }
+
+    // Пустая база, если путь не задан или файл не читается: геолокация просто не срабатывает
+    pub fn from_path(path: Option<&str>) -> GeoIp {
+        match path {
+            Some(path) => GeoIp::load(path).unwrap_or_else(|e| {
+                tracing::warn!("Failed to load GeoIP database {}: {}", path, e);
+                GeoIp::default()
+            }),
+            None => GeoIp::default(),
+        }
+    }
+
+    // Адрес клиента за доверенными прокси берётся из X-Forwarded-For
+    pub fn with_trusted_proxies(mut self, proxies: TrustedProxies) -> GeoIp {
+        self.proxies = proxies;
+        self
+    }
+
+    // Страна клиента по его адресу
+    pub fn country_for_request(&self, req: &HttpRequest) -> Option<&str> {
+        self.country(self.proxies.client_ip(req)?)
+    }
+
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let key = ip_key(ip);
+        let idx = self.ranges.partition_point(|(start, _, _)| *start <= key);
+        let (_, end, country) = self.ranges.get(idx.checked_sub(1)?)?;
+        (key <= *end).then_some(country.as_str())
+    }
+}
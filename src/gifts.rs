@@ -0,0 +1,711 @@
+// src/gifts.rs
+// Подарочные подписки: покупка тарифа на N периодов для другого человека,
+// доставка кода письмом в назначенное время и погашение кода получателем.
+// Если у получателя уже есть подписка, подарок продлевает её (см. stack_gift).
+use crate::auth;
+use crate::config::Config;
+use crate::db;
+use crate::geoip::GeoIp;
+use crate::idempotency;
+use crate::mailer::{EmailMessage, Mailer};
+use crate::models::{Gift, GiftPurchaseRequest, RedeemGiftRequest, Subscription};
+use crate::payment::{self, ChargeStatus, PaymentGateway};
+use crate::paywall;
+use crate::pricing;
+use crate::tax::TaxBreakdown;
+use actix_web::{HttpRequest, HttpResponse, get, post, web};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use moka::future::Cache;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{PgConnection, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+const MAX_GIFT_PERIODS: i32 = 12;
+const MAX_DELIVERY_DAYS_AHEAD: i64 = 365;
+const MAX_GIFT_MESSAGE_LENGTH: usize = 1000;
+const MAX_RECIPIENT_FIELD_LENGTH: usize = 254;
+const MAX_DELIVERIES_PER_CYCLE: usize = 200;
+const DELIVERY_RETRY_MINUTES: i64 = 15;
+// Через сколько забранный, но не отправленный подарок (сбой процесса) заберут снова
+const SENDING_LEASE_MINUTES: i64 = 10;
+const DEFAULT_GIFT_LIST_LIMIT: i64 = 50;
+const MAX_GIFT_LIST_LIMIT: i64 = 200;
+// Без похожих символов (0/O, 1/I/L)
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+#[derive(Deserialize)]
+pub struct GiftListQuery {
+    limit: Option<i64>,
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(purchase_gift);
+    cfg.service(list_gifts);
+    cfg.service(redeem_gift);
+}
+
+// Код вида GIFT-XXXX-XXXX-XXXX
+fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    let groups: Vec<String> = (0..3)
+        .map(|_| {
+            (0..4)
+                .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+                .collect()
+        })
+        .collect();
+    format!("GIFT-{}", groups.join("-"))
+}
+
+fn validate_gift_request(gift_req: &GiftPurchaseRequest) -> Result<(), &'static str> {
+    if paywall::plan_rank(&gift_req.plan_id).is_none() {
+        return Err("Invalid plan");
+    }
+    if gift_req.periods < 1 || gift_req.periods > MAX_GIFT_PERIODS {
+        return Err("Invalid number of periods");
+    }
+    let email = gift_req.recipient_email.trim();
+    if email.len() > MAX_RECIPIENT_FIELD_LENGTH || !email.contains('@') {
+        return Err("Invalid recipient email");
+    }
+    if gift_req
+        .recipient_name
+        .as_ref()
+        .is_some_and(|n| n.len() > MAX_RECIPIENT_FIELD_LENGTH)
+    {
+        return Err("Recipient name is too long");
+    }
+    if gift_req
+        .message
+        .as_ref()
+        .is_some_and(|m| m.len() > MAX_GIFT_MESSAGE_LENGTH)
+    {
+        return Err("Gift message is too long");
+    }
+    if let Some(deliver_at) = gift_req.deliver_at
+        && deliver_at > Utc::now() + ChronoDuration::days(MAX_DELIVERY_DAYS_AHEAD)
+    {
+        return Err("Delivery date is too far in the future");
+    }
+    Ok(())
+}
+
+#[post("/gifts")]
+pub async fn purchase_gift(
+    pool: web::Data<sqlx::PgPool>,
+    gateway: web::Data<dyn PaymentGateway>,
+    config: web::Data<Config>,
+    geoip: web::Data<GeoIp>,
+    req: HttpRequest,
+    gift_req: web::Json<GiftPurchaseRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    if let Err(e) = validate_gift_request(&gift_req) {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": e})));
+    }
+
+    let idempotency_key = match idempotency::key_from_request(&req) {
+        Ok(key) => key,
+        Err(response) => return Ok(response),
+    };
+    let mut provider_key = None;
+    if let Some(key) = &idempotency_key {
+        let hash = idempotency::request_hash(req.path(), &*gift_req);
+        match idempotency::begin(&pool, user_id, key, &hash).await {
+            Ok(idempotency::Begin::Proceed(key)) => provider_key = Some(key),
+            Ok(idempotency::Begin::Respond(response)) => return Ok(response),
+            Err(e) => {
+                tracing::error!("Database error acquiring idempotency key: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        }
+    }
+
+    let geo_country = geoip.country_for_request(&req);
+    let response = process_gift_purchase(
+        &pool,
+        gateway.get_ref(),
+        &config,
+        user_id,
+        provider_key,
+        &gift_req,
+        geo_country,
+    )
+    .await?;
+
+    match idempotency_key {
+        Some(key) => Ok(idempotency::finish(&pool, user_id, &key, response).await),
+        None => Ok(response),
+    }
+}
+
+async fn process_gift_purchase(
+    pool: &sqlx::PgPool,
+    gateway: &dyn PaymentGateway,
+    config: &Config,
+    user_id: Uuid,
+    idempotency_key: Option<String>,
+    gift_req: &GiftPurchaseRequest,
+    geo_country: Option<&str>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user = match db::get_user_by_id(pool, user_id).await {
+        Ok(user) => user,
+        Err(e) => {
+            tracing::error!("Database error fetching user: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    if user.as_ref().is_some_and(|u| u.flagged_at.is_some()) {
+        return Ok(HttpResponse::Forbidden().json(json!({"error": "Account is under review"})));
+    }
+    let account_country = user.and_then(|u| u.country);
+    let currency = match pricing::resolve_currency(
+        gift_req.currency.as_deref(),
+        account_country.as_deref(),
+        geo_country,
+    ) {
+        Ok((currency, _)) => currency,
+        Err(code) => {
+            return Ok(HttpResponse::BadRequest()
+                .json(json!({"error": "Unsupported currency", "currency": code})));
+        }
+    };
+    let terms = paywall::plan_terms(&gift_req.plan_id, currency).and_then(|(price, days)| {
+        let periods = i64::from(gift_req.periods);
+        Some((price.mul_ratio(periods, 1).ok()?, days * periods))
+    });
+    let Some((amount, duration_days)) = terms else {
+        return Ok(HttpResponse::BadRequest()
+            .json(json!({"error": "Plan is not available in this currency"})));
+    };
+
+    let charge = payment::charge_user(
+        pool,
+        gateway,
+        config,
+        &payment::UserCharge {
+            user_id,
+            subscription_id: None,
+            kind: "gift",
+            new_token: Some(&gift_req.payment_token),
+            amount,
+            description: format!("Gift: {} x{}", gift_req.plan_id, gift_req.periods),
+            geo_country,
+            idempotency_key,
+        },
+    )
+    .await;
+
+    let (payment, charge) = match charge {
+        Ok((payment, charge))
+            if charge.status == ChargeStatus::Succeeded
+                || charge.status == ChargeStatus::Pending =>
+        {
+            (payment, charge)
+        }
+        Ok((_, charge)) => {
+            tracing::warn!("Charge {} not completed: {:?}", charge.id, charge.status);
+            return Ok(HttpResponse::PaymentRequired().json(json!({"error": "Payment failed"})));
+        }
+        Err(e) => return Ok(payment::error_response(&e)),
+    };
+
+    // Оплата ждёт подтверждения (3-D Secure): подарок сохраняется, но доставляется
+    // и погашается только после вебхука payment_intent.succeeded
+    let awaiting_payment = charge.status == ChargeStatus::Pending;
+    let now = Utc::now();
+    let gift = Gift {
+        id: Uuid::new_v4(),
+        code: generate_code(),
+        purchaser_id: user_id,
+        payment_id: payment.id,
+        plan_id: gift_req.plan_id.clone(),
+        periods: gift_req.periods,
+        duration_days: duration_days as i32,
+        currency: currency.code().to_string(),
+        recipient_email: gift_req.recipient_email.trim().to_string(),
+        recipient_name: gift_req.recipient_name.clone(),
+        message: gift_req.message.clone(),
+        deliver_at: gift_req.deliver_at.unwrap_or(now).max(now),
+        delivered_at: None,
+        status: if awaiting_payment {
+            "awaiting_payment"
+        } else {
+            "scheduled"
+        }
+        .to_string(),
+        redeemed_by: None,
+        redeemed_at: None,
+        subscription_id: None,
+        created_at: now,
+    };
+    if let Err(e) = db::create_gift(pool, &gift).await {
+        tracing::error!("Gift creation error: {}", e);
+        // Незавершённое списание вернуть нельзя: его вернёт вебхук, не найдя подарка
+        if !awaiting_payment {
+            payment::refund_quietly(pool, gateway, payment.id, &charge.id).await;
+        }
+        return Ok(
+            HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+        );
+    }
+    tracing::info!(
+        "Gift {} purchased by {}, delivery at {}",
+        gift.id,
+        user_id,
+        gift.deliver_at
+    );
+
+    if awaiting_payment {
+        return Ok(HttpResponse::Accepted().json(json!({
+            "message": "Payment is awaiting confirmation",
+            "gift": gift,
+            "amount": payment.amount().ok(),
+            "tax": TaxBreakdown::from_payment(&payment).ok(),
+        })));
+    }
+    Ok(HttpResponse::Created().json(json!({
+        "gift": gift,
+        "amount": payment.amount().ok(),
+        "tax": TaxBreakdown::from_payment(&payment).ok(),
+    })))
+}
+
+// Подарки, купленные пользователем (с кодами — их можно передать и лично)
+#[get("/gifts")]
+pub async fn list_gifts(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    query: web::Query<GiftListQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_GIFT_LIST_LIMIT)
+        .clamp(1, MAX_GIFT_LIST_LIMIT);
+
+    match db::list_purchaser_gifts(&pool, user_id, limit).await {
+        Ok(gifts) => Ok(HttpResponse::Ok().json(json!({"gifts": gifts}))),
+        Err(e) => {
+            tracing::error!("Database error fetching gifts: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/gifts/redeem")]
+pub async fn redeem_gift(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    redeem_req: web::Json<RedeemGiftRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    let code = redeem_req.code.trim().to_ascii_uppercase();
+
+    if let Err(e) = db::deactivate_lapsed_subscriptions(&pool, user_id).await {
+        tracing::error!("Failed to deactivate lapsed subscriptions: {}", e);
+        return Ok(
+            HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+        );
+    }
+
+    match redeem(&pool, user_id, &code).await {
+        Ok(Ok((gift, result))) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            tracing::info!(
+                "Gift {} redeemed by {}: {}",
+                gift.id,
+                user_id,
+                result["stacking"]
+            );
+            Ok(HttpResponse::Ok().json(result))
+        }
+        Ok(Err(response)) => Ok(response),
+        Err(e) => {
+            tracing::error!("Gift redemption error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Погашение в одной транзакции: код блокируется, повторное погашение невозможно
+async fn redeem(
+    pool: &PgPool,
+    user_id: Uuid,
+    code: &str,
+) -> Result<Result<(Gift, serde_json::Value), HttpResponse>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let Some(gift) = db::lock_gift_by_code(&mut tx, code).await? else {
+        return Ok(Err(
+            HttpResponse::NotFound().json(json!({"error": "Gift code not found"}))
+        ));
+    };
+    match gift.status.as_str() {
+        "scheduled" | "sending" | "delivered" => {}
+        "redeemed" => {
+            return Ok(Err(
+                HttpResponse::Conflict().json(json!({"error": "Gift code already redeemed"}))
+            ));
+        }
+        _ => {
+            return Ok(Err(
+                HttpResponse::Conflict().json(json!({"error": "Gift code is no longer valid"}))
+            ));
+        }
+    }
+
+    let (subscription_id, result) = stack_gift(&mut tx, user_id, &gift).await?;
+    db::redeem_gift(&mut tx, gift.id, user_id, subscription_id).await?;
+    // Созданная подарком подписка привязывается к его оплате: возврат или спор
+    // по платежу прекратит и её
+    if result["stacking"] == "created" {
+        db::set_payment_subscription(&mut tx, gift.payment_id, subscription_id).await?;
+    }
+    let payload =
+        json!({"gift_id": gift.id, "subscription_id": subscription_id, "plan_id": gift.plan_id});
+    db::create_notification(&mut tx, user_id, "gift_redeemed", &payload).await?;
+    if gift.purchaser_id != user_id {
+        db::create_notification(
+            &mut tx,
+            gift.purchaser_id,
+            "gift_redeemed_by_recipient",
+            &payload,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(Ok((gift, result)))
+}
+
+// Правила суммирования: без подписки создаётся новая (без автопродления);
+// тот же тариф — продление на срок подарка; другой тариф — продление текущего
+// тарифа на срок, пересчитанный по соотношению цен в валюте подписки
+async fn stack_gift(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    gift: &Gift,
+) -> Result<(Uuid, serde_json::Value), sqlx::Error> {
+    let gift_days = i64::from(gift.duration_days);
+    let Some(current) = db::lock_active_subscription_for_user(conn, user_id).await? else {
+        let now = Utc::now();
+        let subscription = Subscription {
+            id: Uuid::new_v4(),
+            user_id,
+            plan_id: gift.plan_id.clone(),
+            started_at: now,
+            expires_at: now + ChronoDuration::days(gift_days),
+            is_active: true,
+            cancel_at_period_end: false,
+            canceled_at: None,
+            auto_renew: false, // Платёжного метода получателя нет
+            last_renewal_attempt_at: None,
+            billing_status: "ok".to_string(),
+            dunning_started_at: None,
+            dunning_attempts: 0,
+            next_retry_at: None,
+            grace_until: None,
+            expiration_reason: None,
+            scheduled_plan_id: None,
+            paused_at: None,
+            pause_resumes_at: None,
+            currency: gift.currency.clone(),
+            pending_plan_id: None,
+        };
+        db::insert_subscription(conn, &subscription).await?;
+        return Ok((
+            subscription.id,
+            json!({
+                "stacking": "created",
+                "subscription_id": subscription.id,
+                "plan_id": subscription.plan_id,
+                "days_added": gift_days,
+                "expires_at": subscription.expires_at,
+            }),
+        ));
+    };
+
+    let (stacking, days) = if current.plan_id == gift.plan_id {
+        ("extended", gift_days)
+    } else {
+        ("converted", converted_days(&current, gift, gift_days))
+    };
+    let expires_at = db::extend_subscription_with_gift(
+        conn,
+        current.id,
+        gift_expires_at(&current, days, Utc::now()),
+    )
+    .await?;
+    Ok((
+        current.id,
+        json!({
+            "stacking": stacking,
+            "subscription_id": current.id,
+            "plan_id": current.plan_id,
+            "days_added": days,
+            "expires_at": expires_at,
+        }),
+    ))
+}
+
+// Подарок продлевает от конца периода, а если он уже прошёл (past_due) — от текущего
+// момента, чтобы не съесть льготные дни. Приостановленную подписку — от конца периода
+// на момент паузы: возобновление само сдвинет срок на длительность паузы
+fn gift_expires_at(current: &Subscription, days: i64, now: DateTime<Utc>) -> DateTime<Utc> {
+    let base = if current.paused_at.is_some() {
+        current.expires_at
+    } else {
+        current.expires_at.max(now)
+    };
+    base + ChronoDuration::days(days)
+}
+
+// Срок подарка в днях текущего тарифа: gift_days * цена подарочного / цена текущего
+fn converted_days(current: &Subscription, gift: &Gift, gift_days: i64) -> i64 {
+    let prices = current.billing_currency().ok().and_then(|currency| {
+        Some((
+            paywall::plan_terms(&gift.plan_id, currency)?.0,
+            paywall::plan_terms(&current.plan_id, currency)?.0,
+        ))
+    });
+    match prices {
+        Some((gift_price, current_price)) if current_price.is_positive() => {
+            (gift_days * gift_price.amount_minor() / current_price.amount_minor()).max(1)
+        }
+        _ => gift_days,
+    }
+}
+
+pub fn spawn_gift_delivery_worker(pool: PgPool, config: Config, mailer: Arc<dyn Mailer>) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(config.gift_delivery_interval_secs));
+        loop {
+            interval.tick().await;
+            match deliver_due_gifts(&pool, mailer.as_ref()).await {
+                Ok(0) => {}
+                Ok(delivered) => tracing::info!("Gift delivery: {} sent", delivered),
+                Err(e) => tracing::error!("Gift delivery error: {}", e),
+            }
+        }
+    });
+}
+
+// Подарок сначала забирается (статус sending, отдельная транзакция), письмо
+// отправляется без блокировок в базе; при сбое почты подарок возвращается в
+// scheduled, а отправка откладывается на DELIVERY_RETRY_MINUTES
+async fn deliver_due_gifts(pool: &PgPool, mailer: &dyn Mailer) -> Result<usize, sqlx::Error> {
+    let mut delivered = 0;
+    for _ in 0..MAX_DELIVERIES_PER_CYCLE {
+        let Some(gift) = db::claim_due_gift(pool, SENDING_LEASE_MINUTES).await? else {
+            break;
+        };
+        let purchaser = db::get_user_by_id(pool, gift.purchaser_id).await?;
+        let message = gift_email(&gift, purchaser.as_ref().map(|u| u.username.as_str()));
+        if let Err(e) = mailer.send(&message).await {
+            // Откладываем, чтобы неотправляемое письмо не блокировало очередь
+            tracing::warn!("Failed to send gift {}: {}", gift.id, e);
+            db::postpone_gift_delivery(pool, gift.id, DELIVERY_RETRY_MINUTES).await?;
+            continue;
+        }
+        let mut tx = pool.begin().await?;
+        db::mark_gift_delivered(&mut tx, gift.id).await?;
+        db::create_notification(
+            &mut tx,
+            gift.purchaser_id,
+            "gift_delivered",
+            &json!({"gift_id": gift.id, "recipient_email": gift.recipient_email}),
+        )
+        .await?;
+        tx.commit().await?;
+        delivered += 1;
+    }
+    Ok(delivered)
+}
+
+fn gift_email(gift: &Gift, purchaser_name: Option<&str>) -> EmailMessage {
+    let greeting = match &gift.recipient_name {
+        Some(name) => format!("Hi {},", name),
+        None => "Hi,".to_string(),
+    };
+    let mut text = format!(
+        "{}\n\n{} sent you a gift: {} days of the {} plan.\n",
+        greeting,
+        purchaser_name.unwrap_or("Someone"),
+        gift.duration_days,
+        gift.plan_id
+    );
+    if let Some(message) = &gift.message {
+        text.push_str(&format!("\n\"{}\"\n", message));
+    }
+    text.push_str(&format!(
+        "\nYour gift code: {}\nRedeem it in your account settings after signing in.\n",
+        gift.code
+    ));
+    EmailMessage {
+        to: gift.recipient_email.clone(),
+        subject: "You received a gift subscription".to_string(),
+        text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gift_request(plan_id: &str, periods: i32) -> GiftPurchaseRequest {
+        GiftPurchaseRequest {
+            plan_id: plan_id.to_string(),
+            periods,
+            recipient_email: " friend@example.com ".to_string(),
+            recipient_name: None,
+            message: None,
+            deliver_at: None,
+            payment_token: "pm_card_visa".to_string(),
+            currency: None,
+        }
+    }
+
+    fn subscription(plan_id: &str, currency: &str) -> Subscription {
+        Subscription {
+            plan_id: plan_id.to_string(),
+            currency: currency.to_string(),
+            ..Subscription::test_fixture(Utc::now() + ChronoDuration::days(30))
+        }
+    }
+
+    #[test]
+    fn code_uses_unambiguous_alphabet() {
+        let code = generate_code();
+        assert_eq!(code.len(), "GIFT-XXXX-XXXX-XXXX".len());
+        assert!(code.starts_with("GIFT-"));
+        assert!(
+            code[5..]
+                .bytes()
+                .all(|b| b == b'-' || CODE_ALPHABET.contains(&b))
+        );
+    }
+
+    #[test]
+    fn validates_gift_request() {
+        assert_eq!(validate_gift_request(&gift_request("premium", 3)), Ok(()));
+        assert_eq!(
+            validate_gift_request(&gift_request("gold", 1)),
+            Err("Invalid plan")
+        );
+        assert_eq!(
+            validate_gift_request(&gift_request("basic", 0)),
+            Err("Invalid number of periods")
+        );
+        assert_eq!(
+            validate_gift_request(&gift_request("basic", MAX_GIFT_PERIODS + 1)),
+            Err("Invalid number of periods")
+        );
+
+        let mut req = gift_request("basic", 1);
+        req.recipient_email = "not-an-email".to_string();
+        assert_eq!(validate_gift_request(&req), Err("Invalid recipient email"));
+
+        let mut req = gift_request("basic", 1);
+        req.recipient_name = Some("x".repeat(MAX_RECIPIENT_FIELD_LENGTH + 1));
+        assert_eq!(
+            validate_gift_request(&req),
+            Err("Recipient name is too long")
+        );
+
+        let mut req = gift_request("basic", 1);
+        req.message = Some("x".repeat(MAX_GIFT_MESSAGE_LENGTH + 1));
+        assert_eq!(validate_gift_request(&req), Err("Gift message is too long"));
+
+        let mut req = gift_request("basic", 1);
+        req.deliver_at = Some(Utc::now() + ChronoDuration::days(MAX_DELIVERY_DAYS_AHEAD + 1));
+        assert_eq!(
+            validate_gift_request(&req),
+            Err("Delivery date is too far in the future")
+        );
+    }
+
+    #[test]
+    fn gift_extends_from_period_end_or_now() {
+        let now = Utc::now();
+        let active = subscription("basic", "USD");
+        assert_eq!(
+            gift_expires_at(&active, 30, now),
+            active.expires_at + ChronoDuration::days(30)
+        );
+        let past_due = Subscription {
+            expires_at: now - ChronoDuration::days(3),
+            ..subscription("basic", "USD")
+        };
+        assert_eq!(
+            gift_expires_at(&past_due, 30, now),
+            now + ChronoDuration::days(30)
+        );
+    }
+
+    #[test]
+    fn gift_on_paused_subscription_is_not_stretched_by_resume() {
+        // Пауза 40 дней назад, когда оставалось 30 дней; срок уже «прошёл»
+        let now = Utc::now();
+        let paused_at = now - ChronoDuration::days(40);
+        let paused = Subscription {
+            expires_at: paused_at + ChronoDuration::days(30),
+            paused_at: Some(paused_at),
+            ..subscription("basic", "USD")
+        };
+        let gifted = gift_expires_at(&paused, 30, now);
+        // Возобновление сдвигает срок на длительность паузы (db::resume_subscriptions)
+        let resumed = gifted + (now - paused_at);
+        assert_eq!(resumed, now + ChronoDuration::days(60));
+    }
+
+    #[test]
+    fn converted_days_follow_price_ratio() {
+        // Premium на 30 дней при текущем basic: 30 * 19.99 / 9.99
+        assert_eq!(
+            converted_days(
+                &subscription("basic", "USD"),
+                &Gift::test_fixture("premium"),
+                30
+            ),
+            60
+        );
+        assert_eq!(
+            converted_days(
+                &subscription("premium", "USD"),
+                &Gift::test_fixture("basic"),
+                30
+            ),
+            14
+        );
+    }
+
+    #[test]
+    fn converted_days_fall_back_to_gift_days() {
+        // Валюта подписки неизвестна: срок подарка без пересчёта
+        assert_eq!(
+            converted_days(
+                &subscription("basic", "XYZ"),
+                &Gift::test_fixture("premium"),
+                30
+            ),
+            30
+        );
+    }
+}
@@ -0,0 +1,241 @@
+// src/idempotency.rs
+// Поддержка заголовка Idempotency-Key: первый запрос занимает ключ на время
+// выполнения, итоговый ответ сохраняется и возвращается на повторы.
+use crate::db;
+use actix_web::body;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+const MAX_KEY_LENGTH: usize = 255;
+// Сколько держится блокировка незавершённого запроса (после сбоя процесса или ответа 5xx).
+// Должна быть заметно больше таймаута запросов к платёжному провайдеру
+const LOCK_SECS: i64 = 60;
+
+pub enum Begin {
+    // Ключ свободен; внутри — ключ идемпотентности для провайдера (см. provider_key)
+    Proceed(String),
+    // Готовый ответ: повтор сохранённого результата или отказ
+    Respond(HttpResponse),
+}
+
+// Ключ из заголовка; Err — некорректный заголовок
+pub fn key_from_request(req: &HttpRequest) -> Result<Option<String>, HttpResponse> {
+    let Some(value) = req.headers().get(IDEMPOTENCY_HEADER) else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => Ok(Some(key.to_string())),
+        _ => {
+            Err(HttpResponse::BadRequest().json(json!({"error": "Invalid Idempotency-Key header"})))
+        }
+    }
+}
+
+// Отпечаток запроса: путь и тело. Повтор с тем же ключом и другим телом отклоняется
+pub fn request_hash<T: Serialize>(path: &str, body: &T) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(serde_json::to_vec(body).unwrap_or_default());
+    hex::encode(hasher.finalize())
+}
+
+pub async fn begin(
+    pool: &sqlx::PgPool,
+    user_id: Uuid,
+    key: &str,
+    request_hash: &str,
+) -> Result<Begin, sqlx::Error> {
+    if let Some(acquired_at) =
+        db::begin_idempotent_request(pool, user_id, key, request_hash, LOCK_SECS).await?
+    {
+        return Ok(Begin::Proceed(provider_key(
+            user_id,
+            key,
+            request_hash,
+            acquired_at,
+        )));
+    }
+
+    let Some(record) = db::get_idempotency_record(pool, user_id, key).await? else {
+        // Ключ освободили между запросами — клиент может повторить
+        return Ok(Begin::Respond(HttpResponse::Conflict().json(
+            json!({"error": "Request with this Idempotency-Key is being retried, try again"}),
+        )));
+    };
+
+    if record.request_hash != request_hash {
+        return Ok(Begin::Respond(HttpResponse::UnprocessableEntity().json(
+            json!({"error": "Idempotency-Key was already used with a different request"}),
+        )));
+    }
+
+    if record.status != "completed" {
+        return Ok(Begin::Respond(HttpResponse::Conflict().json(
+            json!({"error": "Request with this Idempotency-Key is still in progress"}),
+        )));
+    }
+
+    let status = record
+        .response_status
+        .and_then(|s| u16::try_from(s).ok())
+        .and_then(|s| StatusCode::from_u16(s).ok())
+        .unwrap_or(StatusCode::OK);
+    tracing::info!("Replaying response for Idempotency-Key {}", key);
+    Ok(Begin::Respond(
+        HttpResponse::build(status)
+            .insert_header(("Idempotent-Replayed", "true"))
+            .json(record.response_body.unwrap_or(serde_json::Value::Null)),
+    ))
+}
+
+// Ключ идемпотентности для провайдера: повтор того же запроса продолжает тот же
+// платёж, и провайдер не спишет деньги второй раз. Другое тело или новое занятие
+// ключа после истечения дают новый платёж, а не повтор старого
+fn provider_key(
+    user_id: Uuid,
+    key: &str,
+    request_hash: &str,
+    acquired_at: DateTime<Utc>,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(user_id.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(key.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(request_hash.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(acquired_at.timestamp_micros().to_be_bytes());
+    hex::encode(hasher.finalize())
+}
+
+// Сохраняет ответ под ключом. Ответы 5xx не сохраняются, но ключ остаётся занятым
+// до истечения блокировки: исход списания у провайдера может быть неизвестен, и
+// мгновенный повтор не должен начать вторую оплату. После LOCK_SECS повтор с тем же
+// телом выполнится заново и продолжит тот же платёж (см. provider_key).
+pub async fn finish(
+    pool: &sqlx::PgPool,
+    user_id: Uuid,
+    key: &str,
+    response: HttpResponse,
+) -> HttpResponse {
+    let status = response.status();
+    if status.is_server_error() {
+        tracing::warn!("Idempotency-Key {} stays locked after {}", key, status);
+        return response;
+    }
+
+    let headers = response.headers().clone();
+    let bytes = match body::to_bytes(response.into_body()).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!(
+                "Failed to read response body for Idempotency-Key {}: {}",
+                key,
+                e
+            );
+            return HttpResponse::InternalServerError()
+                .json(json!({"error": "Internal server error"}));
+        }
+    };
+    let stored_body = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .unwrap_or_else(|_| json!(String::from_utf8_lossy(&bytes)));
+
+    if let Err(e) = db::complete_idempotent_request(
+        pool,
+        user_id,
+        key,
+        i32::from(status.as_u16()),
+        &stored_body,
+    )
+    .await
+    {
+        tracing::error!(
+            "Failed to store response for Idempotency-Key {}: {}",
+            key,
+            e
+        );
+    }
+
+    let mut rebuilt = HttpResponse::build(status);
+    for (name, value) in headers.iter() {
+        rebuilt.insert_header((name.clone(), value.clone()));
+    }
+    rebuilt.body(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use chrono::TimeZone;
+
+    fn acquired_at() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 1, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn key_from_request_validates_header() {
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(key_from_request(&req), Ok(None)));
+
+        let req = TestRequest::default()
+            .insert_header((IDEMPOTENCY_HEADER, "abc-123"))
+            .to_http_request();
+        assert_eq!(
+            key_from_request(&req).ok().flatten().as_deref(),
+            Some("abc-123")
+        );
+
+        let long = "k".repeat(MAX_KEY_LENGTH + 1);
+        let req = TestRequest::default()
+            .insert_header((IDEMPOTENCY_HEADER, long.as_str()))
+            .to_http_request();
+        assert!(key_from_request(&req).is_err());
+    }
+
+    #[test]
+    fn request_hash_depends_on_path_and_body() {
+        let basic = json!({"plan_id": "basic"});
+        let premium = json!({"plan_id": "premium"});
+        let hash = request_hash("/subscription/purchase", &basic);
+        assert_eq!(hash, request_hash("/subscription/purchase", &basic));
+        assert_ne!(hash, request_hash("/subscription/purchase", &premium));
+        assert_ne!(hash, request_hash("/gifts/purchase", &basic));
+    }
+
+    #[test]
+    fn provider_key_is_stable_for_a_retry() {
+        let user_id = Uuid::new_v4();
+        let hash = request_hash("/subscription/purchase", &json!({"plan_id": "basic"}));
+        assert_eq!(
+            provider_key(user_id, "key-1", &hash, acquired_at()),
+            provider_key(user_id, "key-1", &hash, acquired_at())
+        );
+    }
+
+    #[test]
+    fn provider_key_changes_with_body_user_or_acquisition() {
+        let user_id = Uuid::new_v4();
+        let basic = request_hash("/subscription/purchase", &json!({"plan_id": "basic"}));
+        let premium = request_hash("/subscription/purchase", &json!({"plan_id": "premium"}));
+        let key = provider_key(user_id, "key-1", &basic, acquired_at());
+
+        // Тот же ключ с другим телом (после истечения) — новый платёж, не повтор старого
+        assert_ne!(key, provider_key(user_id, "key-1", &premium, acquired_at()));
+        // Тот же запрос после истечения ключа — тоже новый платёж
+        let later = acquired_at() + chrono::Duration::hours(25);
+        assert_ne!(key, provider_key(user_id, "key-1", &basic, later));
+        assert_ne!(
+            key,
+            provider_key(Uuid::new_v4(), "key-1", &basic, acquired_at())
+        );
+        assert_ne!(key, provider_key(user_id, "key-2", &basic, acquired_at()));
+    }
+}
@@ -0,0 +1,288 @@
+// src/invoice.rs
+// Счета по успешным платежам: номер вида INV-2026-000042, снимок реквизитов
+// продавца (из конфигурации) и покупателя, позиции и суммы. Счёт создаётся один
+// раз на платёж и после этого не меняется.
+use crate::config::Config;
+use crate::db;
+use crate::models::{Invoice, Payment};
+use crate::money::{Currency, Money, MoneyError};
+use chrono::{Datelike, Utc};
+use serde_json::json;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+// Выставляет счёт по платежу; повторный вызов возвращает уже выставленный
+pub async fn issue_invoice(
+    conn: &mut PgConnection,
+    config: &Config,
+    payment: &Payment,
+) -> Result<Invoice, sqlx::Error> {
+    if let Some(existing) = db::get_invoice_by_payment(conn, payment.id).await? {
+        return Ok(existing);
+    }
+
+    let buyer = db::get_billing_details(conn, payment.user_id)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+    let issued_at = Utc::now();
+    let sequence = db::next_invoice_number(conn, issued_at.year()).await?;
+
+    let invoice = Invoice {
+        id: Uuid::new_v4(),
+        number: format!(
+            "{}-{}-{:06}",
+            config.invoice_prefix,
+            issued_at.year(),
+            sequence
+        ),
+        payment_id: payment.id,
+        user_id: payment.user_id,
+        issued_at,
+        currency: payment.currency.clone(),
+        seller: json!({
+            "name": config.seller_name,
+            "address": config.seller_address,
+            "vat_id": config.seller_vat_id,
+            "email": config.seller_email,
+        }),
+        buyer: json!({
+            "name": buyer.billing_name.unwrap_or(buyer.username),
+            "email": buyer.email,
+            "address": buyer.billing_address,
+            "country": buyer.country,
+            "vat_id": buyer.vat_id,
+        }),
+        // Позиции указываются без налога; налог — отдельной строкой итогов
+        line_items: json!([{
+            "description": payment.description,
+            "quantity": 1,
+            "unit_amount_minor": payment.net_minor,
+            "amount_minor": payment.net_minor,
+        }]),
+        subtotal_minor: payment.net_minor,
+        discount_minor: 0,
+        tax_minor: payment.tax_minor,
+        total_minor: payment.amount_minor,
+        tax_rate_bps: payment.tax_rate_bps,
+        tax_treatment: payment.tax_treatment.clone(),
+    };
+    db::create_invoice(conn, &invoice).await?;
+    tracing::info!(
+        "Issued invoice {} for payment {}",
+        invoice.number,
+        payment.id
+    );
+    Ok(invoice)
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Строки реквизитов стороны (пустые поля пропускаются)
+fn party_html(party: &serde_json::Value) -> String {
+    let mut lines = Vec::new();
+    for (field, label) in [
+        ("name", ""),
+        ("address", ""),
+        ("country", ""),
+        ("email", ""),
+        ("vat_id", "VAT ID: "),
+    ] {
+        if let Some(value) = party
+            .get(field)
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+        {
+            lines.push(format!("{}{}", label, escape_html(value)).replace('\n', "<br>"));
+        }
+    }
+    lines.join("<br>")
+}
+
+// "VAT (19%)", "VAT (25.5%)"; без ставки — просто "Tax"
+fn tax_label(rate_bps: i32) -> String {
+    if rate_bps == 0 {
+        return "Tax".to_string();
+    }
+    let percent = format!("{}.{:02}", rate_bps / 100, rate_bps % 100);
+    format!(
+        "VAT ({}%)",
+        percent.trim_end_matches('0').trim_end_matches('.')
+    )
+}
+
+// Печатная HTML-версия счёта (для сохранения в PDF — печать из браузера)
+pub fn render_html(invoice: &Invoice) -> Result<String, MoneyError> {
+    let currency = Currency::from_code(&invoice.currency)
+        .ok_or_else(|| MoneyError::UnknownCurrency(invoice.currency.clone()))?;
+    let money = |minor: i64| Money::new(minor, currency).to_string();
+
+    let mut rows = String::new();
+    for item in invoice.line_items.as_array().into_iter().flatten() {
+        let amount = |field: &str| item.get(field).and_then(|v| v.as_i64()).unwrap_or(0);
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td></tr>\n",
+            escape_html(
+                item.get("description")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+            ),
+            item.get("quantity").and_then(|v| v.as_i64()).unwrap_or(1),
+            money(amount("unit_amount_minor")),
+            money(amount("amount_minor")),
+        ));
+    }
+
+    let mut totals = format!(
+        "<tr><td>Subtotal</td><td class=\"num\">{}</td></tr>\n",
+        money(invoice.subtotal_minor)
+    );
+    if invoice.discount_minor != 0 {
+        totals.push_str(&format!(
+            "<tr><td>Discount</td><td class=\"num\">-{}</td></tr>\n",
+            money(invoice.discount_minor)
+        ));
+    }
+    totals.push_str(&format!(
+        "<tr><td>{}</td><td class=\"num\">{}</td></tr>\n<tr class=\"total\"><td>Total</td><td class=\"num\">{}</td></tr>\n",
+        tax_label(invoice.tax_rate_bps),
+        money(invoice.tax_minor),
+        money(invoice.total_minor)
+    ));
+    // Обязательная пометка для счетов с обратным начислением НДС
+    let notes = if invoice.tax_treatment == "reverse_charge" {
+        "<p>Reverse charge: VAT to be accounted for by the recipient.</p>\n"
+    } else {
+        ""
+    };
+
+    Ok(format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>Invoice {number}</title>
+<style>
+body {{ font-family: sans-serif; margin: 40px; color: #222; }}
+table {{ border-collapse: collapse; width: 100%; margin-top: 24px; }}
+th, td {{ border-bottom: 1px solid #ddd; padding: 6px 8px; text-align: left; }}
+.num {{ text-align: right; }}
+.parties {{ display: flex; justify-content: space-between; margin-top: 24px; }}
+.totals {{ width: 40%; margin-left: auto; }}
+.total td {{ font-weight: bold; }}
+@media print {{ body {{ margin: 0; }} }}
+</style>
+</head>
+<body>
+<h1>Invoice {number}</h1>
+<p>Issued: {issued}<br>Payment: {payment_id}</p>
+<div class="parties">
+<div><strong>Seller</strong><br>{seller}</div>
+<div><strong>Bill to</strong><br>{buyer}</div>
+</div>
+<table>
+<tr><th>Description</th><th class="num">Qty</th><th class="num">Unit price</th><th class="num">Amount</th></tr>
+{rows}</table>
+<table class="totals">
+{totals}</table>
+{notes}</body>
+</html>
+"#,
+        number = escape_html(&invoice.number),
+        issued = invoice.issued_at.format("%Y-%m-%d"),
+        payment_id = invoice.payment_id,
+        seller = party_html(&invoice.seller),
+        buyer = party_html(&invoice.buyer),
+        rows = rows,
+        totals = totals,
+        notes = notes,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invoice(tax_treatment: &str) -> Invoice {
+        Invoice {
+            id: Uuid::new_v4(),
+            number: "INV-2026-000042".to_string(),
+            payment_id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            issued_at: Utc::now(),
+            currency: "EUR".to_string(),
+            seller: json!({"name": "News Ltd", "vat_id": "DE123", "email": ""}),
+            buyer: json!({"name": "<script>", "address": "Street 1\nBerlin"}),
+            line_items: json!([{
+                "description": "Premium & more",
+                "quantity": 1,
+                "unit_amount_minor": 1000,
+                "amount_minor": 1000,
+            }]),
+            subtotal_minor: 1000,
+            discount_minor: 0,
+            tax_minor: 190,
+            total_minor: 1190,
+            tax_rate_bps: 1900,
+            tax_treatment: tax_treatment.to_string(),
+        }
+    }
+
+    #[test]
+    fn tax_label_trims_trailing_zeros() {
+        assert_eq!(tax_label(0), "Tax");
+        assert_eq!(tax_label(1900), "VAT (19%)");
+        assert_eq!(tax_label(2550), "VAT (25.5%)");
+        assert_eq!(tax_label(705), "VAT (7.05%)");
+    }
+
+    #[test]
+    fn party_details_are_escaped_and_empty_fields_skipped() {
+        assert_eq!(
+            party_html(&json!({"name": "A & B", "email": "", "vat_id": "DE1"})),
+            "A &amp; B<br>VAT ID: DE1"
+        );
+        assert_eq!(
+            party_html(&json!({"address": "Street 1\nBerlin"})),
+            "Street 1<br>Berlin"
+        );
+    }
+
+    #[test]
+    fn rendered_invoice_contains_totals_and_escaped_text() {
+        let html = render_html(&invoice("standard")).unwrap();
+        assert!(html.contains("Invoice INV-2026-000042"));
+        assert!(html.contains("Premium &amp; more"));
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("VAT (19%)"));
+        assert!(html.contains(&Money::new(1190, Currency::Eur).to_string()));
+        assert!(!html.contains("Reverse charge"));
+        assert!(!html.contains("Discount"));
+    }
+
+    #[test]
+    fn reverse_charge_invoice_has_note() {
+        let html = render_html(&invoice("reverse_charge")).unwrap();
+        assert!(html.contains("Reverse charge"));
+    }
+
+    #[test]
+    fn unknown_currency_is_an_error() {
+        let mut invoice = invoice("standard");
+        invoice.currency = "XYZ".to_string();
+        assert!(render_html(&invoice).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+// src/mailer.rs
+// Отправка писем: общий трейт, HTTP-адаптер для JSON API почтового сервиса
+// и адаптер, который только пишет письма в лог (для локальной разработки).
+use crate::config::Config;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EmailMessage {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Network(String),
+    Provider { status: u16, message: String },
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Network(e) => write!(f, "mail provider unreachable: {}", e),
+            MailError::Provider { status, message } => {
+                write!(f, "mail provider error ({}): {}", status, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, message: &EmailMessage) -> Result<(), MailError>;
+}
+
+pub fn mailer_from_config(config: &Config) -> Arc<dyn Mailer> {
+    match config.mail_provider.as_str() {
+        "http" => Arc::new(HttpMailer::new(
+            &config.mail_api_url,
+            &config.mail_api_key,
+            &config.mail_from,
+        )),
+        other => {
+            if other != "log" {
+                tracing::warn!("Unknown mail provider '{}', logging emails instead", other);
+            }
+            Arc::new(LogMailer)
+        }
+    }
+}
+
+// Зависший почтовый сервис не должен останавливать очередь доставки
+const SEND_TIMEOUT_SECS: u64 = 10;
+
+// POST {url} с JSON {"from", "to", "subject", "text"} и Bearer-ключом
+pub struct HttpMailer {
+    client: reqwest::Client,
+    url: String,
+    api_key: String,
+    from: String,
+}
+
+impl HttpMailer {
+    pub fn new(url: &str, api_key: &str, from: &str) -> Self {
+        HttpMailer {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(SEND_TIMEOUT_SECS))
+                .build()
+                .expect("Failed to build mail HTTP client"),
+            url: url.to_string(),
+            api_key: api_key.to_string(),
+            from: from.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for HttpMailer {
+    async fn send(&self, message: &EmailMessage) -> Result<(), MailError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .json(&json!({
+                "from": self.from,
+                "to": message.to,
+                "subject": message.subject,
+                "text": message.text,
+            }))
+            .send()
+            .await
+            .map_err(|e| MailError::Network(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        Err(MailError::Provider {
+            status: status.as_u16(),
+            message: response.text().await.unwrap_or_default(),
+        })
+    }
+}
+
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, message: &EmailMessage) -> Result<(), MailError> {
+        tracing::info!(
+            "Email to {}: {}\n{}",
+            message.to,
+            message.subject,
+            message.text
+        );
+        Ok(())
+    }
+}
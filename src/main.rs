@@ -4,12 +4,36 @@ use moka::future::Cache;
 use sqlx::PgPool;
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
+mod admin;
 mod auth;
+mod billing;
 mod config;
 mod db;
+mod decisions;
+mod editor;
+mod experiments;
+mod family;
+mod geoip;
+mod gifts;
+mod idempotency;
+mod institutions;
+mod invoice;
+mod mailer;
 mod ml;
 mod models;
+mod money;
+mod network;
+mod offers;
+mod organizations;
+mod payment;
 mod paywall;
+mod pricing;
+mod renewal;
+mod rules;
+mod subscription;
+mod tax;
+mod visitors;
+mod webhooks;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -27,21 +51,83 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to connect to Postgres");
 
-    let cache: Cache<String, serde_json::Value> = Cache::new(1000);
+    // Инвалидация по предикату нужна для сброса решений при изменении подписки
+    let cache: Cache<String, serde_json::Value> = Cache::builder()
+        .max_capacity(1000)
+        .time_to_live(std::time::Duration::from_secs(
+            paywall::DECISION_CACHE_TTL_SECS,
+        ))
+        .support_invalidation_closures()
+        .build();
 
     let ml_model = ml::initialize_model(&pool)
         .await
         .expect("Failed to initialize ML model");
 
+    let gateway = payment::gateway_from_config(&config);
+    let proxies = network::TrustedProxies::from_config(&config);
+    let geoip = web::Data::new(
+        geoip::GeoIp::from_path(config.geoip_db_path.as_deref())
+            .with_trusted_proxies(proxies.clone()),
+    );
+    let institution_directory = web::Data::new(institutions::InstitutionDirectory::new(proxies));
+    if let Err(e) = institution_directory.reload(&pool).await {
+        tracing::error!("Failed to load institution directory: {}", e);
+    }
+    institutions::spawn_directory_refresh_worker(
+        pool.clone(),
+        institution_directory.clone(),
+        config.institution_refresh_secs,
+    );
+
+    let rule_engine = web::Data::new(rules::RuleEngine::from_config(&config));
+    rules::spawn_rules_reload_worker(rule_engine.clone(), cache.clone(), config.rules_reload_secs);
+
+    decisions::spawn_decision_purge_worker(pool.clone(), config.decision_retention_days);
+
+    let experiment_registry = web::Data::new(experiments::ExperimentRegistry::default());
+    if let Err(e) = experiment_registry.reload(&pool).await {
+        tracing::error!("Failed to load running experiment: {}", e);
+    }
+    experiments::spawn_experiment_refresh_worker(
+        pool.clone(),
+        experiment_registry.clone(),
+        cache.clone(),
+        config.experiment_refresh_secs,
+    );
+
+    renewal::spawn_renewal_worker(pool.clone(), config.clone(), cache.clone(), gateway.clone());
+    let mailer = mailer::mailer_from_config(&config);
+    gifts::spawn_gift_delivery_worker(pool.clone(), config.clone(), mailer.clone());
+
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(ml_model.clone()))
             .app_data(web::Data::new(cache.clone()))
             .app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::from(gateway.clone()))
+            .app_data(geoip.clone())
+            .app_data(institution_directory.clone())
+            .app_data(rule_engine.clone())
+            .app_data(experiment_registry.clone())
+            .app_data(web::Data::from(mailer.clone()))
             .wrap(Logger::default())
             .configure(auth::init_routes)
             .configure(paywall::init_routes)
+            .configure(editor::init_routes)
+            .configure(subscription::init_routes)
+            .configure(billing::init_routes)
+            .configure(gifts::init_routes)
+            .configure(family::init_routes)
+            .configure(organizations::init_routes)
+            .configure(institutions::init_routes)
+            .configure(rules::init_routes)
+            .configure(decisions::init_routes)
+            .configure(experiments::init_routes)
+            .configure(offers::init_routes)
+            .configure(webhooks::init_routes)
+            .configure(admin::init_routes)
     })
     .bind(("127.0.0.1", 8080))?
     .run()
@@ -1,5 +1,6 @@
 // src/models.rs
-use chrono::{DateTime, Utc};
+use crate::money::{Currency, Money, MoneyError};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid; // Добавлен импорт
@@ -11,6 +12,10 @@ pub struct User {
     pub email: String,
     pub password_hash: String,
     pub created_at: DateTime<Utc>,
+    pub country: Option<String>,           // ISO 3166-1 alpha-2
+    pub role: String,                      // user | support | admin
+    pub flagged_at: Option<DateTime<Utc>>, // Аккаунт на проверке (chargeback и т.п.)
+    pub email_verified_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, FromRow)] // Добавлен FromRow
@@ -21,6 +26,68 @@ pub struct Subscription {
     pub started_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
     pub is_active: bool,
+    pub cancel_at_period_end: bool, // Доступ сохраняется до expires_at
+    pub canceled_at: Option<DateTime<Utc>>,
+    pub auto_renew: bool,
+    pub last_renewal_attempt_at: Option<DateTime<Utc>>,
+    pub billing_status: String, // ok | past_due | expired | awaiting_payment
+    pub dunning_started_at: Option<DateTime<Utc>>,
+    pub dunning_attempts: i32,
+    pub next_retry_at: Option<DateTime<Utc>>,
+    pub grace_until: Option<DateTime<Utc>>, // Доступ в статусе past_due сохраняется до этого момента
+    pub expiration_reason: Option<String>,
+    pub scheduled_plan_id: Option<String>, // Понижение тарифа со следующего периода
+    pub paused_at: Option<DateTime<Utc>>,
+    pub pause_resumes_at: Option<DateTime<Utc>>, // Плановое автоматическое возобновление
+    pub currency: String,                        // Фиксируется при покупке
+    pub pending_plan_id: Option<String>,         // Повышение, ждущее подтверждения оплаты
+}
+
+impl Subscription {
+    pub fn billing_currency(&self) -> Result<Currency, MoneyError> {
+        Currency::from_code(&self.currency)
+            .ok_or_else(|| MoneyError::UnknownCurrency(self.currency.clone()))
+    }
+
+    // До какого момента подписка даёт доступ (с учётом льготного периода past_due)
+    pub fn access_until(&self) -> DateTime<Utc> {
+        match self.grace_until {
+            Some(grace_until) if self.billing_status == "past_due" => {
+                self.expires_at.max(grace_until)
+            }
+            _ => self.expires_at,
+        }
+    }
+}
+
+// Общая заготовка для тестов: активная basic-подписка в USD за 30 дней до expires_at
+#[cfg(test)]
+impl Subscription {
+    pub fn test_fixture(expires_at: DateTime<Utc>) -> Subscription {
+        Subscription {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            plan_id: "basic".to_string(),
+            started_at: expires_at - chrono::Duration::days(30),
+            expires_at,
+            is_active: true,
+            cancel_at_period_end: false,
+            canceled_at: None,
+            auto_renew: true,
+            last_renewal_attempt_at: None,
+            billing_status: "ok".to_string(),
+            dunning_started_at: None,
+            dunning_attempts: 0,
+            next_retry_at: None,
+            grace_until: None,
+            expiration_reason: None,
+            scheduled_plan_id: None,
+            paused_at: None,
+            pause_resumes_at: None,
+            currency: "USD".to_string(),
+            pending_plan_id: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, FromRow)] // Добавлен FromRow
@@ -29,7 +96,66 @@ pub struct Content {
     pub title: String,
     pub body: String,
     pub required_plan: String,
+    pub tags: Vec<String>,
     pub created_at: DateTime<Utc>,
+    pub publish_at: Option<DateTime<Utc>>,   // None — черновик
+    pub premium_from: Option<DateTime<Utc>>, // До этого момента материал бесплатен
+    pub free_from: Option<DateTime<Utc>>,    // С этого момента материал бесплатен (архив)
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Content {
+    pub fn is_published(&self, at: DateTime<Utc>) -> bool {
+        self.publish_at.is_some_and(|publish_at| publish_at <= at)
+    }
+
+    // Уровень доступа в момент at с учётом окон
+    pub fn plan_at(&self, at: DateTime<Utc>) -> &str {
+        let free_window = self.premium_from.is_some_and(|from| at < from)
+            || self.free_from.is_some_and(|from| from <= at);
+        if free_window {
+            "free"
+        } else {
+            &self.required_plan
+        }
+    }
+
+    // Ближайшая смена уровня доступа после at: решение до неё не кешируется
+    pub fn next_window_change(&self, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        [self.premium_from, self.free_from]
+            .into_iter()
+            .flatten()
+            .filter(|change| *change > at)
+            .min()
+    }
+}
+
+// Создание и полная замена материала редактором; публикация — отдельными запросами
+#[derive(Deserialize)]
+pub struct ContentRequest {
+    pub title: String,
+    pub body: String,
+    pub required_plan: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub premium_from: Option<DateTime<Utc>>,
+    pub free_from: Option<DateTime<Utc>>,
+}
+
+// Проверенные редактируемые поля материала (editor::validate)
+#[derive(Clone, Debug)]
+pub struct ContentChanges {
+    pub title: String,
+    pub body: String,
+    pub required_plan: String,
+    pub tags: Vec<String>,
+    pub premium_from: Option<DateTime<Utc>>,
+    pub free_from: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct PublishRequest {
+    pub publish_at: Option<DateTime<Utc>>, // None — сейчас
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -59,12 +185,522 @@ pub struct RegisterRequest {
     pub username: String,
     pub email: String,
     pub password: String,
+    #[serde(default)]
+    pub country: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PurchaseRequest {
     pub plan_id: String,
     pub payment_token: String,
+    #[serde(default)]
+    pub currency: Option<String>, // Явный выбор валюты; иначе по стране аккаунта или IP
+    #[serde(default)]
+    pub offer_id: Option<Uuid>, // Показанное предложение (пробный период, скидка) для первого периода
+}
+
+// Суммы в минимальных единицах валюты
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct Payment {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub subscription_id: Option<Uuid>,
+    pub kind: String, // purchase | renewal | upgrade | gift
+    pub amount_minor: i64,
+    pub refunded_minor: i64,
+    pub currency: String,
+    pub status: String, // pending | succeeded | failed | partially_refunded | refunded
+    pub provider_reference: Option<String>,
+    pub failure_reason: Option<String>,
+    pub description: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    // Налоговая разбивка: amount_minor = net_minor + tax_minor
+    pub net_minor: i64,
+    pub tax_minor: i64,
+    pub tax_rate_bps: i32,
+    pub tax_country: Option<String>,
+    pub tax_treatment: String, // not_taxed | standard | reverse_charge
+    pub tax_rate_version: Option<String>,
+    #[serde(skip)]
+    pub idempotency_key: String, // Ключ, с которым списание отправлено провайдеру
+}
+
+impl Payment {
+    pub fn amount(&self) -> Result<Money, MoneyError> {
+        Ok(Money::new(self.amount_minor, self.currency()?))
+    }
+
+    pub fn refunded(&self) -> Result<Money, MoneyError> {
+        Ok(Money::new(self.refunded_minor, self.currency()?))
+    }
+
+    fn currency(&self) -> Result<Currency, MoneyError> {
+        Currency::from_code(&self.currency)
+            .ok_or_else(|| MoneyError::UnknownCurrency(self.currency.clone()))
+    }
+}
+
+// Возврат неприменённого платежа, ещё не выполненный у провайдера
+#[derive(Clone, Debug, FromRow)]
+pub struct PendingRefund {
+    pub payment_id: Uuid,
+    pub charge_id: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct LedgerEntry {
+    pub id: i64,
+    pub user_id: Uuid,
+    pub payment_id: Option<Uuid>,
+    pub entry_type: String, // charge | refund | credit
+    pub amount_minor: i64,  // charge > 0, refund и credit < 0
+    pub currency: String,
+    pub description: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// Неизменяемый снимок: реквизиты сторон и позиции копируются на момент оплаты
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct Invoice {
+    pub id: Uuid,
+    pub number: String,
+    pub payment_id: Uuid,
+    pub user_id: Uuid,
+    pub issued_at: DateTime<Utc>,
+    pub currency: String,
+    pub seller: serde_json::Value,
+    pub buyer: serde_json::Value,
+    pub line_items: serde_json::Value,
+    pub subtotal_minor: i64,
+    pub discount_minor: i64,
+    pub tax_minor: i64,
+    pub total_minor: i64,
+    pub tax_rate_bps: i32,
+    pub tax_treatment: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct BillingDetails {
+    pub username: String,
+    pub email: String,
+    pub country: Option<String>,
+    pub billing_name: Option<String>,
+    pub billing_address: Option<String>,
+    pub vat_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BillingDetailsRequest {
+    pub billing_name: Option<String>,
+    pub billing_address: Option<String>,
+    pub vat_id: Option<String>,
+    pub country: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminRefundRequest {
+    pub amount_minor: Option<i64>, // Без суммы — возврат всего невозвращённого остатка
+    pub reason: String,
+    #[serde(default = "default_subscription_action")]
+    pub subscription_action: String, // keep | revoke | shorten
+    pub shorten_days: Option<i64>,
+}
+
+fn default_subscription_action() -> String {
+    "keep".to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct Gift {
+    pub id: Uuid,
+    pub code: String,
+    pub purchaser_id: Uuid,
+    pub payment_id: Uuid,
+    pub plan_id: String,
+    pub periods: i32,
+    pub duration_days: i32,
+    pub currency: String,
+    pub recipient_email: String,
+    pub recipient_name: Option<String>,
+    pub message: Option<String>,
+    pub deliver_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub status: String, // awaiting_payment | scheduled | sending | delivered | redeemed | voided
+    pub redeemed_by: Option<Uuid>,
+    pub redeemed_at: Option<DateTime<Utc>>,
+    pub subscription_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Общая заготовка для тестов: оплаченный подарок на 30 дней, ждущий доставки
+#[cfg(test)]
+impl Gift {
+    pub fn test_fixture(plan_id: &str) -> Gift {
+        let now = Utc::now();
+        Gift {
+            id: Uuid::new_v4(),
+            code: "GIFT-TEST-TEST-TEST".to_string(),
+            purchaser_id: Uuid::new_v4(),
+            payment_id: Uuid::new_v4(),
+            plan_id: plan_id.to_string(),
+            periods: 1,
+            duration_days: 30,
+            currency: "USD".to_string(),
+            recipient_email: "friend@example.com".to_string(),
+            recipient_name: None,
+            message: None,
+            deliver_at: now,
+            delivered_at: None,
+            status: "scheduled".to_string(),
+            redeemed_by: None,
+            redeemed_at: None,
+            subscription_id: None,
+            created_at: now,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GiftPurchaseRequest {
+    pub plan_id: String,
+    #[serde(default = "default_gift_periods")]
+    pub periods: i32, // Число оплачиваемых периодов тарифа
+    pub recipient_email: String,
+    pub recipient_name: Option<String>,
+    pub message: Option<String>,
+    pub deliver_at: Option<DateTime<Utc>>, // Без даты — сразу
+    pub payment_token: String,
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+fn default_gift_periods() -> i32 {
+    1
+}
+
+#[derive(Deserialize)]
+pub struct RedeemGiftRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct FamilyMember {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub subscription_id: Uuid,
+    pub email: String,
+    pub member_id: Option<Uuid>,
+    #[serde(skip_serializing)]
+    pub invite_token: String, // Уходит только в письме-приглашении
+    pub status: String, // invited | active | removed | left | ended
+    pub invited_at: DateTime<Utc>,
+    pub joined_at: Option<DateTime<Utc>>,
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct FamilyInviteRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct FamilyJoinRequest {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct Organization {
+    pub id: Uuid,
+    pub name: String,
+    pub plan_id: String, // basic | premium
+    pub seats: i32,
+    pub license_expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Членство пользователя вместе с данными организации (GET /organizations)
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct OrganizationMembership {
+    pub organization_id: Uuid,
+    pub name: String,
+    pub plan_id: String,
+    pub license_expires_at: DateTime<Utc>,
+    pub role: String,       // member | admin
+    pub joined_via: String, // staff | invite | domain
+    pub joined_at: DateTime<Utc>,
+}
+
+// Участник в отчёте об использовании мест
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct OrganizationMember {
+    pub user_id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub role: String,
+    pub joined_via: String,
+    pub joined_at: DateTime<Utc>,
+    pub last_active_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct OrganizationInvite {
+    pub id: Uuid,
+    pub organization_id: Uuid,
+    pub email: String,
+    pub role: String,
+    #[serde(skip_serializing)]
+    pub token: String, // Уходит только в письме-приглашении
+    pub status: String, // pending | accepted | revoked
+    pub invited_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub accepted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct OrganizationDomain {
+    pub domain: String,
+    pub organization_id: Uuid,
+    pub verification_token: String, // Значение TXT-записи для проверки владения
+    pub verified_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateOrganizationRequest {
+    pub name: String,
+    pub plan_id: String,
+    pub seats: i32,
+    pub license_expires_at: DateTime<Utc>,
+    pub admin_user_id: Uuid, // Первый администратор организации
+}
+
+// Продление, докупка мест или смена уровня; отсутствующие поля не меняются
+#[derive(Deserialize)]
+pub struct OrganizationLicenseRequest {
+    pub plan_id: Option<String>,
+    pub seats: Option<i32>,
+    pub license_expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct OrganizationInviteRequest {
+    pub email: String,
+    #[serde(default = "default_organization_role")]
+    pub role: String,
+}
+
+fn default_organization_role() -> String {
+    "member".to_string()
+}
+
+#[derive(Deserialize)]
+pub struct OrganizationJoinRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct OrganizationDomainRequest {
+    pub domain: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct Institution {
+    pub id: Uuid,
+    pub name: String,
+    pub plan_id: String, // basic | premium
+    pub is_active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct InstitutionIpRange {
+    pub institution_id: Uuid,
+    pub cidr: String,
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct InstitutionReferrer {
+    pub institution_id: Uuid,
+    pub host: String,
+}
+
+// Строка отчёта об использовании: сумма за месяц по материалу, метрике и способу доступа
+#[derive(Clone, Debug, FromRow)]
+pub struct InstitutionUsageRow {
+    pub content_id: Uuid,
+    pub title: Option<String>, // None — материал удалён
+    pub month: NaiveDate,
+    pub metric: String,
+    pub access_method: String,
+    pub count: i64,
+}
+
+#[derive(Deserialize)]
+pub struct InstitutionRequest {
+    pub name: String,
+    pub plan_id: String,
+    #[serde(default)]
+    pub ip_ranges: Vec<String>,
+    #[serde(default)]
+    pub referrers: Vec<String>,
+}
+
+// Отсутствующие поля не меняются; списки диапазонов и referrer'ов заменяются целиком
+#[derive(Deserialize)]
+pub struct InstitutionUpdateRequest {
+    pub name: Option<String>,
+    pub plan_id: Option<String>,
+    pub is_active: Option<bool>,
+    pub ip_ranges: Option<Vec<String>>,
+    pub referrers: Option<Vec<String>>,
+}
+
+// Сохранённое решение пейвола; record — полная запись (decisions::DecisionRecord)
+#[derive(Serialize, Clone, Debug, FromRow)]
+pub struct PaywallDecision {
+    pub id: Uuid,
+    pub content_id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub visitor_id: Option<Uuid>,
+    pub institution_id: Option<Uuid>,
+    pub rule: Option<String>,
+    pub outcome: String,
+    pub access_granted: bool,
+    pub sample_reason: String, // sampled | debug
+    pub record: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Clone, Debug, FromRow)]
+pub struct Experiment {
+    pub id: Uuid,
+    pub key: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub status: String, // draft | running | stopped
+    pub traffic_percent: i32,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub stopped_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Clone, Debug, FromRow)]
+pub struct ExperimentVariant {
+    pub experiment_id: Uuid,
+    pub key: String,
+    pub name: String,
+    pub position: i32,
+    pub weight: i32,
+    pub rules: Option<serde_json::Value>,
+}
+
+// Итоги варианта: пользователи с показом и те, кто после первого показа
+// оформил подписку или оплатил покупку
+#[derive(Clone, Debug, FromRow)]
+pub struct ExperimentVariantStats {
+    pub variant_key: String,
+    pub exposed_users: i64,
+    pub converted_users: i64,
+    pub paying_users: i64,
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct ExperimentRevenueRow {
+    pub variant_key: String,
+    pub currency: String,
+    pub net_minor: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ExperimentVariantRequest {
+    pub key: String,
+    pub name: Option<String>,
+    #[serde(default = "default_variant_weight")]
+    pub weight: i32,
+    pub rules: Option<serde_json::Value>,
+}
+
+fn default_variant_weight() -> i32 {
+    1
+}
+
+#[derive(Deserialize)]
+pub struct CreateExperimentRequest {
+    pub key: String,
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default = "default_traffic_percent")]
+    pub traffic_percent: i32,
+    pub variants: Vec<ExperimentVariantRequest>,
+}
+
+fn default_traffic_percent() -> i32 {
+    100
+}
+
+// Накопленная статистика варианта предложения в контексте показа
+#[derive(Serialize, Clone, Debug, FromRow)]
+pub struct OfferArmStats {
+    pub context: String,
+    pub arm: String,
+    pub impressions: i64,
+    pub conversions: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Clone, Debug, FromRow)]
+pub struct OfferImpression {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub content_id: Uuid,
+    pub context: String,
+    pub arm: String,
+    pub policy: String, // thompson | epsilon_greedy
+    pub shown_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub converted_at: Option<DateTime<Utc>>,
+    pub redeemed: bool,
+    pub subscription_id: Option<Uuid>,
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct IdempotencyRecord {
+    pub request_hash: String,
+    pub status: String, // in_progress | completed
+    pub response_status: Option<i32>,
+    pub response_body: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CancelRequest {
+    #[serde(default)]
+    pub immediate: bool, // false: отмена в конце оплаченного периода
+    pub reason: String,
+    pub comment: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangePlanRequest {
+    pub plan_id: String,
+    pub payment_token: Option<String>, // По умолчанию сохранённый платёжный метод
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PauseRequest {
+    pub months: u32, // 1..=3
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AutoRenewRequest {
+    pub enabled: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)] // Clone для использования в ML
@@ -78,3 +714,75 @@ pub struct MLFeatures {
     pub user_total_interactions: f64, // Изменено на f64 для соответствия ML модели
     pub content_avg_interaction_score: f64, // Изменено на f64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn content(premium_from: Option<DateTime<Utc>>, free_from: Option<DateTime<Utc>>) -> Content {
+        let now = Utc::now();
+        Content {
+            id: Uuid::new_v4(),
+            title: "Title".to_string(),
+            body: "Body".to_string(),
+            required_plan: "premium".to_string(),
+            tags: Vec::new(),
+            created_at: now,
+            publish_at: Some(now),
+            premium_from,
+            free_from,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn premium_window_starts_at_premium_from() {
+        let start = Utc::now();
+        let content = content(Some(start), None);
+        assert_eq!(content.plan_at(start - Duration::seconds(1)), "free");
+        assert_eq!(content.plan_at(start), "premium");
+    }
+
+    #[test]
+    fn archive_is_free_from_free_from() {
+        let start = Utc::now();
+        let end = start + Duration::days(7);
+        let content = content(Some(start), Some(end));
+        assert_eq!(content.plan_at(end - Duration::seconds(1)), "premium");
+        assert_eq!(content.plan_at(end), "free");
+        assert_eq!(content.plan_at(end + Duration::days(365)), "free");
+    }
+
+    #[test]
+    fn content_without_windows_keeps_required_plan() {
+        let content = content(None, None);
+        assert_eq!(content.plan_at(Utc::now()), "premium");
+        assert_eq!(content.next_window_change(Utc::now()), None);
+    }
+
+    #[test]
+    fn next_window_change_skips_past_boundaries() {
+        let start = Utc::now();
+        let end = start + Duration::days(7);
+        let content = content(Some(start), Some(end));
+        assert_eq!(
+            content.next_window_change(start - Duration::hours(1)),
+            Some(start)
+        );
+        // Ровно на границе она уже наступила
+        assert_eq!(content.next_window_change(start), Some(end));
+        assert_eq!(content.next_window_change(end), None);
+    }
+
+    #[test]
+    fn scheduled_content_is_published_at_publish_at() {
+        let publish_at = Utc::now() + Duration::hours(1);
+        let mut content = content(None, None);
+        content.publish_at = Some(publish_at);
+        assert!(!content.is_published(publish_at - Duration::seconds(1)));
+        assert!(content.is_published(publish_at));
+        content.publish_at = None;
+        assert!(!content.is_published(publish_at));
+    }
+}
@@ -0,0 +1,253 @@
+// src/money.rs
+// Денежные суммы: целое число минимальных единиц (центов, копеек) и ISO-код валюты.
+// Арифметика только проверяемая: переполнение и смешение валют — ошибки.
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Currency {
+    Usd,
+    Eur,
+    Gbp,
+    Rub,
+}
+
+impl Currency {
+    pub fn from_code(code: &str) -> Option<Currency> {
+        match code.to_ascii_uppercase().as_str() {
+            "USD" => Some(Currency::Usd),
+            "EUR" => Some(Currency::Eur),
+            "GBP" => Some(Currency::Gbp),
+            "RUB" => Some(Currency::Rub),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Currency::Usd => "USD",
+            Currency::Eur => "EUR",
+            Currency::Gbp => "GBP",
+            Currency::Rub => "RUB",
+        }
+    }
+
+    // Число знаков после запятой (ISO 4217)
+    pub fn minor_exponent(self) -> u32 {
+        match self {
+            Currency::Usd | Currency::Eur | Currency::Gbp | Currency::Rub => 2,
+        }
+    }
+
+    fn minor_per_major(self) -> i64 {
+        10_i64.pow(self.minor_exponent())
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MoneyError {
+    CurrencyMismatch(Currency, Currency),
+    UnknownCurrency(String),
+    Overflow,
+    DivisionByZero,
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyError::CurrencyMismatch(a, b) => write!(f, "currency mismatch: {} vs {}", a, b),
+            MoneyError::UnknownCurrency(code) => write!(f, "unknown currency: {}", code),
+            MoneyError::Overflow => write!(f, "money arithmetic overflow"),
+            MoneyError::DivisionByZero => write!(f, "money division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Money {
+    amount_minor: i64,
+    currency: Currency,
+}
+
+impl Money {
+    pub const fn new(amount_minor: i64, currency: Currency) -> Money {
+        Money {
+            amount_minor,
+            currency,
+        }
+    }
+
+    pub const fn zero(currency: Currency) -> Money {
+        Money::new(0, currency)
+    }
+
+    pub fn amount_minor(self) -> i64 {
+        self.amount_minor
+    }
+
+    pub fn currency(self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.amount_minor > 0
+    }
+
+    fn same_currency(self, other: Money) -> Result<(), MoneyError> {
+        if self.currency != other.currency {
+            return Err(MoneyError::CurrencyMismatch(self.currency, other.currency));
+        }
+        Ok(())
+    }
+
+    pub fn checked_add(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        let amount = self
+            .amount_minor
+            .checked_add(other.amount_minor)
+            .ok_or(MoneyError::Overflow)?;
+        Ok(Money::new(amount, self.currency))
+    }
+
+    pub fn checked_sub(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        let amount = self
+            .amount_minor
+            .checked_sub(other.amount_minor)
+            .ok_or(MoneyError::Overflow)?;
+        Ok(Money::new(amount, self.currency))
+    }
+
+    pub fn checked_neg(self) -> Result<Money, MoneyError> {
+        let amount = self
+            .amount_minor
+            .checked_neg()
+            .ok_or(MoneyError::Overflow)?;
+        Ok(Money::new(amount, self.currency))
+    }
+
+    // Доля суммы numerator/denominator, округлённая до минимальной единицы валюты
+    // (половина — от нуля). Промежуточный результат в i128, поэтому без потери точности.
+    pub fn mul_ratio(self, numerator: i64, denominator: i64) -> Result<Money, MoneyError> {
+        if denominator == 0 {
+            return Err(MoneyError::DivisionByZero);
+        }
+        let product = i128::from(self.amount_minor) * i128::from(numerator);
+        let denominator = i128::from(denominator);
+        let quotient = product / denominator;
+        let remainder = product % denominator;
+        let rounded = if remainder.abs() * 2 >= denominator.abs() {
+            quotient + product.signum() * denominator.signum()
+        } else {
+            quotient
+        };
+        let amount = i64::try_from(rounded).map_err(|_| MoneyError::Overflow)?;
+        Ok(Money::new(amount, self.currency))
+    }
+
+    pub fn max(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        Ok(if other.amount_minor > self.amount_minor {
+            other
+        } else {
+            self
+        })
+    }
+
+    pub fn min(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        Ok(if other.amount_minor < self.amount_minor {
+            other
+        } else {
+            self
+        })
+    }
+
+    // Сумма в основных единицах строкой без потери точности: "9.99"
+    pub fn to_major_string(self) -> String {
+        let per_major = self.currency.minor_per_major();
+        let sign = if self.amount_minor < 0 { "-" } else { "" };
+        let abs = self.amount_minor.unsigned_abs();
+        let major = abs / per_major as u64;
+        let exponent = self.currency.minor_exponent() as usize;
+        if exponent == 0 {
+            return format!("{}{}", sign, major);
+        }
+        let minor = abs % per_major as u64;
+        format!("{}{}.{:0width$}", sign, major, minor, width = exponent)
+    }
+}
+
+// "9.99 USD"
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.to_major_string(), self.currency)
+    }
+}
+
+// В JSON: {"amount": "9.99", "amount_minor": 999, "currency": "USD"}
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Money", 3)?;
+        state.serialize_field("amount", &self.to_major_string())?;
+        state.serialize_field("amount_minor", &self.amount_minor)?;
+        state.serialize_field("currency", self.currency.code())?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd(minor: i64) -> Money {
+        Money::new(minor, Currency::Usd)
+    }
+
+    #[test]
+    fn checked_arithmetic_overflows() {
+        assert_eq!(usd(i64::MAX).checked_add(usd(1)), Err(MoneyError::Overflow));
+        assert_eq!(usd(i64::MIN).checked_sub(usd(1)), Err(MoneyError::Overflow));
+        assert_eq!(usd(i64::MIN).checked_neg(), Err(MoneyError::Overflow));
+        assert_eq!(usd(i64::MAX).mul_ratio(2, 1), Err(MoneyError::Overflow));
+        assert_eq!(usd(100).checked_add(usd(-250)), Ok(usd(-150)));
+    }
+
+    #[test]
+    fn mixed_currencies_are_rejected() {
+        let eur = Money::new(100, Currency::Eur);
+        assert_eq!(
+            usd(100).checked_add(eur),
+            Err(MoneyError::CurrencyMismatch(Currency::Usd, Currency::Eur))
+        );
+        assert!(usd(100).max(eur).is_err());
+    }
+
+    #[test]
+    fn mul_ratio_rounds_half_away_from_zero() {
+        assert_eq!(usd(5).mul_ratio(1, 2), Ok(usd(3)));
+        assert_eq!(usd(-5).mul_ratio(1, 2), Ok(usd(-3)));
+        assert_eq!(usd(5).mul_ratio(-1, 2), Ok(usd(-3)));
+        assert_eq!(usd(999).mul_ratio(1, 3), Ok(usd(333)));
+        // Промежуточное произведение больше i64, результат — нет
+        assert_eq!(usd(i64::MAX).mul_ratio(3, 3), Ok(usd(i64::MAX)));
+        assert_eq!(usd(1).mul_ratio(1, 0), Err(MoneyError::DivisionByZero));
+    }
+
+    #[test]
+    fn formats_major_units() {
+        assert_eq!(usd(999).to_major_string(), "9.99");
+        assert_eq!(usd(5).to_major_string(), "0.05");
+        assert_eq!(usd(-1050).to_string(), "-10.50 USD");
+        assert_eq!(usd(i64::MIN).to_major_string(), "-92233720368547758.08");
+    }
+}
@@ -0,0 +1,228 @@
+// src/network.rs
+// Адрес клиента и диапазоны IP. За балансировщиком адрес соединения — адрес
+// прокси, поэтому X-Forwarded-For учитывается только от доверенных прокси
+// (TRUSTED_PROXIES), иначе клиент мог бы подставить любой адрес.
+use crate::config::Config;
+use actix_web::HttpRequest;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+// IPv4 хранится как IPv4-mapped IPv6, чтобы оба семейства были в одной шкале
+pub fn ip_key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+// Сеть в нотации CIDR ("10.0.0.0/8", "2001:db8::/32"); одиночный адрес — /32 или /128
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+    start: u128,
+    end: u128,
+}
+
+impl IpRange {
+    // Биты хоста обнуляются: "10.1.2.3/8" становится "10.0.0.0/8"
+    pub fn parse(value: &str) -> Option<IpRange> {
+        let value = value.trim();
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32u8,
+            IpAddr::V6(_) => 128u8,
+        };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return None;
+        }
+        // У IPv4 в общей шкале маскируются только младшие 32 бита
+        let host_bits = u32::from(max_prefix - prefix);
+        let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+        let start = ip_key(addr) & mask;
+        let end = start | !mask;
+        let network = match addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv6Addr::from(start).to_ipv4_mapped()?),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(start)),
+        };
+        Some(IpRange {
+            network,
+            prefix,
+            start,
+            end,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let key = ip_key(ip);
+        self.start <= key && key <= self.end
+    }
+
+    // Размер диапазона: при пересечении побеждает более узкий
+    pub fn size(&self) -> u128 {
+        self.end - self.start
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    ranges: Vec<IpRange>,
+}
+
+impl TrustedProxies {
+    pub fn from_config(config: &Config) -> TrustedProxies {
+        let ranges = config
+            .trusted_proxies
+            .iter()
+            .filter(|value| !value.trim().is_empty())
+            .filter_map(|value| {
+                let range = IpRange::parse(value);
+                if range.is_none() {
+                    tracing::warn!("Ignoring invalid TRUSTED_PROXIES entry '{}'", value);
+                }
+                range
+            })
+            .collect();
+        TrustedProxies { ranges }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(ip))
+    }
+
+    // Адрес клиента: от адреса соединения идём по X-Forwarded-For справа налево,
+    // пока адреса принадлежат доверенным прокси. Первый недоверенный адрес — клиент;
+    // некорректная запись обрывает цепочку на последнем проверенном адресе
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+
+        let hops: Vec<&str> = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            let Some(ip) = parse_forwarded_ip(hop) else {
+                break;
+            };
+            client = ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+// Запись X-Forwarded-For: адрес, возможно с портом ("1.2.3.4:80", "[2001:db8::1]:443")
+fn parse_forwarded_ip(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn proxies(ranges: &[&str]) -> TrustedProxies {
+        TrustedProxies {
+            ranges: ranges.iter().filter_map(|r| IpRange::parse(r)).collect(),
+        }
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_clears_host_bits() {
+        let range = IpRange::parse("10.1.2.3/8").unwrap();
+        assert_eq!(range.to_string(), "10.0.0.0/8");
+        assert!(range.contains(ip("10.255.255.255")));
+        assert!(!range.contains(ip("11.0.0.0")));
+    }
+
+    #[test]
+    fn parse_single_address_and_invalid_prefix() {
+        let range = IpRange::parse("192.168.1.7").unwrap();
+        assert_eq!(range.to_string(), "192.168.1.7/32");
+        assert_eq!(range.size(), 0);
+        assert!(IpRange::parse("10.0.0.0/33").is_none());
+        assert!(IpRange::parse("2001:db8::/129").is_none());
+        assert!(IpRange::parse("not-an-ip/8").is_none());
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        let range = IpRange::parse("2001:db8:1234::1/32").unwrap();
+        assert_eq!(range.to_string(), "2001:db8::/32");
+        assert!(range.contains(ip("2001:db8:ffff::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+        // IPv4 не попадает в IPv6-диапазон
+        assert!(!range.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn client_ip_ignores_forwarded_for_from_untrusted_peer() {
+        let req = TestRequest::default()
+            .peer_addr("203.0.113.5:4000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "1.2.3.4"))
+            .to_http_request();
+        assert_eq!(
+            proxies(&["10.0.0.0/8"]).client_ip(&req),
+            Some(ip("203.0.113.5"))
+        );
+    }
+
+    #[test]
+    fn client_ip_skips_trusted_hops() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "1.2.3.4, 198.51.100.7, 10.0.0.9"))
+            .to_http_request();
+        // Подставленный клиентом адрес левее первого недоверенного не учитывается
+        assert_eq!(
+            proxies(&["10.0.0.0/8"]).client_ip(&req),
+            Some(ip("198.51.100.7"))
+        );
+    }
+
+    #[test]
+    fn client_ip_handles_ports_and_garbage() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "[2001:db8::1]:443"))
+            .to_http_request();
+        assert_eq!(
+            proxies(&["10.0.0.0/8"]).client_ip(&req),
+            Some(ip("2001:db8::1"))
+        );
+
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "1.2.3.4, garbage"))
+            .to_http_request();
+        assert_eq!(
+            proxies(&["10.0.0.0/8"]).client_ip(&req),
+            Some(ip("10.0.0.2"))
+        );
+    }
+}
@@ -0,0 +1,783 @@
+// src/offers.rs
+// Выбор предложения для исхода offer (по умолчанию — после положительного прогноза
+// PaywallModel). Вариант (пробный период, скидка, первый месяц за 1) выбирает
+// контекстный бандит: статистика показов и покупок ведётся отдельно для каждого
+// контекста (устройство и уровень материала) и дополняется общей статистикой
+// варианта, пока своих наблюдений в контексте мало. Награда — покупка подписки,
+// пока предложение действует. Симуляция проверяет политику на заданных или
+// наблюдаемых конверсиях без участия пользователей.
+use crate::auth;
+use crate::config::Config;
+use crate::db;
+use crate::models::{OfferArmStats, OfferImpression};
+use crate::money::{Money, MoneyError};
+use crate::rules::Facts;
+use actix_web::{HttpRequest, HttpResponse, get, post, web};
+use chrono::{Duration, Utc};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Сколько наблюдений других контекстов максимум учитывается как априорное знание
+const PRIOR_WEIGHT: f64 = 20.0;
+const DEFAULT_SIMULATION_ROUNDS: usize = 10_000;
+const DEFAULT_SIMULATION_RUNS: usize = 20;
+// Ограничение на rounds × runs × число политик: симуляция идёт в обработчике запроса
+const MAX_SIMULATION_STEPS: usize = 20_000_000;
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(offer_stats);
+    cfg.service(simulate);
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Arm {
+    #[serde(rename = "trial_7d")]
+    Trial7d,
+    #[serde(rename = "discount_30")]
+    Discount30,
+    #[serde(rename = "first_month_1")]
+    FirstMonth1,
+}
+
+pub const ARMS: [Arm; 3] = [Arm::Trial7d, Arm::Discount30, Arm::FirstMonth1];
+// Без вводных предложений (пробный период, первый месяц за 1)
+const RETURNING_ARMS: [Arm; 1] = [Arm::Discount30];
+
+impl Arm {
+    pub fn key(self) -> &'static str {
+        match self {
+            Arm::Trial7d => "trial_7d",
+            Arm::Discount30 => "discount_30",
+            Arm::FirstMonth1 => "first_month_1",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Arm> {
+        ARMS.into_iter().find(|arm| arm.key() == key)
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Arm::Trial7d => 0,
+            Arm::Discount30 => 1,
+            Arm::FirstMonth1 => 2,
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Arm::Trial7d => "Try 7 days for free",
+            Arm::Discount30 => "Get 30% off your first month",
+            Arm::FirstMonth1 => "Your first month for 1 (in your billing currency)",
+        }
+    }
+
+    // Цена и длительность первого периода на условиях предложения;
+    // дальше подписка продлевается по обычной цене тарифа
+    pub fn first_period(self, price: Money, period_days: i64) -> Result<(Money, i64), MoneyError> {
+        match self {
+            Arm::Trial7d => Ok((Money::zero(price.currency()), 7)),
+            Arm::Discount30 => Ok((price.mul_ratio(70, 100)?, period_days)),
+            Arm::FirstMonth1 => {
+                let one = Money::new(
+                    10_i64.pow(price.currency().minor_exponent()),
+                    price.currency(),
+                );
+                Ok((one.min(price)?, 30))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum Policy {
+    Thompson,
+    EpsilonGreedy { epsilon: f64 },
+    Random, // Только для сравнения в симуляции
+}
+
+impl Policy {
+    pub fn from_config(config: &Config) -> Policy {
+        match config.offer_policy.as_str() {
+            "epsilon_greedy" => Policy::EpsilonGreedy {
+                epsilon: config.offer_epsilon.clamp(0.0, 1.0),
+            },
+            "thompson" => Policy::Thompson,
+            other => {
+                tracing::warn!("Unknown OFFER_POLICY '{}', using thompson", other);
+                Policy::Thompson
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Policy::Thompson => "thompson",
+            Policy::EpsilonGreedy { .. } => "epsilon_greedy",
+            Policy::Random => "random",
+        }
+    }
+
+    // Выбор среди допустимых для пользователя вариантов (allowed не пуст)
+    pub fn choose<R: Rng>(&self, estimates: &[Estimate; 3], allowed: &[Arm], rng: &mut R) -> Arm {
+        match self {
+            Policy::Thompson => best_arm(
+                estimates.map(|e| sample_beta(e.alpha, e.beta, rng)),
+                allowed,
+            ),
+            Policy::EpsilonGreedy { epsilon } => {
+                if rng.gen_bool(epsilon.clamp(0.0, 1.0)) {
+                    allowed[rng.gen_range(0..allowed.len())]
+                } else {
+                    best_arm(estimates.map(|e| e.mean()), allowed)
+                }
+            }
+            Policy::Random => allowed[rng.gen_range(0..allowed.len())],
+        }
+    }
+}
+
+// При равенстве — первый в порядке allowed
+fn best_arm(scores: [f64; 3], allowed: &[Arm]) -> Arm {
+    let mut best = allowed[0];
+    for arm in allowed {
+        if scores[arm.index()] > scores[best.index()] {
+            best = *arm;
+        }
+    }
+    best
+}
+
+// Варианты, доступные пользователю: после любой подписки или погашенного
+// предложения вводные недоступны
+async fn allowed_arms(pool: &PgPool, user_id: Uuid) -> Result<&'static [Arm], sqlx::Error> {
+    if db::has_subscription_history(pool, user_id).await? {
+        Ok(&RETURNING_ARMS)
+    } else {
+        Ok(&ARMS)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct Counts {
+    pub impressions: i64,
+    pub conversions: i64,
+}
+
+impl Counts {
+    fn failures(self) -> f64 {
+        (self.impressions - self.conversions).max(0) as f64
+    }
+}
+
+// Апостериорное Beta(alpha, beta) для конверсии варианта в контексте
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Estimate {
+    pub alpha: f64,
+    pub beta: f64,
+}
+
+impl Estimate {
+    // Равномерное априорное, сдвинутое к конверсии варианта в других контекстах
+    // с весом их наблюдений (не больше PRIOR_WEIGHT)
+    pub fn from_counts(own: Counts, others: Counts) -> Estimate {
+        let weight = (others.impressions as f64).min(PRIOR_WEIGHT);
+        let others_rate = (others.conversions as f64 + 1.0) / (others.impressions as f64 + 2.0);
+        Estimate {
+            alpha: 1.0 + own.conversions as f64 + weight * others_rate,
+            beta: 1.0 + own.failures() + weight * (1.0 - others_rate),
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.alpha / (self.alpha + self.beta)
+    }
+}
+
+// Статистика по контекстам: [показы и покупки варианта] в порядке ARMS
+#[derive(Default)]
+pub struct StatsTable {
+    contexts: HashMap<String, [Counts; 3]>,
+}
+
+impl StatsTable {
+    pub fn from_rows(rows: &[OfferArmStats]) -> StatsTable {
+        let mut table = StatsTable::default();
+        for row in rows {
+            if let Some(arm) = Arm::from_key(&row.arm) {
+                let counts =
+                    &mut table.contexts.entry(row.context.clone()).or_default()[arm.index()];
+                counts.impressions += row.impressions;
+                counts.conversions += row.conversions;
+            }
+        }
+        table
+    }
+
+    pub fn estimates(&self, context: &str) -> [Estimate; 3] {
+        let own = self.contexts.get(context).copied().unwrap_or_default();
+        let mut others = [Counts::default(); 3];
+        for (key, counts) in &self.contexts {
+            if key == context {
+                continue;
+            }
+            for (total, counts) in others.iter_mut().zip(counts) {
+                total.impressions += counts.impressions;
+                total.conversions += counts.conversions;
+            }
+        }
+        let mut estimates = [Estimate {
+            alpha: 1.0,
+            beta: 1.0,
+        }; 3];
+        for i in 0..ARMS.len() {
+            estimates[i] = Estimate::from_counts(own[i], others[i]);
+        }
+        estimates
+    }
+
+    fn record(&mut self, context: &str, arm: Arm, converted: bool) {
+        let counts = &mut self.contexts.entry(context.to_string()).or_default()[arm.index()];
+        counts.impressions += 1;
+        counts.conversions += i64::from(converted);
+    }
+}
+
+// Нормальное распределение (Бокс — Мюллер)
+fn sample_normal<R: Rng>(rng: &mut R) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.r#gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+// Гамма-распределение (Марсалья — Цанг); для shape < 1 — через shape + 1
+fn sample_gamma<R: Rng>(shape: f64, rng: &mut R) -> f64 {
+    if shape < 1.0 {
+        let u: f64 = rng.gen_range(f64::EPSILON..1.0);
+        return sample_gamma(shape + 1.0, rng) * u.powf(1.0 / shape);
+    }
+    let d = shape - 1.0 / 3.0;
+    let c = 1.0 / (9.0 * d).sqrt();
+    loop {
+        let x = sample_normal(rng);
+        let v = (1.0 + c * x).powi(3);
+        if v <= 0.0 {
+            continue;
+        }
+        let u: f64 = rng.gen_range(f64::EPSILON..1.0);
+        if u.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+            return d * v;
+        }
+    }
+}
+
+fn sample_beta<R: Rng>(alpha: f64, beta: f64, rng: &mut R) -> f64 {
+    let x = sample_gamma(alpha, rng);
+    let y = sample_gamma(beta, rng);
+    x / (x + y)
+}
+
+pub fn context_key(facts: &Facts) -> String {
+    format!("{}:{}", facts.device, facts.content_plan)
+}
+
+// Действующее предложение пользователя или новый выбор бандита (не сохраняется: dry-run)
+pub async fn preview(
+    pool: &PgPool,
+    config: &Config,
+    user_id: Uuid,
+    content_id: Uuid,
+    facts: &Facts,
+) -> Result<(OfferImpression, bool), sqlx::Error> {
+    let allowed = allowed_arms(pool, user_id).await?;
+    if let Some(active) = db::get_active_offer(pool, user_id).await?
+        && Arm::from_key(&active.arm).is_some_and(|arm| allowed.contains(&arm))
+    {
+        return Ok((active, false));
+    }
+    let table = StatsTable::from_rows(&db::list_offer_arm_stats(pool).await?);
+    let context = context_key(facts);
+    let policy = Policy::from_config(config);
+    let arm = policy.choose(&table.estimates(&context), allowed, &mut rand::thread_rng());
+    let now = Utc::now();
+    let impression = OfferImpression {
+        id: Uuid::new_v4(),
+        user_id,
+        content_id,
+        context,
+        arm: arm.key().to_string(),
+        policy: policy.name().to_string(),
+        shown_at: now,
+        expires_at: now + Duration::hours(config.offer_valid_hours.max(1)),
+        converted_at: None,
+        redeemed: false,
+        subscription_id: None,
+    };
+    Ok((impression, true))
+}
+
+// Предложение для показа: пока предыдущее действует, пользователь видит его же,
+// и новый показ не засчитывается. Ошибки не ломают выдачу: ответ без предложения
+pub async fn present(
+    pool: &PgPool,
+    config: &Config,
+    user_id: Uuid,
+    content_id: Uuid,
+    facts: &Facts,
+) -> Option<OfferImpression> {
+    let result = async {
+        let (impression, new) = preview(pool, config, user_id, content_id, facts).await?;
+        if new {
+            let mut tx = pool.begin().await?;
+            db::create_offer_impression(&mut tx, &impression).await?;
+            db::increment_offer_arm_stats(&mut tx, &impression.context, &impression.arm, 1, 0)
+                .await?;
+            tx.commit().await?;
+        }
+        Ok::<_, sqlx::Error>(impression)
+    }
+    .await;
+    match result {
+        Ok(impression) => Some(impression),
+        Err(e) => {
+            tracing::warn!("Failed to present offer to user {}: {}", user_id, e);
+            None
+        }
+    }
+}
+
+pub fn offer_json(impression: &OfferImpression) -> serde_json::Value {
+    json!({
+        "id": impression.id,
+        "arm": impression.arm,
+        "title": Arm::from_key(&impression.arm).map(Arm::title),
+        "expires_at": impression.expires_at,
+    })
+}
+
+// Предложение, которое пользователь может применить к покупке
+pub async fn redeemable(
+    pool: &PgPool,
+    user_id: Uuid,
+    offer_id: Uuid,
+) -> Result<Option<(OfferImpression, Arm)>, sqlx::Error> {
+    let offer = db::get_offer_impression(pool, offer_id)
+        .await?
+        .filter(|offer| {
+            offer.user_id == user_id
+                && offer.converted_at.is_none()
+                && offer.expires_at > Utc::now()
+        });
+    let Some((offer, arm)) =
+        offer.and_then(|offer| Arm::from_key(&offer.arm).map(|arm| (offer, arm)))
+    else {
+        return Ok(None);
+    };
+    if !allowed_arms(pool, user_id).await?.contains(&arm) {
+        return Ok(None);
+    }
+    Ok(Some((offer, arm)))
+}
+
+// Награда бандиту: покупка на условиях предложения или любая покупка, пока оно действует
+pub async fn record_conversion(
+    pool: &PgPool,
+    user_id: Uuid,
+    redeemed: Option<Uuid>,
+    subscription_id: Uuid,
+) {
+    let result = async {
+        let offer_id = match redeemed {
+            Some(offer_id) => Some(offer_id),
+            None => db::get_active_offer(pool, user_id)
+                .await?
+                .map(|offer| offer.id),
+        };
+        let Some(offer_id) = offer_id else {
+            return Ok(());
+        };
+        let mut tx = pool.begin().await?;
+        if let Some(offer) =
+            db::convert_offer_impression(&mut tx, offer_id, subscription_id, redeemed.is_some())
+                .await?
+        {
+            db::increment_offer_arm_stats(&mut tx, &offer.context, &offer.arm, 0, 1).await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::warn!(
+            "Failed to record offer conversion for user {}: {}",
+            user_id,
+            e
+        );
+    }
+}
+
+#[get("/admin/offers")]
+pub async fn offer_stats(
+    pool: web::Data<sqlx::PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_staff(&pool, &req).await {
+        return Ok(response);
+    }
+    let rows = match db::list_offer_arm_stats(&pool).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            tracing::error!("Database error fetching offer statistics: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    let table = StatsTable::from_rows(&rows);
+    let mut keys: Vec<&String> = table.contexts.keys().collect();
+    keys.sort();
+    let contexts: Vec<_> = keys
+        .into_iter()
+        .map(|context| {
+            let counts = table.contexts[context];
+            let estimates = table.estimates(context);
+            let arms: Vec<_> = ARMS
+                .iter()
+                .map(|arm| {
+                    let (counts, estimate) = (counts[arm.index()], estimates[arm.index()]);
+                    json!({
+                        "arm": arm,
+                        "impressions": counts.impressions,
+                        "conversions": counts.conversions,
+                        "conversion_rate": (counts.impressions > 0)
+                            .then(|| counts.conversions as f64 / counts.impressions as f64),
+                        "estimate": estimate.mean(),
+                    })
+                })
+                .collect();
+            json!({"context": context, "arms": arms})
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(json!({
+        "policy": Policy::from_config(&config),
+        "offer_valid_hours": config.offer_valid_hours,
+        "contexts": contexts,
+    })))
+}
+
+// Контекст симуляции: доля трафика и истинная конверсия каждого варианта
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SimulatedContext {
+    pub context: String,
+    pub weight: f64,
+    pub rates: HashMap<Arm, f64>,
+}
+
+#[derive(Deserialize)]
+pub struct SimulationRequest {
+    // По умолчанию — политика из конфигурации и случайный выбор для сравнения
+    pub policies: Option<Vec<Policy>>,
+    // По умолчанию — наблюдаемые конверсии и доли трафика контекстов
+    pub contexts: Option<Vec<SimulatedContext>>,
+    pub rounds: Option<usize>,
+    pub runs: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+fn observed_contexts(rows: &[OfferArmStats]) -> Vec<SimulatedContext> {
+    let table = StatsTable::from_rows(rows);
+    let mut contexts: Vec<SimulatedContext> = table
+        .contexts
+        .iter()
+        .map(|(context, counts)| {
+            let rates = ARMS
+                .iter()
+                .map(|arm| {
+                    let counts = counts[arm.index()];
+                    let rate =
+                        (counts.conversions as f64 + 1.0) / (counts.impressions as f64 + 2.0);
+                    (*arm, rate)
+                })
+                .collect();
+            SimulatedContext {
+                context: context.clone(),
+                weight: counts.iter().map(|c| c.impressions).sum::<i64>() as f64,
+                rates,
+            }
+        })
+        .filter(|context| context.weight > 0.0)
+        .collect();
+    contexts.sort_by(|a, b| a.context.cmp(&b.context));
+    contexts
+}
+
+// Прогон политики на синтетическом трафике: каждый раунд — показ в случайном контексте,
+// покупка — с истинной вероятностью выбранного варианта. Сожаление (regret) —
+// недополученные ожидаемые покупки относительно лучшего варианта контекста
+fn simulate_policy(
+    policy: &Policy,
+    contexts: &[SimulatedContext],
+    rounds: usize,
+    runs: usize,
+    seed: u64,
+) -> serde_json::Value {
+    let total_weight: f64 = contexts.iter().map(|c| c.weight).sum();
+    let rate =
+        |context: &SimulatedContext, arm: Arm| context.rates.get(&arm).copied().unwrap_or(0.0);
+    let best_rates: Vec<f64> = contexts
+        .iter()
+        .map(|c| ARMS.iter().map(|arm| rate(c, *arm)).fold(0.0, f64::max))
+        .collect();
+
+    let mut conversions = Vec::with_capacity(runs);
+    let mut regrets = Vec::with_capacity(runs);
+    let mut pulls = vec![[0usize; 3]; contexts.len()];
+    let mut late_best = 0usize;
+    let late_start = rounds - rounds / 10;
+    for run in 0..runs {
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(run as u64));
+        let mut table = StatsTable::default();
+        let (mut converted, mut regret) = (0usize, 0.0);
+        for round in 0..rounds {
+            let mut point = rng.gen_range(0.0..total_weight);
+            let index = contexts
+                .iter()
+                .position(|c| {
+                    point -= c.weight;
+                    point < 0.0
+                })
+                .unwrap_or(contexts.len() - 1);
+            let context = &contexts[index];
+            let arm = policy.choose(&table.estimates(&context.context), &ARMS, &mut rng);
+            let p = rate(context, arm);
+            let success = rng.gen_bool(p.clamp(0.0, 1.0));
+            table.record(&context.context, arm, success);
+            converted += usize::from(success);
+            regret += best_rates[index] - p;
+            pulls[index][arm.index()] += 1;
+            if round >= late_start && p >= best_rates[index] {
+                late_best += 1;
+            }
+        }
+        conversions.push(converted as f64);
+        regrets.push(regret);
+    }
+
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+    let std_dev = |values: &[f64]| {
+        let m = mean(values);
+        (values.iter().map(|v| (v - m).powi(2)).sum::<f64>() / values.len() as f64).sqrt()
+    };
+    let arm_share: Vec<_> = contexts
+        .iter()
+        .zip(&pulls)
+        .map(|(context, pulls)| {
+            let total = pulls.iter().sum::<usize>().max(1) as f64;
+            let shares: HashMap<Arm, f64> = ARMS
+                .iter()
+                .map(|arm| (*arm, pulls[arm.index()] as f64 / total))
+                .collect();
+            json!({"context": context.context, "share": shares})
+        })
+        .collect();
+    json!({
+        "policy": policy,
+        "mean_conversions": mean(&conversions),
+        "conversion_rate": mean(&conversions) / rounds as f64,
+        "mean_regret": mean(&regrets),
+        "regret_std_dev": std_dev(&regrets),
+        // Доля показов лучшего варианта в последних 10% раундов
+        "best_arm_rate_late": late_best as f64 / (runs * (rounds - late_start)).max(1) as f64,
+        "arm_share": arm_share,
+    })
+}
+
+#[post("/admin/offers/simulate")]
+pub async fn simulate(
+    pool: web::Data<sqlx::PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    simulation_req: web::Json<SimulationRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_staff(&pool, &req).await {
+        return Ok(response);
+    }
+    let simulation_req = simulation_req.into_inner();
+
+    let contexts = match simulation_req.contexts {
+        Some(contexts) => contexts,
+        None => match db::list_offer_arm_stats(&pool).await {
+            Ok(rows) => observed_contexts(&rows),
+            Err(e) => {
+                tracing::error!("Database error fetching offer statistics: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        },
+    };
+    if contexts.is_empty() {
+        return Ok(HttpResponse::BadRequest()
+            .json(json!({"error": "No observed offer statistics, provide contexts"})));
+    }
+    // Сумма конечных весов тоже может переполниться до inf, и выбор контекста упадёт
+    let valid_contexts = contexts.iter().all(|c| {
+        c.weight.is_finite() && c.weight > 0.0 && c.rates.values().all(|r| (0.0..=1.0).contains(r))
+    }) && contexts.iter().map(|c| c.weight).sum::<f64>().is_finite();
+    if !valid_contexts {
+        return Ok(HttpResponse::BadRequest()
+            .json(json!({"error": "Context weights must be positive with a finite sum and rates within 0..1"})));
+    }
+    let policies = simulation_req
+        .policies
+        .unwrap_or_else(|| vec![Policy::from_config(&config), Policy::Random]);
+    if policies.is_empty()
+        || policies.iter().any(
+            |p| matches!(p, Policy::EpsilonGreedy { epsilon } if !(0.0..=1.0).contains(epsilon)),
+        )
+    {
+        return Ok(HttpResponse::BadRequest()
+            .json(json!({"error": "Provide at least one policy; epsilon must be within 0..1"})));
+    }
+    let rounds = simulation_req.rounds.unwrap_or(DEFAULT_SIMULATION_ROUNDS);
+    let runs = simulation_req.runs.unwrap_or(DEFAULT_SIMULATION_RUNS);
+    if rounds == 0
+        || runs == 0
+        || rounds.saturating_mul(runs).saturating_mul(policies.len()) > MAX_SIMULATION_STEPS
+    {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": format!("rounds × runs × policies must be between 1 and {}", MAX_SIMULATION_STEPS),
+        })));
+    }
+    let seed = simulation_req.seed.unwrap_or_else(rand::random);
+
+    // Расчёт занимает процессор: вне потоков обработки запросов
+    let simulated_contexts = contexts.clone();
+    let results = web::block(move || {
+        policies
+            .iter()
+            .map(|policy| simulate_policy(policy, &simulated_contexts, rounds, runs, seed))
+            .collect::<Vec<_>>()
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "rounds": rounds,
+        "runs": runs,
+        "seed": seed,
+        "contexts": contexts,
+        "results": results,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Currency;
+
+    fn usd(minor: i64) -> Money {
+        Money::new(minor, Currency::Usd)
+    }
+
+    fn stats(context: &str, arm: Arm, impressions: i64, conversions: i64) -> OfferArmStats {
+        OfferArmStats {
+            context: context.to_string(),
+            arm: arm.key().to_string(),
+            impressions,
+            conversions,
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn first_period_terms() {
+        assert_eq!(Arm::Trial7d.first_period(usd(999), 30), Ok((usd(0), 7)));
+        assert_eq!(
+            Arm::Discount30.first_period(usd(999), 30),
+            Ok((usd(699), 30))
+        );
+        assert_eq!(
+            Arm::FirstMonth1.first_period(usd(999), 30),
+            Ok((usd(100), 30))
+        );
+        // Тариф дешевле единицы валюты не дорожает
+        assert_eq!(
+            Arm::FirstMonth1.first_period(usd(50), 30),
+            Ok((usd(50), 30))
+        );
+    }
+
+    #[test]
+    fn arm_keys_round_trip() {
+        for arm in ARMS {
+            assert_eq!(Arm::from_key(arm.key()), Some(arm));
+        }
+        assert_eq!(Arm::from_key("free_forever"), None);
+    }
+
+    #[test]
+    fn best_arm_only_among_allowed() {
+        let scores = [0.9, 0.1, 0.5];
+        assert_eq!(best_arm(scores, &ARMS), Arm::Trial7d);
+        assert_eq!(best_arm(scores, &RETURNING_ARMS), Arm::Discount30);
+        // При равенстве — первый в порядке allowed
+        assert_eq!(
+            best_arm([0.5; 3], &[Arm::FirstMonth1, Arm::Trial7d]),
+            Arm::FirstMonth1
+        );
+    }
+
+    #[test]
+    fn returning_users_never_get_introductory_offers() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let estimates = StatsTable::default().estimates("desktop:premium");
+        for policy in [
+            Policy::Thompson,
+            Policy::EpsilonGreedy { epsilon: 1.0 },
+            Policy::Random,
+        ] {
+            for _ in 0..100 {
+                assert_eq!(
+                    policy.choose(&estimates, &RETURNING_ARMS, &mut rng),
+                    Arm::Discount30
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn greedy_policy_exploits_best_mean() {
+        let table = StatsTable::from_rows(&[
+            stats("mobile:premium", Arm::Trial7d, 100, 5),
+            stats("mobile:premium", Arm::Discount30, 100, 30),
+            stats("mobile:premium", Arm::FirstMonth1, 100, 10),
+        ]);
+        let estimates = table.estimates("mobile:premium");
+        let mut rng = StdRng::seed_from_u64(1);
+        let greedy = Policy::EpsilonGreedy { epsilon: 0.0 };
+        assert_eq!(greedy.choose(&estimates, &ARMS, &mut rng), Arm::Discount30);
+        // Thompson почти всегда выбирает явно лучший вариант
+        let wins = (0..200)
+            .filter(|_| Policy::Thompson.choose(&estimates, &ARMS, &mut rng) == Arm::Discount30)
+            .count();
+        assert!(wins > 180, "{}", wins);
+    }
+
+    #[test]
+    fn other_contexts_shift_prior_with_limited_weight() {
+        let table = StatsTable::from_rows(&[stats("desktop:basic", Arm::Trial7d, 1000, 500)]);
+        let estimate = table.estimates("mobile:premium")[Arm::Trial7d.index()];
+        // Вес чужих наблюдений ограничен PRIOR_WEIGHT
+        assert!((estimate.alpha + estimate.beta - (2.0 + PRIOR_WEIGHT)).abs() < 1e-9);
+        assert!((estimate.mean() - 0.5).abs() < 1e-3);
+        // Без наблюдений — равномерное априорное
+        let empty = table.estimates("mobile:premium")[Arm::Discount30.index()];
+        assert_eq!((empty.alpha, empty.beta), (1.0, 1.0));
+    }
+}
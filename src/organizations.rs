@@ -0,0 +1,924 @@
+// src/organizations.rs
+// Лицензии организаций: поддержка заводит организацию с тарифом, числом мест и
+// сроком лицензии, администраторы организации приглашают участников и
+// регистрируют email-домены. Пользователь с подтверждённым email из домена,
+// подтверждённого поддержкой, присоединяется сам. Участники получают доступ
+// уровня лицензии в get_content, пока лицензия действует.
+use crate::auth;
+use crate::db;
+use crate::mailer::{EmailMessage, Mailer};
+use crate::models::{
+    CreateOrganizationRequest, Organization, OrganizationDomain, OrganizationDomainRequest,
+    OrganizationInvite, OrganizationInviteRequest, OrganizationJoinRequest,
+    OrganizationLicenseRequest, User,
+};
+use crate::paywall;
+use actix_web::{HttpRequest, HttpResponse, delete, get, post, put, web};
+use chrono::{Duration, Utc};
+use moka::future::Cache;
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// Лицензия даёт доступ уровня обычного тарифа; family для организаций не продаётся
+const ORGANIZATION_PLANS: &[&str] = &["basic", "premium"];
+const ORGANIZATION_ROLES: &[&str] = &["member", "admin"];
+const MAX_ORGANIZATION_SEATS: i32 = 100_000;
+const MAX_NAME_LENGTH: usize = 200;
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_DOMAIN_LENGTH: usize = 253;
+const ACTIVE_WINDOW_DAYS: i64 = 30;
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_organizations);
+    cfg.service(join_organization);
+    cfg.service(auto_join_organization);
+    cfg.service(get_organization_report);
+    cfg.service(invite_member);
+    cfg.service(revoke_invite);
+    cfg.service(remove_member);
+    cfg.service(add_domain);
+    cfg.service(create_organization);
+    cfg.service(update_license);
+    cfg.service(verify_domain);
+}
+
+// Администратор организации или сотрудник поддержки; Err — готовый ответ 401/403
+async fn require_organization_admin(
+    pool: &PgPool,
+    req: &HttpRequest,
+    organization_id: Uuid,
+) -> Result<Uuid, HttpResponse> {
+    let Some(user_id) = auth::get_user_id_from_request(req) else {
+        return Err(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"})));
+    };
+    let role = async {
+        let mut conn = pool.acquire().await?;
+        db::get_organization_role(&mut conn, organization_id, user_id).await
+    }
+    .await;
+    match role {
+        Ok(Some(role)) if role == "admin" => Ok(user_id),
+        Ok(_) => auth::require_staff(pool, req).await,
+        Err(e) => {
+            tracing::error!("Database error checking organization role: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+fn email_domain(email: &str) -> Option<String> {
+    email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim().to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+}
+
+// Домен в нижнем регистре без точки в конце; None — не похоже на домен
+pub fn normalize_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    let valid = domain.len() <= MAX_DOMAIN_LENGTH
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    valid.then_some(domain)
+}
+
+fn license_active(organization: &Organization) -> bool {
+    organization.license_expires_at > Utc::now()
+}
+
+// Организации, в которых состоит пользователь
+#[get("/organizations")]
+pub async fn list_organizations(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    match db::list_user_organizations(&pool, user_id).await {
+        Ok(organizations) => Ok(HttpResponse::Ok().json(json!({"organizations": organizations}))),
+        Err(e) => {
+            tracing::error!("Database error fetching organizations: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Отчёт об использовании мест: занятые и свободные места, приглашения,
+// активность участников за последние 30 дней и домены
+#[get("/organizations/{organization_id}")]
+pub async fn get_organization_report(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let organization_id = path.into_inner();
+    if let Err(response) = require_organization_admin(&pool, &req, organization_id).await {
+        return Ok(response);
+    }
+
+    let report = async {
+        let Some(organization) = db::get_organization(&pool, organization_id).await? else {
+            return Ok(None);
+        };
+        let members = db::list_organization_members(&pool, organization_id).await?;
+        let invites = db::list_pending_organization_invites(&pool, organization_id).await?;
+        let domains = db::list_organization_domains(&pool, organization_id).await?;
+        Ok::<_, sqlx::Error>(Some((organization, members, invites, domains)))
+    }
+    .await;
+
+    match report {
+        Ok(Some((organization, members, invites, domains))) => {
+            let purchased = i64::from(organization.seats);
+            let used = members.len() as i64;
+            let pending = invites.len() as i64;
+            let active_since = Utc::now() - Duration::days(ACTIVE_WINDOW_DAYS);
+            let active = members
+                .iter()
+                .filter(|m| m.last_active_at.is_some_and(|t| t > active_since))
+                .count();
+            let never_active = members
+                .iter()
+                .filter(|m| m.last_active_at.is_none())
+                .count();
+            let utilization_percent = if purchased > 0 {
+                used * 100 / purchased
+            } else {
+                0
+            };
+            Ok(HttpResponse::Ok().json(json!({
+                "license_active": license_active(&organization),
+                "organization": organization,
+                "seats": {
+                    "purchased": purchased,
+                    "used": used,
+                    "pending_invites": pending,
+                    "available": (purchased - used - pending).max(0),
+                    "utilization_percent": utilization_percent,
+                    "over_allocated": used + pending > purchased,
+                },
+                "activity": {
+                    "window_days": ACTIVE_WINDOW_DAYS,
+                    "active_members": active,
+                    "never_active_members": never_active,
+                },
+                "members": members,
+                "pending_invites": invites,
+                "domains": domains,
+            })))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "Organization not found"}))),
+        Err(e) => {
+            tracing::error!("Database error building organization report: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/organizations/{organization_id}/invites")]
+pub async fn invite_member(
+    pool: web::Data<sqlx::PgPool>,
+    mailer: web::Data<dyn Mailer>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    invite_req: web::Json<OrganizationInviteRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let organization_id = path.into_inner();
+    let inviter_id = match require_organization_admin(&pool, &req, organization_id).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let email = invite_req.email.trim().to_string();
+    if email.len() > MAX_EMAIL_LENGTH || !email.contains('@') {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid email"})));
+    }
+    if !ORGANIZATION_ROLES.contains(&invite_req.role.as_str()) {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid role"})));
+    }
+
+    match create_invite(&pool, organization_id, inviter_id, &email, &invite_req.role).await {
+        Ok(Ok((invite, organization))) => {
+            // Приглашение действует и без письма: администратор может отправить его повторно
+            let email_sent = match mailer.send(&invite_email(&invite, &organization)).await {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!("Failed to send organization invite {}: {}", invite.id, e);
+                    false
+                }
+            };
+            Ok(HttpResponse::Created().json(json!({
+                "invite": invite,
+                "email_sent": email_sent,
+            })))
+        }
+        Ok(Err(response)) => Ok(response),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            Ok(HttpResponse::Conflict().json(json!({"error": "Member already invited"})))
+        }
+        Err(e) => {
+            tracing::error!("Organization invite error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Приглашение резервирует место, поэтому проверка и вставка идут под блокировкой организации
+async fn create_invite(
+    pool: &PgPool,
+    organization_id: Uuid,
+    inviter_id: Uuid,
+    email: &str,
+    role: &str,
+) -> Result<Result<(OrganizationInvite, Organization), HttpResponse>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let Some(organization) = db::lock_organization(&mut tx, organization_id).await? else {
+        return Ok(Err(
+            HttpResponse::NotFound().json(json!({"error": "Organization not found"}))
+        ));
+    };
+    if !license_active(&organization) {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "Organization license has expired"}))
+        ));
+    }
+    if db::is_organization_member_email(&mut tx, organization_id, email).await? {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "Already a member"}))
+        ));
+    }
+    if db::count_organization_seats_used(&mut tx, organization_id).await?
+        >= i64::from(organization.seats)
+    {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "No seats available"}))
+        ));
+    }
+
+    let invite = OrganizationInvite {
+        id: Uuid::new_v4(),
+        organization_id,
+        email: email.to_string(),
+        role: role.to_string(),
+        token: Uuid::new_v4().simple().to_string(),
+        status: "pending".to_string(),
+        invited_by: inviter_id,
+        created_at: Utc::now(),
+        accepted_at: None,
+    };
+    db::create_organization_invite(&mut tx, &invite).await?;
+    tx.commit().await?;
+    tracing::info!(
+        "User {} invited {} to organization {}",
+        inviter_id,
+        invite.email,
+        organization_id
+    );
+    Ok(Ok((invite, organization)))
+}
+
+fn invite_email(invite: &OrganizationInvite, organization: &Organization) -> EmailMessage {
+    EmailMessage {
+        to: invite.email.clone(),
+        subject: format!("You are invited to join {}", organization.name),
+        text: format!(
+            "Hi,\n\n{} has invited you to use their organization subscription.\n\n\
+             Sign in (or register with this email address) and use this invitation code to join:\n{}\n",
+            organization.name, invite.token
+        ),
+    }
+}
+
+// Отзыв неотвеченного приглашения освобождает место
+#[delete("/organizations/{organization_id}/invites/{invite_id}")]
+pub async fn revoke_invite(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (organization_id, invite_id) = path.into_inner();
+    if let Err(response) = require_organization_admin(&pool, &req, organization_id).await {
+        return Ok(response);
+    }
+
+    match db::revoke_organization_invite(&pool, organization_id, invite_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(json!({"revoked": true, "invite_id": invite_id}))),
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({"error": "Invitation not found"}))),
+        Err(e) => {
+            tracing::error!("Database error revoking organization invite: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Администратор удаляет участника; участник может выйти сам
+#[delete("/organizations/{organization_id}/members/{user_id}")]
+pub async fn remove_member(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (organization_id, member_id) = path.into_inner();
+    let actor_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    if actor_id != member_id
+        && let Err(response) = require_organization_admin(&pool, &req, organization_id).await
+    {
+        return Ok(response);
+    }
+
+    match remove(&pool, organization_id, member_id, actor_id).await {
+        Ok(Ok(())) => {
+            paywall::invalidate_user_cache(&cache, member_id);
+            tracing::info!(
+                "User {} removed from organization {} by {}",
+                member_id,
+                organization_id,
+                actor_id
+            );
+            Ok(HttpResponse::Ok().json(json!({"removed": true, "user_id": member_id})))
+        }
+        Ok(Err(response)) => Ok(response),
+        Err(e) => {
+            tracing::error!("Database error removing organization member: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// У организации всегда остаётся хотя бы один администратор
+async fn remove(
+    pool: &PgPool,
+    organization_id: Uuid,
+    member_id: Uuid,
+    actor_id: Uuid,
+) -> Result<Result<(), HttpResponse>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    if db::lock_organization(&mut tx, organization_id)
+        .await?
+        .is_none()
+    {
+        return Ok(Err(
+            HttpResponse::NotFound().json(json!({"error": "Organization not found"}))
+        ));
+    }
+    let Some(role) = db::get_organization_role(&mut tx, organization_id, member_id).await? else {
+        return Ok(Err(
+            HttpResponse::NotFound().json(json!({"error": "Member not found"}))
+        ));
+    };
+    if role == "admin" && db::count_organization_admins(&mut tx, organization_id).await? <= 1 {
+        return Ok(Err(HttpResponse::Conflict().json(
+            json!({"error": "Organization must keep at least one admin"}),
+        )));
+    }
+
+    db::remove_organization_member(&mut tx, organization_id, member_id).await?;
+    if actor_id != member_id {
+        db::create_notification(
+            &mut tx,
+            member_id,
+            "organization_membership_ended",
+            &json!({"organization_id": organization_id, "reason": "removed"}),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(Ok(()))
+}
+
+// Регистрация домена для автоприсоединения; работает после проверки TXT-записи поддержкой
+#[post("/organizations/{organization_id}/domains")]
+pub async fn add_domain(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    domain_req: web::Json<OrganizationDomainRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let organization_id = path.into_inner();
+    if let Err(response) = require_organization_admin(&pool, &req, organization_id).await {
+        return Ok(response);
+    }
+    let Some(domain) = normalize_domain(&domain_req.domain) else {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid domain"})));
+    };
+
+    match db::get_organization(&pool, organization_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "Organization not found"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error fetching organization: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    }
+
+    let record = OrganizationDomain {
+        domain,
+        organization_id,
+        verification_token: format!("paywall-verification={}", Uuid::new_v4().simple()),
+        verified_at: None,
+        created_at: Utc::now(),
+    };
+    match db::create_organization_domain(&pool, &record).await {
+        Ok(()) => Ok(HttpResponse::Created().json(json!({
+            "domain": record,
+            "dns_record": {
+                "type": "TXT",
+                "name": record.domain,
+                "value": record.verification_token,
+            },
+        }))),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            Ok(HttpResponse::Conflict().json(json!({"error": "Domain already registered"})))
+        }
+        Err(e) => {
+            tracing::error!("Database error registering organization domain: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/organizations/join")]
+pub async fn join_organization(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    join_req: web::Json<OrganizationJoinRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    match join(&pool, user_id, join_req.token.trim()).await {
+        Ok(Ok(organization)) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            tracing::info!("User {} joined organization {}", user_id, organization.id);
+            Ok(HttpResponse::Ok().json(json!({"joined": true, "organization": organization})))
+        }
+        Ok(Err(response)) => Ok(response),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            Ok(HttpResponse::Conflict().json(json!({"error": "Already a member"})))
+        }
+        Err(e) => {
+            tracing::error!("Organization join error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Приглашение принимает только владелец адреса, на который оно отправлено
+async fn join(
+    pool: &PgPool,
+    user_id: Uuid,
+    token: &str,
+) -> Result<Result<Organization, HttpResponse>, sqlx::Error> {
+    let Some(user) = db::get_user_by_id(pool, user_id).await? else {
+        return Ok(Err(
+            HttpResponse::NotFound().json(json!({"error": "User not found"}))
+        ));
+    };
+
+    let mut tx = pool.begin().await?;
+    let invite = db::lock_organization_invite_by_token(&mut tx, token)
+        .await?
+        .filter(|i| i.status == "pending");
+    let Some(invite) = invite else {
+        return Ok(Err(
+            HttpResponse::NotFound().json(json!({"error": "Invitation not found"}))
+        ));
+    };
+    if !invite.email.eq_ignore_ascii_case(&user.email) {
+        return Ok(Err(HttpResponse::Forbidden().json(
+            json!({"error": "Invitation was sent to a different email"}),
+        )));
+    }
+    let Some(organization) = db::lock_organization(&mut tx, invite.organization_id).await? else {
+        return Ok(Err(
+            HttpResponse::NotFound().json(json!({"error": "Organization not found"}))
+        ));
+    };
+    if !license_active(&organization) {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "Organization license has expired"}))
+        ));
+    }
+    // Место уже занято приглашением; перебор возможен, только если мест стало меньше
+    if db::count_organization_seats_used(&mut tx, organization.id).await?
+        > i64::from(organization.seats)
+    {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "No seats available"}))
+        ));
+    }
+
+    db::add_organization_member(&mut tx, organization.id, user_id, &invite.role, "invite").await?;
+    db::accept_organization_invite(&mut tx, invite.id).await?;
+    db::create_notification(
+        &mut tx,
+        invite.invited_by,
+        "organization_member_joined",
+        &json!({"organization_id": organization.id, "email": invite.email}),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(Ok(organization))
+}
+
+// Присоединение по подтверждённому email к организации, владеющей его доменом
+#[post("/organizations/auto-join")]
+pub async fn auto_join_organization(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+    let user = match db::get_user_by_id(&pool, user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(HttpResponse::NotFound().json(json!({"error": "User not found"}))),
+        Err(e) => {
+            tracing::error!("Database error fetching user: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    match join_by_domain(&pool, &user).await {
+        Ok(Ok(organization)) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            Ok(HttpResponse::Ok().json(json!({"joined": true, "organization": organization})))
+        }
+        Ok(Err(response)) => Ok(response),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            Ok(HttpResponse::Conflict().json(json!({"error": "Already a member"})))
+        }
+        Err(e) => {
+            tracing::error!("Organization auto-join error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Автоприсоединение: email пользователя подтверждён, домен подтверждён организацией,
+// лицензия действует и есть свободное место. Также вызывается после подтверждения email
+pub async fn join_by_domain(
+    pool: &PgPool,
+    user: &User,
+) -> Result<Result<Organization, HttpResponse>, sqlx::Error> {
+    if user.email_verified_at.is_none() {
+        return Ok(Err(
+            HttpResponse::Forbidden().json(json!({"error": "Email address is not verified"}))
+        ));
+    }
+    let organization_id = match email_domain(&user.email) {
+        Some(domain) => db::get_verified_domain_organization(pool, &domain).await?,
+        None => None,
+    };
+    let Some(organization_id) = organization_id else {
+        return Ok(Err(HttpResponse::NotFound()
+            .json(json!({"error": "No organization for your email domain"}))));
+    };
+
+    let mut tx = pool.begin().await?;
+    let Some(organization) = db::lock_organization(&mut tx, organization_id).await? else {
+        return Ok(Err(HttpResponse::NotFound()
+            .json(json!({"error": "No organization for your email domain"}))));
+    };
+    if !license_active(&organization) {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "Organization license has expired"}))
+        ));
+    }
+    if db::get_organization_role(&mut tx, organization.id, user.id)
+        .await?
+        .is_some()
+    {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "Already a member"}))
+        ));
+    }
+
+    // Приглашение на этот адрес уже держит место — оно принимается вместе с присоединением
+    let invited_roles =
+        db::accept_pending_organization_invites_for_email(&mut tx, organization.id, &user.email)
+            .await?;
+    if db::count_organization_seats_used(&mut tx, organization.id).await?
+        >= i64::from(organization.seats)
+    {
+        return Ok(Err(
+            HttpResponse::Conflict().json(json!({"error": "No seats available"}))
+        ));
+    }
+    let role = if invited_roles.iter().any(|r| r == "admin") {
+        "admin"
+    } else {
+        "member"
+    };
+    db::add_organization_member(&mut tx, organization.id, user.id, role, "domain").await?;
+    tx.commit().await?;
+    tracing::info!(
+        "User {} joined organization {} by email domain",
+        user.id,
+        organization.id
+    );
+    Ok(Ok(organization))
+}
+
+fn validate_plan_and_seats(plan_id: Option<&str>, seats: Option<i32>) -> Result<(), HttpResponse> {
+    if plan_id.is_some_and(|p| !ORGANIZATION_PLANS.contains(&p)) {
+        return Err(HttpResponse::BadRequest().json(json!({"error": "Invalid plan"})));
+    }
+    if seats.is_some_and(|s| !(1..=MAX_ORGANIZATION_SEATS).contains(&s)) {
+        return Err(HttpResponse::BadRequest().json(json!({"error": "Invalid seats"})));
+    }
+    Ok(())
+}
+
+// Поддержка заводит организацию после оплаты лицензии; первый администратор занимает место
+#[post("/admin/organizations")]
+pub async fn create_organization(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    create_req: web::Json<CreateOrganizationRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let staff_id = match auth::require_staff(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let name = create_req.name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid organization name"})));
+    }
+    if let Err(response) =
+        validate_plan_and_seats(Some(&create_req.plan_id), Some(create_req.seats))
+    {
+        return Ok(response);
+    }
+    if create_req.license_expires_at <= Utc::now() {
+        return Ok(HttpResponse::BadRequest()
+            .json(json!({"error": "License expiry must be in the future"})));
+    }
+
+    match db::get_user_by_id(&pool, create_req.admin_user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(HttpResponse::NotFound().json(json!({"error": "User not found"}))),
+        Err(e) => {
+            tracing::error!("Database error fetching user: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    }
+
+    let organization = Organization {
+        id: Uuid::new_v4(),
+        name: name.to_string(),
+        plan_id: create_req.plan_id.clone(),
+        seats: create_req.seats,
+        license_expires_at: create_req.license_expires_at,
+        created_at: Utc::now(),
+    };
+    let result = async {
+        let mut tx = pool.begin().await?;
+        db::create_organization(&mut tx, &organization).await?;
+        db::add_organization_member(
+            &mut tx,
+            organization.id,
+            create_req.admin_user_id,
+            "admin",
+            "staff",
+        )
+        .await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::error!("Database error creating organization: {}", e);
+        return Ok(
+            HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+        );
+    }
+    paywall::invalidate_user_cache(&cache, create_req.admin_user_id);
+
+    let details = json!({
+        "organization_id": organization.id,
+        "plan_id": organization.plan_id,
+        "seats": organization.seats,
+        "license_expires_at": organization.license_expires_at,
+    });
+    if let Err(e) = db::record_admin_action(
+        &pool,
+        staff_id,
+        "organization_created",
+        Some(create_req.admin_user_id),
+        None,
+        &details,
+    )
+    .await
+    {
+        tracing::error!("Failed to record admin action: {}", e);
+    }
+    Ok(HttpResponse::Created().json(json!({"organization": organization})))
+}
+
+// Продление, изменение числа мест или уровня лицензии. Уменьшение мест ниже
+// занятых не удаляет участников: новые присоединения блокируются до освобождения мест
+#[put("/admin/organizations/{organization_id}/license")]
+pub async fn update_license(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    license_req: web::Json<OrganizationLicenseRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let staff_id = match auth::require_staff(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let organization_id = path.into_inner();
+    if let Err(response) =
+        validate_plan_and_seats(license_req.plan_id.as_deref(), license_req.seats)
+    {
+        return Ok(response);
+    }
+
+    let organization = match db::update_organization_license(
+        &pool,
+        organization_id,
+        license_req.plan_id.as_deref(),
+        license_req.seats,
+        license_req.license_expires_at,
+    )
+    .await
+    {
+        Ok(Some(organization)) => organization,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "Organization not found"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error updating organization license: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    // Уровень или срок доступа всех участников изменился
+    match db::list_organization_members(&pool, organization_id).await {
+        Ok(members) => {
+            for member in &members {
+                paywall::invalidate_user_cache(&cache, member.user_id);
+            }
+        }
+        Err(e) => tracing::error!(
+            "Failed to list members of organization {} for cache invalidation: {}",
+            organization_id,
+            e
+        ),
+    }
+
+    let details = json!({
+        "organization_id": organization.id,
+        "plan_id": organization.plan_id,
+        "seats": organization.seats,
+        "license_expires_at": organization.license_expires_at,
+    });
+    if let Err(e) = db::record_admin_action(
+        &pool,
+        staff_id,
+        "organization_license_updated",
+        None,
+        None,
+        &details,
+    )
+    .await
+    {
+        tracing::error!("Failed to record admin action: {}", e);
+    }
+    Ok(HttpResponse::Ok().json(json!({"organization": organization})))
+}
+
+// Поддержка подтверждает домен после проверки TXT-записи с verification_token
+#[post("/admin/organizations/{organization_id}/domains/{domain}/verify")]
+pub async fn verify_domain(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    path: web::Path<(Uuid, String)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let staff_id = match auth::require_staff(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let (organization_id, domain) = path.into_inner();
+    let Some(domain) = normalize_domain(&domain) else {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid domain"})));
+    };
+
+    match db::verify_organization_domain(&pool, organization_id, &domain).await {
+        Ok(true) => {
+            if let Err(e) = db::record_admin_action(
+                &pool,
+                staff_id,
+                "organization_domain_verified",
+                None,
+                None,
+                &json!({"organization_id": organization_id, "domain": domain}),
+            )
+            .await
+            {
+                tracing::error!("Failed to record admin action: {}", e);
+            }
+            Ok(HttpResponse::Ok().json(json!({"domain": domain, "verified": true})))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({"error": "Domain not found"}))),
+        Err(e) => {
+            tracing::error!("Database error verifying organization domain: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn organization(license_expires_at: chrono::DateTime<Utc>) -> Organization {
+        Organization {
+            id: Uuid::new_v4(),
+            name: "Acme".to_string(),
+            plan_id: "premium".to_string(),
+            seats: 10,
+            license_expires_at,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn domains_are_normalized() {
+        assert_eq!(
+            normalize_domain(" Acme.COM. "),
+            Some("acme.com".to_string())
+        );
+        assert_eq!(
+            normalize_domain("mail.acme-corp.co.uk"),
+            Some("mail.acme-corp.co.uk".to_string())
+        );
+        assert_eq!(normalize_domain("localhost"), None);
+        assert_eq!(normalize_domain("-acme.com"), None);
+        assert_eq!(normalize_domain("acme..com"), None);
+        assert_eq!(normalize_domain("acme_corp.com"), None);
+        assert_eq!(normalize_domain(&format!("{}.com", "a".repeat(64))), None);
+    }
+
+    #[test]
+    fn email_domain_uses_last_at_sign() {
+        assert_eq!(email_domain("Jane@Acme.com"), Some("acme.com".to_string()));
+        assert_eq!(
+            email_domain("\"a@b\"@acme.com"),
+            Some("acme.com".to_string())
+        );
+        assert_eq!(email_domain("jane@"), None);
+        assert_eq!(email_domain("jane"), None);
+    }
+
+    #[test]
+    fn plan_and_seats_are_limited() {
+        assert!(validate_plan_and_seats(Some("premium"), Some(1)).is_ok());
+        assert!(validate_plan_and_seats(None, None).is_ok());
+        // Семейный тариф организациям не продаётся
+        assert!(validate_plan_and_seats(Some("family"), None).is_err());
+        assert!(validate_plan_and_seats(None, Some(0)).is_err());
+        assert!(validate_plan_and_seats(None, Some(MAX_ORGANIZATION_SEATS + 1)).is_err());
+    }
+
+    #[test]
+    fn license_expiry() {
+        assert!(license_active(&organization(
+            Utc::now() + Duration::days(1)
+        )));
+        assert!(!license_active(&organization(
+            Utc::now() - Duration::seconds(1)
+        )));
+    }
+}
@@ -0,0 +1,1218 @@
+// src/payment.rs
+// Интеграция с платёжным провайдером: общий трейт, HTTP-адаптер для Stripe-совместимого
+// API и фейковый шлюз для локальной разработки и тестов.
+use crate::config::Config;
+use crate::db;
+use crate::invoice;
+use crate::models::{Payment, PendingRefund};
+use crate::money::{Currency, Money, MoneyError};
+use crate::tax;
+use actix_web::HttpResponse;
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgConnection, PgPool};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+// Неприменённый платёж возвращает обработчик вебхука; воркер подбирает оставшиеся
+const PENDING_REFUND_RETRY_AFTER_MINS: i64 = 5;
+// Дальше возврат ждёт разбора поддержкой (ошибка — в pending_refunds.last_error)
+const MAX_PENDING_REFUND_ATTEMPTS: i32 = 10;
+const MAX_PENDING_REFUNDS_PER_CYCLE: i64 = 50;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChargeStatus {
+    Succeeded,
+    Pending,
+    Failed,
+    Refunded,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Charge {
+    pub id: String, // Идентификатор платежа у провайдера
+    pub status: ChargeStatus,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Refund {
+    pub id: String,
+    pub charge_id: String,
+    pub amount: Money,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChargeRequest {
+    pub customer_id: String,
+    pub payment_method_id: String,
+    pub amount: Money,
+    pub description: String,
+    pub idempotency_key: String, // Повтор с тем же ключом не приводит к двойному списанию
+    pub payment_id: Uuid,        // В metadata: вебхук найдёт платёж, если ответ на запрос потерян
+}
+
+#[derive(Debug)]
+pub enum PaymentError {
+    Declined(String),
+    NoPaymentMethod,
+    Network(String),
+    Provider { status: u16, message: String },
+    InvalidResponse(String),
+    InvalidAmount(MoneyError),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentError::Declined(reason) => write!(f, "payment declined: {}", reason),
+            PaymentError::NoPaymentMethod => write!(f, "no payment method on file"),
+            PaymentError::Network(e) => write!(f, "payment provider unreachable: {}", e),
+            PaymentError::Provider { status, message } => {
+                write!(f, "payment provider error ({}): {}", status, message)
+            }
+            PaymentError::InvalidResponse(e) => write!(f, "invalid provider response: {}", e),
+            PaymentError::InvalidAmount(e) => write!(f, "invalid amount: {}", e),
+            PaymentError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PaymentError {}
+
+impl PaymentError {
+    // Провайдер мог провести списание, но ответ не получен или не разобран
+    pub fn is_outcome_unknown(&self) -> bool {
+        match self {
+            PaymentError::Network(_) | PaymentError::InvalidResponse(_) => true,
+            PaymentError::Provider { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+impl From<sqlx::Error> for PaymentError {
+    fn from(e: sqlx::Error) -> Self {
+        PaymentError::Database(e)
+    }
+}
+
+impl From<MoneyError> for PaymentError {
+    fn from(e: MoneyError) -> Self {
+        PaymentError::InvalidAmount(e)
+    }
+}
+
+#[async_trait]
+pub trait PaymentGateway: Send + Sync {
+    async fn create_customer(&self, user_id: Uuid, email: &str) -> Result<String, PaymentError>;
+    // Привязка одноразового токена к клиенту; возвращает многоразовый идентификатор метода
+    async fn attach_payment_method(
+        &self,
+        customer_id: &str,
+        payment_token: &str,
+    ) -> Result<String, PaymentError>;
+    async fn charge(&self, request: &ChargeRequest) -> Result<Charge, PaymentError>;
+    // amount = None — полный возврат. Повтор с тем же ключом возвращает уже созданный возврат
+    async fn refund(
+        &self,
+        charge_id: &str,
+        amount: Option<Money>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Refund, PaymentError>;
+    async fn fetch_status(&self, charge_id: &str) -> Result<ChargeStatus, PaymentError>;
+}
+
+// Выбор шлюза по PAYMENT_GATEWAY (stripe | fake)
+pub fn gateway_from_config(config: &Config) -> Arc<dyn PaymentGateway> {
+    match config.payment_gateway.as_str() {
+        "stripe" => Arc::new(StripeGateway::new(
+            &config.payment_api_url,
+            &config.payment_api_key,
+        )),
+        other => {
+            if other != "fake" {
+                tracing::warn!("Unknown payment gateway '{}', using fake gateway", other);
+            }
+            tracing::warn!("Using fake payment gateway, no real charges will be made");
+            Arc::new(FakeGateway::new())
+        }
+    }
+}
+
+// HTTP-ответ для ошибки списания в обработчиках
+pub fn error_response(e: &PaymentError) -> HttpResponse {
+    match e {
+        PaymentError::Declined(_) => {
+            HttpResponse::PaymentRequired().json(json!({"error": "Payment failed"}))
+        }
+        PaymentError::NoPaymentMethod => {
+            HttpResponse::BadRequest().json(json!({"error": "Payment token required"}))
+        }
+        _ => {
+            tracing::error!("Payment processing error: {}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "Payment processing error"}))
+        }
+    }
+}
+
+// Компенсация списания, после которого не удалось выдать доступ
+pub async fn refund_quietly(
+    pool: &PgPool,
+    gateway: &dyn PaymentGateway,
+    payment_id: Uuid,
+    charge_id: &str,
+) {
+    match refund_payment(pool, gateway, payment_id, charge_id, None).await {
+        Ok(refund) => tracing::info!("Refunded charge {} ({})", charge_id, refund.id),
+        Err(e) => tracing::error!("Failed to refund charge {}: {}", charge_id, e),
+    }
+}
+
+// Возврат у провайдера с отражением в платеже и журнале; amount = None — полный возврат
+pub async fn refund_payment(
+    pool: &PgPool,
+    gateway: &dyn PaymentGateway,
+    payment_id: Uuid,
+    charge_id: &str,
+    amount: Option<Money>,
+) -> Result<Refund, PaymentError> {
+    let refund = gateway.refund(charge_id, amount, None).await?;
+    let mut tx = pool.begin().await?;
+    if let Some(payment) = db::lock_payment(&mut tx, payment_id).await? {
+        let refundable = payment.amount()?.checked_sub(payment.refunded()?)?;
+        record_refund(
+            &mut tx,
+            &payment,
+            refund.amount.min(refundable)?,
+            &refund.id,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(refund)
+}
+
+// Ключ возврата неприменённого платежа: один на списание, повтор не вернёт деньги дважды
+fn unapplied_refund_key(charge_id: &str) -> String {
+    format!("refund:{}", charge_id)
+}
+
+// Возврат, записанный обработчиком вебхука: выполняется у провайдера вне его
+// транзакции, затем отражается в платеже и журнале
+pub async fn complete_pending_refund(
+    pool: &PgPool,
+    gateway: &dyn PaymentGateway,
+    pending: &PendingRefund,
+) -> Result<(), PaymentError> {
+    let key = unapplied_refund_key(&pending.charge_id);
+    let refund = match gateway.refund(&pending.charge_id, None, Some(&key)).await {
+        Ok(refund) => refund,
+        Err(e) => {
+            db::record_pending_refund_error(pool, pending.payment_id, &e.to_string()).await?;
+            return Err(e);
+        }
+    };
+
+    let mut tx = pool.begin().await?;
+    if !db::lock_open_pending_refund(&mut tx, pending.payment_id).await? {
+        return Ok(());
+    }
+    if let Some(payment) = db::lock_payment(&mut tx, pending.payment_id).await? {
+        // Вебхук charge.refunded мог уже отразить этот возврат
+        let refundable = payment.amount()?.checked_sub(payment.refunded()?)?;
+        let amount = refund.amount.min(refundable)?;
+        record_refund(&mut tx, &payment, amount, &refund.id).await?;
+        db::create_notification(
+            &mut tx,
+            payment.user_id,
+            "payment_refunded",
+            &json!({"payment_id": payment.id, "amount": amount, "reason": pending.reason}),
+        )
+        .await?;
+    }
+    db::complete_pending_refund(&mut tx, pending.payment_id).await?;
+    tx.commit().await?;
+    tracing::warn!(
+        "Payment {} refunded as unapplied: {}",
+        pending.payment_id,
+        pending.reason
+    );
+    Ok(())
+}
+
+// Повтор возвратов, которые не удалось выполнить сразу после обработки вебхука
+pub async fn retry_pending_refunds(
+    pool: &PgPool,
+    gateway: &dyn PaymentGateway,
+) -> Result<(), sqlx::Error> {
+    let pending = db::list_stale_pending_refunds(
+        pool,
+        PENDING_REFUND_RETRY_AFTER_MINS,
+        MAX_PENDING_REFUND_ATTEMPTS,
+        MAX_PENDING_REFUNDS_PER_CYCLE,
+    )
+    .await?;
+    for refund in &pending {
+        match complete_pending_refund(pool, gateway, refund).await {
+            Ok(()) => {}
+            Err(PaymentError::Database(e)) => return Err(e),
+            Err(e) => tracing::error!(
+                "Failed to refund unapplied payment {}: {}",
+                refund.payment_id,
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+// Отражение возврата (своего или пришедшего вебхуком) по заблокированному платежу
+pub async fn record_refund(
+    conn: &mut PgConnection,
+    payment: &Payment,
+    amount: Money,
+    refund_reference: &str,
+) -> Result<(), PaymentError> {
+    // Заодно проверяет, что возврат в валюте платежа
+    let refunded = payment.refunded()?.checked_add(amount)?;
+    if !amount.is_positive() {
+        return Ok(());
+    }
+    db::add_payment_refund(conn, payment.id, amount.amount_minor()).await?;
+    let status = if refunded.amount_minor() >= payment.amount_minor {
+        "refunded"
+    } else {
+        "partially_refunded"
+    };
+    db::transition_payment(conn, payment.id, status, None, None).await?;
+    db::append_ledger_entry(
+        conn,
+        payment.user_id,
+        Some(payment.id),
+        "refund",
+        amount.checked_neg()?,
+        &format!("Refund {}", refund_reference),
+    )
+    .await?;
+    Ok(())
+}
+
+// Неденежный кредит (зачёт остатка тарифа при повышении)
+pub async fn record_credit(
+    pool: &PgPool,
+    user_id: Uuid,
+    payment_id: Option<Uuid>,
+    amount: Money,
+    description: &str,
+) -> Result<(), PaymentError> {
+    if !amount.is_positive() {
+        return Ok(());
+    }
+    let mut conn = pool.acquire().await?;
+    db::append_ledger_entry(
+        &mut conn,
+        user_id,
+        payment_id,
+        "credit",
+        amount.checked_neg()?,
+        description,
+    )
+    .await?;
+    Ok(())
+}
+
+fn status_name(status: &ChargeStatus) -> &'static str {
+    match status {
+        ChargeStatus::Succeeded => "succeeded",
+        ChargeStatus::Pending => "pending",
+        ChargeStatus::Failed => "failed",
+        ChargeStatus::Refunded => "refunded",
+    }
+}
+
+// Параметры списания с пользователя
+pub struct UserCharge<'a> {
+    pub user_id: Uuid,
+    pub subscription_id: Option<Uuid>,
+    pub kind: &'a str, // purchase | renewal | upgrade
+    pub new_token: Option<&'a str>,
+    pub amount: Money, // Цена до налоговой разбивки (см. TAX_PRICING_MODE)
+    pub description: String,
+    pub geo_country: Option<&'a str>, // Страна по IP, если в реквизитах её нет
+    // Ключ идемпотентности у провайдера; None — новый платёж с ключом по его идентификатору
+    pub idempotency_key: Option<String>,
+}
+
+// Списание с пользователя: при необходимости создаёт клиента у провайдера и
+// привязывает новый токен, иначе использует сохранённый платёжный метод.
+// Каждая попытка записывается в payments вместе с налоговой разбивкой.
+// Повтор с тем же ключом идемпотентности продолжает ранее созданный платёж.
+pub async fn charge_user(
+    pool: &PgPool,
+    gateway: &dyn PaymentGateway,
+    config: &Config,
+    request: &UserCharge<'_>,
+) -> Result<(Payment, Charge), PaymentError> {
+    let user_id = request.user_id;
+    let existing = match &request.idempotency_key {
+        Some(key) => db::get_payment_by_idempotency_key(pool, key).await?,
+        None => None,
+    };
+    // Исход уже известен: к провайдеру не обращаемся
+    if let Some(payment) = &existing
+        && payment.status != "pending"
+    {
+        let charge = recorded_charge(payment)?;
+        tracing::info!(
+            "Replaying payment {} for user {} ({:?})",
+            payment.id,
+            user_id,
+            charge.status
+        );
+        return Ok((payment.clone(), charge));
+    }
+
+    let (payment, result, payment_method_id) = match existing {
+        // Провайдер уже принял списание (ответ pending): узнаём итог, а не отправляем повторно
+        Some(payment) if payment.provider_reference.is_some() => {
+            let charge_id = payment.provider_reference.clone().unwrap_or_default();
+            let status = gateway.fetch_status(&charge_id).await?;
+            let charge = Charge {
+                id: charge_id,
+                status,
+            };
+            (payment, Ok(charge), None)
+        }
+        existing => {
+            let customer_id = ensure_customer(pool, gateway, user_id).await?;
+            let payment_method_id = match request.new_token {
+                Some(token) => gateway.attach_payment_method(&customer_id, token).await?,
+                None => {
+                    let mut conn = pool.acquire().await?;
+                    db::get_default_payment_method(&mut conn, user_id)
+                        .await?
+                        .ok_or(PaymentError::NoPaymentMethod)?
+                }
+            };
+            // Платёж без ответа провайдера отправляется повторно с тем же ключом
+            let payment = match existing {
+                Some(payment) => payment,
+                None => create_pending_payment(pool, config, request).await?,
+            };
+            let result = gateway
+                .charge(&ChargeRequest {
+                    customer_id,
+                    payment_method_id: payment_method_id.clone(),
+                    amount: payment.amount()?,
+                    description: payment.description.clone(),
+                    idempotency_key: payment.idempotency_key.clone(),
+                    payment_id: payment.id,
+                })
+                .await;
+            (payment, result, Some(payment_method_id))
+        }
+    };
+    let amount = payment.amount()?;
+
+    // Сбой записи не отменяет уже прошедшее списание: платёж останется pending
+    // и попадёт в finance_payment_discrepancies
+    if let Err(e) = record_charge_result(pool, &payment, &result).await {
+        tracing::error!("Failed to record result of payment {}: {}", payment.id, e);
+    }
+    let charge = match result {
+        Ok(charge) => charge,
+        Err(e) => {
+            tracing::warn!(
+                "Charge of {} for user {} failed (payment {}): {}",
+                amount,
+                user_id,
+                payment.id,
+                e
+            );
+            return Err(e);
+        }
+    };
+
+    // Счёт выставляется отдельно: его сбой не влияет на учёт платежа
+    if charge.status == ChargeStatus::Succeeded
+        && let Err(e) = issue_invoice(pool, config, &payment).await
+    {
+        tracing::error!("Failed to issue invoice for payment {}: {}", payment.id, e);
+    }
+
+    // Новый метод становится методом по умолчанию (для автопродления) только после успешной оплаты
+    if request.new_token.is_some()
+        && charge.status == ChargeStatus::Succeeded
+        && let Some(payment_method_id) = &payment_method_id
+    {
+        db::save_payment_method(pool, user_id, payment_method_id).await?;
+    }
+
+    match charge.status {
+        ChargeStatus::Succeeded | ChargeStatus::Pending => tracing::info!(
+            "Charged user {}: {} incl. tax {} ({:?}, charge {}, payment {})",
+            user_id,
+            amount,
+            Money::new(payment.tax_minor, amount.currency()),
+            charge.status,
+            charge.id,
+            payment.id
+        ),
+        _ => tracing::warn!(
+            "Charge of {} for user {} not completed: provider returned {:?} (charge {}, payment {})",
+            amount,
+            user_id,
+            charge.status,
+            charge.id,
+            payment.id
+        ),
+    }
+    Ok((payment, charge))
+}
+
+// Новый платёж в статусе pending с налоговой разбивкой
+async fn create_pending_payment(
+    pool: &PgPool,
+    config: &Config,
+    request: &UserCharge<'_>,
+) -> Result<Payment, PaymentError> {
+    let user_id = request.user_id;
+    let tax = tax::calculate(
+        &mut *pool.acquire().await?,
+        config,
+        user_id,
+        request.amount,
+        request.geo_country,
+    )
+    .await?;
+
+    let now = Utc::now();
+    let id = Uuid::new_v4();
+    let payment = Payment {
+        id,
+        user_id,
+        subscription_id: request.subscription_id,
+        kind: request.kind.to_string(),
+        amount_minor: tax.total.amount_minor(),
+        refunded_minor: 0,
+        currency: tax.total.currency().code().to_string(),
+        status: "pending".to_string(),
+        provider_reference: None,
+        failure_reason: None,
+        description: request.description.clone(),
+        created_at: now,
+        updated_at: now,
+        net_minor: tax.net.amount_minor(),
+        tax_minor: tax.tax.amount_minor(),
+        tax_rate_bps: tax.rate_bps,
+        tax_country: tax.country.clone(),
+        tax_treatment: tax.treatment.as_str().to_string(),
+        tax_rate_version: tax.rate_version.clone(),
+        // Без ключа от вызывающего — идентификатор платежа: повтор запроса к провайдеру не спишет дважды
+        idempotency_key: request
+            .idempotency_key
+            .clone()
+            .unwrap_or_else(|| id.to_string()),
+    };
+    db::create_payment(&mut *pool.acquire().await?, &payment).await?;
+    Ok(payment)
+}
+
+// Результат уже завершённого платежа в виде ответа провайдера
+fn recorded_charge(payment: &Payment) -> Result<Charge, PaymentError> {
+    let status = match payment.status.as_str() {
+        "succeeded" => ChargeStatus::Succeeded,
+        "refunded" | "partially_refunded" => ChargeStatus::Refunded,
+        "pending" => ChargeStatus::Pending,
+        "failed" => {
+            return Err(PaymentError::Declined(
+                payment
+                    .failure_reason
+                    .clone()
+                    .unwrap_or_else(|| "payment failed".to_string()),
+            ));
+        }
+        // Списание прошло, но оспорено держателем карты: повтор не должен выдать доступ
+        "disputed" => {
+            return Err(PaymentError::Declined(
+                "payment is disputed by the cardholder".to_string(),
+            ));
+        }
+        other => {
+            return Err(PaymentError::Declined(format!(
+                "payment is in unexpected status {}",
+                other
+            )));
+        }
+    };
+    Ok(Charge {
+        id: payment.provider_reference.clone().unwrap_or_default(),
+        status,
+    })
+}
+
+// Привязка карты без списания (пробный период): первое списание сделает автопродление
+pub async fn attach_card(
+    pool: &PgPool,
+    gateway: &dyn PaymentGateway,
+    user_id: Uuid,
+    token: &str,
+) -> Result<(), PaymentError> {
+    let customer_id = ensure_customer(pool, gateway, user_id).await?;
+    let payment_method_id = gateway.attach_payment_method(&customer_id, token).await?;
+    db::save_payment_method(pool, user_id, &payment_method_id).await?;
+    Ok(())
+}
+
+async fn record_charge_result(
+    pool: &PgPool,
+    payment: &Payment,
+    result: &Result<Charge, PaymentError>,
+) -> Result<(), PaymentError> {
+    let mut tx = pool.begin().await?;
+    // Платёж мог завершить вебхук, пока шёл запрос: проводка не повторяется
+    let Some(current) = db::lock_payment(&mut tx, payment.id).await? else {
+        return Err(PaymentError::Database(sqlx::Error::RowNotFound));
+    };
+    if current.status != "pending" {
+        return Ok(());
+    }
+    match result {
+        Ok(charge) => {
+            db::transition_payment(
+                &mut tx,
+                payment.id,
+                status_name(&charge.status),
+                Some(&charge.id),
+                None,
+            )
+            .await?;
+            if charge.status == ChargeStatus::Succeeded {
+                record_settled_charge(&mut tx, payment).await?;
+            }
+        }
+        // Исход неизвестен: платёж остаётся pending до повтора с тем же ключом или вебхука
+        Err(e) if e.is_outcome_unknown() => {
+            tracing::warn!("Outcome of payment {} is unknown: {}", payment.id, e);
+        }
+        Err(e) => {
+            db::transition_payment(&mut tx, payment.id, "failed", None, Some(&e.to_string()))
+                .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn issue_invoice(
+    pool: &PgPool,
+    config: &Config,
+    payment: &Payment,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    invoice::issue_invoice(&mut tx, config, payment).await?;
+    tx.commit().await
+}
+
+// Проводка по успешно списанному платежу
+pub async fn record_settled_charge(
+    conn: &mut PgConnection,
+    payment: &Payment,
+) -> Result<(), PaymentError> {
+    db::append_ledger_entry(
+        conn,
+        payment.user_id,
+        Some(payment.id),
+        "charge",
+        payment.amount()?,
+        &payment.description,
+    )
+    .await?;
+    Ok(())
+}
+
+async fn ensure_customer(
+    pool: &PgPool,
+    gateway: &dyn PaymentGateway,
+    user_id: Uuid,
+) -> Result<String, PaymentError> {
+    if let Some(customer_id) = db::get_payment_customer_id(pool, user_id).await? {
+        return Ok(customer_id);
+    }
+    let user = db::get_user_by_id(pool, user_id)
+        .await?
+        .ok_or(PaymentError::Database(sqlx::Error::RowNotFound))?;
+    let customer_id = gateway.create_customer(user_id, &user.email).await?;
+    db::set_payment_customer_id(pool, user_id, &customer_id).await?;
+    Ok(customer_id)
+}
+
+// Адаптер для Stripe-совместимого API (form-encoded запросы, Bearer-авторизация)
+const PROVIDER_TIMEOUT_SECS: u64 = 15;
+
+pub struct StripeGateway {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct StripeObject {
+    id: String,
+    status: Option<String>,
+    amount: Option<i64>,
+    currency: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StripeErrorBody {
+    error: StripeErrorDetail,
+}
+
+#[derive(Deserialize)]
+struct StripeErrorDetail {
+    message: Option<String>,
+    code: Option<String>,
+}
+
+impl StripeGateway {
+    pub fn new(base_url: &str, api_key: &str) -> Self {
+        StripeGateway {
+            // Таймаут заметно меньше блокировки Idempotency-Key (60 с): зависший запрос
+            // не переживёт блокировку, и повтор клиента не начнётся параллельно
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(PROVIDER_TIMEOUT_SECS))
+                .build()
+                .expect("Failed to build payment HTTP client"),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+        }
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<StripeObject, PaymentError> {
+        let response = request
+            .bearer_auth(&self.api_key)
+            .send()
+            .await
+            .map_err(|e| PaymentError::Network(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return response
+                .json::<StripeObject>()
+                .await
+                .map_err(|e| PaymentError::InvalidResponse(e.to_string()));
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        let detail = serde_json::from_str::<StripeErrorBody>(&body)
+            .ok()
+            .map(|b| b.error);
+        let message = detail
+            .as_ref()
+            .and_then(|d| d.message.clone())
+            .unwrap_or_else(|| body.clone());
+        // 402 — карта отклонена (card_declined, insufficient_funds и т.п.)
+        if status.as_u16() == 402 {
+            let code = detail.and_then(|d| d.code).unwrap_or_default();
+            return Err(PaymentError::Declined(
+                format!("{} {}", code, message).trim().to_string(),
+            ));
+        }
+        Err(PaymentError::Provider {
+            status: status.as_u16(),
+            message,
+        })
+    }
+
+    fn parse_status(status: Option<&str>) -> ChargeStatus {
+        match status {
+            Some("succeeded") => ChargeStatus::Succeeded,
+            Some("processing") | Some("requires_action") | Some("requires_capture") => {
+                ChargeStatus::Pending
+            }
+            Some("refunded") => ChargeStatus::Refunded,
+            _ => ChargeStatus::Failed,
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentGateway for StripeGateway {
+    async fn create_customer(&self, user_id: Uuid, email: &str) -> Result<String, PaymentError> {
+        let params = [
+            ("email", email.to_string()),
+            ("metadata[user_id]", user_id.to_string()),
+        ];
+        let customer = self
+            .send(
+                self.client
+                    .post(format!("{}/v1/customers", self.base_url))
+                    .form(&params),
+            )
+            .await?;
+        Ok(customer.id)
+    }
+
+    async fn attach_payment_method(
+        &self,
+        customer_id: &str,
+        payment_token: &str,
+    ) -> Result<String, PaymentError> {
+        // Токен от клиента попадает в путь запроса: "/" или ".." сменили бы адрес
+        if !is_valid_payment_token(payment_token) {
+            return Err(PaymentError::Declined("invalid_payment_token".to_string()));
+        }
+        let params = [("customer", customer_id.to_string())];
+        let method = self
+            .send(
+                self.client
+                    .post(format!(
+                        "{}/v1/payment_methods/{}/attach",
+                        self.base_url, payment_token
+                    ))
+                    .form(&params),
+            )
+            .await?;
+        Ok(method.id)
+    }
+
+    async fn charge(&self, request: &ChargeRequest) -> Result<Charge, PaymentError> {
+        let params = [
+            ("amount", request.amount.amount_minor().to_string()),
+            ("currency", request.amount.currency().code().to_lowercase()),
+            ("customer", request.customer_id.clone()),
+            ("payment_method", request.payment_method_id.clone()),
+            ("description", request.description.clone()),
+            ("confirm", "true".to_string()),
+            ("off_session", "true".to_string()),
+            ("metadata[payment_id]", request.payment_id.to_string()),
+        ];
+        let intent = self
+            .send(
+                self.client
+                    .post(format!("{}/v1/payment_intents", self.base_url))
+                    .header("Idempotency-Key", &request.idempotency_key)
+                    .form(&params),
+            )
+            .await?;
+        Ok(Charge {
+            status: Self::parse_status(intent.status.as_deref()),
+            id: intent.id,
+        })
+    }
+
+    async fn refund(
+        &self,
+        charge_id: &str,
+        amount: Option<Money>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Refund, PaymentError> {
+        let mut params = vec![("payment_intent", charge_id.to_string())];
+        if let Some(amount) = amount {
+            params.push(("amount", amount.amount_minor().to_string()));
+        }
+        let mut request = self
+            .client
+            .post(format!("{}/v1/refunds", self.base_url))
+            .form(&params);
+        if let Some(key) = idempotency_key {
+            request = request.header("Idempotency-Key", key);
+        }
+        let refund = self.send(request).await?;
+        let currency = refund
+            .currency
+            .as_deref()
+            .and_then(Currency::from_code)
+            .or(amount.map(Money::currency))
+            .ok_or_else(|| PaymentError::InvalidResponse("refund without currency".to_string()))?;
+        let refunded = match (refund.amount, amount) {
+            (Some(minor), _) => Money::new(minor, currency),
+            (None, Some(requested)) => requested,
+            (None, None) => {
+                return Err(PaymentError::InvalidResponse(
+                    "refund without amount".to_string(),
+                ));
+            }
+        };
+        Ok(Refund {
+            id: refund.id,
+            charge_id: charge_id.to_string(),
+            amount: refunded,
+        })
+    }
+
+    async fn fetch_status(&self, charge_id: &str) -> Result<ChargeStatus, PaymentError> {
+        let intent = self
+            .send(self.client.get(format!(
+                "{}/v1/payment_intents/{}",
+                self.base_url, charge_id
+            )))
+            .await?;
+        Ok(Self::parse_status(intent.status.as_deref()))
+    }
+}
+
+// Идентификатор платёжного метода провайдера: pm_ и буквы, цифры, "_"
+fn is_valid_payment_token(token: &str) -> bool {
+    token.strip_prefix("pm_").is_some_and(|rest| {
+        !rest.is_empty() && rest.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+// Сценарий сбоя для фейкового шлюза
+#[derive(Clone, Debug)]
+pub enum FakeFailure {
+    Decline,
+    Network,
+    Pending,
+}
+
+// Списание в фейковом шлюзе: сумма, уже возвращённая часть и статус
+struct FakeCharge {
+    amount: Money,
+    refunded: Money,
+    status: ChargeStatus,
+}
+
+// Фейковый шлюз в памяти. Сбои задаются токенами (содержащими "decline",
+// "network_error" или "pending") либо очередью через fail_next.
+#[derive(Default)]
+pub struct FakeGateway {
+    charges: Mutex<HashMap<String, FakeCharge>>,
+    // Ключ идемпотентности -> списание, как у провайдера
+    charge_keys: Mutex<HashMap<String, String>>,
+    refund_keys: Mutex<HashMap<String, Refund>>,
+    scripted: Mutex<VecDeque<FakeFailure>>,
+}
+
+impl FakeGateway {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Следующий вызов charge завершится заданным сбоем
+    #[allow(dead_code)] // Используется в тестах и отладке
+    pub fn fail_next(&self, failure: FakeFailure) {
+        self.scripted.lock().unwrap().push_back(failure);
+    }
+
+    fn failure_for(&self, payment_method_id: &str) -> Option<FakeFailure> {
+        if let Some(failure) = self.scripted.lock().unwrap().pop_front() {
+            return Some(failure);
+        }
+        if payment_method_id.contains("decline") {
+            Some(FakeFailure::Decline)
+        } else if payment_method_id.contains("network_error") {
+            Some(FakeFailure::Network)
+        } else if payment_method_id.contains("pending") {
+            Some(FakeFailure::Pending)
+        } else {
+            None
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentGateway for FakeGateway {
+    async fn create_customer(&self, user_id: Uuid, _email: &str) -> Result<String, PaymentError> {
+        Ok(format!("cus_fake_{}", user_id.simple()))
+    }
+
+    async fn attach_payment_method(
+        &self,
+        _customer_id: &str,
+        payment_token: &str,
+    ) -> Result<String, PaymentError> {
+        // Токен сохраняется в идентификаторе, чтобы сценарии сбоев работали и при продлении
+        Ok(format!("pm_fake_{}", payment_token))
+    }
+
+    async fn charge(&self, request: &ChargeRequest) -> Result<Charge, PaymentError> {
+        // Повтор с тем же ключом возвращает уже созданное списание
+        let replayed = self
+            .charge_keys
+            .lock()
+            .unwrap()
+            .get(&request.idempotency_key)
+            .cloned();
+        if let Some(id) = replayed {
+            let status = self.fetch_status(&id).await?;
+            return Ok(Charge { id, status });
+        }
+        let status = match self.failure_for(&request.payment_method_id) {
+            Some(FakeFailure::Decline) => {
+                return Err(PaymentError::Declined("card_declined".to_string()));
+            }
+            Some(FakeFailure::Network) => {
+                return Err(PaymentError::Network("simulated timeout".to_string()));
+            }
+            Some(FakeFailure::Pending) => ChargeStatus::Pending,
+            None => ChargeStatus::Succeeded,
+        };
+        let id = format!("pi_fake_{}", Uuid::new_v4().simple());
+        self.charges.lock().unwrap().insert(
+            id.clone(),
+            FakeCharge {
+                amount: request.amount,
+                refunded: Money::zero(request.amount.currency()),
+                status: status.clone(),
+            },
+        );
+        self.charge_keys
+            .lock()
+            .unwrap()
+            .insert(request.idempotency_key.clone(), id.clone());
+        tracing::info!(
+            "Fake gateway charge {}: {} ({:?})",
+            id,
+            request.amount,
+            status
+        );
+        Ok(Charge { id, status })
+    }
+
+    async fn refund(
+        &self,
+        charge_id: &str,
+        amount: Option<Money>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Refund, PaymentError> {
+        let mut refund_keys = self.refund_keys.lock().unwrap();
+        if let Some(refund) = idempotency_key.and_then(|key| refund_keys.get(key)) {
+            return Ok(refund.clone());
+        }
+        let mut charges = self.charges.lock().unwrap();
+        let charge = charges
+            .get_mut(charge_id)
+            .ok_or_else(|| PaymentError::Provider {
+                status: 404,
+                message: format!("No such charge: {}", charge_id),
+            })?;
+        // Как у провайдера: возврат только по проведённому списанию и не больше остатка
+        let rejected = |message: &str| PaymentError::Provider {
+            status: 400,
+            message: format!("{}: {}", message, charge_id),
+        };
+        if charge.status != ChargeStatus::Succeeded {
+            return Err(rejected("Charge cannot be refunded"));
+        }
+        let remaining = charge.amount.checked_sub(charge.refunded)?;
+        let amount = amount.unwrap_or(remaining);
+        if !amount.is_positive() || remaining.checked_sub(amount)?.amount_minor() < 0 {
+            return Err(rejected("Refund amount exceeds remaining charge"));
+        }
+        charge.refunded = charge.refunded.checked_add(amount)?;
+        if charge.refunded == charge.amount {
+            charge.status = ChargeStatus::Refunded;
+        }
+        let refund = Refund {
+            id: format!("re_fake_{}", Uuid::new_v4().simple()),
+            charge_id: charge_id.to_string(),
+            amount,
+        };
+        if let Some(key) = idempotency_key {
+            refund_keys.insert(key.to_string(), refund.clone());
+        }
+        Ok(refund)
+    }
+
+    async fn fetch_status(&self, charge_id: &str) -> Result<ChargeStatus, PaymentError> {
+        self.charges
+            .lock()
+            .unwrap()
+            .get(charge_id)
+            .map(|charge| charge.status.clone())
+            .ok_or_else(|| PaymentError::Provider {
+                status: 404,
+                message: format!("No such charge: {}", charge_id),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(payment_method_id: &str, key: &str) -> ChargeRequest {
+        ChargeRequest {
+            customer_id: "cus_fake_test".to_string(),
+            payment_method_id: payment_method_id.to_string(),
+            amount: Money::new(999, Currency::Usd),
+            description: "Test".to_string(),
+            idempotency_key: key.to_string(),
+            payment_id: Uuid::new_v4(),
+        }
+    }
+
+    #[test]
+    fn payment_token_must_be_a_plain_method_id() {
+        assert!(is_valid_payment_token("pm_card_visa"));
+        assert!(is_valid_payment_token("pm_1NxYz2AbC"));
+        assert!(!is_valid_payment_token("pm_"));
+        assert!(!is_valid_payment_token("tok_visa"));
+        assert!(!is_valid_payment_token("pm_x/../../customers/cus_y"));
+        assert!(!is_valid_payment_token("pm_x?expand=customer"));
+        assert!(!is_valid_payment_token("pm_x%2F.."));
+    }
+
+    #[tokio::test]
+    async fn stripe_rejects_unsafe_token_without_request() {
+        // Адрес недоступен: запрос к провайдеру дал бы ошибку сети, а не отказ
+        let gateway = StripeGateway::new("http://127.0.0.1:9", "sk_test");
+        assert!(matches!(
+            gateway
+                .attach_payment_method("cus_1", "pm_x/../../customers/cus_y")
+                .await,
+            Err(PaymentError::Declined(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fake_charge_succeeds_and_replays_by_key() {
+        let gateway = FakeGateway::new();
+        let first = gateway
+            .charge(&request("pm_fake_ok", "key-1"))
+            .await
+            .unwrap();
+        assert_eq!(first.status, ChargeStatus::Succeeded);
+
+        // Тот же ключ — то же списание, второго не создаётся
+        let replay = gateway
+            .charge(&request("pm_fake_ok", "key-1"))
+            .await
+            .unwrap();
+        assert_eq!(replay.id, first.id);
+        assert_eq!(gateway.charges.lock().unwrap().len(), 1);
+
+        let other = gateway
+            .charge(&request("pm_fake_ok", "key-2"))
+            .await
+            .unwrap();
+        assert_ne!(other.id, first.id);
+    }
+
+    #[tokio::test]
+    async fn unapplied_refund_is_replayed_by_key() {
+        let gateway = FakeGateway::new();
+        let charge = gateway
+            .charge(&request("pm_fake_ok", "key-1"))
+            .await
+            .unwrap();
+        let key = unapplied_refund_key(&charge.id);
+        let first = gateway.refund(&charge.id, None, Some(&key)).await.unwrap();
+        // Повтор после сбоя фиксации возвращает тот же возврат, а не ошибку
+        let replay = gateway.refund(&charge.id, None, Some(&key)).await.unwrap();
+        assert_eq!(replay.id, first.id);
+        assert_eq!(replay.amount, Money::new(999, Currency::Usd));
+        assert!(gateway.refund(&charge.id, None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fake_failures_from_token() {
+        let gateway = FakeGateway::new();
+        assert!(matches!(
+            gateway.charge(&request("pm_fake_decline", "k1")).await,
+            Err(PaymentError::Declined(_))
+        ));
+        let network = gateway
+            .charge(&request("pm_fake_network_error", "k2"))
+            .await
+            .unwrap_err();
+        assert!(network.is_outcome_unknown());
+        let pending = gateway
+            .charge(&request("pm_fake_pending", "k3"))
+            .await
+            .unwrap();
+        assert_eq!(pending.status, ChargeStatus::Pending);
+        assert_eq!(
+            gateway.fetch_status(&pending.id).await.unwrap(),
+            ChargeStatus::Pending
+        );
+    }
+
+    #[tokio::test]
+    async fn fake_fail_next_applies_once() {
+        let gateway = FakeGateway::new();
+        gateway.fail_next(FakeFailure::Network);
+        assert!(matches!(
+            gateway.charge(&request("pm_fake_ok", "key-1")).await,
+            Err(PaymentError::Network(_))
+        ));
+        // Сбой до создания списания: повтор с тем же ключом проходит
+        let retry = gateway
+            .charge(&request("pm_fake_ok", "key-1"))
+            .await
+            .unwrap();
+        assert_eq!(retry.status, ChargeStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn fake_refund_rejects_over_refund_and_pending_charge() {
+        let gateway = FakeGateway::new();
+        let charge = gateway
+            .charge(&request("pm_fake_ok", "key-1"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            gateway
+                .refund(&charge.id, Some(Money::new(1000, Currency::Usd)), None)
+                .await,
+            Err(PaymentError::Provider { status: 400, .. })
+        ));
+        // Отклонённый возврат ничего не меняет
+        let full = gateway.refund(&charge.id, None, None).await.unwrap();
+        assert_eq!(full.amount, Money::new(999, Currency::Usd));
+
+        let pending = gateway
+            .charge(&request("pm_fake_pending", "key-2"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            gateway.refund(&pending.id, None, None).await,
+            Err(PaymentError::Provider { status: 400, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn fake_refund_marks_full_refund_only() {
+        let gateway = FakeGateway::new();
+        let charge = gateway
+            .charge(&request("pm_fake_ok", "key-1"))
+            .await
+            .unwrap();
+
+        let partial = gateway
+            .refund(&charge.id, Some(Money::new(500, Currency::Usd)), None)
+            .await
+            .unwrap();
+        assert_eq!(partial.amount, Money::new(500, Currency::Usd));
+        assert_eq!(
+            gateway.fetch_status(&charge.id).await.unwrap(),
+            ChargeStatus::Succeeded
+        );
+
+        // Полный возврат после частичного — только остаток
+        let full = gateway.refund(&charge.id, None, None).await.unwrap();
+        assert_eq!(full.amount, Money::new(499, Currency::Usd));
+        assert_eq!(
+            gateway.fetch_status(&charge.id).await.unwrap(),
+            ChargeStatus::Refunded
+        );
+        assert!(matches!(
+            gateway.refund(&charge.id, None, None).await,
+            Err(PaymentError::Provider { status: 400, .. })
+        ));
+        assert!(matches!(
+            gateway.refund("pi_missing", None, None).await,
+            Err(PaymentError::Provider { status: 404, .. })
+        ));
+    }
+}
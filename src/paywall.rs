@@ -2,39 +2,121 @@
 use crate::auth; // Для проверки токена
 use crate::config::Config;
 use crate::db;
+use crate::decisions::{self, DecisionRecord};
+use crate::experiments::{self, Assignment, ExperimentRegistry};
+use crate::geoip::GeoIp;
+use crate::idempotency;
+use crate::institutions::{self, InstitutionDirectory, InstitutionMatch};
 use crate::ml; // Для ML анализа
-use crate::models::{PurchaseRequest, Subscription, UserBehavior}; // Убран Content
+use crate::models::{
+    Content, MLFeatures, OfferImpression, PurchaseRequest, Subscription, UserBehavior,
+};
+use crate::money::{Currency, Money};
+use crate::offers;
+use crate::payment::{self, ChargeStatus, PaymentGateway};
+use crate::pricing::{self, CurrencySource};
+use crate::rules::{Evaluation, Facts, Outcome, RequestContext, RuleEngine, RuleSet};
+use crate::tax::{self, TaxBreakdown};
+use crate::visitors;
 use actix_web::{HttpRequest, HttpResponse, get, post, web}; // Убраны неиспользуемые
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use moka::future::Cache;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use uuid::Uuid;
 
+// Время жизни закешированного решения: ограничивает устаревание доступа, о смене
+// которого кеш не узнаёт (истечение семейной подписки владельца, окна материала)
+pub const DECISION_CACHE_TTL_SECS: u64 = 300;
+
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(get_content);
     cfg.service(purchase_subscription);
+    cfg.service(list_plans);
     cfg.service(get_user_profile);
 }
 
+// Сброс закешированных решений о доступе для пользователя (после изменения подписки)
+pub fn invalidate_user_cache(cache: &Cache<String, serde_json::Value>, user_id: Uuid) {
+    let suffix = format!("_user_{}", user_id);
+    if let Err(e) = cache.invalidate_entries_if(move |key, _| key.ends_with(&suffix)) {
+        tracing::warn!("Failed to invalidate cache for user {}: {}", user_id, e);
+    }
+}
+
+// Сброс решений по материалу (после его изменения редактором)
+pub fn invalidate_content_cache(cache: &Cache<String, serde_json::Value>, content_id: Uuid) {
+    let prefix = format!("content_{}_", content_id);
+    if let Err(e) = cache.invalidate_entries_if(move |key, _| key.starts_with(&prefix)) {
+        tracing::warn!(
+            "Failed to invalidate cache for content {}: {}",
+            content_id,
+            e
+        );
+    }
+}
+
+// Сброс решений, принятых с учётом учреждения (после изменения его диапазонов или тарифа)
+pub fn invalidate_institution_cache(
+    cache: &Cache<String, serde_json::Value>,
+    institution_id: Uuid,
+) {
+    let marker = format!("_inst_{}", institution_id);
+    if let Err(e) = cache.invalidate_entries_if(move |key, _| key.contains(&marker)) {
+        tracing::warn!(
+            "Failed to invalidate cache for institution {}: {}",
+            institution_id,
+            e
+        );
+    }
+}
+
+// Экстракторы actix: каждый сервис — отдельный аргумент
+#[allow(clippy::too_many_arguments)]
 #[get("/content/{content_id}")]
 pub async fn get_content(
     pool: web::Data<sqlx::PgPool>,
     ml_model: web::Data<ml::PaywallModel>,
     cache: web::Data<Cache<String, serde_json::Value>>,
+    config: web::Data<Config>,
+    institutions: web::Data<InstitutionDirectory>,
+    engine: web::Data<RuleEngine>,
+    experiments: web::Data<ExperimentRegistry>,
     req: HttpRequest,
     path: web::Path<Uuid>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let content_id = path.into_inner();
 
-    let user_id = match auth::get_user_id_from_request(&req) {
-        Some(id) => id,
-        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    // Сеть или сайт учреждения открывают доступ и без входа в аккаунт
+    let institution = institutions.match_request(&req);
+    let viewer = match auth::get_user_id_from_request(&req) {
+        Some(user_id) => Viewer::User(user_id),
+        None => match visitors::visitor_from_request(&req, &config.jwt_secret) {
+            Some(visitor_id) => Viewer::Visitor(visitor_id),
+            None => Viewer::NewVisitor,
+        },
     };
+    // Новому посетителю cookie выдаётся с любым ответом
+    let new_visitor = matches!(viewer, Viewer::NewVisitor).then(Uuid::new_v4);
+    // Вариант эксперимента для вошедшего пользователя (кеш сбрасывается при смене эксперимента)
+    let assignment = viewer
+        .user_id()
+        .and_then(|user_id| experiments.assign(user_id));
 
-    let cache_key = format!("content_{}_user_{}", content_id, user_id);
-    if let Some(cached_response) = cache.get(&cache_key).await {
+    // Отладочный запрос сотрудника вычисляется заново, чтобы получить запись решения
+    let debug = decisions::debug_requested(&pool, &req).await;
+    let cache_key = decision_cache_key(content_id, institution.as_ref(), &viewer);
+    if !debug && let Some(cached_response) = cache.get(&cache_key).await {
         tracing::info!("Cache hit for key: {}", cache_key);
-        return Ok(HttpResponse::Ok().json(cached_response));
+        if let Some(institution) = &institution {
+            institutions::record_usage(&pool, institution, content_id, &cached_response).await;
+        }
+        if let (Some(assignment), Some(user_id)) = (&assignment, viewer.user_id())
+            && cached_response.get("experiment").is_some()
+        {
+            experiments::record_exposure(&pool, assignment, user_id).await;
+        }
+        return Ok(respond(cached_response, new_visitor, &config, None));
     }
 
     // Получение контента с обработкой ошибок
@@ -49,97 +131,598 @@ pub async fn get_content(
         }
     };
 
-    // Проверка подписки с обработкой ошибок
-    let subscription = match db::get_active_subscription(&pool, user_id).await {
-        Ok(sub_opt) => sub_opt,
+    let ctx = RequestContext::from_request(&req);
+    // Черновик или материал до publish_at неотличим от несуществующего
+    if !content.is_published(ctx.at) {
+        return Ok(HttpResponse::NotFound().json(json!({"error": "Content not found"})));
+    }
+    let (mut facts, access) = match gather_facts(
+        &pool,
+        &config,
+        &viewer,
+        &content,
+        institution.as_ref(),
+        &ctx,
+    )
+    .await
+    {
+        Ok(gathered) => gathered,
         Err(e) => {
-            tracing::error!("Database error fetching subscription: {}", e);
+            tracing::error!("Database error gathering access facts: {}", e);
             return Ok(
                 HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
             );
         }
     };
-
-    let has_access = match &subscription {
-        Some(sub) => match content.required_plan.as_str() {
-            "free" => true,
-            "basic" => sub.plan_id == "basic" || sub.plan_id == "premium",
-            "premium" => sub.plan_id == "premium",
-            _ => false,
-        },
-        None => content.required_plan == "free",
+    let rules = assignment
+        .as_ref()
+        .and_then(|assignment| assignment.rules.clone())
+        .unwrap_or_else(|| engine.current());
+    let (evaluation, ml_features) = decide(
+        &rules.rules,
+        &mut facts,
+        &pool,
+        &ml_model,
+        &viewer,
+        &content,
+    )
+    .await;
+    // Вариант предложения выбирает бандит (offers.rs)
+    let offer = match (&evaluation.outcome, viewer.user_id()) {
+        (Outcome::Offer { .. }, Some(user_id)) => {
+            offers::present(&pool, &config, user_id, content_id, &facts).await
+        }
+        _ => None,
+    };
+    let decision = Decision {
+        facts,
+        access,
+        evaluation,
+        ml_features,
+        experiment: assignment,
+        offer,
     };
+    let evaluation = &decision.evaluation;
 
-    let response = if has_access {
-        let behavior = UserBehavior {
-            user_id,
-            content_id,
-            view_time_seconds: 0,
-            scroll_depth_percent: 0.0,
-            interaction_score: 0.0,
-            timestamp: Utc::now(),
+    if let Some(user_id) = viewer.user_id() {
+        if evaluation.outcome.grants_access() {
+            let behavior = UserBehavior {
+                user_id,
+                content_id,
+                view_time_seconds: 0,
+                scroll_depth_percent: 0.0,
+                interaction_score: 0.0,
+                timestamp: Utc::now(),
+            };
+            // Логируем поведение, но не прерываем запрос при ошибке
+            if let Err(e) = db::log_user_behavior(&pool, &behavior).await {
+                tracing::warn!("Failed to log user behavior: {}", e);
+            }
+        }
+    } else if let Some(visitor_id) = viewer.visitor_id().or(new_visitor) {
+        // Просмотр по счётчику учитывается, остальные ответы лишь отмечают визит
+        let result = if matches!(evaluation.outcome, Outcome::Meter { .. }) {
+            visitors::record_view(&pool, visitor_id, content_id).await
+        } else {
+            visitors::touch(&pool, visitor_id).await
         };
-        // Логируем поведение, но не прерываем запрос при ошибке
-        if let Err(e) = db::log_user_behavior(&pool, &behavior).await {
-            tracing::warn!("Failed to log user behavior: {}", e);
+        if let Err(e) = result {
+            tracing::warn!("Failed to record visitor {}: {}", visitor_id, e);
         }
+    }
 
+    let mut response = render_decision(
+        &content,
+        &decision,
+        institution.as_ref(),
+        config.anonymous_meter_window_days,
+    );
+    // Показом варианта считается платный материал без права доступа: только там варианты различаются
+    if let (Some(assignment), Some(user_id)) = (&decision.experiment, viewer.user_id())
+        && decision.facts.content_plan != "free"
+        && decision.facts.entitlements.is_empty()
+    {
+        response["experiment"] = json!({
+            "key": assignment.experiment,
+            "variant": assignment.variant,
+        });
+        experiments::record_exposure(&pool, assignment, user_id).await;
+    }
+    // Ответ с предложением не кешируется: после покупки или истечения выбирается новое.
+    // Доступ по подписке, которая закончится раньше записи кеша, тоже не кешируется
+    if !evaluation.volatile
+        && decision.offer.is_none()
+        && outlives_cache(decision.access.subscription.as_ref(), Utc::now())
+    {
+        cache.insert(cache_key.clone(), response.clone()).await;
+        tracing::info!("Cached response for key: {}", cache_key);
+    }
+    if let Some(institution) = &institution {
+        institutions::record_usage(&pool, institution, content_id, &response).await;
+    }
+
+    // Запись решения: по отладочному запросу или выборочно
+    let sample_reason = if debug {
+        Some(decisions::SAMPLE_DEBUG)
+    } else {
+        decisions::sample_reason(&config, evaluation.outcome.grants_access())
+    };
+    let mut record = None;
+    if let Some(reason) = sample_reason {
+        match DecisionRecord::build(
+            &pool,
+            content_id,
+            viewer.user_id(),
+            viewer.visitor_id().or(new_visitor),
+            institution.as_ref(),
+            &decision,
+            &rules,
+        )
+        .await
+        {
+            Ok(built) => {
+                decisions::persist(&pool, &built, reason).await;
+                record = Some(built).filter(|_| debug);
+            }
+            Err(e) => tracing::warn!("Failed to build decision record: {}", e),
+        }
+    }
+
+    Ok(respond(response, new_visitor, &config, record.as_ref()))
+}
+
+// Подписки нет или она действует дольше, чем живёт запись кеша
+fn outlives_cache(subscription: Option<&Subscription>, now: DateTime<Utc>) -> bool {
+    let cached_until = now + Duration::seconds(DECISION_CACHE_TTL_SECS as i64);
+    subscription.is_none_or(|subscription| subscription.access_until() > cached_until)
+}
+
+// Кто запрашивает материал
+pub enum Viewer {
+    User(Uuid),
+    Visitor(Uuid), // Анонимный посетитель с действительной cookie
+    NewVisitor,
+}
+
+impl Viewer {
+    pub fn user_id(&self) -> Option<Uuid> {
+        match self {
+            Viewer::User(user_id) => Some(*user_id),
+            _ => None,
+        }
+    }
+
+    pub fn visitor_id(&self) -> Option<Uuid> {
+        match self {
+            Viewer::Visitor(visitor_id) => Some(*visitor_id),
+            _ => None,
+        }
+    }
+}
+
+// Решение зависит от учреждения, поэтому оно входит в ключ (перед user_id,
+// чтобы invalidate_user_cache находил запись по суффиксу)
+fn decision_cache_key(
+    content_id: Uuid,
+    institution: Option<&InstitutionMatch>,
+    viewer: &Viewer,
+) -> String {
+    let mut key = format!("content_{}", content_id);
+    if let Some(institution) = institution {
+        key.push_str(&format!("_inst_{}", institution.id));
+    }
+    match viewer.user_id() {
+        Some(user_id) => key.push_str(&format!("_user_{}", user_id)),
+        None => key.push_str("_anon"),
+    }
+    key
+}
+
+fn respond(
+    mut response: serde_json::Value,
+    new_visitor: Option<Uuid>,
+    config: &Config,
+    record: Option<&DecisionRecord>,
+) -> HttpResponse {
+    let mut builder = HttpResponse::Ok();
+    if let Some(cookie) = new_visitor.and_then(|id| visitors::visitor_cookie(id, config)) {
+        builder.cookie(cookie);
+    }
+    if let Some(record) = record {
+        builder.insert_header(("X-Paywall-Decision-Id", record.id.to_string()));
+        builder.insert_header(("X-Paywall-Decision", record.summary()));
+        response["decision_record"] = json!(record);
+    }
+    builder.json(response)
+}
+
+// Источник доступа зрителя; grants = false — тариф источника ниже уровня материала
+#[derive(Serialize, Clone, Debug)]
+pub struct AccessSource {
+    pub source: &'static str, // personal | family | organization | institution
+    pub id: Uuid,             // Подписка, организация или учреждение
+    pub plan_id: String,
+    pub grants: bool,
+}
+
+pub struct Access {
+    pub subscription: Option<Subscription>, // Личная действующая подписка
+    pub sources: Vec<AccessSource>,
+}
+
+// Решение вместе со всем, что на него повлияло
+pub struct Decision {
+    pub facts: Facts,
+    pub access: Access,
+    pub evaluation: Evaluation,
+    pub ml_features: Option<MLFeatures>, // Признаки, если модель вызывалась
+    pub experiment: Option<Assignment>,
+    pub offer: Option<OfferImpression>, // Предложение для исхода offer
+}
+
+// Факты для правил и найденные источники доступа.
+// Чтение без побочных эффектов: используется и в dry-run
+pub async fn gather_facts(
+    pool: &sqlx::PgPool,
+    config: &Config,
+    viewer: &Viewer,
+    content: &Content,
+    institution: Option<&InstitutionMatch>,
+    ctx: &RequestContext,
+) -> Result<(Facts, Access), sqlx::Error> {
+    // Срочные новости и архив бесплатны в своих окнах
+    let required_plan = content.plan_at(ctx.at);
+    let paid = required_plan != "free";
+    let mut subscription = None;
+    let mut sources = Vec::new();
+    let source = |source: &'static str, id: Uuid, plan_id: &str| AccessSource {
+        source,
+        id,
+        plan_id: plan_id.to_string(),
+        grants: plan_grants(plan_id, required_plan),
+    };
+
+    if let Viewer::User(user_id) = viewer {
+        subscription = db::get_active_subscription(pool, *user_id).await?;
+        if paid {
+            if let Some(sub) = &subscription {
+                sources.push(source("personal", sub.id, &sub.plan_id));
+            }
+            // Участник семейного тарифа получает доступ владельца
+            if let Some(family) = db::get_family_subscription(pool, *user_id).await? {
+                sources.push(source("family", family.id, &family.plan_id));
+            }
+            // Участник организации с действующей лицензией получает её уровень доступа
+            for (organization_id, plan_id) in db::get_organization_licenses(pool, *user_id).await? {
+                sources.push(source("organization", organization_id, &plan_id));
+            }
+        }
+    }
+    if paid && let Some(institution) = institution {
+        sources.push(source("institution", institution.id, &institution.plan_id));
+    }
+    let mut entitlements: Vec<&'static str> = Vec::new();
+    for granted in sources.iter().filter(|s| s.grants) {
+        if !entitlements.contains(&granted.source) {
+            entitlements.push(granted.source);
+        }
+    }
+
+    let since = ctx.at - Duration::days(config.anonymous_meter_window_days);
+    let (meter_views, already_viewed) = match viewer {
+        Viewer::User(user_id) => {
+            db::get_user_meter_views(pool, *user_id, content.id, since).await?
+        }
+        Viewer::Visitor(visitor_id) => {
+            visitors::meter_views(pool, *visitor_id, content.id, since).await?
+        }
+        Viewer::NewVisitor => (0, false),
+    };
+
+    let facts = Facts {
+        logged_in: matches!(viewer, Viewer::User(_)),
+        plan: subscription.as_ref().map(|s| s.plan_id.clone()),
+        entitlements,
+        institution: institution.is_some(),
+        content_plan: required_plan.to_string(),
+        content_tags: content.tags.clone(),
+        content_age_hours: (ctx.at - content.publish_at.unwrap_or(content.created_at)).num_hours(),
+        referrer: ctx.referrer.clone(),
+        device: ctx.device,
+        meter_views,
+        already_viewed,
+        ml_positive: None,
+        at: ctx.at,
+    };
+    Ok((
+        facts,
+        Access {
+            subscription,
+            sources,
+        },
+    ))
+}
+
+// Правила вычисляются без прогноза модели; модель вызывается, только если до
+// неё дошло условие ml_positive. Для анонимных посетителей прогноз — false
+pub async fn decide(
+    rules: &RuleSet,
+    facts: &mut Facts,
+    pool: &sqlx::PgPool,
+    ml_model: &ml::PaywallModel,
+    viewer: &Viewer,
+    content: &Content,
+) -> (Evaluation, Option<MLFeatures>) {
+    let (mut evaluation, ml_features) = match rules.evaluate(facts) {
+        Some(evaluation) => (evaluation, None),
+        None => {
+            let mut ml_features = None;
+            let ml_positive = match viewer.user_id() {
+                Some(user_id) => match ml::extract_features(pool, user_id, content.id).await {
+                    Ok(features) => {
+                        let positive = ml_model.predict(&features);
+                        ml_features = Some(features);
+                        positive
+                    }
+                    Err(e) => {
+                        tracing::error!("Feature extraction error: {}", e);
+                        false // В случае ошибки ML, доступ не предоставляется
+                    }
+                },
+                None => false,
+            };
+            facts.ml_positive = Some(ml_positive);
+            // С известным прогнозом вычисление всегда завершается
+            let evaluation = rules.evaluate(facts).unwrap_or(Evaluation {
+                rule: None,
+                outcome: Outcome::HardWall { message: None },
+                volatile: false,
+                trace: Vec::new(),
+            });
+            (evaluation, ml_features)
+        }
+    };
+    // На границе окна доступа (premium_from, free_from) решение устареет
+    if content.next_window_change(facts.at).is_some() {
+        evaluation.volatile = true;
+    }
+    (evaluation, ml_features)
+}
+
+fn render_decision(
+    content: &Content,
+    decision: &Decision,
+    institution: Option<&InstitutionMatch>,
+    window_days: i64,
+) -> serde_json::Value {
+    let facts = &decision.facts;
+    let evaluation = &decision.evaluation;
+    let meter = |limit: Option<i64>, counted: bool| {
+        let used = facts.meter_views + i64::from(counted && !facts.already_viewed);
+        let mut meter = json!({"views_used": used, "window_days": window_days});
+        if let Some(limit) = limit {
+            meter["views_limit"] = json!(limit);
+            meter["views_remaining"] = json!((limit - used).max(0));
+        }
+        meter
+    };
+    let denied = |chars: usize, decision: &str| {
         json!({
-            "content": content,
-            "access_granted": true,
+            "content": visitors::teaser(content, chars),
+            "access_granted": false,
+            "decision": decision,
         })
-    } else {
-        // Извлечение признаков с обработкой ошибок
-        let features_result = ml::extract_features(&pool, user_id, content_id).await;
-        let ml_decision = match features_result {
-            Ok(features) => ml_model.predict(&features),
+    };
+
+    match &evaluation.outcome {
+        Outcome::Grant | Outcome::Meter { .. } => {
+            let mut granted = json!({
+                "content": content,
+                "access_granted": true,
+                "decision": "grant",
+            });
+            if let Outcome::Meter { limit } = &evaluation.outcome {
+                granted["decision"] = json!("meter");
+                granted["access_via"] = json!("meter");
+                granted["meter"] = meter(*limit, true);
+            } else if facts.content_plan == "free" || facts.entitlements.contains(&"personal") {
+                // Личная подписка или бесплатный материал: источник не указывается
+            } else if let Some(&source) = facts.entitlements.first() {
+                granted["access_via"] = json!(source);
+                if let Some(institution) = institution.filter(|_| source == "institution") {
+                    granted["institution"] =
+                        json!({"id": institution.id, "name": institution.name});
+                }
+            } else {
+                // Выдано правилом без права доступа (например, переход из поисковика)
+                granted["access_via"] = json!("rule");
+            }
+            // Льготный период после неудачного продления: доступ есть, но клиент
+            // должен показать предупреждение об оплате
+            if let Some(sub) = decision
+                .access
+                .subscription
+                .as_ref()
+                .filter(|s| s.billing_status == "past_due")
+            {
+                granted["billing_problem"] = json!(true);
+                granted["grace_until"] = json!(sub.grace_until);
+            }
+            granted
+        }
+        Outcome::Teaser { chars, message } => {
+            let mut response = denied(*chars, "teaser");
+            response["message"] = json!(message.as_deref().unwrap_or("Subscribe to keep reading"));
+            response
+        }
+        Outcome::Regwall { message, limit } => {
+            let mut response = denied(visitors::TEASER_CHARS, "registration_wall");
+            response["message"] = json!(
+                message
+                    .as_deref()
+                    .unwrap_or("Register for free to keep reading")
+            );
+            response["meter"] = meter(*limit, false);
+            response
+        }
+        Outcome::Offer { message } => {
+            let mut response = denied(visitors::TEASER_CHARS, "offer");
+            response["ml_suggestion"] = json!(
+                message
+                    .as_deref()
+                    .unwrap_or("Access can be granted with a discount or trial")
+            );
+            if let Some(offer) = &decision.offer {
+                response["offer"] = offers::offer_json(offer);
+            }
+            response
+        }
+        Outcome::HardWall { message } => {
+            let mut response = denied(0, "hard_wall");
+            response["message"] = json!(
+                message
+                    .as_deref()
+                    .unwrap_or("Upgrade your subscription to access this content")
+            );
+            response
+        }
+    }
+}
+
+pub const PLANS: &[&str] = &["basic", "premium", "family"];
+
+// Цена в валюте и длительность периода для тарифа; None — нет тарифа или цены в этой валюте
+pub fn plan_terms(plan_id: &str, currency: Currency) -> Option<(Money, i64)> {
+    let price_minor = match (plan_id, currency) {
+        ("basic", Currency::Usd) => 999,
+        ("basic", Currency::Eur) => 899,
+        ("basic", Currency::Gbp) => 799,
+        ("basic", Currency::Rub) => 69900,
+        ("premium", Currency::Usd) => 1999,
+        ("premium", Currency::Eur) => 1799,
+        ("premium", Currency::Gbp) => 1599,
+        ("premium", Currency::Rub) => 139900,
+        ("family", Currency::Usd) => 2999,
+        ("family", Currency::Eur) => 2699,
+        ("family", Currency::Gbp) => 2399,
+        ("family", Currency::Rub) => 209900,
+        _ => return None,
+    };
+    Some((
+        Money::new(price_minor, currency),
+        plan_period_days(plan_id)?,
+    ))
+}
+
+pub fn plan_period_days(plan_id: &str) -> Option<i64> {
+    match plan_id {
+        "basic" | "premium" | "family" => Some(30),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PlansQuery {
+    currency: Option<String>,
+}
+
+// Цены тарифов в валюте клиента (у подписчика — в валюте его подписки)
+#[get("/plans")]
+pub async fn list_plans(
+    pool: web::Data<sqlx::PgPool>,
+    config: web::Data<Config>,
+    geoip: web::Data<GeoIp>,
+    req: HttpRequest,
+    query: web::Query<PlansQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut account_country = None;
+    if let Some(user_id) = auth::get_user_id_from_request(&req) {
+        let subscription = match db::get_active_subscription(&pool, user_id).await {
+            Ok(sub) => sub,
             Err(e) => {
-                tracing::error!("Feature extraction error: {}", e);
-                false // В случае ошибки ML, доступ не предоставляется
+                tracing::error!("Database error fetching subscription: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
             }
         };
+        if let Some(currency) = subscription.and_then(|s| s.billing_currency().ok()) {
+            return Ok(HttpResponse::Ok().json(price_list(
+                &config,
+                currency,
+                CurrencySource::Subscription,
+            )));
+        }
+        account_country = match db::get_user_by_id(&pool, user_id).await {
+            Ok(user) => user.and_then(|u| u.country),
+            Err(e) => {
+                tracing::error!("Database error fetching user: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        };
+    }
 
-        if ml_decision {
-            json!({
-                "content": content,
-                "access_granted": false,
-                "ml_suggestion": "Access can be granted with a discount or trial",
-            })
-        } else {
-            json!({
-                "content": content,
-                "access_granted": false,
-                "message": "Upgrade your subscription to access this content",
-            })
+    match pricing::resolve_currency(
+        query.currency.as_deref(),
+        account_country.as_deref(),
+        geoip.country_for_request(&req),
+    ) {
+        Ok((currency, source)) => {
+            Ok(HttpResponse::Ok().json(price_list(&config, currency, source)))
         }
-    };
+        Err(code) => Ok(HttpResponse::BadRequest()
+            .json(json!({"error": "Unsupported currency", "currency": code}))),
+    }
+}
 
-    cache.insert(cache_key.clone(), response.clone()).await;
-    tracing::info!("Cached response for key: {}", cache_key);
+fn price_list(config: &Config, currency: Currency, source: CurrencySource) -> serde_json::Value {
+    let plans: Vec<_> = PLANS
+        .iter()
+        .filter_map(|plan_id| {
+            plan_terms(plan_id, currency).map(|(price, period_days)| {
+                json!({"plan_id": plan_id, "price": price, "period_days": period_days})
+            })
+        })
+        .collect();
+    json!({
+        "currency": currency.code(),
+        "currency_source": source,
+        // Налог добавляется при оплате, если цены указаны без него
+        "tax_inclusive": tax::TaxMode::from_config(config) == tax::TaxMode::Inclusive,
+        "plans": plans,
+    })
+}
 
-    Ok(HttpResponse::Ok().json(response))
+// Порядок тарифов для смены плана: больше — выше
+pub fn plan_rank(plan_id: &str) -> Option<u8> {
+    match plan_id {
+        "basic" => Some(1),
+        "premium" => Some(2),
+        "family" => Some(3),
+        _ => None,
+    }
 }
 
-// Фиктивная интеграция с платежной системой
-async fn process_payment(
-    _config: &Config,
-    _token: &str,
-    _amount: f64,
-    _currency: &str,
-) -> Result<bool, Box<dyn std::error::Error>> {
-    tracing::info!(
-        "Processing payment: token={}, amount={}, currency={}",
-        _token,
-        _amount,
-        _currency
-    );
-    Ok(true) // Всегда успешно для демонстрации
+// Уровень доступа к контенту: семейный тариф даёт доступ premium
+fn entitlement_rank(plan_id: &str) -> u8 {
+    match plan_id {
+        "free" => 0,
+        "basic" => 1,
+        "premium" | "family" => 2,
+        _ => u8::MAX, // Неизвестный уровень контента недоступен
+    }
+}
+
+fn plan_grants(plan_id: &str, required_plan: &str) -> bool {
+    required_plan == "free" || entitlement_rank(plan_id) >= entitlement_rank(required_plan)
 }
 
 #[post("/subscription/purchase")]
 pub async fn purchase_subscription(
     pool: web::Data<sqlx::PgPool>,
+    gateway: web::Data<dyn PaymentGateway>,
     config: web::Data<Config>,
+    geoip: web::Data<GeoIp>,
     req: HttpRequest,
     purchase_req: web::Json<PurchaseRequest>,
 ) -> Result<HttpResponse, actix_web::Error> {
@@ -148,42 +731,297 @@ pub async fn purchase_subscription(
         None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
     };
 
-    let (amount, duration_days) = match purchase_req.plan_id.as_str() {
-        "basic" => (9.99, 30),
-        "premium" => (19.99, 30),
-        _ => return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid plan"}))),
+    // Повтор запроса с тем же Idempotency-Key не приводит к повторному списанию
+    let idempotency_key = match idempotency::key_from_request(&req) {
+        Ok(key) => key,
+        Err(response) => return Ok(response),
     };
+    let mut provider_key = None;
+    if let Some(key) = &idempotency_key {
+        let hash = idempotency::request_hash(req.path(), &*purchase_req);
+        match idempotency::begin(&pool, user_id, key, &hash).await {
+            Ok(idempotency::Begin::Proceed(key)) => provider_key = Some(key),
+            Ok(idempotency::Begin::Respond(response)) => return Ok(response),
+            Err(e) => {
+                tracing::error!("Database error acquiring idempotency key: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        }
+    }
+
+    let geo_country = geoip.country_for_request(&req);
+    let response = process_purchase(
+        &pool,
+        gateway.get_ref(),
+        &config,
+        user_id,
+        provider_key,
+        &purchase_req,
+        geo_country,
+    )
+    .await?;
+
+    match idempotency_key {
+        Some(key) => Ok(idempotency::finish(&pool, user_id, &key, response).await),
+        None => Ok(response),
+    }
+}
 
-    match process_payment(&config, &purchase_req.payment_token, amount, "USD").await {
-        Ok(true) => {
-            let new_subscription = Subscription {
-                id: Uuid::new_v4(),
+async fn process_purchase(
+    pool: &sqlx::PgPool,
+    gateway: &dyn PaymentGateway,
+    config: &Config,
+    user_id: Uuid,
+    idempotency_key: Option<String>,
+    purchase_req: &PurchaseRequest,
+    geo_country: Option<&str>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if plan_rank(&purchase_req.plan_id).is_none() {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid plan"})));
+    }
+
+    let user = match db::get_user_by_id(pool, user_id).await {
+        Ok(user) => user,
+        Err(e) => {
+            tracing::error!("Database error fetching user: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    // Аккаунт с открытым спором по платежу не может совершать новые покупки
+    if user.as_ref().is_some_and(|u| u.flagged_at.is_some()) {
+        return Ok(HttpResponse::Forbidden().json(json!({"error": "Account is under review"})));
+    }
+    let account_country = user.and_then(|u| u.country);
+    let (currency, currency_source) = match pricing::resolve_currency(
+        purchase_req.currency.as_deref(),
+        account_country.as_deref(),
+        geo_country,
+    ) {
+        Ok(resolved) => resolved,
+        Err(code) => {
+            return Ok(HttpResponse::BadRequest()
+                .json(json!({"error": "Unsupported currency", "currency": code})));
+        }
+    };
+    let (mut amount, mut duration_days) = match plan_terms(&purchase_req.plan_id, currency) {
+        Some(terms) => terms,
+        None => {
+            return Ok(HttpResponse::BadRequest()
+                .json(json!({"error": "Plan is not available in this currency"})));
+        }
+    };
+    // Предложение меняет только первый период; продление — по цене тарифа
+    let offer = match purchase_req.offer_id {
+        Some(offer_id) => match offers::redeemable(pool, user_id, offer_id).await {
+            Ok(Some(offer)) => Some(offer),
+            Ok(None) => {
+                return Ok(
+                    HttpResponse::BadRequest().json(json!({"error": "Offer is not available"}))
+                );
+            }
+            Err(e) => {
+                tracing::error!("Database error fetching offer: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        },
+        None => None,
+    };
+    if let Some((_, arm)) = &offer {
+        match arm.first_period(amount, duration_days) {
+            Ok(terms) => (amount, duration_days) = terms,
+            Err(e) => {
+                tracing::error!("Failed to apply offer {}: {}", arm.key(), e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        }
+    }
+    tracing::debug!(
+        "Purchase currency for user {}: {} ({:?})",
+        user_id,
+        currency,
+        currency_source
+    );
+
+    // У пользователя может быть только одна активная подписка; смена тарифа — через /subscription/change
+    match db::get_active_subscription(pool, user_id).await {
+        Ok(Some(_)) => {
+            return Ok(HttpResponse::Conflict().json(json!({
+                "error": "Active subscription already exists, use /subscription/change",
+            })));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    }
+    match db::get_paused_subscription(pool, user_id).await {
+        Ok(Some(_)) => {
+            return Ok(HttpResponse::Conflict().json(json!({
+                "error": "Subscription is paused, use /subscription/resume",
+            })));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    }
+    // Предыдущая покупка ещё ждёт подтверждения оплаты: вторая списала бы деньги дважды
+    match db::get_awaiting_payment_subscription(pool, user_id).await {
+        Ok(Some(_)) => {
+            return Ok(HttpResponse::Conflict().json(json!({
+                "error": "Previous purchase is awaiting payment confirmation",
+            })));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    }
+    if let Err(e) = db::deactivate_lapsed_subscriptions(pool, user_id).await {
+        tracing::error!("Failed to deactivate lapsed subscriptions: {}", e);
+        return Ok(
+            HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+        );
+    }
+
+    // Бесплатный первый период (пробный): карта только привязывается, списание — при продлении
+    let paid = if amount.is_positive() {
+        let charge = payment::charge_user(
+            pool,
+            gateway,
+            config,
+            &payment::UserCharge {
                 user_id,
-                plan_id: purchase_req.plan_id.clone(),
-                started_at: Utc::now(),
-                expires_at: Utc::now() + chrono::Duration::days(duration_days),
-                is_active: true,
-            };
+                subscription_id: None,
+                kind: "purchase",
+                new_token: Some(&purchase_req.payment_token),
+                amount,
+                description: format!("Subscription: {}", purchase_req.plan_id),
+                geo_country,
+                idempotency_key,
+            },
+        )
+        .await;
+        match charge {
+            Ok((payment, charge))
+                if charge.status == ChargeStatus::Succeeded
+                    || charge.status == ChargeStatus::Pending =>
+            {
+                Some((payment, charge))
+            }
+            Ok((_, charge)) => {
+                tracing::warn!("Charge {} not completed: {:?}", charge.id, charge.status);
+                return Ok(HttpResponse::PaymentRequired().json(json!({"error": "Payment failed"})));
+            }
+            Err(e) => return Ok(payment::error_response(&e)),
+        }
+    } else {
+        if let Err(e) =
+            payment::attach_card(pool, gateway, user_id, &purchase_req.payment_token).await
+        {
+            return Ok(payment::error_response(&e));
+        }
+        None
+    };
+
+    // Оплата ждёт подтверждения (3-D Secure): подписка создаётся неактивной,
+    // её включит вебхук payment_intent.succeeded
+    let awaiting_payment = paid
+        .as_ref()
+        .is_some_and(|(_, charge)| charge.status == ChargeStatus::Pending);
+    let new_subscription = Subscription {
+        id: Uuid::new_v4(),
+        user_id,
+        plan_id: purchase_req.plan_id.clone(),
+        started_at: Utc::now(),
+        expires_at: Utc::now() + chrono::Duration::days(duration_days),
+        is_active: !awaiting_payment,
+        cancel_at_period_end: false,
+        canceled_at: None,
+        auto_renew: true,
+        last_renewal_attempt_at: None,
+        billing_status: if awaiting_payment {
+            "awaiting_payment"
+        } else {
+            "ok"
+        }
+        .to_string(),
+        dunning_started_at: None,
+        dunning_attempts: 0,
+        next_retry_at: None,
+        grace_until: None,
+        expiration_reason: None,
+        scheduled_plan_id: None,
+        paused_at: None,
+        pause_resumes_at: None,
+        currency: currency.code().to_string(),
+        pending_plan_id: None,
+    };
 
-            // Обработка ошибки создания подписки
-            match db::create_subscription(&pool, &new_subscription).await {
-                Ok(()) => Ok(HttpResponse::Ok().json(json!({
-                    "message": "Subscription purchased successfully",
+    // Обработка ошибки создания подписки
+    match db::create_subscription(pool, &new_subscription).await {
+        Ok(()) => {
+            if let Some((payment, _)) = &paid
+                && let Err(e) =
+                    db::link_payment_subscription(pool, payment.id, new_subscription.id).await
+            {
+                tracing::error!("Failed to link payment {}: {}", payment.id, e);
+            }
+            offers::record_conversion(
+                pool,
+                user_id,
+                offer.as_ref().map(|(offer, _)| offer.id),
+                new_subscription.id,
+            )
+            .await;
+            let payment = paid.as_ref().map(|(payment, _)| payment);
+            if awaiting_payment {
+                return Ok(HttpResponse::Accepted().json(json!({
+                    "message": "Payment is awaiting confirmation",
                     "subscription_id": new_subscription.id,
-                    "expires_at": new_subscription.expires_at,
-                }))),
-                Err(e) => {
-                    tracing::error!("Subscription creation error: {}", e);
-                    Ok(HttpResponse::InternalServerError()
-                        .json(json!({"error": "Internal server error"})))
-                }
+                    "payment_id": payment.map(|p| p.id),
+                    "status": "awaiting_payment",
+                })));
             }
+            Ok(HttpResponse::Ok().json(json!({
+                "message": "Subscription purchased successfully",
+                "subscription_id": new_subscription.id,
+                "payment_id": payment.map(|p| p.id),
+                "amount": amount,
+                "tax": payment.and_then(|p| TaxBreakdown::from_payment(p).ok()),
+                "offer": offer.as_ref().map(|(_, arm)| arm),
+                "expires_at": new_subscription.expires_at,
+            })))
+        }
+        // Параллельная покупка успела создать подписку (uniq_subscriptions_active_user)
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            tracing::warn!("Concurrent purchase for user {}, refunding", user_id);
+            if let Some((payment, charge)) = &paid {
+                payment::refund_quietly(pool, gateway, payment.id, &charge.id).await;
+            }
+            Ok(HttpResponse::Conflict()
+                .json(json!({"error": "Active subscription already exists"})))
         }
-        Ok(false) => Ok(HttpResponse::PaymentRequired().json(json!({"error": "Payment failed"}))),
         Err(e) => {
-            tracing::error!("Payment processing error: {}", e);
-            Ok(HttpResponse::InternalServerError()
-                .json(json!({"error": "Payment processing error"})))
+            tracing::error!("Subscription creation error: {}", e);
+            if let Some((payment, charge)) = &paid {
+                payment::refund_quietly(pool, gateway, payment.id, &charge.id).await;
+            }
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
         }
     }
 }
@@ -207,3 +1045,56 @@ pub async fn get_user_profile(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(expires_in: Duration, billing_status: &str) -> Subscription {
+        Subscription {
+            billing_status: billing_status.to_string(),
+            ..Subscription::test_fixture(Utc::now() + expires_in)
+        }
+    }
+
+    #[test]
+    fn grant_without_subscription_is_cacheable() {
+        assert!(outlives_cache(None, Utc::now()));
+    }
+
+    #[test]
+    fn subscription_ending_before_cache_expiry_is_not_cached() {
+        let ending = subscription(Duration::seconds(60), "ok");
+        assert!(!outlives_cache(Some(&ending), Utc::now()));
+        let long = subscription(Duration::days(10), "ok");
+        assert!(outlives_cache(Some(&long), Utc::now()));
+    }
+
+    #[test]
+    fn grace_period_counts_only_when_past_due() {
+        let mut past_due = subscription(-Duration::days(1), "past_due");
+        past_due.grace_until = Some(Utc::now() + Duration::days(3));
+        assert_eq!(past_due.access_until(), past_due.grace_until.unwrap());
+        assert!(outlives_cache(Some(&past_due), Utc::now()));
+
+        // Льготный период истёк: решение не переживёт его
+        past_due.grace_until = Some(Utc::now() + Duration::seconds(30));
+        assert!(!outlives_cache(Some(&past_due), Utc::now()));
+
+        let mut expired = subscription(Duration::seconds(30), "expired");
+        expired.grace_until = Some(Utc::now() + Duration::days(3));
+        assert_eq!(expired.access_until(), expired.expires_at);
+        assert!(!outlives_cache(Some(&expired), Utc::now()));
+    }
+
+    #[test]
+    fn family_plan_grants_premium_content() {
+        assert!(plan_grants("family", "premium"));
+        assert!(plan_grants("family", "basic"));
+        assert!(plan_grants("basic", "free"));
+        assert!(!plan_grants("basic", "premium"));
+        // Неизвестный уровень материала закрыт для всех
+        assert!(!plan_grants("family", "gold"));
+        assert!(plan_rank("family") > plan_rank("premium"));
+    }
+}
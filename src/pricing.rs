@@ -0,0 +1,110 @@
+// src/pricing.rs
+// Региональные цены: выбор валюты покупки. Порядок: явная валюта в запросе,
+// страна аккаунта, страна по IP (локальная база GeoIP), иначе USD.
+use crate::money::Currency;
+use serde::Serialize;
+
+pub const DEFAULT_CURRENCY: Currency = Currency::Usd;
+
+// Страны еврозоны
+const EUR_COUNTRIES: &[&str] = &[
+    "AT", "BE", "CY", "DE", "EE", "ES", "FI", "FR", "GR", "HR", "IE", "IT", "LT", "LU", "LV", "MT",
+    "NL", "PT", "SI", "SK",
+];
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CurrencySource {
+    Subscription, // Валюта действующей подписки
+    Request,
+    Account,
+    Geolocation,
+    Default,
+}
+
+// Код страны в верхнем регистре; None для всего, что не похоже на ISO 3166-1 alpha-2
+pub fn normalize_country(country: &str) -> Option<String> {
+    let country = country.trim();
+    (country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()))
+        .then(|| country.to_ascii_uppercase())
+}
+
+pub fn currency_for_country(country: &str) -> Currency {
+    match country {
+        "GB" => Currency::Gbp,
+        "RU" => Currency::Rub,
+        c if EUR_COUNTRIES.contains(&c) => Currency::Eur,
+        _ => DEFAULT_CURRENCY,
+    }
+}
+
+// Err — в запросе указана неподдерживаемая валюта
+pub fn resolve_currency(
+    requested: Option<&str>,
+    account_country: Option<&str>,
+    geo_country: Option<&str>,
+) -> Result<(Currency, CurrencySource), String> {
+    if let Some(code) = requested {
+        return Currency::from_code(code)
+            .map(|currency| (currency, CurrencySource::Request))
+            .ok_or_else(|| code.to_string());
+    }
+    if let Some(country) = account_country {
+        return Ok((currency_for_country(country), CurrencySource::Account));
+    }
+    if let Some(country) = geo_country {
+        return Ok((currency_for_country(country), CurrencySource::Geolocation));
+    }
+    Ok((DEFAULT_CURRENCY, CurrencySource::Default))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn country_codes_are_normalized() {
+        assert_eq!(normalize_country(" de "), Some("DE".to_string()));
+        assert_eq!(normalize_country("DEU"), None);
+        assert_eq!(normalize_country("1A"), None);
+        assert_eq!(normalize_country(""), None);
+    }
+
+    #[test]
+    fn regional_currencies() {
+        assert_eq!(currency_for_country("DE"), Currency::Eur);
+        assert_eq!(currency_for_country("GB"), Currency::Gbp);
+        assert_eq!(currency_for_country("RU"), Currency::Rub);
+        // Вне еврозоны, хотя и в ЕС
+        assert_eq!(currency_for_country("SE"), Currency::Usd);
+        assert_eq!(currency_for_country("US"), Currency::Usd);
+    }
+
+    #[test]
+    fn currency_resolution_order() {
+        assert_eq!(
+            resolve_currency(Some("gbp"), Some("DE"), Some("RU")),
+            Ok((Currency::Gbp, CurrencySource::Request))
+        );
+        assert_eq!(
+            resolve_currency(None, Some("DE"), Some("RU")),
+            Ok((Currency::Eur, CurrencySource::Account))
+        );
+        assert_eq!(
+            resolve_currency(None, None, Some("RU")),
+            Ok((Currency::Rub, CurrencySource::Geolocation))
+        );
+        assert_eq!(
+            resolve_currency(None, None, None),
+            Ok((Currency::Usd, CurrencySource::Default))
+        );
+    }
+
+    #[test]
+    fn unsupported_requested_currency_is_rejected() {
+        assert_eq!(
+            resolve_currency(Some("JPY"), Some("DE"), None),
+            Err("JPY".to_string())
+        );
+    }
+}
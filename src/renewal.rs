@@ -0,0 +1,500 @@
+// src/renewal.rs
+// Фоновое автопродление подписок с повторными попытками списания (dunning)
+// и плановым возобновлением приостановленных подписок. Безопасно при нескольких инстансах сервиса:
+// цикл выполняет только держатель advisory lock, а строки подписок блокируются
+// через FOR UPDATE SKIP LOCKED.
+use crate::config::Config;
+use crate::db;
+use crate::family;
+use crate::models::Subscription;
+use crate::payment::{self, ChargeStatus, PaymentError, PaymentGateway};
+use crate::paywall;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use moka::future::Cache;
+use serde_json::json;
+use sqlx::{PgConnection, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Ключ advisory lock для лидера цикла продления
+const RENEWAL_LOCK_KEY: i64 = 0x5041_5957_0001;
+// Ограничение на число продлений за один цикл
+const MAX_RENEWALS_PER_CYCLE: usize = 500;
+// Через сколько повторно проверяется списание с неизвестным исходом
+const PENDING_RECHECK_MINUTES: i64 = 60;
+
+// Итог попытки продления
+enum RenewalCharge {
+    Paid,
+    Failed,
+    // Исход неизвестен (ответ pending, сетевой сбой): не считается неудачей
+    Pending,
+}
+
+pub fn spawn_renewal_worker(
+    pool: PgPool,
+    config: Config,
+    cache: Cache<String, serde_json::Value>,
+    gateway: Arc<dyn PaymentGateway>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.renewal_interval_secs));
+        loop {
+            interval.tick().await;
+            match run_renewal_cycle(&pool, &config, &cache, gateway.as_ref()).await {
+                Ok(0) => {}
+                Ok(renewed) => tracing::info!("Renewal cycle finished: {} renewed", renewed),
+                Err(e) => tracing::error!("Renewal cycle error: {}", e),
+            }
+        }
+    });
+}
+
+async fn run_renewal_cycle(
+    pool: &PgPool,
+    config: &Config,
+    cache: &Cache<String, serde_json::Value>,
+    gateway: &dyn PaymentGateway,
+) -> Result<usize, sqlx::Error> {
+    // Блокировка уровня сессии: держим соединение до конца цикла
+    let mut lock_conn = pool.acquire().await?;
+    let is_leader: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+        .bind(RENEWAL_LOCK_KEY)
+        .fetch_one(&mut *lock_conn)
+        .await?;
+    if !is_leader {
+        tracing::debug!("Renewal cycle skipped: another instance holds the lock");
+        return Ok(0);
+    }
+
+    let result = async {
+        resume_due_pauses(pool, cache).await?;
+        payment::retry_pending_refunds(pool, gateway).await?;
+        expire_stale_renewals(pool, config, cache).await?;
+        family::end_lapsed_memberships(pool, cache).await?;
+        renew_due_subscriptions(pool, config, cache, gateway).await
+    }
+    .await;
+
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(RENEWAL_LOCK_KEY)
+        .execute(&mut *lock_conn)
+        .await?;
+
+    result
+}
+
+// Плановое возобновление приостановленных подписок
+async fn resume_due_pauses(
+    pool: &PgPool,
+    cache: &Cache<String, serde_json::Value>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let resumed = db::resume_subscriptions(&mut tx, None).await?;
+    for subscription in &resumed {
+        db::create_notification(
+            &mut tx,
+            subscription.user_id,
+            "subscription_resumed",
+            &json!({"subscription_id": subscription.id, "expires_at": subscription.expires_at}),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    for subscription in &resumed {
+        paywall::invalidate_user_cache(cache, subscription.user_id);
+        tracing::info!(
+            "Subscription {} resumed, expires at {}",
+            subscription.id,
+            subscription.expires_at
+        );
+    }
+    Ok(())
+}
+
+// Подписки, продление которых пропущено дольше льготного периода
+async fn expire_stale_renewals(
+    pool: &PgPool,
+    config: &Config,
+    cache: &Cache<String, serde_json::Value>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let expired =
+        db::expire_stale_renewals(&mut tx, max_overdue_days(&config.dunning_retry_days)).await?;
+    for subscription in &expired {
+        db::create_notification(
+            &mut tx,
+            subscription.user_id,
+            "subscription_expired",
+            &json!({"subscription_id": subscription.id, "reason": "lapsed"}),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    for subscription in &expired {
+        paywall::invalidate_user_cache(cache, subscription.user_id);
+        tracing::warn!(
+            "Subscription {} expired without renewal: overdue since {}",
+            subscription.id,
+            subscription.expires_at
+        );
+    }
+    Ok(())
+}
+
+async fn renew_due_subscriptions(
+    pool: &PgPool,
+    config: &Config,
+    cache: &Cache<String, serde_json::Value>,
+    gateway: &dyn PaymentGateway,
+) -> Result<usize, sqlx::Error> {
+    let mut renewed = 0;
+
+    for _ in 0..MAX_RENEWALS_PER_CYCLE {
+        let mut tx = pool.begin().await?;
+        let subscription = match db::lock_next_renewal_candidate(
+            &mut tx,
+            config.renewal_lead_hours,
+            max_overdue_days(&config.dunning_retry_days),
+        )
+        .await?
+        {
+            Some(sub) => sub,
+            None => break,
+        };
+
+        let renewal_plan = renewal_plan(&subscription).to_string();
+
+        // Строка остаётся заблокированной на время списания
+        match charge_renewal(pool, gateway, config, &subscription, &renewal_plan).await? {
+            RenewalCharge::Paid => {
+                let duration_days = paywall::plan_period_days(&renewal_plan).unwrap_or_default();
+                let expires_at =
+                    db::extend_subscription(&mut tx, subscription.id, &renewal_plan, duration_days)
+                        .await?;
+                if subscription.billing_status == "past_due" {
+                    db::create_notification(
+                        &mut tx,
+                        subscription.user_id,
+                        "renewal_recovered",
+                        &json!({"subscription_id": subscription.id, "expires_at": expires_at}),
+                    )
+                    .await?;
+                }
+                tx.commit().await?;
+                renewed += 1;
+                tracing::info!(
+                    "Subscription {} renewed until {}",
+                    subscription.id,
+                    expires_at
+                );
+            }
+            RenewalCharge::Pending => {
+                // Позже — проверка статуса или повтор с тем же ключом; вебхук может завершить раньше
+                let recheck_at = Utc::now() + ChronoDuration::minutes(PENDING_RECHECK_MINUTES);
+                db::defer_renewal(&mut tx, subscription.id, recheck_at).await?;
+                tx.commit().await?;
+                tracing::info!(
+                    "Renewal of subscription {} is pending, rechecking at {}",
+                    subscription.id,
+                    recheck_at
+                );
+            }
+            RenewalCharge::Failed => {
+                handle_failed_renewal(&mut tx, config, &subscription).await?;
+                tx.commit().await?;
+            }
+        }
+        // Флаг проблемы с оплатой в ответе get_content должен обновиться
+        paywall::invalidate_user_cache(cache, subscription.user_id);
+    }
+
+    Ok(renewed)
+}
+
+// Запланированное понижение тарифа применяется со следующего периода
+fn renewal_plan(subscription: &Subscription) -> &str {
+    subscription
+        .scheduled_plan_id
+        .as_deref()
+        .unwrap_or(&subscription.plan_id)
+}
+
+// Ключ идемпотентности попытки: один на период и номер попытки. Повтор после
+// неизвестного исхода идёт с тем же ключом и не спишет второй раз; новая попытка
+// по расписанию — только после окончательного отказа
+pub fn renewal_key(subscription: &Subscription) -> String {
+    let attempt = if subscription.billing_status == "past_due" {
+        subscription.dunning_attempts
+    } else {
+        0
+    };
+    format!(
+        "renewal:{}:{}:{}",
+        subscription.id,
+        subscription.expires_at.timestamp(),
+        attempt
+    )
+}
+
+// Попытка списания за следующий период
+async fn charge_renewal(
+    pool: &PgPool,
+    gateway: &dyn PaymentGateway,
+    config: &Config,
+    subscription: &Subscription,
+    plan_id: &str,
+) -> Result<RenewalCharge, sqlx::Error> {
+    // Продление — в валюте, зафиксированной при покупке
+    let terms = subscription
+        .billing_currency()
+        .ok()
+        .and_then(|currency| paywall::plan_terms(plan_id, currency));
+    let (amount, _) = match terms {
+        Some(terms) => terms,
+        None => {
+            tracing::warn!(
+                "Subscription {} has no price for plan {} in {}, cannot renew",
+                subscription.id,
+                plan_id,
+                subscription.currency
+            );
+            return Ok(RenewalCharge::Failed);
+        }
+    };
+
+    let charge = payment::charge_user(
+        pool,
+        gateway,
+        config,
+        &payment::UserCharge {
+            user_id: subscription.user_id,
+            subscription_id: Some(subscription.id),
+            kind: "renewal",
+            new_token: None,
+            amount,
+            description: format!("Renewal: {}", plan_id),
+            geo_country: None,
+            idempotency_key: Some(renewal_key(subscription)),
+        },
+    )
+    .await;
+
+    match charge {
+        Ok((_, charge)) if charge.status == ChargeStatus::Succeeded => Ok(RenewalCharge::Paid),
+        Ok((_, charge)) if charge.status == ChargeStatus::Pending => Ok(RenewalCharge::Pending),
+        Ok((_, charge)) => {
+            tracing::warn!(
+                "Renewal charge {} for subscription {} not completed: {:?}",
+                charge.id,
+                subscription.id,
+                charge.status
+            );
+            Ok(RenewalCharge::Failed)
+        }
+        Err(PaymentError::Database(e)) => Err(e),
+        Err(e) if e.is_outcome_unknown() => {
+            tracing::warn!(
+                "Renewal payment outcome unknown for subscription {}: {}",
+                subscription.id,
+                e
+            );
+            Ok(RenewalCharge::Pending)
+        }
+        Err(e) => {
+            tracing::warn!(
+                "Renewal payment failed for subscription {}: {}",
+                subscription.id,
+                e
+            );
+            Ok(RenewalCharge::Failed)
+        }
+    }
+}
+
+// Переход по расписанию повторных попыток: past_due -> ... -> expired
+async fn handle_failed_renewal(
+    conn: &mut PgConnection,
+    config: &Config,
+    subscription: &Subscription,
+) -> Result<(), sqlx::Error> {
+    let schedule = &config.dunning_retry_days;
+
+    if subscription.billing_status != "past_due" {
+        let started_at = Utc::now();
+        if let Some(next_retry_at) = next_retry_at(schedule, started_at, 1) {
+            let grace_until = grace_until(schedule, subscription.expires_at, started_at);
+            db::start_dunning(conn, subscription.id, next_retry_at, grace_until).await?;
+            db::create_notification(
+                conn,
+                subscription.user_id,
+                "renewal_failed",
+                &json!({
+                    "subscription_id": subscription.id,
+                    "next_retry_at": next_retry_at,
+                    "grace_until": grace_until,
+                }),
+            )
+            .await?;
+            tracing::info!(
+                "Subscription {} entered grace period until {}",
+                subscription.id,
+                grace_until
+            );
+            return Ok(());
+        }
+    } else if let Some(started_at) = subscription.dunning_started_at {
+        let failures = subscription.dunning_attempts + 1;
+        if let Some(next_retry_at) = next_retry_at(schedule, started_at, failures) {
+            db::record_dunning_failure(conn, subscription.id, next_retry_at).await?;
+            db::create_notification(
+                conn,
+                subscription.user_id,
+                "renewal_retry_failed",
+                &json!({
+                    "subscription_id": subscription.id,
+                    "attempt": failures,
+                    "next_retry_at": next_retry_at,
+                    "grace_until": subscription.grace_until,
+                }),
+            )
+            .await?;
+            return Ok(());
+        }
+    }
+
+    // Оплаченное время и льготный период не отнимаются
+    let access_until = db::end_subscription_after_failed_renewal(conn, subscription.id).await?;
+    db::create_notification(
+        conn,
+        subscription.user_id,
+        "subscription_expired",
+        &json!({
+            "subscription_id": subscription.id,
+            "reason": "payment_failed",
+            "access_until": access_until,
+        }),
+    )
+    .await?;
+    tracing::info!(
+        "Subscription {} will not renew after failed attempts, access until {}",
+        subscription.id,
+        access_until
+    );
+    Ok(())
+}
+
+// Льготный период покрывает все попытки и ещё сутки на работу воркера
+fn grace_until(
+    schedule: &[i64],
+    expires_at: DateTime<Utc>,
+    started_at: DateTime<Utc>,
+) -> DateTime<Utc> {
+    expires_at.max(started_at + ChronoDuration::days(max_overdue_days(schedule)))
+}
+
+// Самый длинный льготный период: день последней попытки и ещё сутки. Дольше
+// просроченное продление уже не выполняется
+fn max_overdue_days(schedule: &[i64]) -> i64 {
+    schedule.iter().copied().max().unwrap_or(0) + 1
+}
+
+// Время следующей попытки после `failures` неудач; None, если попытки исчерпаны
+fn next_retry_at(
+    schedule: &[i64],
+    started_at: DateTime<Utc>,
+    failures: i32,
+) -> Option<DateTime<Utc>> {
+    let index = usize::try_from(failures - 1).ok()?;
+    schedule
+        .get(index)
+        .map(|days| started_at + ChronoDuration::days(*days))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(billing_status: &str, dunning_attempts: i32) -> Subscription {
+        Subscription {
+            billing_status: billing_status.to_string(),
+            dunning_attempts,
+            ..Subscription::test_fixture(Utc::now())
+        }
+    }
+
+    #[test]
+    fn retries_follow_schedule_from_first_failure() {
+        let schedule = [1, 3, 5, 7];
+        let started_at = Utc::now();
+        for (failures, days) in [(1, 1), (2, 3), (3, 5), (4, 7)] {
+            assert_eq!(
+                next_retry_at(&schedule, started_at, failures),
+                Some(started_at + ChronoDuration::days(days))
+            );
+        }
+    }
+
+    #[test]
+    fn retries_end_when_schedule_is_exhausted() {
+        let schedule = [1, 3, 5, 7];
+        let started_at = Utc::now();
+        assert_eq!(next_retry_at(&schedule, started_at, 5), None);
+        // До первой неудачи повторной попытки нет
+        assert_eq!(next_retry_at(&schedule, started_at, 0), None);
+        assert_eq!(next_retry_at(&[], started_at, 1), None);
+    }
+
+    #[test]
+    fn grace_period_covers_last_retry() {
+        let started_at = Utc::now();
+        let grace = grace_until(&[1, 3, 5, 7], started_at, started_at);
+        assert_eq!(grace, started_at + ChronoDuration::days(8));
+        // Оплаченное время не сокращается
+        let expires_at = started_at + ChronoDuration::days(30);
+        assert_eq!(grace_until(&[1, 3], expires_at, started_at), expires_at);
+    }
+
+    #[test]
+    fn overdue_renewals_are_attempted_only_within_grace() {
+        assert_eq!(max_overdue_days(&[1, 3, 5, 7]), 8);
+        assert_eq!(max_overdue_days(&[7, 1]), 8);
+        assert_eq!(max_overdue_days(&[]), 1);
+    }
+
+    #[test]
+    fn renewal_key_changes_with_each_dunning_attempt() {
+        let mut past_due = subscription("past_due", 1);
+        let first = renewal_key(&past_due);
+        past_due.dunning_attempts = 2;
+        assert_ne!(renewal_key(&past_due), first);
+        // Вне dunning номер попытки не участвует в ключе
+        let ok = subscription("ok", 3);
+        assert!(renewal_key(&ok).ends_with(":0"));
+    }
+
+    #[test]
+    fn renewal_uses_scheduled_plan() {
+        let mut sub = subscription("ok", 0);
+        assert_eq!(renewal_plan(&sub), "basic");
+        sub.scheduled_plan_id = Some("premium".to_string());
+        assert_eq!(renewal_plan(&sub), "premium");
+    }
+
+    #[test]
+    fn renewal_key_is_stable_within_period() {
+        let mut sub = subscription("past_due", 1);
+        let first = renewal_key(&sub);
+        // Смена тарифа не меняет ключ: повтор после неизвестного исхода не спишет второй раз
+        sub.plan_id = "premium".to_string();
+        sub.scheduled_plan_id = Some("basic".to_string());
+        assert_eq!(renewal_key(&sub), first);
+        sub.dunning_attempts = 2;
+        assert_ne!(renewal_key(&sub), first);
+        sub.dunning_attempts = 1;
+        sub.expires_at += ChronoDuration::days(30);
+        assert_ne!(renewal_key(&sub), first);
+    }
+}
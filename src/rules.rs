@@ -0,0 +1,1199 @@
+// src/rules.rs
+// Правила доступа к контенту. Продукт описывает упорядоченный список правил в
+// JSON-файле (PAYWALL_RULES_PATH): первое правило, условия которого выполнены,
+// определяет исход — выдать материал, открыть по счётчику, показать анонс,
+// стену регистрации, предложение или жёсткую стену. Файл перечитывается при
+// изменении; файл с ошибками не применяется, остаются прежние правила.
+// Без файла действуют встроенные правила, повторяющие прежнюю логику.
+use crate::auth;
+use crate::config::Config;
+use crate::db;
+use crate::decisions::DecisionRecord;
+use crate::experiments::ExperimentRegistry;
+use crate::institutions;
+use crate::ml;
+use crate::offers;
+use crate::paywall::{self, Decision, Viewer};
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse, get, post, web};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+const ENTITLEMENT_SOURCES: &[&str] = &["personal", "family", "organization", "institution"];
+const DEVICES: &[&str] = &["mobile", "tablet", "desktop", "bot"];
+const WEEKDAYS: &[&str] = &["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MAX_TEASER_CHARS: usize = 5000;
+const MAX_RULES: usize = 200;
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_rules);
+    cfg.service(reload_rules);
+    cfg.service(dry_run);
+}
+
+// Условие правила: все заданные поля должны выполняться (И).
+// any — хотя бы одно из вложенных условий, not — вложенное условие не выполнено
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Condition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logged_in: Option<bool>,
+    // Личный тариф пользователя; "none" — без подписки
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plan: Option<Vec<String>>,
+    // Есть доступ к уровню материала хотя бы по одному источнику
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entitled: Option<bool>,
+    // Источник доступа: personal | family | organization | institution
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entitlement: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub institution: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_plan: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_tags_any: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_tags_all: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_age_hours_lt: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_age_hours_gte: Option<i64>,
+    // Хосты Referer ("news.google.com", "*.facebook.com"); "none" — без Referer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Vec<String>>,
+    // Платных материалов, открытых по счётчику за окно
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meter_views_lt: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meter_views_gte: Option<i64>,
+    // Материал уже открывался в окне счётчика
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub already_viewed: Option<bool>,
+    // Прогноз модели: пользователь склонен к покупке (для анонимных — false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ml_positive: Option<bool>,
+    // Часы по UTC [с, до); "с" больше "до" — интервал через полночь
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hours_utc: Option<[u32; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weekdays: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub any: Option<Vec<Condition>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not: Option<Box<Condition>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Grant,
+    // Выдать с учётом просмотра в счётчике; limit — для показа остатка
+    Meter {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<i64>,
+    },
+    Teaser {
+        #[serde(default = "default_teaser_chars")]
+        chars: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    Regwall {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<i64>,
+    },
+    Offer {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    HardWall {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+}
+
+fn default_teaser_chars() -> usize {
+    crate::visitors::TEASER_CHARS
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Grant => "grant",
+            Outcome::Meter { .. } => "meter",
+            Outcome::Teaser { .. } => "teaser",
+            Outcome::Regwall { .. } => "regwall",
+            Outcome::Offer { .. } => "offer",
+            Outcome::HardWall { .. } => "hard_wall",
+        }
+    }
+
+    pub fn grants_access(&self) -> bool {
+        matches!(self, Outcome::Grant | Outcome::Meter { .. })
+    }
+
+    // Ответ содержит остаток счётчика конкретного посетителя и должен засчитать просмотр
+    pub fn is_metered(&self) -> bool {
+        matches!(self, Outcome::Meter { .. } | Outcome::Regwall { .. })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default)]
+    pub when: Condition,
+    pub then: Outcome,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSet {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub rules: Vec<Rule>,
+}
+
+// Всё, что известно о запросе на момент решения
+#[derive(Serialize, Clone, Debug)]
+pub struct Facts {
+    pub logged_in: bool,
+    pub plan: Option<String>,
+    pub entitlements: Vec<&'static str>, // В порядке personal, family, organization, institution
+    pub institution: bool,
+    pub content_plan: String,
+    pub content_tags: Vec<String>,
+    pub content_age_hours: i64,
+    pub referrer: Option<String>,
+    pub device: &'static str,
+    pub meter_views: i64,
+    pub already_viewed: bool,
+    pub ml_positive: Option<bool>, // None — модель ещё не вызывалась
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RuleTrace {
+    pub rule: String,
+    pub matched: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Evaluation {
+    pub rule: Option<String>, // None — ни одно правило не подошло
+    pub outcome: Outcome,
+    // Решение зависит от счётчика, Referer, устройства или времени и не кешируется
+    pub volatile: bool,
+    pub trace: Vec<RuleTrace>,
+}
+
+impl Condition {
+    // None — для ответа нужен прогноз модели, которого ещё нет.
+    // Прогноз проверяется последним, чтобы не вызывать модель без необходимости
+    fn matches(&self, facts: &Facts, volatile: &mut bool) -> Option<bool> {
+        if let Some(expected) = self.logged_in
+            && facts.logged_in != expected
+        {
+            return Some(false);
+        }
+        if let Some(plans) = &self.plan {
+            let plan = facts.plan.as_deref().unwrap_or("none");
+            if !plans.iter().any(|p| p == plan) {
+                return Some(false);
+            }
+        }
+        if let Some(expected) = self.entitled
+            && facts.entitlements.is_empty() == expected
+        {
+            return Some(false);
+        }
+        if let Some(sources) = &self.entitlement
+            && !facts
+                .entitlements
+                .iter()
+                .any(|source| sources.iter().any(|s| s == source))
+        {
+            return Some(false);
+        }
+        if let Some(expected) = self.institution
+            && facts.institution != expected
+        {
+            return Some(false);
+        }
+        if let Some(plans) = &self.content_plan
+            && !plans.contains(&facts.content_plan)
+        {
+            return Some(false);
+        }
+        if let Some(tags) = &self.content_tags_any
+            && !tags.iter().any(|tag| facts.content_tags.contains(tag))
+        {
+            return Some(false);
+        }
+        if let Some(tags) = &self.content_tags_all
+            && !tags.iter().all(|tag| facts.content_tags.contains(tag))
+        {
+            return Some(false);
+        }
+        if self.content_age_hours_lt.is_some() || self.content_age_hours_gte.is_some() {
+            *volatile = true;
+            if self
+                .content_age_hours_lt
+                .is_some_and(|hours| facts.content_age_hours >= hours)
+                || self
+                    .content_age_hours_gte
+                    .is_some_and(|hours| facts.content_age_hours < hours)
+            {
+                return Some(false);
+            }
+        }
+        if let Some(patterns) = &self.referrer {
+            *volatile = true;
+            let matched = match &facts.referrer {
+                Some(host) => patterns
+                    .iter()
+                    .any(|pattern| institutions::host_matches(pattern, host)),
+                None => patterns.iter().any(|pattern| pattern == "none"),
+            };
+            if !matched {
+                return Some(false);
+            }
+        }
+        if let Some(devices) = &self.device {
+            *volatile = true;
+            if !devices.iter().any(|d| d == facts.device) {
+                return Some(false);
+            }
+        }
+        if self.meter_views_lt.is_some()
+            || self.meter_views_gte.is_some()
+            || self.already_viewed.is_some()
+        {
+            *volatile = true;
+            if self
+                .meter_views_lt
+                .is_some_and(|limit| facts.meter_views >= limit)
+                || self
+                    .meter_views_gte
+                    .is_some_and(|limit| facts.meter_views < limit)
+                || self
+                    .already_viewed
+                    .is_some_and(|expected| facts.already_viewed != expected)
+            {
+                return Some(false);
+            }
+        }
+        if self.hours_utc.is_some()
+            || self.weekdays.is_some()
+            || self.after.is_some()
+            || self.before.is_some()
+        {
+            *volatile = true;
+            if !self.matches_time(facts.at) {
+                return Some(false);
+            }
+        }
+        if let Some(any) = &self.any {
+            let mut matched = false;
+            for condition in any {
+                if condition.matches(facts, volatile)? {
+                    matched = true;
+                    break;
+                }
+            }
+            if !matched {
+                return Some(false);
+            }
+        }
+        if let Some(not) = &self.not
+            && not.matches(facts, volatile)?
+        {
+            return Some(false);
+        }
+        if let Some(expected) = self.ml_positive {
+            match facts.ml_positive {
+                None => return None,
+                Some(positive) if positive != expected => return Some(false),
+                Some(_) => {}
+            }
+        }
+        Some(true)
+    }
+
+    fn matches_time(&self, at: DateTime<Utc>) -> bool {
+        if let Some([from, to]) = self.hours_utc {
+            let hour = at.hour();
+            let inside = if from < to {
+                from <= hour && hour < to
+            } else {
+                hour >= from || hour < to
+            };
+            if !inside {
+                return false;
+            }
+        }
+        if let Some(days) = &self.weekdays {
+            let today = at.weekday().to_string().to_ascii_lowercase();
+            if !days.contains(&today) {
+                return false;
+            }
+        }
+        self.after.is_none_or(|after| at >= after) && self.before.is_none_or(|before| at < before)
+    }
+
+    fn validate(&self, path: &str, problems: &mut Vec<String>) {
+        let mut check_values = |field: &str, values: &Option<Vec<String>>, allowed: &[&str]| {
+            if let Some(values) = values {
+                for value in values {
+                    if !allowed.contains(&value.as_str()) {
+                        problems.push(format!("{}: unknown {} '{}'", path, field, value));
+                    }
+                }
+            }
+        };
+        let plans: Vec<&str> = paywall::PLANS.iter().copied().chain(["none"]).collect();
+        check_values("plan", &self.plan, &plans);
+        let content_plans: Vec<&str> = ["free"]
+            .into_iter()
+            .chain(paywall::PLANS.iter().copied())
+            .collect();
+        check_values("content_plan", &self.content_plan, &content_plans);
+        check_values("entitlement", &self.entitlement, ENTITLEMENT_SOURCES);
+        check_values("device", &self.device, DEVICES);
+        check_values("weekday", &self.weekdays, WEEKDAYS);
+
+        if let Some([from, to]) = self.hours_utc
+            && (from > 23 || to > 24 || from == to)
+        {
+            problems.push(format!(
+                "{}: hours_utc must be [from, to) within 0..24",
+                path
+            ));
+        }
+        if let (Some(after), Some(before)) = (self.after, self.before)
+            && after >= before
+        {
+            problems.push(format!("{}: after must be earlier than before", path));
+        }
+        if let Some(any) = &self.any {
+            if any.is_empty() {
+                problems.push(format!("{}: any must not be empty", path));
+            }
+            for (i, condition) in any.iter().enumerate() {
+                condition.validate(&format!("{}.any[{}]", path, i), problems);
+            }
+        }
+        if let Some(not) = &self.not {
+            not.validate(&format!("{}.not", path), problems);
+        }
+    }
+}
+
+impl RuleSet {
+    // Встроенные правила: прежняя логика get_content, счётчик анонимных посетителей из конфигурации
+    pub fn defaults(config: &Config) -> RuleSet {
+        let free_views = config.anonymous_free_views.max(0);
+        let rule = |name: &str, when: Condition, then: Outcome| Rule {
+            name: name.to_string(),
+            when,
+            then,
+        };
+        RuleSet {
+            version: Some("builtin".to_string()),
+            rules: vec![
+                rule(
+                    "free-content",
+                    Condition {
+                        content_plan: Some(vec!["free".to_string()]),
+                        ..Condition::default()
+                    },
+                    Outcome::Grant,
+                ),
+                rule(
+                    "entitled",
+                    Condition {
+                        entitled: Some(true),
+                        ..Condition::default()
+                    },
+                    Outcome::Grant,
+                ),
+                rule(
+                    "anonymous-meter",
+                    Condition {
+                        logged_in: Some(false),
+                        any: Some(vec![
+                            Condition {
+                                already_viewed: Some(true),
+                                ..Condition::default()
+                            },
+                            Condition {
+                                meter_views_lt: Some(free_views),
+                                ..Condition::default()
+                            },
+                        ]),
+                        ..Condition::default()
+                    },
+                    Outcome::Meter {
+                        limit: Some(free_views),
+                    },
+                ),
+                rule(
+                    "anonymous-regwall",
+                    Condition {
+                        logged_in: Some(false),
+                        ..Condition::default()
+                    },
+                    Outcome::Regwall {
+                        message: None,
+                        limit: Some(free_views),
+                    },
+                ),
+                rule(
+                    "ml-offer",
+                    Condition {
+                        ml_positive: Some(true),
+                        ..Condition::default()
+                    },
+                    Outcome::Offer { message: None },
+                ),
+                rule(
+                    "upgrade",
+                    Condition::default(),
+                    Outcome::HardWall { message: None },
+                ),
+            ],
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<RuleSet, String> {
+        let rules: RuleSet = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if self.rules.is_empty() {
+            problems.push("rules must not be empty".to_string());
+        }
+        if self.rules.len() > MAX_RULES {
+            problems.push(format!("at most {} rules are allowed", MAX_RULES));
+        }
+        let mut names = HashSet::new();
+        for rule in &self.rules {
+            if rule.name.trim().is_empty() {
+                problems.push("rule name must not be empty".to_string());
+            } else if !names.insert(rule.name.as_str()) {
+                problems.push(format!("duplicate rule name '{}'", rule.name));
+            }
+            rule.when.validate(&rule.name, &mut problems);
+            match &rule.then {
+                Outcome::Teaser { chars, .. } if *chars > MAX_TEASER_CHARS => {
+                    problems.push(format!(
+                        "{}: teaser chars must be at most {}",
+                        rule.name, MAX_TEASER_CHARS
+                    ))
+                }
+                Outcome::Meter { limit: Some(limit) }
+                | Outcome::Regwall {
+                    limit: Some(limit), ..
+                } if *limit < 0 => {
+                    problems.push(format!("{}: limit must not be negative", rule.name));
+                }
+                _ => {}
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+
+    // None — одно из проверенных условий требует прогноза модели (см. paywall::decide)
+    pub fn evaluate(&self, facts: &Facts) -> Option<Evaluation> {
+        let mut volatile = false;
+        let mut trace = Vec::new();
+        for rule in &self.rules {
+            let matched = rule.when.matches(facts, &mut volatile)?;
+            trace.push(RuleTrace {
+                rule: rule.name.clone(),
+                matched,
+            });
+            if matched {
+                return Some(Evaluation {
+                    rule: Some(rule.name.clone()),
+                    outcome: rule.then.clone(),
+                    volatile: volatile || rule.then.is_metered(),
+                    trace,
+                });
+            }
+        }
+        Some(Evaluation {
+            rule: None,
+            outcome: Outcome::HardWall { message: None },
+            volatile,
+            trace,
+        })
+    }
+}
+
+// Данные запроса, не зависящие от пользователя
+pub struct RequestContext {
+    pub referrer: Option<String>,
+    pub device: &'static str,
+    pub at: DateTime<Utc>,
+}
+
+impl RequestContext {
+    pub fn from_request(req: &HttpRequest) -> RequestContext {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        RequestContext {
+            referrer: institutions::referrer_host(req),
+            device: device_from_user_agent(user_agent),
+            at: Utc::now(),
+        }
+    }
+}
+
+// Грубая классификация по User-Agent: для правил достаточно класса устройства
+pub fn device_from_user_agent(user_agent: &str) -> &'static str {
+    let ua = user_agent.to_ascii_lowercase();
+    if ["bot", "crawler", "spider", "slurp"]
+        .iter()
+        .any(|marker| ua.contains(marker))
+    {
+        "bot"
+    } else if ua.contains("ipad")
+        || ua.contains("tablet")
+        || (ua.contains("android") && !ua.contains("mobile"))
+    {
+        "tablet"
+    } else if ua.contains("mobi") || ua.contains("iphone") || ua.contains("android") {
+        "mobile"
+    } else {
+        "desktop"
+    }
+}
+
+#[derive(Debug)]
+pub struct LoadedRules {
+    pub rules: RuleSet,
+    pub source: &'static str, // builtin | file | candidate (dry-run) | experiment
+    pub loaded_at: DateTime<Utc>,
+    modified: Option<SystemTime>,
+}
+
+impl LoadedRules {
+    // Набор правил не из файла: кандидат dry-run или вариант эксперимента
+    pub fn new(rules: RuleSet, source: &'static str) -> LoadedRules {
+        LoadedRules {
+            rules,
+            source,
+            loaded_at: Utc::now(),
+            modified: None,
+        }
+    }
+}
+
+struct EngineState {
+    current: Arc<LoadedRules>,
+    last_error: Option<String>,
+    failed_modified: Option<SystemTime>, // Версия файла с ошибкой, чтобы не разбирать её повторно
+}
+
+pub struct RuleEngine {
+    path: Option<String>,
+    state: RwLock<EngineState>,
+}
+
+impl RuleEngine {
+    pub fn from_config(config: &Config) -> RuleEngine {
+        let engine = RuleEngine {
+            path: config
+                .paywall_rules_path
+                .clone()
+                .filter(|path| !path.trim().is_empty()),
+            state: RwLock::new(EngineState {
+                current: Arc::new(LoadedRules {
+                    rules: RuleSet::defaults(config),
+                    source: "builtin",
+                    loaded_at: Utc::now(),
+                    modified: None,
+                }),
+                last_error: None,
+                failed_modified: None,
+            }),
+        };
+        if let Err(e) = engine.reload(true) {
+            tracing::error!("Failed to load paywall rules, using built-in rules: {}", e);
+        }
+        engine
+    }
+
+    pub fn current(&self) -> Arc<LoadedRules> {
+        match self.state.read() {
+            Ok(state) => state.current.clone(),
+            Err(poisoned) => poisoned.into_inner().current.clone(),
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut EngineState) -> T) -> T {
+        match self.state.write() {
+            Ok(mut state) => f(&mut state),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.with_state(|state| state.last_error.clone())
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    // Ok(true) — правила заменены; без force неизменённый файл не перечитывается
+    pub fn reload(&self, force: bool) -> Result<bool, String> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let modified = std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .map_err(|e| format!("{}: {}", path, e));
+        let modified = match modified {
+            Ok(modified) => modified,
+            Err(e) => return Err(self.fail(e, None)),
+        };
+        let unchanged = self.with_state(|state| {
+            state.current.modified == Some(modified) || state.failed_modified == Some(modified)
+        });
+        if unchanged && !force {
+            return Ok(false);
+        }
+
+        let parsed = std::fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path, e))
+            .and_then(|text| RuleSet::parse(&text));
+        match parsed {
+            Ok(rules) => {
+                self.with_state(|state| {
+                    state.current = Arc::new(LoadedRules {
+                        rules,
+                        source: "file",
+                        loaded_at: Utc::now(),
+                        modified: Some(modified),
+                    });
+                    state.last_error = None;
+                    state.failed_modified = None;
+                });
+                Ok(true)
+            }
+            Err(e) => Err(self.fail(e, Some(modified))),
+        }
+    }
+
+    fn fail(&self, error: String, modified: Option<SystemTime>) -> String {
+        self.with_state(|state| {
+            state.last_error = Some(error.clone());
+            state.failed_modified = modified;
+        });
+        error
+    }
+}
+
+// Новые правила меняют решения, поэтому кеш решений сбрасывается целиком
+pub fn spawn_rules_reload_worker(
+    engine: web::Data<RuleEngine>,
+    cache: Cache<String, serde_json::Value>,
+    interval_secs: u64,
+) {
+    if engine.path().is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            match engine.reload(false) {
+                Ok(true) => {
+                    cache.invalidate_all();
+                    tracing::info!(
+                        "Reloaded paywall rules ({} rules)",
+                        engine.current().rules.rules.len()
+                    );
+                }
+                Ok(false) => {}
+                Err(e) => tracing::error!("Invalid paywall rules, keeping previous: {}", e),
+            }
+        }
+    });
+}
+
+fn rules_json(engine: &RuleEngine) -> serde_json::Value {
+    let current = engine.current();
+    json!({
+        "source": current.source,
+        "path": engine.path(),
+        "loaded_at": current.loaded_at,
+        "last_error": engine.last_error(),
+        "version": current.rules.version,
+        "rules": current.rules.rules,
+    })
+}
+
+#[get("/admin/rules")]
+pub async fn get_rules(
+    pool: web::Data<sqlx::PgPool>,
+    engine: web::Data<RuleEngine>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_staff(&pool, &req).await {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(rules_json(&engine)))
+}
+
+#[post("/admin/rules/reload")]
+pub async fn reload_rules(
+    pool: web::Data<sqlx::PgPool>,
+    engine: web::Data<RuleEngine>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let staff_id = match auth::require_staff(&pool, &req).await {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    if engine.path().is_none() {
+        return Ok(HttpResponse::Conflict()
+            .json(json!({"error": "No rules file configured (PAYWALL_RULES_PATH)"})));
+    }
+
+    match engine.reload(true) {
+        Ok(_) => {
+            cache.invalidate_all();
+            let current = engine.current();
+            let details = json!({
+                "version": current.rules.version,
+                "rule_count": current.rules.rules.len(),
+            });
+            if let Err(e) =
+                db::record_admin_action(&pool, staff_id, "rules_reloaded", None, None, &details)
+                    .await
+            {
+                tracing::warn!("Failed to record admin action: {}", e);
+            }
+            Ok(HttpResponse::Ok().json(rules_json(&engine)))
+        }
+        Err(e) => {
+            tracing::error!("Rules reload failed: {}", e);
+            Ok(HttpResponse::UnprocessableEntity()
+                .json(json!({"error": "Invalid rules file", "details": e})))
+        }
+    }
+}
+
+// Проверка правил на паре пользователь/материал без побочных эффектов.
+// rules — необязательный набор-кандидат вместо действующего; без него
+// пользователь получает правила своего варианта идущего эксперимента
+#[derive(Deserialize)]
+pub struct DryRunRequest {
+    pub content_id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub at: Option<DateTime<Utc>>,
+    pub meter_views: Option<i64>,
+    pub already_viewed: Option<bool>,
+    pub rules: Option<RuleSet>,
+}
+
+#[post("/admin/rules/dry-run")]
+pub async fn dry_run(
+    pool: web::Data<sqlx::PgPool>,
+    ml_model: web::Data<ml::PaywallModel>,
+    config: web::Data<Config>,
+    engine: web::Data<RuleEngine>,
+    experiments: web::Data<ExperimentRegistry>,
+    req: HttpRequest,
+    dry_run_req: web::Json<DryRunRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = auth::require_staff(&pool, &req).await {
+        return Ok(response);
+    }
+    let dry_run_req = dry_run_req.into_inner();
+
+    let candidate = match dry_run_req.rules {
+        Some(candidate) => {
+            if let Err(e) = candidate.validate() {
+                return Ok(HttpResponse::UnprocessableEntity()
+                    .json(json!({"error": "Invalid rules", "details": e})));
+            }
+            Some(Arc::new(LoadedRules::new(candidate, "candidate")))
+        }
+        None => None,
+    };
+
+    let content = match db::get_content_by_id(&pool, dry_run_req.content_id).await {
+        Ok(Some(c)) => c,
+        Ok(None) => return Ok(HttpResponse::NotFound().json(json!({"error": "Content not found"}))),
+        Err(e) => {
+            tracing::error!("Database error fetching content: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    let viewer = match dry_run_req.user_id {
+        Some(user_id) => match db::get_user_by_id(&pool, user_id).await {
+            Ok(Some(_)) => Viewer::User(user_id),
+            Ok(None) => {
+                return Ok(HttpResponse::NotFound().json(json!({"error": "User not found"})));
+            }
+            Err(e) => {
+                tracing::error!("Database error fetching user: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        },
+        None => Viewer::NewVisitor,
+    };
+    let assignment = match candidate {
+        Some(_) => None,
+        None => viewer
+            .user_id()
+            .and_then(|user_id| experiments.assign(user_id)),
+    };
+    let rules = candidate
+        .or_else(|| assignment.as_ref().and_then(|a| a.rules.clone()))
+        .unwrap_or_else(|| engine.current());
+    let ctx = RequestContext {
+        referrer: dry_run_req
+            .referrer
+            .as_deref()
+            .and_then(|value| {
+                institutions::url_host(value).or_else(|| Some(value.to_ascii_lowercase()))
+            })
+            .filter(|host| !host.is_empty()),
+        device: device_from_user_agent(dry_run_req.user_agent.as_deref().unwrap_or("")),
+        at: dry_run_req.at.unwrap_or_else(Utc::now),
+    };
+    // Читатель получил бы 404
+    if !content.is_published(ctx.at) {
+        return Ok(HttpResponse::NotFound()
+            .json(json!({"error": "Content is not published at this time"})));
+    }
+
+    let (mut facts, access) =
+        match paywall::gather_facts(&pool, &config, &viewer, &content, None, &ctx).await {
+            Ok(gathered) => gathered,
+            Err(e) => {
+                tracing::error!("Database error gathering facts: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        };
+    if let Some(views) = dry_run_req.meter_views {
+        facts.meter_views = views.max(0);
+    }
+    if let Some(viewed) = dry_run_req.already_viewed {
+        facts.already_viewed = viewed;
+    }
+    let (evaluation, ml_features) = paywall::decide(
+        &rules.rules,
+        &mut facts,
+        &pool,
+        &ml_model,
+        &viewer,
+        &content,
+    )
+    .await;
+    // Предложение, которое увидел бы пользователь; показ не записывается
+    let offer = match (&evaluation.outcome, viewer.user_id()) {
+        (Outcome::Offer { .. }, Some(user_id)) => {
+            match offers::preview(&pool, &config, user_id, content.id, &facts).await {
+                Ok((offer, _)) => Some(offer),
+                Err(e) => {
+                    tracing::error!("Database error choosing offer: {}", e);
+                    return Ok(HttpResponse::InternalServerError()
+                        .json(json!({"error": "Internal server error"})));
+                }
+            }
+        }
+        _ => None,
+    };
+    let decision = Decision {
+        facts,
+        access,
+        evaluation,
+        ml_features,
+        experiment: assignment,
+        offer,
+    };
+
+    // Запись решения не сохраняется: dry-run ничего не меняет
+    match DecisionRecord::build(
+        &pool,
+        content.id,
+        dry_run_req.user_id,
+        None,
+        None,
+        &decision,
+        &rules,
+    )
+    .await
+    {
+        Ok(record) => Ok(HttpResponse::Ok().json(json!({
+            "rules_source": rules.source,
+            "decision": {
+                "rule": record.rule,
+                "outcome": record.outcome,
+                "access_granted": record.access_granted,
+                "cacheable": record.cacheable,
+            },
+            "record": record,
+        }))),
+        Err(e) => {
+            tracing::error!("Database error building decision record: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn config() -> Config {
+        envy::from_iter(
+            [
+                ("DATABASE_URL", "postgres://localhost/test"),
+                ("JWT_SECRET", "secret"),
+                ("PAYMENT_API_KEY", "key"),
+                ("PAYMENT_API_URL", "http://localhost"),
+                ("ANONYMOUS_FREE_VIEWS", "3"),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string())),
+        )
+        .unwrap()
+    }
+
+    fn facts() -> Facts {
+        Facts {
+            logged_in: false,
+            plan: None,
+            entitlements: Vec::new(),
+            institution: false,
+            content_plan: "premium".to_string(),
+            content_tags: vec!["politics".to_string()],
+            content_age_hours: 10,
+            referrer: None,
+            device: "desktop",
+            meter_views: 0,
+            already_viewed: false,
+            ml_positive: None,
+            // Среда, 12:00 UTC
+            at: Utc.with_ymd_and_hms(2024, 5, 15, 12, 0, 0).unwrap(),
+        }
+    }
+
+    fn rules(json: &str) -> RuleSet {
+        RuleSet::parse(json).unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = rules(
+            r#"{"rules": [
+                {"name": "tagged", "when": {"content_tags_any": ["sport"]}, "then": {"outcome": "grant"}},
+                {"name": "anonymous", "when": {"logged_in": false}, "then": {"outcome": "hard_wall"}},
+                {"name": "rest", "then": {"outcome": "grant"}}
+            ]}"#,
+        );
+        let evaluation = rules.evaluate(&facts()).unwrap();
+        assert_eq!(evaluation.rule.as_deref(), Some("anonymous"));
+        assert_eq!(evaluation.trace.len(), 2);
+        assert!(!evaluation.trace[0].matched);
+        assert!(!evaluation.volatile);
+    }
+
+    #[test]
+    fn no_matching_rule_is_hard_wall() {
+        let rules = rules(
+            r#"{"rules": [{"name": "members", "when": {"logged_in": true}, "then": {"outcome": "grant"}}]}"#,
+        );
+        let evaluation = rules.evaluate(&facts()).unwrap();
+        assert_eq!(evaluation.rule, None);
+        assert_eq!(evaluation.outcome, Outcome::HardWall { message: None });
+    }
+
+    #[test]
+    fn default_rules_meter_anonymous_visitors() {
+        let rules = RuleSet::defaults(&config());
+        let mut facts = facts();
+        let evaluation = rules.evaluate(&facts).unwrap();
+        assert_eq!(evaluation.rule.as_deref(), Some("anonymous-meter"));
+        assert!(evaluation.volatile);
+
+        facts.meter_views = 3;
+        let evaluation = rules.evaluate(&facts).unwrap();
+        assert_eq!(evaluation.rule.as_deref(), Some("anonymous-regwall"));
+
+        // Повторный просмотр не расходует счётчик
+        facts.already_viewed = true;
+        let evaluation = rules.evaluate(&facts).unwrap();
+        assert_eq!(evaluation.rule.as_deref(), Some("anonymous-meter"));
+    }
+
+    #[test]
+    fn entitled_reader_gets_cacheable_grant() {
+        let mut facts = facts();
+        facts.logged_in = true;
+        facts.plan = Some("premium".to_string());
+        facts.entitlements = vec!["personal"];
+        let evaluation = RuleSet::defaults(&config()).evaluate(&facts).unwrap();
+        assert_eq!(evaluation.rule.as_deref(), Some("entitled"));
+        assert!(!evaluation.volatile);
+    }
+
+    #[test]
+    fn request_dependent_conditions_mark_decision_volatile() {
+        for when in [
+            r#"{"referrer": ["news.google.com"]}"#,
+            r#"{"device": ["mobile"]}"#,
+            r#"{"meter_views_lt": 5}"#,
+            r#"{"content_age_hours_lt": 24}"#,
+            r#"{"hours_utc": [9, 17]}"#,
+            r#"{"any": [{"device": ["mobile"]}]}"#,
+        ] {
+            let rules = rules(&format!(
+                r#"{{"rules": [{{"name": "r", "when": {}, "then": {{"outcome": "grant"}}}}]}}"#,
+                when
+            ));
+            assert!(rules.evaluate(&facts()).unwrap().volatile, "{}", when);
+        }
+    }
+
+    #[test]
+    fn unconditional_meter_rule_is_not_cacheable() {
+        // Остаток счётчика у каждого посетителя свой, а просмотр должен засчитываться
+        for then in [r#"{"outcome": "meter"}"#, r#"{"outcome": "regwall"}"#] {
+            let rules = rules(&format!(
+                r#"{{"rules": [{{"name": "r", "then": {}}}]}}"#,
+                then
+            ));
+            assert!(rules.evaluate(&facts()).unwrap().volatile, "{}", then);
+        }
+    }
+
+    #[test]
+    fn volatile_conditions_in_skipped_rules_still_count() {
+        // Решение второго правила зависит от того, что первое не подошло по устройству
+        let rules = rules(
+            r#"{"rules": [
+                {"name": "mobile", "when": {"device": ["mobile"]}, "then": {"outcome": "grant"}},
+                {"name": "rest", "then": {"outcome": "hard_wall"}}
+            ]}"#,
+        );
+        let evaluation = rules.evaluate(&facts()).unwrap();
+        assert_eq!(evaluation.rule.as_deref(), Some("rest"));
+        assert!(evaluation.volatile);
+    }
+
+    #[test]
+    fn ml_condition_waits_for_prediction() {
+        let rules = rules(
+            r#"{"rules": [{"name": "offer", "when": {"ml_positive": true}, "then": {"outcome": "offer"}}]}"#,
+        );
+        let mut facts = facts();
+        assert!(rules.evaluate(&facts).is_none());
+        facts.ml_positive = Some(true);
+        assert_eq!(
+            rules.evaluate(&facts).unwrap().rule.as_deref(),
+            Some("offer")
+        );
+    }
+
+    #[test]
+    fn time_conditions() {
+        let at = facts().at;
+        let hours = |from, to| Condition {
+            hours_utc: Some([from, to]),
+            ..Condition::default()
+        };
+        assert!(hours(9, 17).matches_time(at));
+        assert!(!hours(13, 17).matches_time(at));
+        // Интервал через полночь
+        assert!(hours(22, 13).matches_time(at));
+        assert!(!hours(22, 6).matches_time(at));
+
+        let weekdays = Condition {
+            weekdays: Some(vec!["wed".to_string()]),
+            ..Condition::default()
+        };
+        assert!(weekdays.matches_time(at));
+
+        // after включительно, before исключительно
+        let window = Condition {
+            after: Some(at),
+            before: Some(at + chrono::Duration::hours(1)),
+            ..Condition::default()
+        };
+        assert!(window.matches_time(at));
+        assert!(!window.matches_time(at + chrono::Duration::hours(1)));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for json in [
+            r#"{"rules": []}"#,
+            r#"{"rules": [{"name": "a", "then": {"outcome": "grant"}}, {"name": "a", "then": {"outcome": "grant"}}]}"#,
+            r#"{"rules": [{"name": "a", "when": {"plan": ["gold"]}, "then": {"outcome": "grant"}}]}"#,
+            r#"{"rules": [{"name": "a", "when": {"hours_utc": [5, 5]}, "then": {"outcome": "grant"}}]}"#,
+            r#"{"rules": [{"name": "a", "when": {"any": []}, "then": {"outcome": "grant"}}]}"#,
+            r#"{"rules": [{"name": "a", "when": {"colour": "red"}, "then": {"outcome": "grant"}}]}"#,
+            r#"{"rules": [{"name": "a", "then": {"outcome": "meter", "limit": -1}}]}"#,
+        ] {
+            assert!(RuleSet::parse(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn device_detection() {
+        assert_eq!(device_from_user_agent("Googlebot/2.1"), "bot");
+        assert_eq!(
+            device_from_user_agent("Mozilla/5.0 (iPad; CPU OS 17_0)"),
+            "tablet"
+        );
+        assert_eq!(
+            device_from_user_agent("Mozilla/5.0 (Linux; Android 14) Mobile"),
+            "mobile"
+        );
+        assert_eq!(
+            device_from_user_agent("Mozilla/5.0 (X11; Linux x86_64)"),
+            "desktop"
+        );
+    }
+}
@@ -0,0 +1,731 @@
+// src/subscription.rs
+use crate::auth;
+use crate::config::Config;
+use crate::db;
+use crate::models::{
+    AutoRenewRequest, CancelRequest, ChangePlanRequest, PauseRequest, Subscription,
+};
+use crate::money::{Money, MoneyError};
+use crate::payment::{self, ChargeStatus, PaymentGateway};
+use crate::paywall;
+use crate::tax::TaxBreakdown;
+use actix_web::{HttpRequest, HttpResponse, post, web};
+use chrono::{DateTime, Months, Utc};
+use moka::future::Cache;
+use serde_json::json;
+
+// Самая длинная пауза в месяцах
+const MAX_PAUSE_MONTHS: u32 = 3;
+
+// Допустимые ответы опроса об отмене
+const CANCELLATION_REASONS: &[&str] = &[
+    "too_expensive",
+    "not_enough_content",
+    "technical_issues",
+    "switching_service",
+    "temporary",
+    "other",
+];
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(cancel_subscription);
+    cfg.service(undo_cancellation);
+    cfg.service(set_auto_renew);
+    cfg.service(change_plan);
+    cfg.service(pause_subscription);
+    cfg.service(resume_subscription);
+}
+
+// Стоимость оставшейся части текущего периода по цене тарифа
+fn prorated_amount(
+    price: Money,
+    period_days: i64,
+    subscription: &Subscription,
+    now: DateTime<Utc>,
+) -> Result<Money, MoneyError> {
+    let period_secs = period_days * 86400;
+    let remaining_secs = (subscription.expires_at - now)
+        .num_seconds()
+        .clamp(0, period_secs);
+    price.mul_ratio(remaining_secs, period_secs)
+}
+
+// Кредит за остаток текущего тарифа и сумма доплаты (не меньше нуля)
+fn upgrade_amounts(
+    current_price: Money,
+    new_price: Money,
+    period_days: i64,
+    subscription: &Subscription,
+    now: DateTime<Utc>,
+) -> Result<(Money, Money), MoneyError> {
+    let credit = prorated_amount(current_price, period_days, subscription, now)?;
+    let new_cost = prorated_amount(new_price, period_days, subscription, now)?;
+    let amount_due = new_cost
+        .checked_sub(credit)?
+        .max(Money::zero(new_price.currency()))?;
+    Ok((credit, amount_due))
+}
+
+// Ключ идемпотентности доплаты за повышение: повтор запроса после таймаута
+// продолжает тот же платёж, а после отказа новая попытка идёт с новым ключом.
+// Сумма в ключ не входит: она пересчитывается посекундно, а повтор продолжает
+// pending-платёж с уже сохранённой суммой
+fn upgrade_key(subscription: &Subscription, plan_id: &str, failed_attempts: i64) -> String {
+    format!(
+        "upgrade:{}:{}:{}:{}",
+        subscription.id,
+        plan_id,
+        subscription.expires_at.timestamp(),
+        failed_attempts
+    )
+}
+
+#[post("/subscription/cancel")]
+pub async fn cancel_subscription(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    cancel_req: web::Json<CancelRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    if !CANCELLATION_REASONS.contains(&cancel_req.reason.as_str()) {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "Invalid cancellation reason",
+            "allowed_reasons": CANCELLATION_REASONS,
+        })));
+    }
+
+    // Приостановленную подписку тоже можно отменить
+    let subscription = match db::get_active_subscription(&pool, user_id).await {
+        Ok(Some(sub)) => Ok(Some(sub)),
+        Ok(None) => db::get_paused_subscription(&pool, user_id).await,
+        Err(e) => Err(e),
+    };
+    let subscription = match subscription {
+        Ok(Some(sub)) => sub,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "No active subscription"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+    // Во время паузы доступа нет, и ждать конца периода нечего: отмена сразу
+    let immediate = cancel_req.immediate || subscription.paused_at.is_some();
+
+    if subscription.cancel_at_period_end && !immediate {
+        return Ok(
+            HttpResponse::Conflict().json(json!({"error": "Cancellation already scheduled"}))
+        );
+    }
+
+    match db::cancel_subscription(
+        &pool,
+        &subscription,
+        immediate,
+        &cancel_req.reason,
+        cancel_req.comment.as_deref(),
+    )
+    .await
+    {
+        Ok(updated) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            tracing::info!(
+                "Subscription {} canceled (immediate={}, reason={})",
+                updated.id,
+                immediate,
+                cancel_req.reason
+            );
+            Ok(HttpResponse::Ok().json(json!({
+                "message": if immediate {
+                    "Subscription canceled"
+                } else {
+                    "Subscription will be canceled at the end of the billing period"
+                },
+                "subscription_id": updated.id,
+                "access_until": updated.expires_at,
+                "cancel_at_period_end": updated.cancel_at_period_end,
+            })))
+        }
+        Err(e) => {
+            tracing::error!("Subscription cancellation error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/subscription/cancel/undo")]
+pub async fn undo_cancellation(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    let subscription = match db::get_active_subscription(&pool, user_id).await {
+        Ok(Some(sub)) => sub,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "No active subscription"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    match db::undo_cancellation(&pool, subscription.id).await {
+        Ok(Some(updated)) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            Ok(HttpResponse::Ok().json(json!({
+                "message": "Cancellation undone",
+                "subscription_id": updated.id,
+                "expires_at": updated.expires_at,
+            })))
+        }
+        Ok(None) => Ok(
+            HttpResponse::Conflict().json(json!({"error": "No scheduled cancellation to undo"}))
+        ),
+        Err(e) => {
+            tracing::error!("Undo cancellation error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/subscription/auto-renew")]
+pub async fn set_auto_renew(
+    pool: web::Data<sqlx::PgPool>,
+    req: HttpRequest,
+    renew_req: web::Json<AutoRenewRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    let subscription = match db::get_active_subscription(&pool, user_id).await {
+        Ok(Some(sub)) => sub,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "No active subscription"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    match db::set_auto_renew(&pool, subscription.id, renew_req.enabled).await {
+        Ok(()) => Ok(HttpResponse::Ok().json(json!({
+            "subscription_id": subscription.id,
+            "auto_renew": renew_req.enabled,
+            "expires_at": subscription.expires_at,
+        }))),
+        Err(e) => {
+            tracing::error!("Auto-renew update error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/subscription/change")]
+pub async fn change_plan(
+    pool: web::Data<sqlx::PgPool>,
+    gateway: web::Data<dyn PaymentGateway>,
+    config: web::Data<Config>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    change_req: web::Json<ChangePlanRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    let new_rank = match paywall::plan_rank(&change_req.plan_id) {
+        Some(rank) => rank,
+        None => return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid plan"}))),
+    };
+
+    let subscription = match db::get_active_subscription(&pool, user_id).await {
+        Ok(Some(sub)) => sub,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "No active subscription"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    if subscription.billing_status == "past_due" {
+        return Ok(HttpResponse::Conflict()
+            .json(json!({"error": "Resolve the outstanding payment before changing plans"})));
+    }
+    if subscription.pending_plan_id.is_some() {
+        return Ok(HttpResponse::Conflict()
+            .json(json!({"error": "Previous upgrade is awaiting payment confirmation"})));
+    }
+
+    // Цены в валюте подписки: валюта не меняется до конца её жизни
+    let new_terms = subscription
+        .billing_currency()
+        .ok()
+        .and_then(|currency| paywall::plan_terms(&change_req.plan_id, currency));
+    let Some((new_price, period_days)) = new_terms else {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "Plan is not available in the subscription currency",
+            "currency": subscription.currency,
+        })));
+    };
+
+    let current_rank = paywall::plan_rank(&subscription.plan_id).unwrap_or(0);
+
+    // Возврат к текущему тарифу отменяет запланированное понижение
+    if change_req.plan_id == subscription.plan_id {
+        if subscription.scheduled_plan_id.is_none() {
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Already on this plan"})));
+        }
+        return match db::schedule_plan_change(&pool, subscription.id, None).await {
+            Ok(updated) => Ok(HttpResponse::Ok().json(json!({
+                "message": "Scheduled plan change removed",
+                "subscription_id": updated.id,
+                "plan_id": updated.plan_id,
+            }))),
+            Err(e) => {
+                tracing::error!("Plan change error: {}", e);
+                Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})))
+            }
+        };
+    }
+
+    // Понижение: текущий тариф действует до конца оплаченного периода
+    if new_rank < current_rank {
+        return match db::schedule_plan_change(&pool, subscription.id, Some(&change_req.plan_id))
+            .await
+        {
+            Ok(updated) => Ok(HttpResponse::Ok().json(json!({
+                "message": "Plan change scheduled for the end of the billing period",
+                "subscription_id": updated.id,
+                "plan_id": updated.plan_id,
+                "scheduled_plan_id": updated.scheduled_plan_id,
+                "effective_at": updated.expires_at,
+            }))),
+            Err(e) => {
+                tracing::error!("Plan change error: {}", e);
+                Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})))
+            }
+        };
+    }
+
+    // Повышение: кредит за неиспользованный остаток старого тарифа засчитывается
+    // в стоимость остатка периода по новому тарифу, разница списывается сразу
+    let now = Utc::now();
+    let current_price = paywall::plan_terms(&subscription.plan_id, new_price.currency())
+        .map(|(price, _)| price)
+        .unwrap_or(Money::zero(new_price.currency()));
+    let (credit, amount_due) =
+        match upgrade_amounts(current_price, new_price, period_days, &subscription, now) {
+            Ok(amounts) => amounts,
+            Err(e) => {
+                tracing::error!("Upgrade price calculation error: {}", e);
+                return Ok(HttpResponse::InternalServerError()
+                    .json(json!({"error": "Internal server error"})));
+            }
+        };
+
+    let mut paid = None;
+    if amount_due.is_positive() {
+        let failed_attempts =
+            match db::count_failed_payments(&pool, subscription.id, "upgrade").await {
+                Ok(count) => count,
+                Err(e) => {
+                    tracing::error!("Database error counting upgrade attempts: {}", e);
+                    return Ok(HttpResponse::InternalServerError()
+                        .json(json!({"error": "Internal server error"})));
+                }
+            };
+        let charge = payment::charge_user(
+            &pool,
+            gateway.get_ref(),
+            &config,
+            &payment::UserCharge {
+                user_id,
+                subscription_id: Some(subscription.id),
+                kind: "upgrade",
+                new_token: change_req.payment_token.as_deref(),
+                amount: amount_due,
+                description: format!(
+                    "Upgrade: {} -> {}",
+                    subscription.plan_id, change_req.plan_id
+                ),
+                geo_country: None,
+                idempotency_key: Some(upgrade_key(
+                    &subscription,
+                    &change_req.plan_id,
+                    failed_attempts,
+                )),
+            },
+        )
+        .await;
+        match charge {
+            Ok((payment, charge)) if charge.status == ChargeStatus::Succeeded => {
+                paid = Some((payment, charge.id))
+            }
+            // Тариф сменится, когда вебхук подтвердит платёж
+            Ok((payment, charge)) if charge.status == ChargeStatus::Pending => {
+                if let Err(e) =
+                    db::set_pending_upgrade(&pool, subscription.id, &change_req.plan_id).await
+                {
+                    tracing::error!("Failed to save pending upgrade: {}", e);
+                    return Ok(HttpResponse::InternalServerError()
+                        .json(json!({"error": "Internal server error"})));
+                }
+                if let Err(e) = payment::record_credit(
+                    &pool,
+                    user_id,
+                    Some(payment.id),
+                    credit,
+                    &format!("Unused {} credit on upgrade", subscription.plan_id),
+                )
+                .await
+                {
+                    tracing::error!("Failed to record upgrade credit: {}", e);
+                }
+                tracing::info!(
+                    "Upgrade of subscription {} to {} awaits payment {}",
+                    subscription.id,
+                    change_req.plan_id,
+                    payment.id
+                );
+                return Ok(HttpResponse::Accepted().json(json!({
+                    "message": "Upgrade is awaiting payment confirmation",
+                    "subscription_id": subscription.id,
+                    "plan_id": subscription.plan_id,
+                    "pending_plan_id": change_req.plan_id,
+                    "payment_id": payment.id,
+                    "status": "awaiting_payment",
+                })));
+            }
+            Ok((_, charge)) => {
+                tracing::warn!("Charge {} not completed: {:?}", charge.id, charge.status);
+                return Ok(HttpResponse::PaymentRequired().json(json!({"error": "Payment failed"})));
+            }
+            Err(e) => return Ok(payment::error_response(&e)),
+        }
+    }
+
+    match db::upgrade_subscription_plan(&pool, subscription.id, &change_req.plan_id).await {
+        Ok(updated) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            if let Err(e) = payment::record_credit(
+                &pool,
+                user_id,
+                paid.as_ref().map(|(payment, _)| payment.id),
+                credit,
+                &format!("Unused {} credit on upgrade", subscription.plan_id),
+            )
+            .await
+            {
+                tracing::error!("Failed to record upgrade credit: {}", e);
+            }
+            // Фактически списанная сумма с учётом налога
+            let tax = paid
+                .as_ref()
+                .and_then(|(payment, _)| TaxBreakdown::from_payment(payment).ok());
+            let amount_charged = tax.as_ref().map(|t| t.total).unwrap_or(amount_due);
+            tracing::info!(
+                "Subscription {} upgraded {} -> {} (charged {})",
+                updated.id,
+                subscription.plan_id,
+                updated.plan_id,
+                amount_charged
+            );
+            Ok(HttpResponse::Ok().json(json!({
+                "message": "Plan upgraded",
+                "subscription_id": updated.id,
+                "plan_id": updated.plan_id,
+                "prorated_credit": credit,
+                "amount_charged": amount_charged,
+                "tax": tax,
+                "expires_at": updated.expires_at,
+            })))
+        }
+        Err(e) => {
+            tracing::error!("Plan upgrade error after payment: {}", e);
+            if let Some((payment, charge_id)) = paid {
+                payment::refund_quietly(&pool, gateway.get_ref(), payment.id, &charge_id).await;
+            }
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[post("/subscription/pause")]
+pub async fn pause_subscription(
+    pool: web::Data<sqlx::PgPool>,
+    config: web::Data<Config>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+    pause_req: web::Json<PauseRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    let resume_at = match pause_resumes_at(Utc::now(), pause_req.months) {
+        Some(date) => date,
+        None => {
+            return Ok(HttpResponse::BadRequest()
+                .json(json!({"error": "Pause duration must be between 1 and 3 months"})));
+        }
+    };
+
+    let subscription = match db::get_active_subscription(&pool, user_id).await {
+        Ok(Some(sub)) => sub,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "No active subscription"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    if let Some(reason) = pause_refusal(&subscription) {
+        return Ok(HttpResponse::Conflict().json(json!({"error": reason})));
+    }
+
+    match db::count_pauses_last_year(&pool, user_id).await {
+        Ok(count) if count >= config.max_pauses_per_year => {
+            return Ok(HttpResponse::Conflict().json(json!({
+                "error": "Pause limit reached",
+                "max_pauses_per_year": config.max_pauses_per_year,
+            })));
+        }
+        Ok(_) => {}
+        Err(e) => {
+            tracing::error!("Database error counting pauses: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    }
+
+    match db::pause_subscription(&pool, &subscription, resume_at).await {
+        Ok(updated) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            tracing::info!("Subscription {} paused until {}", updated.id, resume_at);
+            Ok(HttpResponse::Ok().json(json!({
+                "message": "Subscription paused",
+                "subscription_id": updated.id,
+                "paused_at": updated.paused_at,
+                "resumes_at": updated.pause_resumes_at,
+            })))
+        }
+        Err(e) => {
+            tracing::error!("Subscription pause error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+// Дата возобновления; None, если пауза короче месяца или длиннее MAX_PAUSE_MONTHS
+fn pause_resumes_at(now: DateTime<Utc>, months: u32) -> Option<DateTime<Utc>> {
+    if !(1..=MAX_PAUSE_MONTHS).contains(&months) {
+        return None;
+    }
+    now.checked_add_months(Months::new(months))
+}
+
+// Почему подписку нельзя приостановить; None — можно
+fn pause_refusal(subscription: &Subscription) -> Option<&'static str> {
+    if subscription.billing_status == "past_due" {
+        Some("Resolve the outstanding payment before pausing")
+    } else if subscription.cancel_at_period_end {
+        Some("Subscription is scheduled for cancellation")
+    } else {
+        None
+    }
+}
+
+#[post("/subscription/resume")]
+pub async fn resume_subscription(
+    pool: web::Data<sqlx::PgPool>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = match auth::get_user_id_from_request(&req) {
+        Some(id) => id,
+        None => return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))),
+    };
+
+    let subscription = match db::get_paused_subscription(&pool, user_id).await {
+        Ok(Some(sub)) => sub,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(json!({"error": "No paused subscription"})));
+        }
+        Err(e) => {
+            tracing::error!("Database error fetching subscription: {}", e);
+            return Ok(
+                HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+            );
+        }
+    };
+
+    let resumed = match pool.begin().await {
+        Ok(mut tx) => match db::resume_subscriptions(&mut tx, Some(subscription.id)).await {
+            Ok(resumed) => tx.commit().await.map(|_| resumed),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+
+    match resumed.map(|subs| subs.into_iter().next()) {
+        Ok(Some(updated)) => {
+            paywall::invalidate_user_cache(&cache, user_id);
+            Ok(HttpResponse::Ok().json(json!({
+                "message": "Subscription resumed",
+                "subscription_id": updated.id,
+                "expires_at": updated.expires_at,
+            })))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({"error": "No paused subscription"}))),
+        Err(e) => {
+            tracing::error!("Subscription resume error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": "Internal server error"})))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Currency;
+    use chrono::Duration;
+
+    fn usd(minor: i64) -> Money {
+        Money::new(minor, Currency::Usd)
+    }
+
+    #[test]
+    fn prorated_amount_covers_remaining_share() {
+        let now = Utc::now();
+        let half = Subscription::test_fixture(now + Duration::days(15));
+        assert_eq!(prorated_amount(usd(1000), 30, &half, now), Ok(usd(500)));
+        let one_day = Subscription::test_fixture(now + Duration::days(1));
+        assert_eq!(prorated_amount(usd(999), 30, &one_day, now), Ok(usd(33)));
+    }
+
+    #[test]
+    fn prorated_amount_is_clamped_to_the_period() {
+        let now = Utc::now();
+        // Истёкшая подписка: остатка нет
+        let expired = Subscription::test_fixture(now - Duration::days(2));
+        assert_eq!(prorated_amount(usd(1000), 30, &expired, now), Ok(usd(0)));
+        let ends_now = Subscription::test_fixture(now);
+        assert_eq!(prorated_amount(usd(1000), 30, &ends_now, now), Ok(usd(0)));
+        // Период длиннее тарифного (например, после подарка): не больше полной цены
+        let stacked = Subscription::test_fixture(now + Duration::days(90));
+        assert_eq!(prorated_amount(usd(1000), 30, &stacked, now), Ok(usd(1000)));
+    }
+
+    #[test]
+    fn upgrade_charges_difference_of_remainders() {
+        let now = Utc::now();
+        let half = Subscription::test_fixture(now + Duration::days(15));
+        let (credit, due) = upgrade_amounts(usd(999), usd(1999), 30, &half, now).unwrap();
+        // 4.995 и 9.995 округляются от нуля
+        assert_eq!(credit, usd(500));
+        assert_eq!(due, usd(500));
+    }
+
+    #[test]
+    fn upgrade_amount_due_is_never_negative() {
+        let now = Utc::now();
+        let half = Subscription::test_fixture(now + Duration::days(15));
+        // Новый тариф дешевле в валюте подписки: доплаты нет, кредит не возвращается деньгами
+        let (credit, due) = upgrade_amounts(usd(1999), usd(999), 30, &half, now).unwrap();
+        assert_eq!(credit, usd(1000));
+        assert_eq!(due, usd(0));
+
+        let expired = Subscription::test_fixture(now - Duration::days(1));
+        let (credit, due) = upgrade_amounts(usd(999), usd(1999), 30, &expired, now).unwrap();
+        assert_eq!((credit, due), (usd(0), usd(0)));
+    }
+
+    #[test]
+    fn upgrade_amounts_reject_mixed_currencies() {
+        let now = Utc::now();
+        let half = Subscription::test_fixture(now + Duration::days(15));
+        let eur = Money::new(1999, Currency::Eur);
+        assert!(upgrade_amounts(usd(999), eur, 30, &half, now).is_err());
+    }
+
+    #[test]
+    fn upgrade_key_survives_retry_with_recalculated_amount() {
+        let now = Utc::now();
+        let sub = Subscription::test_fixture(now + Duration::days(15));
+        // Повтор через минуту: доплата пересчитана, ключ тот же
+        let retry_at = now + Duration::seconds(60);
+        let (_, first_due) = upgrade_amounts(usd(99900), usd(199900), 30, &sub, now).unwrap();
+        let (_, retry_due) = upgrade_amounts(usd(99900), usd(199900), 30, &sub, retry_at).unwrap();
+        assert_ne!(first_due, retry_due);
+        // К повтору подписка уже помечена ожидающим повышением
+        let mut reloaded = sub.clone();
+        reloaded.pending_plan_id = Some("premium".to_string());
+        let key = upgrade_key(&sub, "premium", 0);
+        assert_eq!(key, upgrade_key(&reloaded, "premium", 0));
+
+        assert_ne!(key, upgrade_key(&sub, "premium", 1));
+        assert_ne!(key, upgrade_key(&sub, "family", 0));
+        // Новый период — новый ключ
+        let mut renewed = sub.clone();
+        renewed.expires_at += Duration::days(30);
+        assert_ne!(key, upgrade_key(&renewed, "premium", 0));
+    }
+
+    #[test]
+    fn pause_lasts_one_to_three_months() {
+        let now = Utc::now();
+        assert_eq!(pause_resumes_at(now, 0), None);
+        assert_eq!(pause_resumes_at(now, 4), None);
+        for months in 1..=3 {
+            assert_eq!(
+                pause_resumes_at(now, months),
+                now.checked_add_months(Months::new(months))
+            );
+        }
+    }
+
+    #[test]
+    fn pause_refused_for_past_due_or_canceling_subscription() {
+        let mut sub = Subscription::test_fixture(Utc::now() + Duration::days(10));
+        assert_eq!(pause_refusal(&sub), None);
+        sub.cancel_at_period_end = true;
+        assert!(pause_refusal(&sub).is_some());
+        sub.cancel_at_period_end = false;
+        sub.billing_status = "past_due".to_string();
+        assert!(pause_refusal(&sub).is_some());
+    }
+}
@@ -0,0 +1,321 @@
+// src/tax.rs
+// НДС / налог с продаж при списании. Юрисдикция — страна платёжных реквизитов
+// (иначе страна по IP), ставка берётся из таблицы tax_rates на дату платежа.
+// Цены тарифов трактуются как включающие налог или без него (TAX_PRICING_MODE).
+// B2B-покупатель из другой страны ЕС с корректным VAT ID платит без НДС (reverse charge).
+use crate::config::Config;
+use crate::db;
+use crate::models::Payment;
+use crate::money::{Money, MoneyError};
+use crate::payment::PaymentError;
+use crate::pricing;
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+const BPS_SCALE: i64 = 10_000;
+
+const EU_COUNTRIES: &[&str] = &[
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GR", "HR", "HU", "IE", "IT",
+    "LT", "LU", "LV", "MT", "NL", "PL", "PT", "RO", "SE", "SI", "SK",
+];
+
+// Форматы номеров без префикса страны: '9' — цифра, 'A' — буква, 'X' — буква или цифра
+const VAT_ID_FORMATS: &[(&str, &[&str])] = &[
+    ("AT", &["U99999999"]),
+    ("BE", &["0999999999", "1999999999"]),
+    ("BG", &["999999999", "9999999999"]),
+    ("CY", &["99999999A"]),
+    ("CZ", &["99999999", "999999999", "9999999999"]),
+    ("DE", &["999999999"]),
+    ("DK", &["99999999"]),
+    ("EE", &["999999999"]),
+    ("ES", &["X9999999X"]),
+    ("FI", &["99999999"]),
+    ("FR", &["XX999999999"]),
+    ("GB", &["999999999", "999999999999"]),
+    ("GR", &["999999999"]),
+    ("HR", &["99999999999"]),
+    ("HU", &["99999999"]),
+    ("IE", &["9999999A", "9999999AA", "9A99999A"]),
+    ("IT", &["99999999999"]),
+    ("LT", &["999999999", "999999999999"]),
+    ("LU", &["99999999"]),
+    ("LV", &["99999999999"]),
+    ("MT", &["99999999"]),
+    ("NL", &["999999999B99"]),
+    ("PL", &["9999999999"]),
+    ("PT", &["999999999"]),
+    (
+        "RO",
+        &[
+            "99",
+            "999",
+            "9999",
+            "99999",
+            "999999",
+            "9999999",
+            "99999999",
+            "999999999",
+            "9999999999",
+        ],
+    ),
+    ("SE", &["999999999999"]),
+    ("SI", &["99999999"]),
+    ("SK", &["9999999999"]),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaxMode {
+    Inclusive, // Цена тарифа уже содержит налог
+    Exclusive, // Налог добавляется сверху
+}
+
+impl TaxMode {
+    pub fn from_config(config: &Config) -> TaxMode {
+        match config.tax_pricing_mode.as_str() {
+            "exclusive" => TaxMode::Exclusive,
+            _ => TaxMode::Inclusive,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaxTreatment {
+    NotTaxed, // Юрисдикция неизвестна или отсутствует в таблице ставок
+    Standard,
+    ReverseCharge,
+}
+
+impl TaxTreatment {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TaxTreatment::NotTaxed => "not_taxed",
+            TaxTreatment::Standard => "standard",
+            TaxTreatment::ReverseCharge => "reverse_charge",
+        }
+    }
+
+    fn from_str(value: &str) -> TaxTreatment {
+        match value {
+            "standard" => TaxTreatment::Standard,
+            "reverse_charge" => TaxTreatment::ReverseCharge,
+            _ => TaxTreatment::NotTaxed,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TaxBreakdown {
+    pub net: Money,
+    pub tax: Money,
+    pub total: Money, // Сумма к списанию
+    pub rate_bps: i32,
+    pub country: Option<String>,
+    pub treatment: TaxTreatment,
+    pub rate_version: Option<String>,
+}
+
+impl TaxBreakdown {
+    // Разбивка, сохранённая в платеже
+    pub fn from_payment(payment: &Payment) -> Result<TaxBreakdown, MoneyError> {
+        let total = payment.amount()?;
+        Ok(TaxBreakdown {
+            net: Money::new(payment.net_minor, total.currency()),
+            tax: Money::new(payment.tax_minor, total.currency()),
+            total,
+            rate_bps: payment.tax_rate_bps,
+            country: payment.tax_country.clone(),
+            treatment: TaxTreatment::from_str(&payment.tax_treatment),
+            rate_version: payment.tax_rate_version.clone(),
+        })
+    }
+}
+
+// VAT ID в каноническом виде (с префиксом страны, без пробелов и разделителей).
+// Для стран без известного формата номер только нормализуется; None — неверный формат
+pub fn normalize_vat_id(vat_id: &str, country: &str) -> Option<String> {
+    let compact: String = vat_id
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '.' | '-'))
+        .collect::<String>()
+        .to_ascii_uppercase();
+    // Греция использует префикс EL
+    let prefix = if country == "GR" { "EL" } else { country };
+    let Some((_, formats)) = VAT_ID_FORMATS.iter().find(|(c, _)| *c == country) else {
+        return (!compact.is_empty()).then_some(compact);
+    };
+
+    let number = compact.strip_prefix(prefix).unwrap_or(&compact);
+    formats
+        .iter()
+        .any(|format| matches_format(number, format))
+        .then(|| format!("{}{}", prefix, number))
+}
+
+fn matches_format(number: &str, format: &str) -> bool {
+    number.len() == format.len()
+        && number.chars().zip(format.chars()).all(|(c, f)| match f {
+            '9' => c.is_ascii_digit(),
+            'A' => c.is_ascii_uppercase(),
+            'X' => c.is_ascii_alphanumeric(),
+            literal => c == literal,
+        })
+}
+
+// Разбивка цены по ставке: в режиме Inclusive налог выделяется из цены
+pub fn split_price(
+    price: Money,
+    rate_bps: i32,
+    mode: TaxMode,
+) -> Result<(Money, Money, Money), MoneyError> {
+    let rate = i64::from(rate_bps);
+    match mode {
+        TaxMode::Inclusive => {
+            let net = price.mul_ratio(BPS_SCALE, BPS_SCALE + rate)?;
+            Ok((net, price.checked_sub(net)?, price))
+        }
+        TaxMode::Exclusive => {
+            let tax = price.mul_ratio(rate, BPS_SCALE)?;
+            Ok((price, tax, price.checked_add(tax)?))
+        }
+    }
+}
+
+// Налог для списания с пользователя по цене тарифа (или доплате при смене тарифа)
+pub async fn calculate(
+    conn: &mut PgConnection,
+    config: &Config,
+    user_id: Uuid,
+    price: Money,
+    geo_country: Option<&str>,
+) -> Result<TaxBreakdown, PaymentError> {
+    let details = db::get_billing_details(conn, user_id).await?;
+    let country = details
+        .as_ref()
+        .and_then(|d| d.country.as_deref())
+        .or(geo_country)
+        .and_then(pricing::normalize_country);
+    let mode = TaxMode::from_config(config);
+    let not_taxed = |country: Option<String>| TaxBreakdown {
+        net: price,
+        tax: Money::zero(price.currency()),
+        total: price,
+        rate_bps: 0,
+        country,
+        treatment: TaxTreatment::NotTaxed,
+        rate_version: None,
+    };
+
+    let Some(country) = country else {
+        return Ok(not_taxed(None));
+    };
+    let Some((rate_bps, version)) =
+        db::get_tax_rate(conn, &country, Utc::now().date_naive()).await?
+    else {
+        return Ok(not_taxed(Some(country)));
+    };
+
+    let (net, tax, total) = split_price(price, rate_bps, mode)?;
+
+    // Reverse charge: покупатель-организация из другой страны ЕС платит сумму без НДС
+    let reverse_charge = EU_COUNTRIES.contains(&country.as_str())
+        && country != config.seller_country
+        && details
+            .as_ref()
+            .and_then(|d| d.vat_id.as_deref())
+            .and_then(|vat_id| normalize_vat_id(vat_id, &country))
+            .is_some();
+    if reverse_charge {
+        return Ok(TaxBreakdown {
+            net,
+            tax: Money::zero(price.currency()),
+            total: net,
+            rate_bps: 0,
+            country: Some(country),
+            treatment: TaxTreatment::ReverseCharge,
+            rate_version: Some(version),
+        });
+    }
+
+    Ok(TaxBreakdown {
+        net,
+        tax,
+        total,
+        rate_bps,
+        country: Some(country),
+        treatment: TaxTreatment::Standard,
+        rate_version: Some(version),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Currency;
+
+    fn eur(minor: i64) -> Money {
+        Money::new(minor, Currency::Eur)
+    }
+
+    #[test]
+    fn inclusive_price_keeps_total() {
+        // 9.99 с НДС 20%: 8.325 округляется до 8.33
+        let (net, tax, total) = split_price(eur(999), 2000, TaxMode::Inclusive).unwrap();
+        assert_eq!(net, eur(833));
+        assert_eq!(tax, eur(166));
+        assert_eq!(total, eur(999));
+    }
+
+    #[test]
+    fn exclusive_price_adds_tax() {
+        let (net, tax, total) = split_price(eur(999), 2000, TaxMode::Exclusive).unwrap();
+        assert_eq!(net, eur(999));
+        assert_eq!(tax, eur(200));
+        assert_eq!(total, eur(1199));
+    }
+
+    #[test]
+    fn zero_rate_is_untaxed_in_both_modes() {
+        for mode in [TaxMode::Inclusive, TaxMode::Exclusive] {
+            let (net, tax, total) = split_price(eur(999), 0, mode).unwrap();
+            assert_eq!((net, tax, total), (eur(999), eur(0), eur(999)));
+        }
+    }
+
+    #[test]
+    fn net_plus_tax_equals_total() {
+        for price in [1, 99, 499, 999, 1999, 12345] {
+            for rate in [500, 1900, 2000, 2500, 2700] {
+                for mode in [TaxMode::Inclusive, TaxMode::Exclusive] {
+                    let (net, tax, total) = split_price(eur(price), rate, mode).unwrap();
+                    assert_eq!(net.checked_add(tax).unwrap(), total);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exclusive_overflow_is_an_error() {
+        assert_eq!(
+            split_price(eur(i64::MAX), 2000, TaxMode::Exclusive).unwrap_err(),
+            MoneyError::Overflow
+        );
+    }
+
+    #[test]
+    fn normalizes_vat_ids() {
+        assert_eq!(
+            normalize_vat_id("de 123.456-789", "DE").as_deref(),
+            Some("DE123456789")
+        );
+        assert_eq!(
+            normalize_vat_id("123456789", "GR").as_deref(),
+            Some("EL123456789")
+        );
+        assert_eq!(normalize_vat_id("DE12345678", "DE"), None);
+        assert_eq!(normalize_vat_id("ATU1234567X", "AT"), None);
+    }
+}
@@ -0,0 +1,171 @@
+// src/visitors.rs
+// Анонимные посетители: идентификатор в собственной cookie, подписанной HMAC
+// (ключ — JWT_SECRET), поэтому подделать чужой счётчик нельзя. Бесплатный
+// контент открыт всем, платный — по счётчику за ANONYMOUS_METER_WINDOW_DAYS
+// дней; сколько материалов и что показывать дальше, решают правила (rules.rs).
+// Очистка cookie сбрасывает счётчик: это мягкая стена, а не защита контента.
+use crate::config::Config;
+use crate::db;
+use crate::models::Content;
+use actix_web::HttpRequest;
+use actix_web::cookie::{Cookie, SameSite, time};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const COOKIE_NAME: &str = "pw_visitor";
+const COOKIE_MAX_AGE_DAYS: i64 = 365;
+pub const TEASER_CHARS: usize = 280;
+
+fn signature(visitor_id: Uuid, secret: &str) -> Option<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(b"visitor:");
+    mac.update(visitor_id.as_bytes());
+    Some(mac)
+}
+
+// Значение cookie: "<uuid>.<hex hmac-sha256>"
+fn sign(visitor_id: Uuid, secret: &str) -> Option<String> {
+    let mac = signature(visitor_id, secret)?;
+    Some(format!(
+        "{}.{}",
+        visitor_id.simple(),
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+// Посетитель из cookie запроса; None — cookie нет или подпись не сходится
+pub fn visitor_from_request(req: &HttpRequest, secret: &str) -> Option<Uuid> {
+    let cookie = req.cookie(COOKIE_NAME)?;
+    let (id, tag) = cookie.value().split_once('.')?;
+    let visitor_id = Uuid::parse_str(id).ok()?;
+    let expected = hex::decode(tag).ok()?;
+    // verify_slice сравнивает за постоянное время
+    signature(visitor_id, secret)?
+        .verify_slice(&expected)
+        .ok()
+        .map(|_| visitor_id)
+}
+
+pub fn visitor_cookie(visitor_id: Uuid, config: &Config) -> Option<Cookie<'static>> {
+    let value = sign(visitor_id, &config.jwt_secret)?;
+    Some(
+        Cookie::build(COOKIE_NAME, value)
+            .path("/")
+            .http_only(true)
+            .secure(config.visitor_cookie_secure)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::days(COOKIE_MAX_AGE_DAYS))
+            .finish(),
+    )
+}
+
+// Анонс материала для стен и предложений: заголовок и начало текста (chars = 0 — без текста)
+pub fn teaser(content: &Content, chars: usize) -> serde_json::Value {
+    let mut teaser: String = content.body.chars().take(chars).collect();
+    if !teaser.is_empty() && teaser.len() < content.body.len() {
+        teaser.push('…');
+    }
+    json!({
+        "id": content.id,
+        "title": content.title,
+        "teaser": teaser,
+        "required_plan": content.required_plan,
+    })
+}
+
+// Состояние счётчика посетителя: (платных материалов в окне, открывал ли этот материал)
+pub async fn meter_views(
+    pool: &PgPool,
+    visitor_id: Uuid,
+    content_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<(i64, bool), sqlx::Error> {
+    let viewed = db::list_anonymous_views_since(pool, visitor_id, since).await?;
+    Ok((viewed.len() as i64, viewed.contains(&content_id)))
+}
+
+pub async fn touch(pool: &PgPool, visitor_id: Uuid) -> Result<(), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    db::touch_anonymous_visitor(&mut conn, visitor_id).await
+}
+
+// Учитывает просмотр по счётчику; повторное чтение в окне лишь обновляет время
+pub async fn record_view(
+    pool: &PgPool,
+    visitor_id: Uuid,
+    content_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    db::touch_anonymous_visitor(&mut tx, visitor_id).await?;
+    db::record_anonymous_view(&mut tx, visitor_id, content_id).await?;
+    tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const SECRET: &str = "secret";
+
+    fn request_with(value: &str) -> HttpRequest {
+        TestRequest::default()
+            .cookie(Cookie::new(COOKIE_NAME, value.to_string()))
+            .to_http_request()
+    }
+
+    fn content(body: &str) -> Content {
+        Content {
+            id: Uuid::new_v4(),
+            title: "Title".to_string(),
+            body: body.to_string(),
+            required_plan: "premium".to_string(),
+            tags: Vec::new(),
+            created_at: Utc::now(),
+            publish_at: Some(Utc::now()),
+            premium_from: None,
+            free_from: None,
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn signed_cookie_identifies_visitor() {
+        let visitor_id = Uuid::new_v4();
+        let value = sign(visitor_id, SECRET).unwrap();
+        assert_eq!(
+            visitor_from_request(&request_with(&value), SECRET),
+            Some(visitor_id)
+        );
+    }
+
+    #[test]
+    fn forged_cookie_is_ignored() {
+        let value = sign(Uuid::new_v4(), SECRET).unwrap();
+        assert_eq!(visitor_from_request(&request_with(&value), "other"), None);
+        // Чужой идентификатор с подписью другого посетителя
+        let (_, tag) = value.split_once('.').unwrap();
+        let forged = format!("{}.{}", Uuid::new_v4().simple(), tag);
+        assert_eq!(visitor_from_request(&request_with(&forged), SECRET), None);
+        assert_eq!(visitor_from_request(&request_with("garbage"), SECRET), None);
+        assert_eq!(
+            visitor_from_request(&TestRequest::default().to_http_request(), SECRET),
+            None
+        );
+    }
+
+    #[test]
+    fn teaser_is_cut_by_characters() {
+        let content = content("Привет, мир");
+        assert_eq!(teaser(&content, 6)["teaser"], "Привет…");
+        assert_eq!(teaser(&content, 100)["teaser"], "Привет, мир");
+        // chars = 0 — только заголовок
+        assert_eq!(teaser(&content, 0)["teaser"], "");
+    }
+}
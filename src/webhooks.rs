@@ -0,0 +1,987 @@
+// src/webhooks.rs
+// Входящие события платёжного провайдера. Подпись проверяется по схеме Stripe:
+// заголовок "t=<unix time>,v1=<hex hmac-sha256>" от строки "<t>.<тело запроса>".
+use crate::config::Config;
+use crate::db;
+use crate::invoice;
+use crate::models::{Gift, Payment};
+use crate::money::Money;
+use crate::payment::{self, ChargeStatus, PaymentError, PaymentGateway};
+use crate::paywall;
+use crate::renewal;
+use actix_web::{HttpRequest, HttpResponse, post, web};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use moka::future::Cache;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SIGNATURE_HEADER: &str = "Stripe-Signature";
+
+#[derive(Deserialize)]
+struct PaymentEvent {
+    id: String,
+    #[serde(rename = "type")]
+    event_type: String,
+    data: PaymentEventData,
+}
+
+#[derive(Deserialize)]
+struct PaymentEventData {
+    object: serde_json::Value,
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(payment_webhook);
+}
+
+fn verify_signature(
+    secret: &str,
+    header: &str,
+    payload: &[u8],
+    now: i64,
+    tolerance_secs: i64,
+) -> Result<(), &'static str> {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.push(value),
+            _ => {}
+        }
+    }
+
+    let timestamp = timestamp.ok_or("missing timestamp")?;
+    // Защита от повторного воспроизведения старых запросов
+    if (now - timestamp).abs() > tolerance_secs {
+        return Err("timestamp outside tolerance");
+    }
+
+    for signature in signatures {
+        let Ok(expected) = hex::decode(signature) else {
+            continue;
+        };
+        let mut mac =
+            HmacSha256::new_from_slice(secret.as_bytes()).map_err(|_| "invalid secret")?;
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(payload);
+        // verify_slice сравнивает за постоянное время
+        if mac.verify_slice(&expected).is_ok() {
+            return Ok(());
+        }
+    }
+    Err("no matching signature")
+}
+
+#[post("/webhooks/payments")]
+pub async fn payment_webhook(
+    pool: web::Data<sqlx::PgPool>,
+    config: web::Data<Config>,
+    cache: web::Data<Cache<String, serde_json::Value>>,
+    gateway: web::Data<dyn PaymentGateway>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, actix_web::Error> {
+    if config.payment_webhook_secret.is_empty() {
+        tracing::warn!("Payment webhook received but PAYMENT_WEBHOOK_SECRET is not set");
+        return Ok(HttpResponse::ServiceUnavailable()
+            .json(json!({"error": "Webhooks are not configured"})));
+    }
+
+    let signature = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    if let Err(reason) = verify_signature(
+        &config.payment_webhook_secret,
+        signature,
+        &body,
+        Utc::now().timestamp(),
+        config.webhook_tolerance_secs,
+    ) {
+        tracing::warn!("Rejected payment webhook: {}", reason);
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid signature"})));
+    }
+
+    let payload: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(value) => value,
+        Err(e) => {
+            tracing::warn!("Malformed payment webhook payload: {}", e);
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Malformed event"})));
+        }
+    };
+    let event: PaymentEvent = match serde_json::from_value(payload.clone()) {
+        Ok(event) => event,
+        Err(e) => {
+            tracing::warn!("Malformed payment webhook event: {}", e);
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Malformed event"})));
+        }
+    };
+
+    if let Err(e) = db::store_payment_event(&pool, &event.id, &event.event_type, &payload).await {
+        tracing::error!("Failed to store payment event {}: {}", event.id, e);
+        return Ok(
+            HttpResponse::InternalServerError().json(json!({"error": "Internal server error"}))
+        );
+    }
+
+    match process_event(&pool, gateway.get_ref(), &config, &event).await {
+        Ok(EventOutcome::Duplicate) => {
+            tracing::info!("Payment event {} already processed", event.id);
+            Ok(HttpResponse::Ok().json(json!({"received": true, "duplicate": true})))
+        }
+        Ok(EventOutcome::Processed(affected_user)) => {
+            if let Some(user_id) = affected_user {
+                paywall::invalidate_user_cache(&cache, user_id);
+            }
+            Ok(HttpResponse::Ok().json(json!({"received": true})))
+        }
+        Err(e) => {
+            // Провайдер повторит доставку; событие останется необработанным
+            tracing::error!("Failed to process payment event {}: {}", event.id, e);
+            if let Err(e) = db::record_payment_event_error(&pool, &event.id, &e.to_string()).await {
+                tracing::error!("Failed to record payment event error: {}", e);
+            }
+            Ok(HttpResponse::InternalServerError()
+                .json(json!({"error": "Event processing failed"})))
+        }
+    }
+}
+
+enum EventOutcome {
+    Duplicate,
+    Processed(Option<Uuid>), // Пользователь, чей доступ мог измениться
+}
+
+// Обработка в одной транзакции с блокировкой события: параллельные повторные
+// доставки ждут завершения первой и видят её как уже обработанную
+async fn process_event(
+    pool: &sqlx::PgPool,
+    gateway: &dyn PaymentGateway,
+    config: &Config,
+    event: &PaymentEvent,
+) -> Result<EventOutcome, PaymentError> {
+    let mut tx = pool.begin().await?;
+    if db::lock_payment_event(&mut tx, &event.id).await?.is_some() {
+        return Ok(EventOutcome::Duplicate);
+    }
+
+    let object = &event.data.object;
+    let affected_user = match event.event_type.as_str() {
+        "payment_intent.succeeded" => {
+            handle_payment_succeeded(&mut tx, gateway, config, object).await?
+        }
+        "payment_intent.payment_failed" => handle_payment_failed(&mut tx, object).await?,
+        "charge.refunded" => handle_charge_refunded(&mut tx, object).await?,
+        "charge.dispute.created" => handle_dispute_created(&mut tx, object).await?,
+        "charge.dispute.closed" => handle_dispute_closed(&mut tx, object).await?,
+        other => {
+            tracing::debug!("Ignoring payment event type {}", other);
+            None
+        }
+    };
+
+    db::mark_payment_event_processed(&mut tx, &event.id).await?;
+    tx.commit().await?;
+
+    if event.event_type == "payment_intent.succeeded" {
+        refund_unapplied_after_commit(pool, gateway, object).await;
+    }
+    Ok(EventOutcome::Processed(affected_user))
+}
+
+// Возврат у провайдера — только после фиксации: при сбое фиксации повтор события
+// не упрётся в уже сделанный возврат. Событие уже обработано, поэтому ошибки здесь
+// не возвращаются провайдеру; неудачный возврат повторит воркер
+async fn refund_unapplied_after_commit(
+    pool: &sqlx::PgPool,
+    gateway: &dyn PaymentGateway,
+    object: &serde_json::Value,
+) {
+    let Ok(charge_id) = charge_id(object) else {
+        return;
+    };
+    let pending = match db::get_pending_refund_by_charge(pool, charge_id).await {
+        Ok(Some(pending)) => pending,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::error!("Failed to fetch pending refund for {}: {}", charge_id, e);
+            return;
+        }
+    };
+    if let Err(e) = payment::complete_pending_refund(pool, gateway, &pending).await {
+        tracing::error!(
+            "Refund of unapplied payment {} failed, will retry: {}",
+            pending.payment_id,
+            e
+        );
+    }
+}
+
+// Идентификатор списания у провайдера из объекта события
+fn charge_id(object: &serde_json::Value) -> Result<&str, PaymentError> {
+    object
+        .get("id")
+        .and_then(|id| id.as_str())
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| PaymentError::InvalidResponse("event object without charge id".to_string()))
+}
+
+// Платёж списания: по идентификатору у провайдера, а если ответ на запрос
+// списания потерян (таймаут, 5xx) — по payment_id из metadata
+async fn lock_charge_payment(
+    conn: &mut PgConnection,
+    charge_id: &str,
+    object: &serde_json::Value,
+) -> Result<Option<Payment>, sqlx::Error> {
+    if let Some(payment) = db::lock_payment_by_reference(conn, charge_id).await? {
+        return Ok(Some(payment));
+    }
+    let payment_id = object
+        .pointer("/metadata/payment_id")
+        .and_then(|id| id.as_str())
+        .and_then(|id| Uuid::parse_str(id).ok());
+    match payment_id {
+        Some(payment_id) => db::lock_unreferenced_payment(conn, payment_id).await,
+        None => Ok(None),
+    }
+}
+
+async fn user_for_object(
+    conn: &mut PgConnection,
+    object: &serde_json::Value,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    match object.get("customer").and_then(|c| c.as_str()) {
+        Some(customer_id) => db::get_user_id_by_payment_customer(conn, customer_id).await,
+        None => Ok(None),
+    }
+}
+
+// Асинхронно завершившееся списание проводится по журналу и применяется к подписке
+// платежа; списание, которое не к чему применить, возвращается
+async fn handle_payment_succeeded(
+    conn: &mut PgConnection,
+    gateway: &dyn PaymentGateway,
+    config: &Config,
+    object: &serde_json::Value,
+) -> Result<Option<Uuid>, PaymentError> {
+    let charge_id = charge_id(object)?;
+    let payment = lock_charge_payment(conn, charge_id, object).await?;
+    if payment.as_ref().is_some_and(|p| p.status != "pending") {
+        return Ok(None);
+    }
+    // Списание, о котором у нас нет записи (повтор в кабинете провайдера), закрывает
+    // просроченную подписку клиента
+    let past_due_subscription = match (&payment, user_for_object(conn, object).await?) {
+        (None, Some(user_id)) => db::lock_active_subscription_for_user(conn, user_id)
+            .await?
+            .filter(|s| s.billing_status == "past_due"),
+        _ => None,
+    };
+    if payment.is_none() && past_due_subscription.is_none() {
+        return Ok(None);
+    }
+
+    // Статус перепроверяется у провайдера, а не берётся из тела события
+    if gateway.fetch_status(charge_id).await? != ChargeStatus::Succeeded {
+        tracing::warn!(
+            "Charge {} is not succeeded at provider, ignoring",
+            charge_id
+        );
+        return Ok(None);
+    }
+
+    if let Some(payment) = payment {
+        db::transition_payment(conn, payment.id, "succeeded", Some(charge_id), None).await?;
+        payment::record_settled_charge(conn, &payment).await?;
+        invoice::issue_invoice(conn, config, &payment).await?;
+        return apply_settled_payment(conn, &payment, charge_id).await;
+    }
+
+    let Some(subscription) = past_due_subscription else {
+        return Ok(None);
+    };
+    let plan_id = subscription
+        .scheduled_plan_id
+        .clone()
+        .unwrap_or_else(|| subscription.plan_id.clone());
+    let Some(duration_days) = paywall::plan_period_days(&plan_id) else {
+        return Ok(None);
+    };
+    let expires_at =
+        db::extend_subscription(conn, subscription.id, &plan_id, duration_days).await?;
+    db::create_notification(
+        conn,
+        subscription.user_id,
+        "renewal_recovered",
+        &json!({"subscription_id": subscription.id, "expires_at": expires_at}),
+    )
+    .await?;
+    tracing::info!(
+        "Subscription {} recovered via webhook, expires at {}",
+        subscription.id,
+        expires_at
+    );
+    Ok(Some(subscription.user_id))
+}
+
+// Подписка, которую оплачивает подтверждённый платёж: покупка ждёт активации,
+// продление — продления или восстановления после истечения; подарок встаёт в
+// очередь доставки. Если применить оплату не к чему (подписка отменена, куплена
+// другая, повышение не состоялось), деньги возвращаются
+async fn apply_settled_payment(
+    conn: &mut PgConnection,
+    payment: &Payment,
+    charge_id: &str,
+) -> Result<Option<Uuid>, PaymentError> {
+    if payment.kind == "gift" {
+        let Some(gift) = db::activate_awaiting_gift(conn, payment.id).await? else {
+            return refund_unapplied(conn, payment, charge_id, "no_gift").await;
+        };
+        db::create_notification(
+            conn,
+            payment.user_id,
+            "gift_payment_confirmed",
+            &json!({"gift_id": gift.id, "deliver_at": gift.deliver_at}),
+        )
+        .await?;
+        tracing::info!(
+            "Gift {} payment confirmed, delivery at {}",
+            gift.id,
+            gift.deliver_at
+        );
+        return Ok(None);
+    }
+
+    let subscription = match payment.subscription_id {
+        Some(subscription_id) => db::lock_subscription(conn, subscription_id).await?,
+        None => None,
+    };
+    let Some(subscription) = subscription else {
+        return refund_unapplied(conn, payment, charge_id, "no_subscription").await;
+    };
+
+    let other_active = db::lock_active_subscription_for_user(conn, payment.user_id)
+        .await?
+        .filter(|s| s.id != subscription.id);
+    match payment.kind.as_str() {
+        "purchase" if subscription.billing_status == "awaiting_payment" => {
+            if other_active.is_some() {
+                db::expire_subscription(conn, subscription.id, "superseded").await?;
+                return refund_unapplied(conn, payment, charge_id, "superseded").await;
+            }
+            let expires_at = db::activate_awaiting_subscription(conn, subscription.id).await?;
+            db::create_notification(
+                conn,
+                payment.user_id,
+                "purchase_confirmed",
+                &json!({"subscription_id": subscription.id, "expires_at": expires_at}),
+            )
+            .await?;
+            tracing::info!(
+                "Subscription {} activated via webhook, expires at {}",
+                subscription.id,
+                expires_at
+            );
+            Ok(Some(payment.user_id))
+        }
+        "renewal" if subscription.is_active && subscription.billing_status != "expired" => {
+            // Ключ другого периода: воркер продления уже применил этот платёж,
+            // узнав статус у провайдера раньше вебхука
+            if subscription.billing_status != "past_due"
+                && renewal::renewal_key(&subscription) != payment.idempotency_key
+            {
+                tracing::info!(
+                    "Renewal payment {} already applied to subscription {}",
+                    payment.id,
+                    subscription.id
+                );
+                return Ok(None);
+            }
+            let plan_id = subscription
+                .scheduled_plan_id
+                .clone()
+                .unwrap_or_else(|| subscription.plan_id.clone());
+            let duration_days = paywall::plan_period_days(&plan_id).unwrap_or_default();
+            let expires_at =
+                db::extend_subscription(conn, subscription.id, &plan_id, duration_days).await?;
+            db::create_notification(
+                conn,
+                payment.user_id,
+                "renewal_recovered",
+                &json!({"subscription_id": subscription.id, "expires_at": expires_at}),
+            )
+            .await?;
+            tracing::info!(
+                "Subscription {} renewed via webhook, expires at {}",
+                subscription.id,
+                expires_at
+            );
+            Ok(Some(payment.user_id))
+        }
+        "upgrade" if subscription.pending_plan_id.is_some() && subscription.is_active => {
+            let Some(updated) = db::apply_pending_upgrade(conn, subscription.id).await? else {
+                return Ok(None);
+            };
+            db::create_notification(
+                conn,
+                payment.user_id,
+                "upgrade_confirmed",
+                &json!({"subscription_id": updated.id, "plan_id": updated.plan_id}),
+            )
+            .await?;
+            tracing::info!(
+                "Subscription {} upgraded to {} via webhook",
+                updated.id,
+                updated.plan_id
+            );
+            Ok(Some(payment.user_id))
+        }
+        // Последняя попытка продления была ещё в обработке, когда попытки закончились
+        "renewal"
+            if subscription.expiration_reason.as_deref() == Some("payment_failed")
+                && other_active.is_none() =>
+        {
+            let plan_id = subscription
+                .scheduled_plan_id
+                .clone()
+                .unwrap_or_else(|| subscription.plan_id.clone());
+            let duration_days = paywall::plan_period_days(&plan_id).unwrap_or_default();
+            let expires_at =
+                db::restore_subscription(conn, subscription.id, &plan_id, duration_days).await?;
+            db::create_notification(
+                conn,
+                payment.user_id,
+                "subscription_restored",
+                &json!({"subscription_id": subscription.id, "expires_at": expires_at}),
+            )
+            .await?;
+            tracing::info!(
+                "Subscription {} restored via webhook, expires at {}",
+                subscription.id,
+                expires_at
+            );
+            Ok(Some(payment.user_id))
+        }
+        _ => {
+            if payment.kind == "upgrade" {
+                db::void_pending_upgrade(conn, subscription.id, payment.id).await?;
+            }
+            refund_unapplied(conn, payment, charge_id, "not_applicable").await
+        }
+    }
+}
+
+// Возврат подтверждённого платежа, который не дал доступа. Здесь он только
+// записывается: деньги возвращаются после фиксации транзакции (см. process_event)
+async fn refund_unapplied(
+    conn: &mut PgConnection,
+    payment: &Payment,
+    charge_id: &str,
+    reason: &str,
+) -> Result<Option<Uuid>, PaymentError> {
+    db::create_pending_refund(conn, payment.id, charge_id, reason).await?;
+    tracing::warn!(
+        "Payment {} ({}) cannot be applied, refund scheduled: {}",
+        payment.id,
+        payment.kind,
+        reason
+    );
+    Ok(Some(payment.user_id))
+}
+
+async fn handle_payment_failed(
+    conn: &mut PgConnection,
+    object: &serde_json::Value,
+) -> Result<Option<Uuid>, PaymentError> {
+    let message = object
+        .pointer("/last_payment_error/message")
+        .and_then(|m| m.as_str())
+        .unwrap_or("Payment failed");
+    if let Some(charge_id) = object.get("id").and_then(|id| id.as_str())
+        && let Some(payment) = lock_charge_payment(conn, charge_id, object).await?
+        && payment.status == "pending"
+    {
+        db::transition_payment(conn, payment.id, "failed", Some(charge_id), Some(message)).await?;
+        // Покупка, ждавшая подтверждения, не состоялась
+        db::void_awaiting_gift(conn, payment.id).await?;
+        if let Some(subscription_id) = payment.subscription_id
+            && let Some(subscription) = db::lock_subscription(conn, subscription_id).await?
+        {
+            if subscription.billing_status == "awaiting_payment" {
+                db::expire_subscription(conn, subscription.id, "payment_failed").await?;
+            }
+            if payment.kind == "upgrade" {
+                db::void_pending_upgrade(conn, subscription.id, payment.id).await?;
+            }
+        }
+    }
+
+    let Some(user_id) = user_for_object(conn, object).await? else {
+        return Ok(None);
+    };
+    db::create_notification(
+        conn,
+        user_id,
+        "payment_failed",
+        &json!({"charge_id": object.get("id"), "message": message}),
+    )
+    .await?;
+    Ok(None)
+}
+
+// Возврат, сделанный в кабинете провайдера, отражается в журнале. amount_refunded —
+// накопленная сумма, поэтому проводится только разница с уже учтённым.
+// Возвращает платёж, если возврат новый
+async fn record_provider_refund(
+    conn: &mut PgConnection,
+    object: &serde_json::Value,
+) -> Result<Option<Payment>, PaymentError> {
+    let Some(reference) = object.get("payment_intent").and_then(|r| r.as_str()) else {
+        return Ok(None);
+    };
+    let Some(payment) = db::lock_payment_by_reference(conn, reference).await? else {
+        tracing::warn!("Refund for unknown payment {}", reference);
+        return Ok(None);
+    };
+    let amount = payment.amount()?;
+    let refunded_total = Money::new(
+        object
+            .get("amount_refunded")
+            .and_then(|a| a.as_i64())
+            .unwrap_or(0),
+        amount.currency(),
+    )
+    .min(amount)?;
+    let refund_reference = object
+        .get("id")
+        .and_then(|id| id.as_str())
+        .unwrap_or(reference);
+    let delta = refunded_total.checked_sub(payment.refunded()?)?;
+    // Возврат уже учтён — он сделан нами (поддержкой или компенсацией), и судьбу
+    // подписки решил тот, кто его делал
+    if !delta.is_positive() {
+        return Ok(None);
+    }
+    payment::record_refund(conn, &payment, delta, refund_reference).await?;
+    Ok(Some(payment))
+}
+
+// Что возврат или спор по оплате подарка делает с самим подарком
+#[derive(Debug, PartialEq)]
+enum GiftReversal {
+    // Код ещё не погашен — аннулируется
+    Void,
+    // Погашение создало новую подписку — она прекращается
+    Expire(Uuid),
+    // Подарок продлил уже оплаченную подписку или больше не действует
+    Keep,
+}
+
+fn gift_reversal(gift: &Gift, payment: &Payment) -> GiftReversal {
+    match gift.status.as_str() {
+        "scheduled" | "sending" | "delivered" => GiftReversal::Void,
+        // Созданная подарком подписка привязывается к его оплате при погашении
+        "redeemed" => match gift.subscription_id {
+            Some(id) if payment.subscription_id == Some(id) => GiftReversal::Expire(id),
+            _ => GiftReversal::Keep,
+        },
+        _ => GiftReversal::Keep,
+    }
+}
+
+// Подписка, которую прекращает возврат или спор по платежу. Для оплаты подарка —
+// созданная им подписка; неиспользованный подарок аннулируется
+async fn reversed_subscription_id(
+    conn: &mut PgConnection,
+    payment: &Payment,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    if payment.kind != "gift" {
+        return Ok(payment.subscription_id);
+    }
+    let Some(gift) = db::lock_gift_by_payment(conn, payment.id).await? else {
+        return Ok(None);
+    };
+    match gift_reversal(&gift, payment) {
+        GiftReversal::Void => {
+            db::void_unredeemed_gift(conn, payment.id).await?;
+            tracing::warn!("Gift {} voided: payment {} reversed", gift.id, payment.id);
+            Ok(None)
+        }
+        GiftReversal::Expire(subscription_id) => Ok(Some(subscription_id)),
+        GiftReversal::Keep => Ok(None),
+    }
+}
+
+// Полный возврат из кабинета провайдера прекращает подписку, оплаченную этим платежом
+async fn handle_charge_refunded(
+    conn: &mut PgConnection,
+    object: &serde_json::Value,
+) -> Result<Option<Uuid>, PaymentError> {
+    let Some(payment) = record_provider_refund(conn, object).await? else {
+        return Ok(None);
+    };
+
+    let fully_refunded = object
+        .get("refunded")
+        .and_then(|r| r.as_bool())
+        .unwrap_or(false);
+    if !fully_refunded {
+        return Ok(None);
+    }
+    let subscription = match reversed_subscription_id(conn, &payment).await? {
+        Some(subscription_id) => db::lock_subscription(conn, subscription_id)
+            .await?
+            .filter(|s| s.is_active),
+        None => None,
+    };
+    let Some(subscription) = subscription else {
+        return Ok(None);
+    };
+    let user_id = subscription.user_id;
+    db::expire_subscription(conn, subscription.id, "refunded").await?;
+    db::create_notification(
+        conn,
+        user_id,
+        "subscription_expired",
+        &json!({"subscription_id": subscription.id, "reason": "refunded"}),
+    )
+    .await?;
+    Ok(Some(user_id))
+}
+
+// Сумма спора в валюте платежа, не больше суммы платежа
+fn dispute_amount(payment: &Payment, object: &serde_json::Value) -> Result<Money, PaymentError> {
+    let amount = payment.amount()?;
+    let disputed = object
+        .get("amount")
+        .and_then(|a| a.as_i64())
+        .unwrap_or(amount.amount_minor());
+    Ok(Money::new(disputed, amount.currency()).min(amount)?)
+}
+
+// Спор по платежу (chargeback): деньги списаны с нас, доступ приостанавливается,
+// аккаунт помечается для проверки поддержкой
+async fn handle_dispute_created(
+    conn: &mut PgConnection,
+    object: &serde_json::Value,
+) -> Result<Option<Uuid>, PaymentError> {
+    let payment = match object.get("payment_intent").and_then(|r| r.as_str()) {
+        Some(reference) => db::lock_payment_by_reference(conn, reference).await?,
+        None => None,
+    };
+    let user_id = match &payment {
+        Some(payment) => Some(payment.user_id),
+        None => user_for_object(conn, object).await?,
+    };
+    let Some(user_id) = user_id else {
+        tracing::warn!("Dispute {:?} without known customer", object.get("id"));
+        return Ok(None);
+    };
+
+    // Приостанавливается подписка, оплаченная оспоренным платежом; для неизвестного
+    // платежа — активная подписка клиента
+    let subscription_id = match &payment {
+        Some(payment) => reversed_subscription_id(conn, payment).await?,
+        None => None,
+    };
+    let payment_known = payment.is_some();
+    if let Some(payment) = payment.filter(|p| p.status != "disputed") {
+        let amount = dispute_amount(&payment, object)?;
+        db::transition_payment(conn, payment.id, "disputed", None, None).await?;
+        db::append_ledger_entry(
+            conn,
+            user_id,
+            Some(payment.id),
+            "chargeback",
+            amount.checked_neg()?,
+            &format!(
+                "Chargeback {}",
+                object
+                    .get("id")
+                    .and_then(|id| id.as_str())
+                    .unwrap_or_default()
+            ),
+        )
+        .await?;
+    }
+    db::flag_user(conn, user_id, "chargeback").await?;
+    tracing::warn!("User {} flagged due to dispute", user_id);
+
+    let subscription = match subscription_id {
+        Some(subscription_id) => db::lock_subscription(conn, subscription_id)
+            .await?
+            .filter(|s| s.is_active),
+        None if !payment_known => db::lock_active_subscription_for_user(conn, user_id).await?,
+        None => None,
+    };
+    let Some(subscription) = subscription else {
+        return Ok(Some(user_id));
+    };
+    // Подписка из подарка принадлежит получателю, а не плательщику
+    db::expire_subscription(conn, subscription.id, "disputed").await?;
+    db::create_notification(
+        conn,
+        subscription.user_id,
+        "subscription_expired",
+        &json!({"subscription_id": subscription.id, "reason": "disputed"}),
+    )
+    .await?;
+    tracing::warn!("Subscription {} suspended due to dispute", subscription.id);
+    Ok(Some(subscription.user_id))
+}
+
+// Выигранный спор возвращает деньги: сторнирующая проводка и прежний статус платежа.
+// Пометку с аккаунта снимает поддержка после проверки
+async fn handle_dispute_closed(
+    conn: &mut PgConnection,
+    object: &serde_json::Value,
+) -> Result<Option<Uuid>, PaymentError> {
+    if object.get("status").and_then(|s| s.as_str()) != Some("won") {
+        return Ok(None);
+    }
+    let Some(reference) = object.get("payment_intent").and_then(|r| r.as_str()) else {
+        return Ok(None);
+    };
+    let Some(payment) = db::lock_payment_by_reference(conn, reference)
+        .await?
+        .filter(|p| p.status == "disputed")
+    else {
+        return Ok(None);
+    };
+
+    let amount = dispute_amount(&payment, object)?;
+    let status = if payment.refunded_minor > 0 {
+        "partially_refunded"
+    } else {
+        "succeeded"
+    };
+    db::transition_payment(conn, payment.id, status, None, None).await?;
+    db::append_ledger_entry(
+        conn,
+        payment.user_id,
+        Some(payment.id),
+        "chargeback_reversal",
+        amount,
+        &format!(
+            "Dispute won {}",
+            object
+                .get("id")
+                .and_then(|id| id.as_str())
+                .unwrap_or_default()
+        ),
+    )
+    .await?;
+    tracing::info!("Dispute on payment {} won", payment.id);
+    Ok(Some(payment.user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "whsec_test";
+    const PAYLOAD: &[u8] = br#"{"type":"payment.succeeded"}"#;
+
+    fn sign(secret: &str, timestamp: i64, payload: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(payload);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let header = format!("t=1000,v1={}", sign(SECRET, 1000, PAYLOAD));
+        assert_eq!(
+            verify_signature(SECRET, &header, PAYLOAD, 1100, 300),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn accepts_any_matching_signature_during_rotation() {
+        let header = format!(
+            "t=1000, v1={}, v1={}",
+            sign("whsec_old", 1000, PAYLOAD),
+            sign(SECRET, 1000, PAYLOAD)
+        );
+        assert_eq!(
+            verify_signature(SECRET, &header, PAYLOAD, 1000, 300),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_expired_timestamp() {
+        let header = format!("t=1000,v1={}", sign(SECRET, 1000, PAYLOAD));
+        assert_eq!(
+            verify_signature(SECRET, &header, PAYLOAD, 1301, 300),
+            Err("timestamp outside tolerance")
+        );
+        assert_eq!(
+            verify_signature(SECRET, &header, PAYLOAD, 699, 300),
+            Err("timestamp outside tolerance")
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_payload_or_timestamp() {
+        let signature = sign(SECRET, 1000, PAYLOAD);
+        let header = format!("t=1000,v1={}", signature);
+        assert_eq!(
+            verify_signature(SECRET, &header, br#"{"type":"payment.failed"}"#, 1000, 300),
+            Err("no matching signature")
+        );
+        // Подпись от другой метки времени не подходит
+        let header = format!("t=1001,v1={}", signature);
+        assert_eq!(
+            verify_signature(SECRET, &header, PAYLOAD, 1000, 300),
+            Err("no matching signature")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_header() {
+        let signature = sign(SECRET, 1000, PAYLOAD);
+        assert_eq!(
+            verify_signature(SECRET, &format!("v1={}", signature), PAYLOAD, 1000, 300),
+            Err("missing timestamp")
+        );
+        assert_eq!(
+            verify_signature(SECRET, "t=1000,v1=zz", PAYLOAD, 1000, 300),
+            Err("no matching signature")
+        );
+        assert_eq!(
+            verify_signature(
+                "whsec_other",
+                &format!("t=1000,v1={}", signature),
+                PAYLOAD,
+                1000,
+                300
+            ),
+            Err("no matching signature")
+        );
+    }
+
+    fn payment(amount_minor: i64) -> Payment {
+        Payment {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            subscription_id: None,
+            kind: "purchase".to_string(),
+            amount_minor,
+            refunded_minor: 0,
+            currency: "USD".to_string(),
+            status: "succeeded".to_string(),
+            provider_reference: Some("pi_1".to_string()),
+            failure_reason: None,
+            description: "Premium".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            net_minor: amount_minor,
+            tax_minor: 0,
+            tax_rate_bps: 0,
+            tax_country: None,
+            tax_treatment: "not_taxed".to_string(),
+            tax_rate_version: None,
+            idempotency_key: "key".to_string(),
+        }
+    }
+
+    fn gift_payment(gift: &Gift) -> Payment {
+        Payment {
+            id: gift.payment_id,
+            kind: "gift".to_string(),
+            ..payment(1999)
+        }
+    }
+
+    #[test]
+    fn refunded_gift_is_voided_until_redeemed() {
+        for status in ["scheduled", "sending", "delivered"] {
+            let gift = Gift {
+                status: status.to_string(),
+                ..Gift::test_fixture("premium")
+            };
+            assert_eq!(
+                gift_reversal(&gift, &gift_payment(&gift)),
+                GiftReversal::Void,
+                "{}",
+                status
+            );
+        }
+        // Неоплаченный или уже аннулированный подарок не трогается
+        for status in ["awaiting_payment", "voided"] {
+            let gift = Gift {
+                status: status.to_string(),
+                ..Gift::test_fixture("premium")
+            };
+            assert_eq!(
+                gift_reversal(&gift, &gift_payment(&gift)),
+                GiftReversal::Keep
+            );
+        }
+    }
+
+    #[test]
+    fn disputed_redeemed_gift_expires_the_subscription_it_created() {
+        let subscription_id = Uuid::new_v4();
+        let gift = Gift {
+            status: "redeemed".to_string(),
+            subscription_id: Some(subscription_id),
+            ..Gift::test_fixture("premium")
+        };
+        let mut payment = gift_payment(&gift);
+        payment.subscription_id = Some(subscription_id);
+        assert_eq!(
+            gift_reversal(&gift, &payment),
+            GiftReversal::Expire(subscription_id)
+        );
+        // Подарок продлил собственную подписку получателя: она оплачена отдельно
+        payment.subscription_id = None;
+        assert_eq!(gift_reversal(&gift, &payment), GiftReversal::Keep);
+    }
+
+    #[test]
+    fn event_without_charge_id_is_rejected() {
+        assert_eq!(charge_id(&json!({"id": "pi_1"})).unwrap(), "pi_1");
+        for object in [json!({}), json!({"id": ""}), json!({"id": 42})] {
+            assert!(matches!(
+                charge_id(&object),
+                Err(PaymentError::InvalidResponse(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn dispute_amount_defaults_to_payment_and_is_capped() {
+        let payment = payment(999);
+        assert_eq!(
+            dispute_amount(&payment, &json!({})).unwrap().amount_minor(),
+            999
+        );
+        assert_eq!(
+            dispute_amount(&payment, &json!({"amount": 500}))
+                .unwrap()
+                .amount_minor(),
+            500
+        );
+        // Спор не может превышать сумму платежа
+        assert_eq!(
+            dispute_amount(&payment, &json!({"amount": 5000}))
+                .unwrap()
+                .amount_minor(),
+            999
+        );
+    }
+}